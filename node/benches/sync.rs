@@ -0,0 +1,214 @@
+//! Measures blocks per second applied by [Node::apply_block] on blocks whose
+//! inputs spend no spaces, with and without the outpoint filter. Backend reads
+//! are counted to show how many lookups the filter avoids.
+//!
+//! Run with `cargo bench -p spaced --bench sync`. The workload can be tuned with
+//! `BENCH_BLOCKS`, `BENCH_TXS` (per block) and `BENCH_TRACKED` (outpoints in the state).
+
+use std::{env, fs, path::PathBuf, sync::Arc, time::Instant};
+
+use protocol::{
+    bitcoin::{
+        absolute::LockTime,
+        block::{Header, Version as BlockVersion},
+        hashes::Hash,
+        transaction::Version,
+        Amount, Block, BlockHash, CompactTarget, OutPoint, ScriptBuf, Sequence, Transaction, TxIn,
+        TxMerkleNode, TxOut, Txid, Witness,
+    },
+    constants::{ChainAnchor, ROLLOUT_BLOCK_INTERVAL},
+    hasher::OutpointKey,
+    SpaceOut,
+};
+use spaced::{
+    backend::{BackendCounters, InstrumentedBackend, SpaceDbBackend, DEFAULT_CACHE_SIZE},
+    node::Node,
+    store::{ChainState, LiveStore, Sha256, Store},
+};
+
+struct Workload {
+    blocks: u32,
+    txs: usize,
+    tracked: usize,
+}
+
+/// xorshift64 to generate deterministic txids
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn txid(&mut self) -> Txid {
+        let mut bytes = [0u8; 32];
+        for chunk in bytes.chunks_mut(8) {
+            chunk.copy_from_slice(&self.next().to_le_bytes());
+        }
+        Txid::from_byte_array(bytes)
+    }
+}
+
+fn env_or(name: &str, default: usize) -> usize {
+    env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+fn tx(inputs: Vec<OutPoint>) -> Transaction {
+    Transaction {
+        version: Version::TWO,
+        lock_time: LockTime::ZERO,
+        input: inputs
+            .into_iter()
+            .map(|previous_output| TxIn {
+                previous_output,
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::new(),
+            })
+            .collect(),
+        output: vec![TxOut {
+            value: Amount::from_sat(1000),
+            script_pubkey: ScriptBuf::new(),
+        }],
+    }
+}
+
+fn blocks(workload: &Workload, genesis: &ChainAnchor) -> Vec<Block> {
+    let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+    let mut prev_blockhash = genesis.hash;
+    let mut blocks = Vec::with_capacity(workload.blocks as usize);
+    for nonce in 0..workload.blocks {
+        let mut txdata = vec![tx(vec![OutPoint::null()])];
+        for _ in 0..workload.txs {
+            txdata.push(tx(vec![
+                OutPoint::new(rng.txid(), 0),
+                OutPoint::new(rng.txid(), 1),
+            ]));
+        }
+        let block = Block {
+            header: Header {
+                version: BlockVersion::ONE,
+                prev_blockhash,
+                merkle_root: TxMerkleNode::all_zeros(),
+                time: nonce,
+                bits: CompactTarget::from_consensus(0x207fffff),
+                nonce,
+            },
+            txdata,
+        };
+        prev_blockhash = block.block_hash();
+        blocks.push(block);
+    }
+    blocks
+}
+
+fn open_chain(
+    path: &PathBuf,
+    workload: &Workload,
+    genesis: &ChainAnchor,
+) -> (LiveStore, Arc<BackendCounters>) {
+    if path.exists() {
+        fs::remove_file(path).expect("remove old bench db");
+    }
+    let backend = InstrumentedBackend::new(
+        SpaceDbBackend::open(path.clone(), DEFAULT_CACHE_SIZE).expect("open store"),
+    );
+    let counters = backend.counters();
+    let store = Store::new(backend);
+    let chain = LiveStore {
+        state: store.begin(genesis).expect("begin"),
+        store,
+    };
+
+    let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+    for _ in 0..workload.tracked {
+        let outpoint = OutPoint::new(rng.txid(), 0);
+        chain.state.insert_spaceout(
+            OutpointKey::from_outpoint::<Sha256>(outpoint),
+            SpaceOut {
+                n: 0,
+                space: None,
+                value: Amount::from_sat(1000),
+                script_pubkey: ScriptBuf::new(),
+            },
+        );
+    }
+    let tx = chain.store.write().expect("write");
+    chain.state.commit(*genesis, tx).expect("commit");
+    (chain, counters)
+}
+
+fn run(
+    name: &str,
+    (mut chain, counters): (LiveStore, Arc<BackendCounters>),
+    blocks: &[Block],
+    genesis: &ChainAnchor,
+) -> f64 {
+    let mut node = Node::new(false);
+    let reads = counters.reads();
+    let start = Instant::now();
+    for (i, block) in blocks.iter().enumerate() {
+        let height = genesis.height + 1 + i as u32;
+        let hash = block.block_hash();
+        node.apply_block(&mut chain, height, hash, block.clone(), false)
+            .expect("apply block");
+        if height % ROLLOUT_BLOCK_INTERVAL == 0 {
+            let tx = chain.store.write().expect("write");
+            chain
+                .state
+                .commit(ChainAnchor { hash, height }, tx)
+                .expect("commit");
+        }
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+    let rate = blocks.len() as f64 / elapsed;
+    println!(
+        "{:<10} {} blocks in {:.2}s: {:.1} blocks/s, {} backend reads",
+        name,
+        blocks.len(),
+        elapsed,
+        rate,
+        counters.reads() - reads
+    );
+    rate
+}
+
+fn main() {
+    let workload = Workload {
+        blocks: env_or("BENCH_BLOCKS", 288) as u32,
+        txs: env_or("BENCH_TXS", 2000),
+        tracked: env_or("BENCH_TRACKED", 100_000),
+    };
+    let genesis = ChainAnchor {
+        hash: BlockHash::all_zeros(),
+        height: 0,
+    };
+    println!(
+        "{} blocks with {} txs each, {} tracked outpoints",
+        workload.blocks, workload.txs, workload.tracked
+    );
+
+    let blocks = blocks(&workload, &genesis);
+    let dir = env::temp_dir().join("spaced-bench");
+    fs::create_dir_all(&dir).expect("create bench dir");
+
+    let chain = open_chain(&dir.join("unfiltered.sdb"), &workload, &genesis);
+    let before = run("unfiltered", chain, &blocks, &genesis);
+
+    let mut chain = open_chain(&dir.join("filtered.sdb"), &workload, &genesis);
+    chain
+        .0
+        .state
+        .enable_outpoint_filter()
+        .expect("build filter");
+    let after = run("filtered", chain, &blocks, &genesis);
+
+    println!("speedup: {:.2}x", after / before);
+    _ = fs::remove_dir_all(&dir);
+}
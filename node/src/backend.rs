@@ -0,0 +1,307 @@
+use std::{
+    fs::OpenOptions,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::{anyhow, Result};
+use spacedb::{
+    db::Database, fs::FileBackend, tx::ReadTransaction, tx::WriteTransaction, Configuration, Hash,
+    Sha256Hasher,
+};
+
+/// Default spacedb node cache size in bytes
+pub const DEFAULT_CACHE_SIZE: usize = 1_000_000;
+
+pub type Entries = Box<dyn Iterator<Item = Result<(Hash, Vec<u8>)>>>;
+pub type Snapshots<'a> = Box<dyn Iterator<Item = Result<Box<dyn StateSnapshot>>> + 'a>;
+
+/// Persistent key/value storage of committed state snapshots
+pub trait StateBackend: Send + Sync {
+    /// Latest committed snapshot
+    fn read(&self) -> Result<Box<dyn StateSnapshot>>;
+
+    /// All committed snapshots, newest first
+    fn snapshots(&self) -> Snapshots<'_>;
+
+    /// Begins a write on top of the latest snapshot
+    fn write(&self) -> Result<Box<dyn StateWrite + '_>>;
+}
+
+/// A read only view of a committed snapshot
+pub trait StateSnapshot: Send + Sync {
+    fn metadata(&self) -> &[u8];
+
+    fn get(&mut self, key: &Hash) -> Result<Option<Vec<u8>>>;
+
+    /// All entries in key order
+    fn iter(&self) -> Entries;
+
+    fn compute_root(&mut self) -> Result<Hash>;
+
+    /// Reverts the backend to this snapshot discarding newer ones
+    fn rollback(&self) -> Result<()>;
+
+    fn boxed_clone(&self) -> Box<dyn StateSnapshot>;
+}
+
+/// Changes committed atomically as a new snapshot
+pub trait StateWrite {
+    fn insert(&mut self, key: Hash, value: Vec<u8>) -> Result<()>;
+
+    fn remove(&mut self, key: Hash) -> Result<()>;
+
+    fn metadata(&mut self, metadata: Vec<u8>) -> Result<()>;
+
+    fn commit(self: Box<Self>) -> Result<()>;
+}
+
+impl Clone for Box<dyn StateSnapshot> {
+    fn clone(&self) -> Self {
+        self.boxed_clone()
+    }
+}
+
+/// spacedb backed by a file or memory
+#[derive(Clone)]
+pub struct SpaceDbBackend(Database<Sha256Hasher>);
+
+impl SpaceDbBackend {
+    pub fn open(path: PathBuf, cache_size: usize) -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(path)?;
+
+        let config = Configuration::new().with_cache_size(cache_size);
+        Ok(Self(Database::new(
+            Box::new(FileBackend::new(file)?),
+            config,
+        )?))
+    }
+
+    pub fn memory() -> Result<Self> {
+        Ok(Self(Database::memory()?))
+    }
+}
+
+impl StateBackend for SpaceDbBackend {
+    fn read(&self) -> Result<Box<dyn StateSnapshot>> {
+        Ok(Box::new(SpaceDbSnapshot(self.0.begin_read()?)))
+    }
+
+    fn snapshots(&self) -> Snapshots<'_> {
+        Box::new(
+            self.0
+                .iter()
+                .map(|snapshot| Ok(Box::new(SpaceDbSnapshot(snapshot?)) as Box<dyn StateSnapshot>)),
+        )
+    }
+
+    fn write(&self) -> Result<Box<dyn StateWrite + '_>> {
+        Ok(Box::new(SpaceDbWrite(Some(self.0.begin_write()?))))
+    }
+}
+
+#[derive(Clone)]
+struct SpaceDbSnapshot(ReadTransaction<Sha256Hasher>);
+
+impl StateSnapshot for SpaceDbSnapshot {
+    fn metadata(&self) -> &[u8] {
+        self.0.metadata()
+    }
+
+    fn get(&mut self, key: &Hash) -> Result<Option<Vec<u8>>> {
+        Ok(self.0.get(key)?)
+    }
+
+    fn iter(&self) -> Entries {
+        Box::new(self.0.iter().map(|entry| Ok(entry?)))
+    }
+
+    fn compute_root(&mut self) -> Result<Hash> {
+        Ok(self.0.compute_root()?)
+    }
+
+    fn rollback(&self) -> Result<()> {
+        Ok(self.0.clone().rollback()?)
+    }
+
+    fn boxed_clone(&self) -> Box<dyn StateSnapshot> {
+        Box::new(self.clone())
+    }
+}
+
+/// spacedb writes consume the transaction on every change
+/// so it's taken out and put back in each call
+struct SpaceDbWrite<'db>(Option<WriteTransaction<'db, Sha256Hasher>>);
+
+impl<'db> SpaceDbWrite<'db> {
+    fn take(&mut self) -> Result<WriteTransaction<'db, Sha256Hasher>> {
+        self.0
+            .take()
+            .ok_or_else(|| anyhow!("write aborted by a previous error"))
+    }
+}
+
+impl StateWrite for SpaceDbWrite<'_> {
+    fn insert(&mut self, key: Hash, value: Vec<u8>) -> Result<()> {
+        let tx = self.take()?;
+        self.0 = Some(tx.insert(key, value)?);
+        Ok(())
+    }
+
+    fn remove(&mut self, key: Hash) -> Result<()> {
+        let tx = self.take()?;
+        self.0 = Some(tx.delete(key)?);
+        Ok(())
+    }
+
+    fn metadata(&mut self, metadata: Vec<u8>) -> Result<()> {
+        match self.0.as_mut() {
+            Some(tx) => Ok(tx.metadata(metadata)?),
+            None => Err(anyhow!("write aborted by a previous error")),
+        }
+    }
+
+    fn commit(mut self: Box<Self>) -> Result<()> {
+        Ok(self.take()?.commit()?)
+    }
+}
+
+/// Number of operations performed through an [InstrumentedBackend]
+#[derive(Debug, Default)]
+pub struct BackendCounters {
+    /// Keys looked up in committed snapshots
+    pub reads: AtomicU64,
+    /// Keys inserted or removed
+    pub writes: AtomicU64,
+    pub commits: AtomicU64,
+}
+
+impl BackendCounters {
+    pub fn reads(&self) -> u64 {
+        self.reads.load(Ordering::Relaxed)
+    }
+
+    pub fn writes(&self) -> u64 {
+        self.writes.load(Ordering::Relaxed)
+    }
+
+    pub fn commits(&self) -> u64 {
+        self.commits.load(Ordering::Relaxed)
+    }
+}
+
+/// Wraps another backend counting reads and writes
+#[derive(Clone)]
+pub struct InstrumentedBackend {
+    inner: Arc<dyn StateBackend>,
+    counters: Arc<BackendCounters>,
+}
+
+impl InstrumentedBackend {
+    pub fn new(inner: impl StateBackend + 'static) -> Self {
+        Self {
+            inner: Arc::new(inner),
+            counters: Arc::new(BackendCounters::default()),
+        }
+    }
+
+    pub fn counters(&self) -> Arc<BackendCounters> {
+        self.counters.clone()
+    }
+
+    fn instrument(&self, snapshot: Box<dyn StateSnapshot>) -> Box<dyn StateSnapshot> {
+        Box::new(InstrumentedSnapshot {
+            inner: snapshot,
+            counters: self.counters.clone(),
+        })
+    }
+}
+
+impl StateBackend for InstrumentedBackend {
+    fn read(&self) -> Result<Box<dyn StateSnapshot>> {
+        Ok(self.instrument(self.inner.read()?))
+    }
+
+    fn snapshots(&self) -> Snapshots<'_> {
+        Box::new(
+            self.inner
+                .snapshots()
+                .map(|snapshot| Ok(self.instrument(snapshot?))),
+        )
+    }
+
+    fn write(&self) -> Result<Box<dyn StateWrite + '_>> {
+        Ok(Box::new(InstrumentedWrite {
+            inner: self.inner.write()?,
+            counters: self.counters.clone(),
+        }))
+    }
+}
+
+struct InstrumentedSnapshot {
+    inner: Box<dyn StateSnapshot>,
+    counters: Arc<BackendCounters>,
+}
+
+impl StateSnapshot for InstrumentedSnapshot {
+    fn metadata(&self) -> &[u8] {
+        self.inner.metadata()
+    }
+
+    fn get(&mut self, key: &Hash) -> Result<Option<Vec<u8>>> {
+        self.counters.reads.fetch_add(1, Ordering::Relaxed);
+        self.inner.get(key)
+    }
+
+    fn iter(&self) -> Entries {
+        self.inner.iter()
+    }
+
+    fn compute_root(&mut self) -> Result<Hash> {
+        self.inner.compute_root()
+    }
+
+    fn rollback(&self) -> Result<()> {
+        self.inner.rollback()
+    }
+
+    fn boxed_clone(&self) -> Box<dyn StateSnapshot> {
+        Box::new(InstrumentedSnapshot {
+            inner: self.inner.clone(),
+            counters: self.counters.clone(),
+        })
+    }
+}
+
+struct InstrumentedWrite<'a> {
+    inner: Box<dyn StateWrite + 'a>,
+    counters: Arc<BackendCounters>,
+}
+
+impl StateWrite for InstrumentedWrite<'_> {
+    fn insert(&mut self, key: Hash, value: Vec<u8>) -> Result<()> {
+        self.counters.writes.fetch_add(1, Ordering::Relaxed);
+        self.inner.insert(key, value)
+    }
+
+    fn remove(&mut self, key: Hash) -> Result<()> {
+        self.counters.writes.fetch_add(1, Ordering::Relaxed);
+        self.inner.remove(key)
+    }
+
+    fn metadata(&mut self, metadata: Vec<u8>) -> Result<()> {
+        self.inner.metadata(metadata)
+    }
+
+    fn commit(self: Box<Self>) -> Result<()> {
+        self.counters.commits.fetch_add(1, Ordering::Relaxed);
+        self.inner.commit()
+    }
+}
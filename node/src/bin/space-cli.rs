@@ -75,6 +75,12 @@ enum Commands {
     /// Export a wallet
     #[command(name = "getserverinfo")]
     GetServerInfo,
+    /// Get sync progress, throughput and estimated time to catch up
+    #[command(name = "getsyncstatus")]
+    GetSyncStatus,
+    /// Get bitcoind, sync, store and wallet service status
+    #[command(name = "getnodestatus")]
+    GetNodeStatus,
     /// Open an auction
     Open {
         /// Space name
@@ -189,6 +195,18 @@ enum Commands {
         #[arg(default_value = "0")]
         target_interval: usize,
     },
+    /// Get the spaces, spaceouts and auction bids inserted, updated and removed
+    /// between two committed snapshots (committed every 36 blocks, on shutdown and after reorgs)
+    #[command(name = "getstatediff")]
+    GetStateDiff {
+        /// Height of the older snapshot
+        from: u32,
+        /// Height of the newer snapshot
+        to: u32,
+    },
+    /// Get protocol-wide statistics such as space counts, burns and revocations
+    #[command(name = "getstats")]
+    GetStats,
     /// Associate the specified data with a given space (not recommended use Fabric instead)
     /// If for whatever reason it's not possible to use other protocols, then you may use this.
     #[command(name = "setrawfallback")]
@@ -325,6 +343,12 @@ struct RpcError {
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let (cli, args) = SpaceCli::configure().await?;
+    if !matches!(
+        args.command,
+        Commands::GetSyncStatus | Commands::GetNodeStatus
+    ) {
+        warn_if_syncing(&cli).await;
+    }
     let result = handle_commands(&cli, args.command).await;
 
     match result {
@@ -381,6 +405,18 @@ async fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Results may be outdated until spaced catches up with bitcoind
+async fn warn_if_syncing(cli: &SpaceCli) {
+    let Ok(status) = cli.client.get_sync_status().await else {
+        return;
+    };
+    if status.bitcoin_tip.is_none() {
+        eprintln!("Warning: spaced hasn't reached bitcoind yet ({})", status);
+    } else if !status.synced {
+        eprintln!("Warning: spaced is still syncing ({})", status);
+    }
+}
+
 fn hash_space(spaceish: &str) -> anyhow::Result<String> {
     let space = normalize_space(&spaceish);
     let sname = SLabel::from_str(&space)?;
@@ -398,6 +434,14 @@ async fn handle_commands(
             let data = cli.client.get_rollout(target).await?;
             println!("{}", serde_json::to_string_pretty(&data)?);
         }
+        Commands::GetStateDiff { from, to } => {
+            let diff = cli.client.get_state_diff(from, to).await?;
+            println!("{}", serde_json::to_string_pretty(&diff)?);
+        }
+        Commands::GetStats => {
+            let stats = cli.client.get_stats().await?;
+            println!("{}", serde_json::to_string_pretty(&stats)?);
+        }
         Commands::EstimateBid { target } => {
             let response = cli.client.estimate_bid(target).await?;
             println!("{} sat", Amount::from_sat(response).to_string());
@@ -438,6 +482,14 @@ async fn handle_commands(
             let result = cli.client.get_server_info().await?;
             println!("{}", serde_json::to_string_pretty(&result).expect("result"));
         }
+        Commands::GetSyncStatus => {
+            let result = cli.client.get_sync_status().await?;
+            println!("{}", serde_json::to_string_pretty(&result).expect("result"));
+        }
+        Commands::GetNodeStatus => {
+            let result = cli.client.get_node_status().await?;
+            println!("{}", serde_json::to_string_pretty(&result).expect("result"));
+        }
         Commands::Open {
             ref space,
             initial_bid,
@@ -1,15 +1,21 @@
-use std::sync::Arc;
+use std::{fs, str::FromStr, sync::Arc};
 
 use anyhow::anyhow;
-use env_logger::Env;
 use log::error;
+use protocol::bitcoin::secp256k1::{Keypair, Secp256k1, XOnlyPublicKey};
 use spaced::{
-    config::{safe_exit, Args},
+    blockfile::BlockFileSource,
+    config::{safe_exit, Args, Command, SnapshotCommand},
+    esplora::EsploraBlockSource,
+    logging,
     rpc::{AsyncChainState, LoadedWallet, RpcServerImpl, WalletManager},
+    snapshot,
     source::{BitcoinBlockSource, BitcoinRpc},
     store,
     sync::Spaced,
+    verify,
     wallets::RpcWallet,
+    zmq,
 };
 use store::LiveSnapshot;
 use tokio::{
@@ -19,7 +25,7 @@ use tokio::{
 
 #[tokio::main]
 async fn main() {
-    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
+    logging::init();
     let sigterm = tokio::signal::ctrl_c();
 
     let mut app = Composer::new();
@@ -61,6 +67,8 @@ impl Composer {
             rx,
             self.shutdown.clone(),
             spaced.num_workers,
+            spaced.tip_notifier.clone(),
+            spaced.metrics.clone(),
         );
 
         self.services.spawn(async move {
@@ -76,6 +84,7 @@ impl Composer {
         let wallet_manager = WalletManager {
             data_dir: spaced.data_dir.join("wallets"),
             network: spaced.network,
+            custom_chain: spaced.custom_chain.clone(),
             rpc: spaced.rpc.clone(),
             wallet_loader: wallet_loader_tx,
             wallets: Arc::new(Default::default()),
@@ -94,7 +103,15 @@ impl Composer {
                 .await
                 .map_err(|e| anyhow!("Chain state error: {}", e))
         });
-        let rpc_server = RpcServerImpl::new(async_chain_state.clone(), wallet_manager);
+        let rpc_server = RpcServerImpl::new(
+            async_chain_state.clone(),
+            wallet_manager,
+            spaced.data_dir.clone(),
+            spaced.block_fetch_method(),
+            spaced.sync_progress.clone(),
+            spaced.metrics_endpoint.then(|| spaced.metrics.clone()),
+            spaced.ready_max_lag,
+        );
 
         let bind = spaced.bind.clone();
         let shutdown = self.shutdown.clone();
@@ -116,8 +133,19 @@ impl Composer {
         let rpc = spaced.rpc.clone();
 
         std::thread::spawn(move || {
-            let source = BitcoinBlockSource::new(rpc);
-            _ = spaced_sender.send(spaced.protocol_sync(source, shutdown));
+            let result = if let Some(replay) = spaced.replay.clone() {
+                spaced.protocol_sync(replay, shutdown)
+            } else if let Some(url) = spaced.esplora_url.clone() {
+                spaced.protocol_sync(EsploraBlockSource::new(&url), shutdown)
+            } else {
+                let source = BitcoinBlockSource::new(rpc).with_rest(spaced.rest);
+                match spaced.blocks_dir.clone() {
+                    None => spaced.protocol_sync(source, shutdown),
+                    Some(dir) => BlockFileSource::new(dir, source)
+                        .and_then(|source| spaced.protocol_sync(source, shutdown)),
+                }
+            };
+            _ = spaced_sender.send(result);
         });
 
         self.services.spawn(async move {
@@ -128,7 +156,43 @@ impl Composer {
     }
 
     async fn run(&mut self) -> anyhow::Result<()> {
-        let spaced = Args::configure().await?;
+        let (mut spaced, command) = Args::configure().await?;
+        match command {
+            Some(Command::Snapshot(SnapshotCommand::Export { path, signing_key })) => {
+                let signing_key = match signing_key {
+                    None => None,
+                    Some(key_path) => {
+                        let secret = fs::read_to_string(&key_path)?;
+                        Some(
+                            Keypair::from_seckey_str(&Secp256k1::new(), secret.trim())
+                                .map_err(|e| anyhow!("Invalid signing key: {}", e))?,
+                        )
+                    }
+                };
+                snapshot::export(&spaced, &path, signing_key.as_ref())?;
+                return Ok(());
+            }
+            Some(Command::Snapshot(SnapshotCommand::Import { path, trusted_key })) => {
+                let trusted_key = trusted_key
+                    .map(|key| XOnlyPublicKey::from_str(&key))
+                    .transpose()
+                    .map_err(|e| anyhow!("Invalid trusted key: {}", e))?;
+                snapshot::import(&spaced, &path, trusted_key.as_ref()).await?;
+            }
+            Some(Command::Verify { repair, rederive }) => {
+                let report = verify::verify(&mut spaced, rederive, repair).await?;
+                println!("{}", serde_json::to_string_pretty(&report)?);
+                if !report.issues.is_empty() && report.repaired.is_none() {
+                    safe_exit(1);
+                }
+                return Ok(());
+            }
+            None => {}
+        }
+
+        if let Some(endpoint) = spaced.zmq_hashblock.clone() {
+            zmq::listen_blocks(endpoint, spaced.tip_notifier.clone(), self.shutdown.clone());
+        }
         self.setup_rpc_services(&spaced).await;
         self.setup_sync_service(spaced).await;
 
@@ -147,7 +211,7 @@ async fn create_async_store(
     shutdown: broadcast::Receiver<()>,
 ) -> (AsyncChainState, JoinHandle<()>) {
     let (tx, rx) = mpsc::channel(32);
-    let async_store = AsyncChainState::new(tx);
+    let async_store = AsyncChainState::new(tx, chain_state.read_handle());
     let client = reqwest::Client::new();
     let handle = tokio::spawn(async move {
         AsyncChainState::handler(&client, rpc, chain_state, block_index, rx, shutdown).await
@@ -0,0 +1,317 @@
+use std::{
+    collections::HashMap,
+    fs,
+    fs::File,
+    io,
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use log::{info, warn};
+use protocol::{
+    bitcoin::{block::Header, consensus::encode::deserialize, Block, BlockHash, Work},
+    constants::ChainAnchor,
+};
+
+use crate::{
+    logging::FETCHER,
+    node::BlockSource,
+    source::{BitcoinBlockSource, BitcoinRpcError},
+};
+
+/// Size of the `[magic][length]` prefix of each block record
+const RECORD_PREFIX_LEN: u64 = 8;
+const HEADER_LEN: usize = 80;
+
+/// Reads blocks straight from the `blk*.dat` files of a local bitcoind.
+///
+/// Block files are append only but blocks are stored in the order they were
+/// downloaded rather than by height, so files are scanned lazily to map block
+/// hashes to their location. Heights and the best chain are still resolved
+/// over RPC, which is also used for blocks that can't be found on disk
+/// such as blocks not yet flushed or pruned. Scanning starts at the oldest
+/// file left on pruned nodes and every block is fetched over RPC if there
+/// are no block files at all.
+#[derive(Clone)]
+pub struct BlockFileSource {
+    rpc: BitcoinBlockSource,
+    dir: PathBuf,
+    /// Obfuscation key from `xor.dat` applied to all block files
+    xor: [u8; 8],
+    index: Arc<Mutex<BlockFileIndex>>,
+}
+
+#[derive(Clone, Copy)]
+struct BlockLocation {
+    file: u32,
+    /// Offset of the serialized block within the file
+    offset: u64,
+    len: u32,
+}
+
+#[derive(Default)]
+struct BlockFileIndex {
+    locations: HashMap<BlockHash, BlockLocation>,
+    cursor: ScanCursor,
+}
+
+/// Position up to which block files were scanned
+#[derive(Clone, Copy, Default)]
+struct ScanCursor {
+    /// Network magic taken from the first record
+    magic: Option<[u8; 4]>,
+    /// File currently being scanned
+    file: u32,
+    /// Offset up to which the current file was scanned
+    offset: u64,
+}
+
+impl BlockFileSource {
+    pub fn new(dir: PathBuf, rpc: BitcoinBlockSource) -> anyhow::Result<Self> {
+        if !dir.is_dir() {
+            return Err(anyhow::anyhow!(
+                "Blocks directory {} does not exist",
+                dir.display()
+            ));
+        }
+
+        let mut xor = [0u8; 8];
+        let xor_path = dir.join("xor.dat");
+        if xor_path.exists() {
+            let key = fs::read(&xor_path)?;
+            if key.len() != xor.len() {
+                return Err(anyhow::anyhow!(
+                    "Expected an {} byte obfuscation key in {}",
+                    xor.len(),
+                    xor_path.display()
+                ));
+            }
+            xor.copy_from_slice(&key);
+        }
+
+        // Pruned nodes delete the oldest files first
+        let first = first_block_file(&dir, 0)?;
+        match first {
+            Some(file) => info!(
+                target: FETCHER,
+                "Reading blocks from {} starting at blk{:05}.dat",
+                dir.display(),
+                file
+            ),
+            None => warn!(
+                target: FETCHER,
+                "No block files found in {}, fetching blocks over RPC",
+                dir.display()
+            ),
+        }
+        let index = BlockFileIndex {
+            locations: HashMap::new(),
+            cursor: ScanCursor {
+                file: first.unwrap_or(0),
+                ..Default::default()
+            },
+        };
+        Ok(Self {
+            rpc,
+            dir,
+            xor,
+            index: Arc::new(Mutex::new(index)),
+        })
+    }
+
+    /// Scans block files until the block is found or no files are left.
+    /// Files are read without holding the index lock so lookups of blocks
+    /// that were already indexed don't wait on a scan.
+    fn locate(&self, hash: &BlockHash) -> io::Result<Option<BlockLocation>> {
+        loop {
+            let mut cursor = {
+                let index = self.index.lock().expect("block file index");
+                if let Some(location) = index.locations.get(hash) {
+                    return Ok(Some(*location));
+                }
+                index.cursor
+            };
+
+            let mut found = Vec::new();
+            let scanned = cursor.scan(&self.dir, &self.xor, &mut found)?;
+
+            let mut index = self.index.lock().expect("block file index");
+            index.locations.extend(found);
+            // Another lookup may have scanned further in the meantime
+            if (cursor.file, cursor.offset) > (index.cursor.file, index.cursor.offset) {
+                index.cursor = cursor;
+            }
+            if !scanned {
+                return Ok(index.locations.get(hash).copied());
+            }
+        }
+    }
+
+    fn read_block(&self, location: BlockLocation) -> io::Result<Block> {
+        let mut file = File::open(block_file_path(&self.dir, location.file))?;
+        let mut raw = vec![0u8; location.len as usize];
+        read_at(&mut file, &self.xor, location.offset, &mut raw)?;
+        deserialize(&raw).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+impl BlockSource for BlockFileSource {
+    fn get_block_hash(&self, height: u32) -> Result<BlockHash, BitcoinRpcError> {
+        self.rpc.get_block_hash(height)
+    }
+
+    fn get_block(&self, hash: &BlockHash) -> Result<Block, BitcoinRpcError> {
+        let location = self
+            .locate(hash)
+            .map_err(|e| BitcoinRpcError::Other(format!("Could not scan block files: {}", e)))?;
+        if let Some(location) = location {
+            match self.read_block(location) {
+                Ok(block) if block.block_hash() == *hash => return Ok(block),
+                Ok(_) => {}
+                // The file was pruned since it was indexed
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    return Err(BitcoinRpcError::Other(format!(
+                        "Could not read block {} from disk: {}",
+                        hash, e
+                    )))
+                }
+            }
+        }
+        self.rpc.get_block(hash)
+    }
+
+    fn get_median_time(&self) -> Result<u64, BitcoinRpcError> {
+        self.rpc.get_median_time()
+    }
+
+    fn get_block_count(&self) -> Result<u64, BitcoinRpcError> {
+        self.rpc.get_block_count()
+    }
+
+    fn get_best_chain(&self) -> Result<ChainAnchor, BitcoinRpcError> {
+        self.rpc.get_best_chain()
+    }
+
+    fn get_best_chain_work(&self) -> Result<Option<Work>, BitcoinRpcError> {
+        self.rpc.get_best_chain_work()
+    }
+}
+
+impl ScanCursor {
+    /// Collects records appended to the current file since the last scan and
+    /// moves on to the next file once it exists. Returns false if there was
+    /// nothing new to index.
+    fn scan(
+        &mut self,
+        dir: &Path,
+        xor: &[u8; 8],
+        found: &mut Vec<(BlockHash, BlockLocation)>,
+    ) -> io::Result<bool> {
+        let mut file = match File::open(block_file_path(dir, self.file)) {
+            Ok(file) => file,
+            // Skip files pruned before they were scanned
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Ok(match first_block_file(dir, self.file + 1)? {
+                    Some(next) => {
+                        self.file = next;
+                        self.offset = 0;
+                        true
+                    }
+                    None => false,
+                })
+            }
+            Err(e) => return Err(e),
+        };
+        let file_len = file.metadata()?.len();
+        let indexed = found.len();
+
+        let mut prefix = [0u8; RECORD_PREFIX_LEN as usize];
+        let mut header = [0u8; HEADER_LEN];
+        while self.offset + RECORD_PREFIX_LEN + HEADER_LEN as u64 <= file_len {
+            // Files are preallocated with zeros past the last record which
+            // unlike records aren't obfuscated
+            read_at(&mut file, &[0u8; 8], self.offset, &mut prefix)?;
+            if prefix[..4] == [0u8; 4] {
+                break;
+            }
+            deobfuscate(xor, self.offset, &mut prefix);
+            let magic: [u8; 4] = prefix[..4].try_into().expect("4 bytes");
+            if *self.magic.get_or_insert(magic) != magic {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "unexpected magic in blk{:05}.dat at offset {}",
+                        self.file, self.offset
+                    ),
+                ));
+            }
+
+            let len = u32::from_le_bytes(prefix[4..].try_into().expect("4 bytes"));
+            let offset = self.offset + RECORD_PREFIX_LEN;
+            if offset + len as u64 > file_len {
+                // Partially written record
+                break;
+            }
+            read_at(&mut file, xor, offset, &mut header)?;
+            let header: Header =
+                deserialize(&header).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            found.push((
+                header.block_hash(),
+                BlockLocation {
+                    file: self.file,
+                    offset,
+                    len,
+                },
+            ));
+            self.offset = offset + len as u64;
+        }
+
+        // bitcoind only starts a new file once the previous one is full
+        if block_file_path(dir, self.file + 1).exists() {
+            self.file += 1;
+            self.offset = 0;
+            return Ok(true);
+        }
+        Ok(found.len() > indexed)
+    }
+}
+
+fn block_file_path(dir: &Path, file: u32) -> PathBuf {
+    dir.join(format!("blk{:05}.dat", file))
+}
+
+/// Lowest numbered block file from `from` onwards
+fn first_block_file(dir: &Path, from: u32) -> io::Result<Option<u32>> {
+    let mut first = None;
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        let file = name
+            .to_str()
+            .and_then(|name| name.strip_prefix("blk"))
+            .and_then(|name| name.strip_suffix(".dat"))
+            .and_then(|number| number.parse::<u32>().ok());
+        if let Some(file) = file.filter(|file| *file >= from) {
+            first = Some(first.map_or(file, |first: u32| first.min(file)));
+        }
+    }
+    Ok(first)
+}
+
+/// Reads and deobfuscates bytes at the given file offset
+fn read_at(file: &mut File, xor: &[u8; 8], offset: u64, buf: &mut [u8]) -> io::Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(buf)?;
+    deobfuscate(xor, offset, buf);
+    Ok(())
+}
+
+/// Applies the obfuscation key to bytes read at the given file offset
+fn deobfuscate(xor: &[u8; 8], offset: u64, buf: &mut [u8]) {
+    if *xor != [0u8; 8] {
+        for (i, byte) in buf.iter_mut().enumerate() {
+            *byte ^= xor[(offset as usize + i) % xor.len()];
+        }
+    }
+}
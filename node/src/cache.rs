@@ -0,0 +1,138 @@
+use std::collections::{BTreeMap, HashMap};
+
+use protocol::SpaceOut;
+use spacedb::Hash;
+
+/// Least recently used cache of decoded spaceouts keyed by `OutpointKey`.
+///
+/// Keys are invalidated as they're written and the generation advances when a
+/// block is applied or reverted, so that a reader which looked up a value
+/// before the block can't cache it afterwards. Readers must capture
+/// [SpaceoutCache::generation] before reading from the store and pass it to
+/// [SpaceoutCache::insert].
+pub struct SpaceoutCache {
+    capacity: usize,
+    entries: HashMap<Hash, (SpaceOut, u64)>,
+    /// Access stamp -> key ordered from least to most recently used
+    order: BTreeMap<u64, Hash>,
+    stamp: u64,
+    generation: u64,
+}
+
+impl SpaceoutCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: HashMap::with_capacity(capacity),
+            order: BTreeMap::new(),
+            stamp: 0,
+            generation: 0,
+        }
+    }
+
+    #[inline]
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    pub fn get(&mut self, key: &Hash) -> Option<SpaceOut> {
+        let stamp = self.next_stamp();
+        let (spaceout, last_used) = self.entries.get_mut(key)?;
+        self.order.remove(last_used);
+        *last_used = stamp;
+        self.order.insert(stamp, *key);
+        Some(spaceout.clone())
+    }
+
+    /// Caches a value read while the cache was at the given generation
+    pub fn insert(&mut self, key: Hash, spaceout: SpaceOut, generation: u64) {
+        if self.capacity == 0 || generation != self.generation {
+            return;
+        }
+        let stamp = self.next_stamp();
+        if let Some((_, last_used)) = self.entries.insert(key, (spaceout, stamp)) {
+            self.order.remove(&last_used);
+        }
+        self.order.insert(stamp, key);
+
+        while self.entries.len() > self.capacity {
+            let (_, evicted) = self.order.pop_first().expect("an entry to evict");
+            self.entries.remove(&evicted);
+        }
+    }
+
+    /// Starts a new generation at a block boundary
+    pub fn advance(&mut self) {
+        self.generation += 1;
+    }
+
+    /// Drops a key that is about to be written
+    pub fn invalidate(&mut self, key: &Hash) {
+        if let Some((_, last_used)) = self.entries.remove(key) {
+            self.order.remove(&last_used);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.generation += 1;
+        self.entries.clear();
+        self.order.clear();
+    }
+
+    #[inline]
+    fn next_stamp(&mut self) -> u64 {
+        self.stamp += 1;
+        self.stamp
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use protocol::{bitcoin::Amount, SpaceOut};
+
+    use super::*;
+
+    fn spaceout(value: u64) -> SpaceOut {
+        SpaceOut {
+            n: 0,
+            space: None,
+            value: Amount::from_sat(value),
+            script_pubkey: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_generation_advances_at_block_boundaries() {
+        let mut cache = SpaceoutCache::new(10);
+        let generation = cache.generation();
+
+        // writes within a block only drop their own keys
+        cache.invalidate(&[1u8; 32]);
+        cache.insert([2u8; 32], spaceout(2), generation);
+        assert!(cache.get(&[2u8; 32]).is_some());
+
+        // values read before a block can't be cached after it
+        cache.advance();
+        cache.insert([3u8; 32], spaceout(3), generation);
+        assert!(cache.get(&[3u8; 32]).is_none());
+        cache.insert([3u8; 32], spaceout(3), cache.generation());
+        assert!(cache.get(&[3u8; 32]).is_some());
+
+        cache.invalidate(&[3u8; 32]);
+        assert!(cache.get(&[3u8; 32]).is_none());
+    }
+
+    #[test]
+    fn test_least_recently_used_is_evicted() {
+        let mut cache = SpaceoutCache::new(2);
+        let generation = cache.generation();
+        cache.insert([1u8; 32], spaceout(1), generation);
+        cache.insert([2u8; 32], spaceout(2), generation);
+        assert!(cache.get(&[1u8; 32]).is_some());
+        cache.insert([3u8; 32], spaceout(3), generation);
+
+        assert!(cache.get(&[1u8; 32]).is_some());
+        assert!(cache.get(&[2u8; 32]).is_none());
+        assert!(cache.get(&[3u8; 32]).is_some());
+    }
+}
@@ -5,16 +5,17 @@ use protocol::hasher::{KeyHasher, SpaceKey};
 use protocol::prepare::{DataSource, TxContext};
 use protocol::{Covenant, RevokeReason, SpaceOut};
 use protocol::validate::{TxChangeSet, UpdateKind, Validator};
-use crate::store::{LiveSnapshot, Sha256};
+use crate::store::Sha256;
 
-pub struct TxChecker<'a> {
-    pub original: &'a mut LiveSnapshot,
+/// Applies transactions on top of any chain state without writing to it
+pub struct TxChecker<'a, S: DataSource> {
+    pub original: &'a mut S,
     pub spaces: BTreeMap<SpaceKey, Option<OutPoint>>,
     pub spaceouts: BTreeMap<OutPoint, Option<SpaceOut>>,
 }
 
-impl<'a> TxChecker<'a> {
-    pub fn new(snap: &'a mut LiveSnapshot) -> Self {
+impl<'a, S: DataSource> TxChecker<'a, S> {
+    pub fn new(snap: &'a mut S) -> Self {
         Self {
             original: snap,
             spaces: Default::default(),
@@ -116,7 +117,7 @@ impl<'a> TxChecker<'a> {
     }
 }
 
-impl DataSource for TxChecker<'_> {
+impl<S: DataSource> DataSource for TxChecker<'_, S> {
     fn get_space_outpoint(&mut self, space_hash: &SpaceKey) -> protocol::errors::Result<Option<OutPoint>> {
         match self.spaces.get(space_hash) {
             None => self.original.get_space_outpoint(space_hash.into()),
@@ -6,23 +6,36 @@ use std::{
     fs,
     net::{IpAddr, SocketAddr},
     path::PathBuf,
+    time::Duration,
 };
 
 use clap::{
     error::{ContextKind, ContextValue},
-    ArgGroup, Parser, ValueEnum,
+    ArgGroup, CommandFactory, Parser, Subcommand, ValueEnum,
 };
 use directories::ProjectDirs;
 use jsonrpsee::core::Serialize;
 use log::error;
-use protocol::bitcoin::Network;
+use protocol::{
+    bitcoin::{hashes::Hash, BlockHash, Network, Work},
+    constants::ChainAnchor,
+};
 use serde::Deserialize;
 use toml::Value;
+use wallet::address::SpaceHrp;
 
 use crate::{
-    source::{BitcoinRpc, BitcoinRpcAuth},
+    headers::HeaderRules,
+    journal::Journal,
+    logging::{self, LogFormat},
+    metrics::Metrics,
+    progress::SyncProgress,
+    prune::{self, RetentionPolicy},
+    replay::ReplayBlockSource,
+    source::{BitcoinRpc, BitcoinRpcAuth, RetryPolicy, TipNotifier},
     store::{LiveStore, Store},
     sync::Spaced,
+    undo::UndoLog,
 };
 
 const RPC_OPTIONS: &str = "RPC Server Options";
@@ -50,10 +63,11 @@ pub struct Args {
     /// Number of concurrent workers allowed during syncing
     #[arg(short, long, env = "SPACED_JOBS", default_value = "8")]
     jobs: u8,
-    /// Bitcoin RPC URL
-    #[arg(long, env = "SPACED_BITCOIN_RPC_URL")]
-    bitcoin_rpc_url: Option<String>,
-    /// Bitcoin RPC cookie file path
+    /// Bitcoin RPC URL. This option can be specified multiple times
+    /// to fail over between endpoints following the majority chain
+    #[arg(long, env = "SPACED_BITCOIN_RPC_URL", value_delimiter = ',')]
+    bitcoin_rpc_url: Vec<String>,
+    /// Bitcoin RPC cookie file path, re-read whenever bitcoind rotates it
     #[arg(long, env = "SPACED_BITCOIN_RPC_COOKIE")]
     bitcoin_rpc_cookie: Option<PathBuf>,
     /// Bitcoin RPC user
@@ -66,6 +80,90 @@ pub struct Args {
     /// Bitcoin RPC password
     #[arg(long, env = "SPACED_BITCOIN_RPC_PASSWORD")]
     bitcoin_rpc_password: Option<String>,
+    /// Delay in milliseconds before retrying a failed Bitcoin RPC request,
+    /// doubled on every attempt
+    #[arg(long, env = "SPACED_BITCOIN_RPC_RETRY_DELAY", default_value = "1000")]
+    bitcoin_rpc_retry_delay: u64,
+    /// Maximum delay in seconds between Bitcoin RPC retries
+    #[arg(long, env = "SPACED_BITCOIN_RPC_RETRY_MAX_DELAY", default_value = "30")]
+    bitcoin_rpc_retry_max_delay: u64,
+    /// Give up on a Bitcoin RPC request after retrying for this many seconds
+    #[arg(long, env = "SPACED_BITCOIN_RPC_RETRY_TIMEOUT", default_value = "300")]
+    bitcoin_rpc_retry_timeout: u64,
+    /// Fraction of each retry delay that is randomized (0 to 1)
+    #[arg(
+        long,
+        env = "SPACED_BITCOIN_RPC_RETRY_JITTER",
+        default_value = "0.2",
+        value_parser = parse_fraction
+    )]
+    bitcoin_rpc_retry_jitter: f64,
+    /// Pause Bitcoin RPC requests after this many consecutive requests
+    /// gave up retrying (0 disables)
+    #[arg(
+        long,
+        env = "SPACED_BITCOIN_RPC_BREAKER_THRESHOLD",
+        default_value = "5"
+    )]
+    bitcoin_rpc_breaker_threshold: u32,
+    /// Seconds Bitcoin RPC requests are paused for once the breaker trips
+    #[arg(
+        long,
+        env = "SPACED_BITCOIN_RPC_BREAKER_COOLDOWN",
+        default_value = "30"
+    )]
+    bitcoin_rpc_breaker_cooldown: u64,
+    /// Read blocks from the blk*.dat files in this bitcoind blocks directory
+    /// instead of fetching them over RPC
+    #[arg(long, env = "SPACED_BITCOIN_BLOCKS_DIR")]
+    bitcoin_blocks_dir: Option<PathBuf>,
+    /// Fetch blocks over bitcoind's binary REST interface (requires -rest)
+    /// instead of JSON-RPC
+    #[arg(long, env = "SPACED_BITCOIN_REST", default_value = "false")]
+    bitcoin_rest: bool,
+    /// Wake up on blocks published by bitcoind's -zmqpubhashblock
+    /// e.g. tcp://127.0.0.1:28332 instead of only polling for them
+    #[arg(long, env = "SPACED_BITCOIN_ZMQ_HASHBLOCK")]
+    bitcoin_zmq_hashblock: Option<String>,
+    /// Sync blocks from an Esplora compatible HTTP API
+    /// e.g. https://mempool.space/api instead of bitcoind
+    #[arg(long, env = "SPACED_ESPLORA_URL")]
+    esplora_url: Option<String>,
+    /// Require the block at a height to have a hash given as HEIGHT:HASH.
+    /// This option can be specified multiple times
+    #[arg(long, env = "SPACED_CHECKPOINT", value_delimiter = ',', value_parser = parse_checkpoint)]
+    checkpoint: Vec<ChainAnchor>,
+    /// Don't sync until bitcoind's best chain has at least this much work
+    /// as a hex number (defaults to a recent value on mainnet, 0 to disable)
+    #[arg(long, env = "SPACED_MIN_CHAIN_WORK")]
+    min_chain_work: Option<String>,
+    /// Log as human readable text or as JSON lines with structured fields
+    #[arg(long, value_enum, env = "SPACED_LOG_FORMAT", default_value = "text")]
+    log_format: LogFormat,
+    /// Log levels per subsystem (sync, fetcher, rpc, store, wallet or wallet:<name>)
+    /// in RUST_LOG syntax e.g. "info,sync=debug,wallet=warn"
+    #[arg(long, env = "SPACED_LOG_LEVEL")]
+    log_level: Option<String>,
+    /// Record the blocks and chain tips served to the node to this file
+    /// so the sync can be replayed without bitcoind e.g. for bug reports.
+    /// An existing file is only replaced with --record-blocks-overwrite
+    #[arg(long, env = "SPACED_RECORD_BLOCKS")]
+    record_blocks: Option<PathBuf>,
+    /// Replace the file given by --record-blocks if it already exists
+    #[arg(
+        long,
+        requires = "record_blocks",
+        env = "SPACED_RECORD_BLOCKS_OVERWRITE",
+        default_value = "false"
+    )]
+    record_blocks_overwrite: bool,
+    /// Sync from a file written with --record-blocks instead of bitcoind
+    #[arg(
+        long,
+        conflicts_with_all = ["record_blocks", "esplora_url"],
+        env = "SPACED_REPLAY_BLOCKS"
+    )]
+    replay_blocks: Option<PathBuf>,
     /// Bind to given address to listen for JSON-RPC connections.
     /// This option can be specified multiple times (default: 127.0.0.1 and ::1 i.e., localhost)
     #[arg(long, help_heading = Some(RPC_OPTIONS), default_values = ["127.0.0.1", "::1"], env = "SPACED_RPC_BIND")]
@@ -73,9 +171,78 @@ pub struct Args {
     /// Listen for JSON-RPC connections on <port>
     #[arg(long, help_heading = Some(RPC_OPTIONS), env = "SPACED_RPC_PORT")]
     rpc_port: Option<u16>,
+    /// Serve Prometheus metrics at /metrics on the JSON-RPC port
+    #[arg(long, help_heading = Some(RPC_OPTIONS), env = "SPACED_METRICS", default_value = "false")]
+    metrics: bool,
+    /// Report /ready only while within <n> blocks of bitcoind's tip
+    #[arg(long, help_heading = Some(RPC_OPTIONS), env = "SPACED_READY_MAX_LAG", default_value = "2")]
+    ready_max_lag: u32,
     /// Index blocks including the full transaction data
     #[arg(long, env = "SPACED_BLOCK_INDEX_FULL", default_value = "false")]
     block_index_full: bool,
+    /// Maximum number of blocks that can be disconnected during a reorg using
+    /// per-block undo records. Deeper reorgs restore the last valid snapshot (0 disables)
+    #[arg(long, env = "SPACED_MAX_REORG_DEPTH", default_value = "144")]
+    max_reorg_depth: u32,
+    /// Prune all but the last <N> committed snapshots. Pruned snapshots are only
+    /// deleted from disk when spaced starts, a running node keeps all it commits.
+    /// Reorgs deeper than the retained snapshots can't be recovered from.
+    #[arg(long, env = "SPACED_PRUNE_KEEP_LAST", value_parser = clap::value_parser!(u32).range(1..))]
+    prune_keep_last: Option<u32>,
+    /// Also keep snapshots committed at multiples of <K> blocks when pruning
+    #[arg(
+        long,
+        requires = "prune_keep_last",
+        env = "SPACED_PRUNE_KEEP_EVERY",
+        default_value = "0"
+    )]
+    prune_keep_every: u32,
+    /// Number of recently read spaceouts to keep decoded in memory (0 disables)
+    #[arg(long, env = "SPACED_SPACEOUT_CACHE_SIZE", default_value = "10000")]
+    spaceout_cache_size: usize,
+    /// Size in bytes of the spacedb node cache of each database
+    #[arg(long, env = "SPACED_DB_CACHE_SIZE", default_value = "1000000")]
+    db_cache_size: usize,
+    #[command(subcommand)]
+    #[serde(skip)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Export or import the committed protocol state
+    #[command(subcommand)]
+    Snapshot(SnapshotCommand),
+    /// Check the committed protocol state for inconsistencies, print a report and exit
+    Verify {
+        /// Rebuild derived indexes from the committed spaceouts
+        #[arg(long)]
+        repair: bool,
+        /// Re-derive the state from the configured block source and compare roots.
+        /// Use --replay-blocks to re-derive from a recorded sync.
+        #[arg(long)]
+        rederive: bool,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum SnapshotCommand {
+    /// Write the latest committed protocol state to a file and exit
+    Export {
+        /// Destination path of the snapshot file
+        path: PathBuf,
+        /// Sign the snapshot with the hex encoded secret key stored in this file
+        #[arg(long)]
+        signing_key: Option<PathBuf>,
+    },
+    /// Load a snapshot into an empty data directory and continue syncing from it
+    Import {
+        /// Path of the snapshot file
+        path: PathBuf,
+        /// Only import snapshots signed by this hex encoded x-only public key
+        #[arg(long)]
+        trusted_key: Option<String>,
+    },
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, ValueEnum, Serialize, Deserialize)]
@@ -89,6 +256,50 @@ pub enum ExtendedNetwork {
     Regtest,
 }
 
+/// A signet or private test chain defined in the `[custom_chain]` table
+/// of the configuration file, e.g.
+///
+/// ```toml
+/// chain = "signet"
+///
+/// [custom_chain]
+/// name = "mutinynet"
+/// genesis_hash = "00000008819873e925422c1ff0f99f7cc9bbb232af63a077a480a3633bee1ef6"
+/// activation = { height = 1000, hash = "0000000000000000000000000000000000000000000000000000000000000000" }
+/// space_hrp = "mts"
+/// bitcoin_rpc_port = 38332
+/// rpc_port = 7220
+/// ```
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CustomChain {
+    /// Name of the chain used for its data directory
+    pub name: String,
+    /// Hash of the genesis block bitcoind must be following
+    pub genesis_hash: BlockHash,
+    /// Block the protocol activates at. An all zeros hash is looked up by height
+    pub activation: ChainAnchor,
+    /// Human-readable part of space addresses (default: the base network's)
+    pub space_hrp: Option<String>,
+    /// Default bitcoind RPC port
+    pub bitcoin_rpc_port: Option<u16>,
+    /// Default spaced RPC port
+    pub rpc_port: Option<u16>,
+}
+
+impl CustomChain {
+    /// Prefix of space addresses of `network` on this chain if it has its own
+    pub fn space_hrp(&self, network: Network) -> anyhow::Result<Option<SpaceHrp>> {
+        self.space_hrp
+            .as_deref()
+            .map(|hrp| {
+                SpaceHrp::new(hrp, network)
+                    .map_err(|e| anyhow::anyhow!("Invalid space address prefix '{}': {}", hrp, e))
+            })
+            .transpose()
+    }
+}
+
 impl ExtendedNetwork {
     pub fn fallback_network(&self) -> Network {
         match self {
@@ -104,7 +315,7 @@ impl ExtendedNetwork {
 impl Args {
     /// Configures spaced node by processing command line arguments
     /// and configuration files
-    pub async fn configure() -> anyhow::Result<Spaced> {
+    pub async fn configure() -> anyhow::Result<(Spaced, Option<Command>)> {
         let mut args = Args::merge_args_config(None);
         let default_dirs = get_default_node_dirs();
 
@@ -121,19 +332,51 @@ impl Args {
             }
             Some(user_specified_path) => Args::merge_args_config(Some(user_specified_path)),
         };
+        logging::configure(args.log_format, args.log_level.as_deref());
 
-        if args.bitcoin_rpc_url.is_none() {
-            args.bitcoin_rpc_url = Some(default_bitcoin_rpc_url(&args.chain).to_string())
+        let custom_chain = match args.config.as_ref() {
+            Some(path) if path.exists() => load_custom_chain(path)?,
+            _ => None,
+        };
+        if let Some(custom) = custom_chain.as_ref() {
+            if !matches!(
+                args.chain,
+                ExtendedNetwork::Signet | ExtendedNetwork::Regtest
+            ) {
+                return Err(anyhow::anyhow!(
+                    "Custom chain '{}' must be based on signet or regtest, not {}",
+                    custom.name,
+                    args.chain
+                ));
+            }
+            // Validated early, wallets use it with their own network
+            custom.space_hrp(args.chain.fallback_network())?;
+        }
+
+        if args.bitcoin_rpc_url.is_empty() {
+            let url = match custom_chain.as_ref().and_then(|c| c.bitcoin_rpc_port) {
+                Some(port) => format!("http://127.0.0.1:{}", port),
+                None => default_bitcoin_rpc_url(&args.chain).to_string(),
+            };
+            args.bitcoin_rpc_url = vec![url]
         }
         if args.rpc_port.is_none() {
-            args.rpc_port = Some(default_spaces_rpc_port(&args.chain));
+            args.rpc_port = Some(
+                custom_chain
+                    .as_ref()
+                    .and_then(|c| c.rpc_port)
+                    .unwrap_or_else(|| default_spaces_rpc_port(&args.chain)),
+            );
         }
 
         let data_dir = match args.data_dir {
             None => default_dirs.data_dir().to_path_buf(),
             Some(data_dir) => data_dir,
         }
-        .join(args.chain.to_string());
+        .join(match custom_chain.as_ref() {
+            Some(custom) => custom.name.clone(),
+            None => args.chain.to_string(),
+        });
 
         let default_port = args.rpc_port.unwrap();
         let rpc_bind_addresses: Vec<SocketAddr> = args
@@ -150,41 +393,98 @@ impl Args {
             .collect();
 
         let bitcoin_rpc_auth = if let Some(cookie) = args.bitcoin_rpc_cookie {
-            let cookie = std::fs::read_to_string(cookie)?;
-            BitcoinRpcAuth::Cookie(cookie)
+            // Fail early on a wrong path, the cookie itself is re-read
+            // whenever bitcoind rotates it
+            std::fs::metadata(&cookie)?;
+            BitcoinRpcAuth::CookieFile(cookie)
         } else if let Some(user) = args.bitcoin_rpc_user {
             BitcoinRpcAuth::UserPass(user, args.bitcoin_rpc_password.expect("password"))
         } else {
             BitcoinRpcAuth::None
         };
 
-        let rpc = BitcoinRpc::new(
-            &args.bitcoin_rpc_url.expect("bitcoin rpc url"),
-            bitcoin_rpc_auth,
-        );
-
-        let genesis = Spaced::genesis(&rpc, args.chain).await?;
+        let rpc = BitcoinRpc::with_endpoints(&args.bitcoin_rpc_url, bitcoin_rpc_auth)
+            .with_retry_policy(RetryPolicy {
+                initial_delay: Duration::from_millis(args.bitcoin_rpc_retry_delay),
+                max_delay: Duration::from_secs(args.bitcoin_rpc_retry_max_delay),
+                max_elapsed: Duration::from_secs(args.bitcoin_rpc_retry_timeout),
+                jitter: args.bitcoin_rpc_retry_jitter,
+                breaker_threshold: args.bitcoin_rpc_breaker_threshold,
+                breaker_cooldown: Duration::from_secs(args.bitcoin_rpc_breaker_cooldown),
+            });
+
+        let replay = match args.replay_blocks.as_ref() {
+            None => None,
+            Some(path) => Some(ReplayBlockSource::open(path).map_err(|e| {
+                anyhow::anyhow!("Could not open recording {}: {}", path.display(), e)
+            })?),
+        };
+        let genesis = Spaced::genesis(
+            &rpc,
+            args.esplora_url.as_deref(),
+            replay.as_ref(),
+            args.chain,
+            custom_chain.as_ref(),
+        )
+        .await?;
+
+        let min_chain_work = match args.min_chain_work.as_deref() {
+            None => HeaderRules::default_min_chain_work(args.chain),
+            Some(work) => Some(
+                Work::from_unprefixed_hex(work.trim_start_matches("0x"))
+                    .map_err(|e| anyhow::anyhow!("Invalid minimum chain work '{}': {}", work, e))?,
+            ),
+        };
+        // The activation block is only trusted if it's compiled in or configured
+        // since it's otherwise looked up from the same source being verified
+        let checkpoints = match custom_chain.as_ref() {
+            None => HeaderRules::default_checkpoints(args.chain),
+            Some(custom) => {
+                let custom_genesis = ChainAnchor {
+                    hash: custom.genesis_hash,
+                    height: 0,
+                };
+                let activation = Some(custom.activation)
+                    .filter(|activation| activation.hash != BlockHash::all_zeros());
+                std::iter::once(custom_genesis).chain(activation).collect()
+            }
+        };
+        let header_rules = HeaderRules::new(args.chain)
+            .with_checkpoints(checkpoints)
+            .with_checkpoints(args.checkpoint.clone())
+            .with_min_chain_work(min_chain_work);
 
         fs::create_dir_all(data_dir.clone())?;
 
         let proto_db_path = data_dir.join("protocol.sdb");
+        let block_db_path = data_dir.join("block_index.sdb");
         let initial_sync = !proto_db_path.exists();
 
-        let chain_store = Store::open(proto_db_path)?;
-        let chain = LiveStore {
+        let retention = args.prune_keep_last.map(|keep_last| RetentionPolicy {
+            keep_last,
+            keep_every: args.prune_keep_every,
+        });
+        if let Some(policy) = retention.as_ref() {
+            prune::compact(&proto_db_path, policy)?;
+            prune::compact(&block_db_path, policy)?;
+        }
+
+        let chain_store = Store::open(proto_db_path, args.db_cache_size)?;
+        let mut chain = LiveStore {
             state: chain_store.begin(&genesis)?,
             store: chain_store,
         };
+        chain.state.enable_outpoint_filter()?;
+        chain.state.enable_spaceout_cache(args.spaceout_cache_size);
 
         let block_index_enabled = args.block_index || args.block_index_full;
         let block_index = if block_index_enabled {
-            let block_db_path = data_dir.join("block_index.sdb");
             if !initial_sync && !block_db_path.exists() {
                 return Err(anyhow::anyhow!(
                     "Block index must be enabled from the initial sync."
                 ));
             }
-            let block_store = Store::open(block_db_path)?;
+            let block_store = Store::open(block_db_path, args.db_cache_size)?;
             let index = LiveStore {
                 state: block_store.begin(&genesis).expect("begin block index"),
                 store: block_store,
@@ -203,16 +503,46 @@ impl Args {
             None
         };
 
-        Ok(Spaced {
+        let undo = {
+            let committed_tip = chain.state.tip.read().expect("tip");
+            UndoLog::open(
+                data_dir.join("undo.dat"),
+                args.max_reorg_depth,
+                &committed_tip,
+            )?
+        };
+
+        let journal = Journal::new(data_dir.join("journal.dat"));
+        let mut spaced = Spaced {
             network: args.chain,
+            custom_chain,
             rpc,
+            blocks_dir: args.bitcoin_blocks_dir,
+            rest: args.bitcoin_rest,
+            zmq_hashblock: args.bitcoin_zmq_hashblock,
+            esplora_url: args.esplora_url,
+            header_rules,
+            tip_notifier: TipNotifier::default(),
+            sync_progress: SyncProgress::default(),
+            metrics: Metrics::default(),
+            metrics_endpoint: args.metrics,
+            ready_max_lag: args.ready_max_lag,
+            record_blocks: args.record_blocks,
+            record_blocks_overwrite: args.record_blocks_overwrite,
+            replay,
             data_dir,
             bind: rpc_bind_addresses,
             chain,
             block_index,
             block_index_full: args.block_index_full,
             num_workers: args.jobs as usize,
-        })
+            undo,
+            journal,
+            retention,
+        };
+        spaced.recover()?;
+
+        Ok((spaced, args.command))
     }
 
     /// Merges configuration file if set and command line arguments (latter takes priority)
@@ -222,14 +552,7 @@ impl Args {
             None => Args::try_parse_from(env::args_os()),
             Some((config_args, keys)) => {
                 config_args_keys = Some(keys);
-                let cmd_args = env::args_os().collect::<Vec<OsString>>();
-                let config_args: Vec<OsString> = config_args.iter().map(|s| s.into()).collect();
-                let all_args = cmd_args
-                    .iter()
-                    .take(1)
-                    .chain(config_args.iter())
-                    .chain(cmd_args.iter().skip(1));
-                Args::try_parse_from(all_args)
+                Args::try_parse_from(merge_args(env::args_os().collect(), config_args))
             }
         };
 
@@ -267,6 +590,30 @@ pub fn safe_exit(code: i32) -> ! {
     std::process::exit(code)
 }
 
+fn parse_checkpoint(checkpoint: &str) -> Result<ChainAnchor, String> {
+    let (height, hash) = checkpoint
+        .split_once(':')
+        .ok_or_else(|| "expected HEIGHT:HASH".to_string())?;
+    Ok(ChainAnchor {
+        height: height
+            .parse()
+            .map_err(|e| format!("invalid height '{}': {}", height, e))?,
+        hash: hash
+            .parse()
+            .map_err(|e| format!("invalid hash '{}': {}", hash, e))?,
+    })
+}
+
+fn parse_fraction(fraction: &str) -> Result<f64, String> {
+    let value: f64 = fraction
+        .parse()
+        .map_err(|e| format!("invalid number '{}': {}", fraction, e))?;
+    if !(0.0..=1.0).contains(&value) {
+        return Err(format!("{} is not between 0 and 1", value));
+    }
+    Ok(value)
+}
+
 pub fn default_bitcoin_rpc_url(network: &ExtendedNetwork) -> &'static str {
     match network {
         ExtendedNetwork::Mainnet | ExtendedNetwork::MainnetAlpha => "http://127.0.0.1:8332",
@@ -277,16 +624,92 @@ pub fn default_bitcoin_rpc_url(network: &ExtendedNetwork) -> &'static str {
     }
 }
 
-fn toml_value_to_string(value: Value) -> Option<String> {
+/// Reads the `[custom_chain]` table of a configuration file if present
+fn load_custom_chain(path: &PathBuf) -> anyhow::Result<Option<CustomChain>> {
+    #[derive(Deserialize)]
+    struct ConfigFile {
+        custom_chain: Option<CustomChain>,
+    }
+
+    let config: ConfigFile = toml::from_str(&fs::read_to_string(path)?)
+        .map_err(|e| anyhow::anyhow!("Invalid custom chain in {}: {}", path.display(), e))?;
+    Ok(config.custom_chain)
+}
+
+/// Values of an option, with arrays passing the option once per element
+fn toml_value_to_strings(key: &str, value: Value) -> Result<Vec<String>, String> {
     match value {
-        Value::String(v) => Some(v),
-        Value::Integer(i) => Some(i.to_string()),
-        Value::Float(f) => Some(f.to_string()),
-        Value::Boolean(b) => Some(b.to_string()),
-        Value::Datetime(d) => Some(d.to_string()),
-        Value::Array(_) => None,
-        Value::Table(_) => None,
+        Value::String(v) => Ok(vec![v]),
+        Value::Integer(i) => Ok(vec![i.to_string()]),
+        Value::Float(f) => Ok(vec![f.to_string()]),
+        Value::Boolean(b) => Ok(vec![b.to_string()]),
+        Value::Datetime(d) => Ok(vec![d.to_string()]),
+        Value::Array(values) => {
+            let mut strings = Vec::with_capacity(values.len());
+            for value in values {
+                if matches!(value, Value::Array(_) | Value::Table(_)) {
+                    return Err(format!("config.{} must be an array of values", key));
+                }
+                strings.extend(toml_value_to_strings(key, value)?);
+            }
+            Ok(strings)
+        }
+        Value::Table(_) => Err(format!("config.{} must be a value or an array", key)),
+    }
+}
+
+/// Converts configuration options to command line arguments returning them
+/// along with the set of options found
+fn config_to_args(
+    config: HashMap<String, Value>,
+) -> Result<(Vec<String>, HashSet<String>), String> {
+    let mut args = Vec::new();
+    let mut config_args = HashSet::new();
+    for (key, value) in config {
+        // Read separately by load_custom_chain
+        if key == "custom_chain" {
+            continue;
+        }
+        let arg = format!("--{}", key.replace('_', "-"));
+        for value in toml_value_to_strings(&key, value)? {
+            args.push(arg.clone());
+            args.push(value);
+        }
+        config_args.insert(arg);
+    }
+    Ok((args, config_args))
+}
+
+/// Puts configuration arguments before command line arguments leaving out
+/// options that are also given on the command line so that they replace
+/// rather than add to the configured values
+fn merge_args(cmd_args: Vec<OsString>, config_args: Vec<String>) -> Vec<OsString> {
+    let command = Args::command();
+    let cmd_options: HashSet<String> = cmd_args
+        .iter()
+        .skip(1)
+        .filter_map(|arg| {
+            let arg = arg.to_str()?;
+            if let Some(long) = arg.strip_prefix("--") {
+                return Some(format!("--{}", long.split('=').next().unwrap_or_default()));
+            }
+            let short = arg.strip_prefix('-')?.chars().next()?;
+            command
+                .get_arguments()
+                .find(|arg| arg.get_short() == Some(short))
+                .and_then(|arg| arg.get_long())
+                .map(|long| format!("--{}", long))
+        })
+        .collect();
+
+    let mut args: Vec<OsString> = cmd_args.iter().take(1).cloned().collect();
+    for pair in config_args.chunks(2) {
+        if !cmd_options.contains(&pair[0]) {
+            args.extend(pair.iter().map(OsString::from));
+        }
     }
+    args.extend(cmd_args.into_iter().skip(1));
+    args
 }
 
 fn load_args(path: Option<PathBuf>) -> Option<(Vec<String>, HashSet<String>)> {
@@ -295,21 +718,17 @@ fn load_args(path: Option<PathBuf>) -> Option<(Vec<String>, HashSet<String>)> {
         Some(p) => p,
     };
 
-    let mut config_args = HashSet::new();
     if let Ok(config_str) = fs::read_to_string(path.clone()) {
-        let config: HashMap<String, toml::Value> = toml::from_str(&config_str).expect("parse");
-        let mut args = Vec::new();
-        for (key, value) in config {
-            let value = match toml_value_to_string(value) {
-                None => continue,
-                Some(v) => v,
-            };
-            let arg = format!("--{}", key.replace('_', "-"));
-            config_args.insert(arg.clone());
-            args.push(arg);
-            args.push(value);
-        }
-        return Some((args, config_args));
+        let parsed = toml::from_str::<HashMap<String, Value>>(&config_str)
+            .map_err(|e| e.to_string())
+            .and_then(config_to_args);
+        return match parsed {
+            Ok(parsed) => Some(parsed),
+            Err(e) => {
+                error!("invalid configuration at {}: {}", path.display(), e);
+                safe_exit(1);
+            }
+        };
     }
 
     error!("could not read configuration at {}", path.to_str().unwrap());
@@ -387,3 +806,84 @@ pub fn default_spaces_rpc_port(chain: &ExtendedNetwork) -> u16 {
         ExtendedNetwork::Regtest => 7218,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn os_args(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    fn config(config: &str) -> Result<(Vec<String>, HashSet<String>), String> {
+        config_to_args(toml::from_str(config).expect("valid toml"))
+    }
+
+    #[test]
+    fn test_config_arrays_repeat_the_option() {
+        let (args, keys) = config(r#"bitcoin_rpc_url = ["http://a", "http://b"]"#).unwrap();
+        assert_eq!(
+            args,
+            vec![
+                "--bitcoin-rpc-url",
+                "http://a",
+                "--bitcoin-rpc-url",
+                "http://b"
+            ]
+        );
+        assert!(keys.contains("--bitcoin-rpc-url"));
+
+        let parsed = Args::try_parse_from(merge_args(os_args(&["spaced"]), args)).unwrap();
+        assert_eq!(parsed.bitcoin_rpc_url, vec!["http://a", "http://b"]);
+    }
+
+    #[test]
+    fn test_config_rejects_unsupported_values() {
+        assert!(config(r#"bitcoin_rpc_url = [["http://a"]]"#).is_err());
+        assert!(config("[bitcoin_rpc]\nurl = \"http://a\"").is_err());
+        assert!(config("[custom_chain]\nname = \"test\"").is_ok());
+    }
+
+    #[test]
+    fn test_command_line_replaces_config() {
+        let (config_args, _) =
+            config("bitcoin_rpc_url = [\"http://a\", \"http://b\"]\njobs = 2").unwrap();
+        let cmd_args = os_args(&["spaced", "--bitcoin-rpc-url=http://c", "-j", "4"]);
+        let args = Args::try_parse_from(merge_args(cmd_args, config_args)).unwrap();
+        assert_eq!(args.bitcoin_rpc_url, vec!["http://c"]);
+        assert_eq!(args.jobs, 4);
+
+        let (config_args, _) = config("jobs = 2").unwrap();
+        let args = Args::try_parse_from(merge_args(os_args(&["spaced"]), config_args)).unwrap();
+        assert_eq!(args.jobs, 2);
+    }
+
+    #[test]
+    fn test_replay_and_recording_flags() {
+        let parse = |args: &[&str]| Args::try_parse_from(os_args(args));
+        let args = parse(&["spaced", "--replay-blocks", "sync.rec"]).unwrap();
+        assert_eq!(args.replay_blocks, Some(PathBuf::from("sync.rec")));
+
+        assert!(parse(&["spaced", "--replay-blocks=a.rec", "--record-blocks=b.rec"]).is_err());
+        assert!(parse(&["spaced", "--record-blocks-overwrite"]).is_err());
+        let args = parse(&[
+            "spaced",
+            "--record-blocks=b.rec",
+            "--record-blocks-overwrite",
+        ])
+        .unwrap();
+        assert!(args.record_blocks_overwrite);
+    }
+
+    #[test]
+    fn test_retry_jitter_is_a_fraction() {
+        let parse = |jitter: &str| {
+            Args::try_parse_from(os_args(&["spaced", "--bitcoin-rpc-retry-jitter", jitter]))
+        };
+        assert_eq!(parse("0").unwrap().bitcoin_rpc_retry_jitter, 0.0);
+        assert_eq!(parse("1").unwrap().bitcoin_rpc_retry_jitter, 1.0);
+        assert!(parse("1.5").is_err());
+        assert!(parse("-0.1").is_err());
+        assert!(parse("half").is_err());
+    }
+}
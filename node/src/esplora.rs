@@ -0,0 +1,105 @@
+use std::str::FromStr;
+
+use protocol::{
+    bitcoin::{block::Header, consensus::encode::deserialize, hex::FromHex, Block, BlockHash},
+    constants::ChainAnchor,
+};
+use serde::Deserialize;
+
+use crate::{node::BlockSource, source::BitcoinRpcError};
+
+/// Reads blocks from an Esplora compatible HTTP API such as
+/// Blockstream's esplora or electrs for deployments without
+/// access to bitcoind.
+#[derive(Clone)]
+pub struct EsploraBlockSource {
+    client: reqwest::blocking::Client,
+    url: String,
+}
+
+#[derive(Deserialize)]
+struct BlockInfo {
+    height: u32,
+    mediantime: Option<u64>,
+}
+
+impl EsploraBlockSource {
+    pub fn new(url: &str) -> Self {
+        Self {
+            client: reqwest::blocking::Client::new(),
+            url: url.trim_end_matches('/').to_string(),
+        }
+    }
+
+    fn get(&self, path: &str) -> Result<reqwest::blocking::Response, BitcoinRpcError> {
+        let res = self.client.get(format!("{}{}", self.url, path)).send()?;
+        let status = res.status();
+        if !status.is_success() {
+            let body = res.text().unwrap_or_default();
+            return Err(BitcoinRpcError::Other(format!(
+                "Esplora {} (HTTP code: {}): {}",
+                path,
+                status,
+                body.trim()
+            )));
+        }
+        Ok(res)
+    }
+
+    fn get_hash(&self, path: &str) -> Result<BlockHash, BitcoinRpcError> {
+        let text = self.get(path)?.text()?;
+        BlockHash::from_str(text.trim()).map_err(|e| {
+            BitcoinRpcError::Other(format!("Esplora {}: invalid block hash: {}", path, e))
+        })
+    }
+
+    fn get_block_info(&self, hash: &BlockHash) -> Result<BlockInfo, BitcoinRpcError> {
+        Ok(self.get(&format!("/block/{}", hash))?.json()?)
+    }
+}
+
+impl BlockSource for EsploraBlockSource {
+    fn get_block_hash(&self, height: u32) -> Result<BlockHash, BitcoinRpcError> {
+        self.get_hash(&format!("/block-height/{}", height))
+    }
+
+    fn get_block(&self, hash: &BlockHash) -> Result<Block, BitcoinRpcError> {
+        let raw = self.get(&format!("/block/{}/raw", hash))?.bytes()?;
+        deserialize(&raw)
+            .map_err(|e| BitcoinRpcError::Other(format!("Block Deserialize error: {}", e)))
+    }
+
+    fn get_block_header(&self, hash: &BlockHash) -> Result<Header, BitcoinRpcError> {
+        let path = format!("/block/{}/header", hash);
+        let text = self.get(&path)?.text()?;
+        Vec::<u8>::from_hex(text.trim())
+            .map_err(|e| e.to_string())
+            .and_then(|raw| deserialize(&raw).map_err(|e| e.to_string()))
+            .map_err(|e| BitcoinRpcError::Other(format!("Esplora {}: invalid header: {}", path, e)))
+    }
+
+    fn get_median_time(&self) -> Result<u64, BitcoinRpcError> {
+        let tip = self.get_hash("/blocks/tip/hash")?;
+        self.get_block_info(&tip)?
+            .mediantime
+            .ok_or_else(|| BitcoinRpcError::Other("Could not fetch median time".to_string()))
+    }
+
+    fn get_block_count(&self) -> Result<u64, BitcoinRpcError> {
+        let text = self.get("/blocks/tip/height")?.text()?;
+        text.trim()
+            .parse()
+            .map_err(|e| BitcoinRpcError::Other(format!("Esplora /blocks/tip/height: {}", e)))
+    }
+
+    fn get_best_chain(&self) -> Result<ChainAnchor, BitcoinRpcError> {
+        // The height is looked up by hash so both refer to the same block
+        // even if the tip changes in between
+        let hash = self.get_hash("/blocks/tip/hash")?;
+        let info = self.get_block_info(&hash)?;
+        Ok(ChainAnchor {
+            hash,
+            height: info.height,
+        })
+    }
+}
@@ -0,0 +1,222 @@
+use spacedb::Hash;
+
+const BUCKET_SIZE: usize = 4;
+const MAX_KICKS: usize = 500;
+const MIN_BUCKETS: usize = 1 << 10;
+/// Rebuild with a larger table once this fraction of slots is used
+const MAX_LOAD: f64 = 0.9;
+
+/// A cuckoo filter over `OutpointKey`s used to skip spacedb lookups
+/// for outpoints that are certainly not tracked.
+///
+/// Keys are already uniformly distributed hashes so bucket indexes and
+/// fingerprints are read directly from their bytes. Removing a key that was
+/// never inserted may evict another key's fingerprint, so callers must only
+/// remove keys that exist. Inserting a key twice is harmless and at most
+/// leaves a false positive behind after it's removed.
+#[derive(Clone)]
+pub struct OutpointFilter {
+    buckets: Vec<[u16; BUCKET_SIZE]>,
+    len: usize,
+    /// Set once an insert fails, after which every lookup is a maybe
+    /// until the filter is rebuilt
+    saturated: bool,
+}
+
+impl OutpointFilter {
+    /// Creates an empty filter able to hold at least `capacity` keys
+    pub fn with_capacity(capacity: usize) -> Self {
+        let buckets = (capacity / BUCKET_SIZE + 1)
+            .next_power_of_two()
+            .max(MIN_BUCKETS);
+        Self {
+            buckets: vec![[0; BUCKET_SIZE]; buckets],
+            len: 0,
+            saturated: false,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Whether the filter should be rebuilt with a larger capacity
+    pub fn needs_rebuild(&self) -> bool {
+        self.saturated || self.len as f64 >= (self.buckets.len() * BUCKET_SIZE) as f64 * MAX_LOAD
+    }
+
+    /// Returns false if the key was definitely never inserted
+    pub fn contains(&self, key: &Hash) -> bool {
+        if self.saturated {
+            return true;
+        }
+        let (fingerprint, i1) = self.locate(key);
+        let i2 = self.alt_index(i1, fingerprint);
+        self.buckets[i1].contains(&fingerprint) || self.buckets[i2].contains(&fingerprint)
+    }
+
+    pub fn insert(&mut self, key: &Hash) {
+        if self.saturated {
+            return;
+        }
+        let (mut fingerprint, i1) = self.locate(key);
+        let i2 = self.alt_index(i1, fingerprint);
+        if self.insert_into(i1, fingerprint) || self.insert_into(i2, fingerprint) {
+            self.len += 1;
+            return;
+        }
+
+        // Relocate existing fingerprints to their alternate buckets
+        let mut index = if fingerprint & 1 == 0 { i1 } else { i2 };
+        for kick in 0..MAX_KICKS {
+            let slot = kick % BUCKET_SIZE;
+            std::mem::swap(&mut fingerprint, &mut self.buckets[index][slot]);
+            index = self.alt_index(index, fingerprint);
+            if self.insert_into(index, fingerprint) {
+                self.len += 1;
+                return;
+            }
+        }
+        self.saturated = true;
+    }
+
+    /// Removes a key that is known to exist
+    pub fn remove(&mut self, key: &Hash) {
+        if self.saturated {
+            return;
+        }
+        let (fingerprint, i1) = self.locate(key);
+        let i2 = self.alt_index(i1, fingerprint);
+        if self.remove_from(i1, fingerprint) || self.remove_from(i2, fingerprint) {
+            self.len -= 1;
+        }
+    }
+
+    #[inline]
+    fn locate(&self, key: &Hash) -> (u16, usize) {
+        // The first and last bytes carry key class bits so skip them
+        let mut fingerprint = u16::from_le_bytes([key[1], key[2]]);
+        if fingerprint == 0 {
+            fingerprint = 1;
+        }
+        let index = u64::from_le_bytes(key[8..16].try_into().unwrap()) as usize;
+        (fingerprint, index & self.mask())
+    }
+
+    #[inline]
+    fn alt_index(&self, index: usize, fingerprint: u16) -> usize {
+        let hash = (fingerprint as u64).wrapping_mul(0x5bd1_e995_5bd1_e995) as usize;
+        (index ^ hash) & self.mask()
+    }
+
+    #[inline]
+    fn mask(&self) -> usize {
+        self.buckets.len() - 1
+    }
+
+    fn insert_into(&mut self, index: usize, fingerprint: u16) -> bool {
+        match self.buckets[index].iter_mut().find(|slot| **slot == 0) {
+            Some(slot) => {
+                *slot = fingerprint;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn remove_from(&mut self, index: usize, fingerprint: u16) -> bool {
+        match self.buckets[index]
+            .iter_mut()
+            .find(|slot| **slot == fingerprint)
+        {
+            Some(slot) => {
+                *slot = 0;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An outpoint-like key with the given fingerprint and bucket bytes
+    fn key(fingerprint: u16, index: u64, tag: u8) -> Hash {
+        let mut key = [0u8; 32];
+        key[1..3].copy_from_slice(&fingerprint.to_le_bytes());
+        key[8..16].copy_from_slice(&index.to_le_bytes());
+        key[20] = tag;
+        key[31] = 1;
+        key
+    }
+
+    #[test]
+    fn test_insert_and_remove() {
+        let mut filter = OutpointFilter::with_capacity(100);
+        let keys: Vec<_> = (0..100).map(|i| key(i as u16 + 1, i * 7919, 0)).collect();
+        for key in keys.iter() {
+            assert!(!filter.contains(key));
+            filter.insert(key);
+        }
+        assert_eq!(filter.len(), 100);
+        assert!(keys.iter().all(|key| filter.contains(key)));
+
+        for key in keys.iter().take(50) {
+            filter.remove(key);
+        }
+        assert_eq!(filter.len(), 50);
+        assert!(keys.iter().take(50).all(|key| !filter.contains(key)));
+        assert!(keys.iter().skip(50).all(|key| filter.contains(key)));
+    }
+
+    #[test]
+    fn test_false_positive() {
+        let mut filter = OutpointFilter::with_capacity(0);
+        let inserted = key(42, 3, 0);
+        let other = key(42, 3, 1);
+        filter.insert(&inserted);
+        assert!(
+            filter.contains(&other),
+            "keys with the same fingerprint and bucket are indistinguishable"
+        );
+
+        // which is why only existing keys may be removed
+        filter.remove(&other);
+        assert!(!filter.contains(&inserted));
+    }
+
+    #[test]
+    fn test_needs_rebuild() {
+        let mut filter = OutpointFilter::with_capacity(0);
+        let capacity = MIN_BUCKETS * BUCKET_SIZE;
+        let mut inserted = 0;
+        while !filter.needs_rebuild() {
+            filter.insert(&key(inserted as u16 % 4096 + 1, inserted as u64, 0));
+            inserted += 1;
+            assert!(inserted <= capacity, "load factor should trigger a rebuild");
+        }
+        assert!(filter.len() as f64 >= capacity as f64 * MAX_LOAD || filter.saturated);
+
+        let rebuilt = OutpointFilter::with_capacity(filter.len() * 2);
+        assert!(!rebuilt.needs_rebuild());
+        assert!(rebuilt.buckets.len() > filter.buckets.len());
+    }
+
+    #[test]
+    fn test_saturated_filter_matches_everything() {
+        let mut filter = OutpointFilter::with_capacity(0);
+        // every key maps to the same two buckets
+        for tag in 0..=2 * BUCKET_SIZE as u8 {
+            filter.insert(&key(1, 0, tag));
+        }
+        assert!(filter.saturated);
+        assert!(filter.needs_rebuild());
+        assert!(filter.contains(&key(2, 5, 0)));
+    }
+}
@@ -0,0 +1,569 @@
+use log::warn;
+use protocol::{
+    bitcoin::{
+        block::Header, hashes::Hash, params::Params, pow::CompactTarget, Block, BlockHash, Work,
+    },
+    constants::ChainAnchor,
+};
+
+use crate::{config::ExtendedNetwork, node::BlockSource, source::BlockFetchError};
+
+/// Consensus rules fetched blocks are checked against before being applied
+#[derive(Clone)]
+pub struct HeaderRules {
+    params: Params,
+    /// Blocks at these heights must have the given hash
+    checkpoints: Vec<ChainAnchor>,
+    /// Best chains with less work reported by the source aren't synced
+    min_chain_work: Option<Work>,
+    /// Regtest difficulty is arbitrary and signets sign blocks rather
+    /// than relying on work so neither is checked
+    check_difficulty: bool,
+    /// Testnet4 retargets from the first block of the period (BIP94)
+    /// so that minimum difficulty blocks can't lower it
+    enforce_bip94: bool,
+}
+
+/// Checks blocks in the order they're emitted keeping track of
+/// what's needed to verify difficulty adjustments
+pub struct HeaderVerifier {
+    rules: HeaderRules,
+    /// Last verified block
+    tip: Option<(ChainAnchor, Header)>,
+    /// Height and header of the first block in the current retarget period
+    period_start: Option<(u32, Header)>,
+    /// Bits of the last block that wasn't mined at minimum difficulty
+    /// as of the given block on networks allowing those
+    regular_bits: Option<(BlockHash, CompactTarget)>,
+    warned_low_work: bool,
+}
+
+impl HeaderRules {
+    pub fn new(network: ExtendedNetwork) -> Self {
+        Self {
+            params: Params::new(network.fallback_network()),
+            checkpoints: Vec::new(),
+            min_chain_work: None,
+            check_difficulty: !matches!(
+                network,
+                ExtendedNetwork::Regtest | ExtendedNetwork::Signet
+            ),
+            enforce_bip94: network == ExtendedNetwork::Testnet4,
+        }
+    }
+
+    pub fn with_checkpoints(mut self, checkpoints: impl IntoIterator<Item = ChainAnchor>) -> Self {
+        self.checkpoints.extend(checkpoints);
+        self
+    }
+
+    /// Blocks whose hash is compiled in rather than taken from the source.
+    /// Activation blocks resolved by height at startup aren't included so
+    /// their networks rely on configured checkpoints.
+    pub fn default_checkpoints(network: ExtendedNetwork) -> Vec<ChainAnchor> {
+        let activation = match network {
+            ExtendedNetwork::Testnet => ChainAnchor::TESTNET(),
+            ExtendedNetwork::Signet => ChainAnchor::SIGNET(),
+            ExtendedNetwork::Regtest => ChainAnchor::REGTEST(),
+            ExtendedNetwork::Mainnet => ChainAnchor::MAINNET(),
+            ExtendedNetwork::MainnetAlpha => ChainAnchor::MAINNET_ALPHA(),
+            ExtendedNetwork::Testnet4 => ChainAnchor::TESTNET4(),
+        };
+        match activation.hash == BlockHash::all_zeros() {
+            true => Vec::new(),
+            false => vec![activation],
+        }
+    }
+
+    pub fn with_min_chain_work(mut self, work: Option<Work>) -> Self {
+        self.min_chain_work = work;
+        self
+    }
+
+    /// Minimum chain work used unless configured otherwise
+    pub fn default_min_chain_work(network: ExtendedNetwork) -> Option<Work> {
+        match network {
+            // Conservatively below the work of the mainnet chain in late 2024
+            ExtendedNetwork::Mainnet | ExtendedNetwork::MainnetAlpha => Some(
+                Work::from_unprefixed_hex(
+                    "000000000000000000000000000000000000000088e186b70e0862c193ec44d6",
+                )
+                .expect("valid work"),
+            ),
+            _ => None,
+        }
+    }
+}
+
+impl HeaderVerifier {
+    pub fn new(rules: HeaderRules) -> Self {
+        Self {
+            rules,
+            tip: None,
+            period_start: None,
+            regular_bits: None,
+            warned_low_work: false,
+        }
+    }
+
+    /// Whether the best chain of the source has at least the minimum chain
+    /// work. Sources that don't report chain work are assumed to have it.
+    pub fn has_min_chain_work(&mut self, src: &impl BlockSource) -> Result<bool, BlockFetchError> {
+        let min = match self.rules.min_chain_work {
+            None => return Ok(true),
+            Some(min) => min,
+        };
+        let work = match src.get_best_chain_work()? {
+            None => return Ok(true),
+            Some(work) => work,
+        };
+        if work < min {
+            if !self.warned_low_work {
+                warn!(
+                    "Best chain has less than the minimum chain work ({:x} < {:x}) - waiting for bitcoind to sync",
+                    work, min
+                );
+                self.warned_low_work = true;
+            }
+            return Ok(false);
+        }
+        self.warned_low_work = false;
+        Ok(true)
+    }
+
+    /// Verifies a block extending `prev` for proof-of-work, difficulty,
+    /// merkle root and checkpoints. `prev` is checked against checkpoints
+    /// too since the first block extends the activation block.
+    pub fn verify(
+        &mut self,
+        src: &impl BlockSource,
+        prev: ChainAnchor,
+        id: ChainAnchor,
+        block: &Block,
+    ) -> Result<(), BlockFetchError> {
+        self.check_checkpoint(prev)?;
+        self.check_checkpoint(id)?;
+
+        let invalid = |reason: &str| BlockFetchError::InvalidBlock(id, reason.to_string());
+        let header = block.header;
+        if header.block_hash() != id.hash {
+            return Err(invalid("header does not match the requested block hash"));
+        }
+        if !block.check_merkle_root() {
+            return Err(invalid("bad merkle root"));
+        }
+        if !block.check_witness_commitment() {
+            return Err(invalid("bad witness commitment"));
+        }
+        if header.target() > self.rules.params.pow_limit {
+            return Err(invalid("target above the network limit"));
+        }
+        if header.validate_pow(header.target()).is_err() {
+            return Err(invalid("insufficient proof of work"));
+        }
+
+        let prev_header = self.prev_header(src, prev)?;
+        let interval = self.rules.params.difficulty_adjustment_interval() as u32;
+        if self.rules.check_difficulty {
+            let expected = self.expected_bits(src, prev, &prev_header, id, &header)?;
+            if expected != header.bits {
+                return Err(invalid("unexpected difficulty"));
+            }
+        }
+
+        if id.height % interval == 0 {
+            self.period_start = Some((id.height, header));
+        }
+        let min_bits = self.rules.params.pow_limit.to_compact_lossy();
+        self.regular_bits = match self.regular_bits {
+            _ if id.height % interval == 0 || header.bits != min_bits => {
+                Some((id.hash, header.bits))
+            }
+            Some((hash, bits)) if hash == prev.hash => Some((id.hash, bits)),
+            _ => None,
+        };
+        self.tip = Some((id, header));
+        Ok(())
+    }
+
+    /// Bits required of a block extending `prev` following bitcoind's
+    /// `GetNextWorkRequired`
+    fn expected_bits(
+        &mut self,
+        src: &impl BlockSource,
+        prev: ChainAnchor,
+        prev_header: &Header,
+        id: ChainAnchor,
+        header: &Header,
+    ) -> Result<CompactTarget, BlockFetchError> {
+        let interval = self.rules.params.difficulty_adjustment_interval() as u32;
+        if id.height % interval == 0 {
+            let first = self.period_start(src, id.height - interval)?;
+            let timespan = prev_header.time.saturating_sub(first.time) as u64;
+            let bits = match self.rules.enforce_bip94 {
+                true => first.bits,
+                false => prev_header.bits,
+            };
+            return Ok(CompactTarget::from_next_work_required(
+                bits,
+                timespan,
+                &self.rules.params,
+            ));
+        }
+        let params = &self.rules.params;
+        if !params.allow_min_difficulty_blocks {
+            return Ok(prev_header.bits);
+        }
+
+        // Test networks allow a minimum difficulty block once no block
+        // was found for twice the target spacing (20 minutes), blocks
+        // after it go back to the difficulty from before
+        if header.time as u64 > prev_header.time as u64 + 2 * params.pow_target_spacing {
+            return Ok(params.pow_limit.to_compact_lossy());
+        }
+        self.last_regular_bits(src, prev, prev_header)
+    }
+
+    /// Bits of the last block up to `prev` that's either the first of its
+    /// retarget period or wasn't mined at minimum difficulty
+    fn last_regular_bits(
+        &mut self,
+        src: &impl BlockSource,
+        prev: ChainAnchor,
+        prev_header: &Header,
+    ) -> Result<CompactTarget, BlockFetchError> {
+        if let Some((hash, bits)) = self.regular_bits {
+            if hash == prev.hash {
+                return Ok(bits);
+            }
+        }
+        let interval = self.rules.params.difficulty_adjustment_interval() as u32;
+        let min_bits = self.rules.params.pow_limit.to_compact_lossy();
+        let mut height = prev.height;
+        let mut header = *prev_header;
+        while height > 0 && height % interval != 0 && header.bits == min_bits {
+            header = src.get_block_header(&header.prev_blockhash)?;
+            height -= 1;
+        }
+        self.regular_bits = Some((prev.hash, header.bits));
+        Ok(header.bits)
+    }
+
+    fn check_checkpoint(&self, id: ChainAnchor) -> Result<(), BlockFetchError> {
+        match self
+            .rules
+            .checkpoints
+            .iter()
+            .find(|checkpoint| checkpoint.height == id.height)
+        {
+            Some(checkpoint) if checkpoint.hash != id.hash => {
+                Err(BlockFetchError::CheckpointMismatch(*checkpoint))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn prev_header(
+        &self,
+        src: &impl BlockSource,
+        prev: ChainAnchor,
+    ) -> Result<Header, BlockFetchError> {
+        match self.tip {
+            Some((tip, header)) if tip.hash == prev.hash => Ok(header),
+            _ => Ok(src.get_block_header(&prev.hash)?),
+        }
+    }
+
+    fn period_start(
+        &mut self,
+        src: &impl BlockSource,
+        height: u32,
+    ) -> Result<Header, BlockFetchError> {
+        if let Some((start, header)) = self.period_start {
+            if start == height {
+                return Ok(header);
+            }
+        }
+        let hash: BlockHash = src.get_block_hash(height)?;
+        let header = src.get_block_header(&hash)?;
+        self.period_start = Some((height, header));
+        Ok(header)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use protocol::bitcoin::{
+        block::Version, blockdata::constants::genesis_block, Network, TxMerkleNode,
+    };
+
+    use super::*;
+    use crate::source::BitcoinRpcError;
+
+    /// Blocks of a chain by height
+    struct Chain(Vec<Block>);
+
+    impl BlockSource for Chain {
+        fn get_block_hash(&self, height: u32) -> Result<BlockHash, BitcoinRpcError> {
+            self.0
+                .get(height as usize)
+                .map(Block::block_hash)
+                .ok_or_else(|| BitcoinRpcError::Other("unknown height".to_string()))
+        }
+
+        fn get_block(&self, hash: &BlockHash) -> Result<Block, BitcoinRpcError> {
+            self.0
+                .iter()
+                .find(|block| block.block_hash() == *hash)
+                .cloned()
+                .ok_or_else(|| BitcoinRpcError::Other("unknown block".to_string()))
+        }
+
+        fn get_median_time(&self) -> Result<u64, BitcoinRpcError> {
+            Ok(0)
+        }
+
+        fn get_block_count(&self) -> Result<u64, BitcoinRpcError> {
+            Ok(self.0.len() as u64 - 1)
+        }
+
+        fn get_best_chain(&self) -> Result<ChainAnchor, BitcoinRpcError> {
+            let height = self.0.len() as u32 - 1;
+            Ok(id(&self.0[height as usize], height))
+        }
+    }
+
+    fn id(block: &Block, height: u32) -> ChainAnchor {
+        ChainAnchor {
+            hash: block.block_hash(),
+            height,
+        }
+    }
+
+    /// Builds a block on `prev` grinding the nonce until its proof of work
+    /// is valid or, if `valid` is false, until it isn't
+    fn mine(prev: &Block, bits: CompactTarget, valid: bool) -> Block {
+        let mut block = Block {
+            header: Header {
+                version: Version::TWO,
+                prev_blockhash: prev.block_hash(),
+                merkle_root: TxMerkleNode::all_zeros(),
+                time: prev.header.time + 1,
+                bits,
+                nonce: 0,
+            },
+            txdata: prev.txdata[..1].to_vec(),
+        };
+        block.header.merkle_root = block.compute_merkle_root().expect("merkle root");
+        while block.header.validate_pow(block.header.target()).is_ok() != valid {
+            block.header.nonce += 1;
+        }
+        block
+    }
+
+    /// Builds a block on `prev` mined `delay` seconds after it
+    fn mine_after(prev: &Block, bits: CompactTarget, delay: u32) -> Block {
+        let mut block = mine(prev, bits, true);
+        block.header.time = prev.header.time + delay;
+        regrind(&mut block);
+        block
+    }
+
+    fn regrind(block: &mut Block) {
+        block.header.nonce = 0;
+        while block.header.validate_pow(block.header.target()).is_err() {
+            block.header.nonce += 1;
+        }
+    }
+
+    fn assert_invalid(result: Result<(), BlockFetchError>, expected: &str) {
+        match result {
+            Err(BlockFetchError::InvalidBlock(_, reason)) => assert_eq!(reason, expected),
+            Err(e) => panic!("expected '{}', got {}", expected, e),
+            Ok(()) => panic!("expected '{}', block was accepted", expected),
+        }
+    }
+
+    #[test]
+    fn test_proof_of_work_failures() {
+        let genesis = genesis_block(Network::Regtest);
+        let chain = Chain(vec![genesis.clone()]);
+        let prev = id(&genesis, 0);
+        let mut verifier = HeaderVerifier::new(HeaderRules::new(ExtendedNetwork::Regtest));
+
+        let weak = mine(&genesis, genesis.header.bits, false);
+        assert_invalid(
+            verifier.verify(&chain, prev, id(&weak, 1), &weak),
+            "insufficient proof of work",
+        );
+
+        let above_limit = mine(&genesis, CompactTarget::from_consensus(0x2100ffff), true);
+        assert_invalid(
+            verifier.verify(&chain, prev, id(&above_limit, 1), &above_limit),
+            "target above the network limit",
+        );
+
+        let valid = mine(&genesis, genesis.header.bits, true);
+        verifier
+            .verify(&chain, prev, id(&valid, 1), &valid)
+            .expect("valid block");
+    }
+
+    #[test]
+    fn test_difficulty_retarget() {
+        // Retarget every 4 blocks
+        let mut params = Params::new(Network::Regtest);
+        params.no_pow_retargeting = false;
+        params.allow_min_difficulty_blocks = false;
+        params.pow_target_spacing = params.pow_target_timespan / 4;
+        let rules = HeaderRules {
+            params: params.clone(),
+            checkpoints: Vec::new(),
+            min_chain_work: None,
+            check_difficulty: true,
+            enforce_bip94: false,
+        };
+
+        let genesis = genesis_block(Network::Regtest);
+        let mut blocks = vec![genesis.clone()];
+        for _ in 1..4 {
+            let prev = blocks.last().expect("genesis");
+            blocks.push(mine(prev, prev.header.bits, true));
+        }
+        let chain = Chain(blocks.clone());
+        let mut verifier = HeaderVerifier::new(rules.clone());
+        for height in 1..4 {
+            verifier
+                .verify(
+                    &chain,
+                    id(&blocks[height - 1], height as u32 - 1),
+                    id(&blocks[height], height as u32),
+                    &blocks[height],
+                )
+                .expect("same difficulty within the period");
+        }
+
+        // The period was mined much faster than targeted
+        let prev = &blocks[3];
+        let timespan = (prev.header.time - genesis.header.time) as u64;
+        let expected = CompactTarget::from_next_work_required(prev.header.bits, timespan, &params);
+        assert_ne!(expected, prev.header.bits);
+
+        let unadjusted = mine(prev, prev.header.bits, true);
+        assert_invalid(
+            verifier.verify(&chain, id(prev, 3), id(&unadjusted, 4), &unadjusted),
+            "unexpected difficulty",
+        );
+        let adjusted = mine(prev, expected, true);
+        verifier
+            .verify(&chain, id(prev, 3), id(&adjusted, 4), &adjusted)
+            .expect("retargeted block");
+
+        // The start of the period is looked up from the source after a restart
+        let mut restarted = HeaderVerifier::new(rules);
+        restarted
+            .verify(&chain, id(prev, 3), id(&adjusted, 4), &adjusted)
+            .expect("retargeted block");
+    }
+
+    #[test]
+    fn test_checkpoints() {
+        let genesis = genesis_block(Network::Regtest);
+        let chain = Chain(vec![genesis.clone()]);
+        let block = mine(&genesis, genesis.header.bits, true);
+
+        let wrong = ChainAnchor {
+            hash: BlockHash::all_zeros(),
+            height: 0,
+        };
+        let rules = HeaderRules::new(ExtendedNetwork::Regtest).with_checkpoints([wrong]);
+        match HeaderVerifier::new(rules).verify(&chain, id(&genesis, 0), id(&block, 1), &block) {
+            Err(BlockFetchError::CheckpointMismatch(checkpoint)) => assert_eq!(checkpoint, wrong),
+            _ => panic!("expected a checkpoint mismatch on the activation block"),
+        }
+
+        let rules = HeaderRules::new(ExtendedNetwork::Regtest)
+            .with_checkpoints(HeaderRules::default_checkpoints(ExtendedNetwork::Regtest));
+        HeaderVerifier::new(rules)
+            .verify(&chain, id(&genesis, 0), id(&block, 1), &block)
+            .expect("compiled in activation block");
+    }
+
+    #[test]
+    fn test_min_difficulty_blocks() {
+        // Testnet rules retargeting every 4 blocks with a limit that's quick to mine
+        let mut params = Params::new(Network::Testnet);
+        params.pow_limit = Params::new(Network::Regtest).pow_limit;
+        params.pow_target_timespan = 4 * params.pow_target_spacing;
+        let rules = |enforce_bip94| HeaderRules {
+            params: params.clone(),
+            checkpoints: Vec::new(),
+            min_chain_work: None,
+            check_difficulty: true,
+            enforce_bip94,
+        };
+        let spacing = params.pow_target_spacing as u32;
+        let min_bits = params.pow_limit.to_compact_lossy();
+        let bits = CompactTarget::from_consensus(0x2000ffff);
+
+        let mut genesis = genesis_block(Network::Regtest);
+        genesis.header.bits = bits;
+        regrind(&mut genesis);
+        let first = mine_after(&genesis, bits, spacing);
+        // No block was found for more than 20 minutes
+        let min_difficulty = mine_after(&first, min_bits, 2 * spacing + 1);
+        let blocks = vec![genesis.clone(), first.clone(), min_difficulty.clone()];
+        let chain = Chain(blocks);
+
+        let mut verifier = HeaderVerifier::new(rules(false));
+        verifier
+            .verify(&chain, id(&genesis, 0), id(&first, 1), &first)
+            .expect("regular block");
+        let early = mine_after(&first, min_bits, 2 * spacing);
+        assert_invalid(
+            verifier.verify(&chain, id(&first, 1), id(&early, 2), &early),
+            "unexpected difficulty",
+        );
+        verifier
+            .verify(
+                &chain,
+                id(&first, 1),
+                id(&min_difficulty, 2),
+                &min_difficulty,
+            )
+            .expect("minimum difficulty block after 20 minutes");
+
+        // Blocks after it go back to the difficulty from before,
+        // which is looked up from the source after a restart
+        let still_min = mine_after(&min_difficulty, min_bits, spacing);
+        let back = mine_after(&min_difficulty, bits, spacing);
+        for verifier in [&mut verifier, &mut HeaderVerifier::new(rules(false))] {
+            assert_invalid(
+                verifier.verify(
+                    &chain,
+                    id(&min_difficulty, 2),
+                    id(&still_min, 3),
+                    &still_min,
+                ),
+                "unexpected difficulty",
+            );
+            verifier
+                .verify(&chain, id(&min_difficulty, 2), id(&back, 3), &back)
+                .expect("regular block");
+        }
+
+        // Testnet4 retargets from the first block of the period rather
+        // than from the last which may be at minimum difficulty
+        let last = mine_after(&min_difficulty, min_bits, 2 * spacing + 1);
+        let chain = Chain(vec![genesis.clone(), first, min_difficulty, last.clone()]);
+        let timespan = (last.header.time - genesis.header.time) as u64;
+        let adjusted = |from| CompactTarget::from_next_work_required(from, timespan, &params);
+        assert_ne!(adjusted(min_bits), adjusted(bits));
+        for (enforce_bip94, from) in [(false, min_bits), (true, bits)] {
+            let retargeted = mine_after(&last, adjusted(from), spacing);
+            HeaderVerifier::new(rules(enforce_bip94))
+                .verify(&chain, id(&last, 3), id(&retargeted, 4), &retargeted)
+                .expect("retargeted block");
+        }
+    }
+}
@@ -0,0 +1,92 @@
+use std::{
+    error::Error,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use hyper::{header, Body, Method, Request, Response, StatusCode};
+use tower::{Layer, Service};
+
+use crate::rpc::{NodeStatus, RpcServerImpl};
+
+/// HTTP middleware answering `GET /health` and `GET /ready` on the RPC server.
+///
+/// Both respond with the node status as JSON: `/health` succeeds while
+/// bitcoind is reachable and `/ready` once spaced has caught up with it.
+/// Otherwise they respond with 503 so load balancers can route around the node.
+#[derive(Clone)]
+pub struct HealthLayer {
+    server: RpcServerImpl,
+}
+
+impl HealthLayer {
+    pub fn new(server: RpcServerImpl) -> Self {
+        Self { server }
+    }
+}
+
+impl<S> Layer<S> for HealthLayer {
+    type Service = HealthService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        HealthService {
+            inner,
+            server: self.server.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct HealthService<S> {
+    inner: S,
+    server: RpcServerImpl,
+}
+
+impl<S> Service<Request<Body>> for HealthService<S>
+where
+    S: Service<Request<Body>, Response = Response<Body>>,
+    S::Error: Into<Box<dyn Error + Send + Sync>> + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = Response<Body>;
+    type Error = Box<dyn Error + Send + Sync>;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        let check: fn(&NodeStatus) -> bool = match request.uri().path() {
+            "/health" if request.method() == Method::GET => |status| status.healthy,
+            "/ready" if request.method() == Method::GET => |status| status.ready,
+            _ => {
+                let response = self.inner.call(request);
+                return Box::pin(async move { response.await.map_err(Into::into) });
+            }
+        };
+
+        let server = self.server.clone();
+        Box::pin(async move {
+            let (status, body) = match server.node_status().await {
+                Ok(node_status) => {
+                    let status = match check(&node_status) {
+                        true => StatusCode::OK,
+                        false => StatusCode::SERVICE_UNAVAILABLE,
+                    };
+                    (status, serde_json::to_string(&node_status)?)
+                }
+                Err(e) => (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    serde_json::json!({ "error": e.to_string() }).to_string(),
+                ),
+            };
+            Response::builder()
+                .status(status)
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body))
+                .map_err(Into::into)
+        })
+    }
+}
@@ -0,0 +1,199 @@
+use std::{
+    fs,
+    fs::{File, OpenOptions},
+    io::{BufReader, Read, Write},
+    path::PathBuf,
+};
+
+use anyhow::Result;
+use bincode::{config, Decode, Encode};
+use log::warn;
+use protocol::constants::ChainAnchor;
+use spacedb::{Hash, NodeHasher, Sha256Hasher};
+
+use crate::{
+    logging::STORE,
+    stats::ChainStats,
+    undo::{BlockUndo, UndoEntry},
+};
+
+/// Changes staged by a single block
+#[derive(Clone, Encode, Decode)]
+pub struct JournalEntry {
+    pub block: ChainAnchor,
+    pub parent: ChainAnchor,
+    /// Values written to the chain state
+    pub chain: Vec<UndoEntry>,
+    /// Values written to the block index
+    pub index: Vec<UndoEntry>,
+    /// Stats after the block if it changed them
+    pub stats: Option<ChainStats>,
+    /// Undo record of the block if undo records are enabled
+    pub undo: Option<BlockUndo>,
+}
+
+/// Write-ahead journal of blocks applied since the last commit.
+///
+/// Each entry is framed as `[length: u32 LE][checksum: 4 bytes][entry]`.
+/// Entries are handed to the OS as blocks are applied so they survive the
+/// process crashing, but are only synced to disk by [Journal::sync] since the
+/// state is fsynced by the commit at each boundary anyway. Blocks lost with
+/// the page cache on power loss are synced again, and a torn write at the end
+/// of the file is detected by its checksum and discarded on recovery.
+pub struct Journal {
+    path: PathBuf,
+    file: Option<File>,
+    len: usize,
+}
+
+impl Journal {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            file: None,
+            len: 0,
+        }
+    }
+
+    /// Number of blocks journaled since the last commit
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Reads all entries that connect to the committed tip. Entries that
+    /// don't connect or follow a corrupted frame are dropped from disk.
+    pub fn recover(&mut self, committed_tip: &ChainAnchor) -> Result<Vec<JournalEntry>> {
+        let mut entries = self.read()?;
+        let connected = entries
+            .iter()
+            .enumerate()
+            .take_while(|(i, entry)| {
+                let expected_parent = if *i == 0 {
+                    committed_tip
+                } else {
+                    &entries[i - 1].block
+                };
+                entry.parent == *expected_parent
+            })
+            .count();
+        if connected < entries.len() {
+            let entry = &entries[connected];
+            warn!(
+                target: STORE,
+                block:% = entry.block.hash,
+                height = entry.block.height;
+                "Journal entry does not connect to the committed state"
+            );
+            entries.truncate(connected);
+        }
+
+        self.rewrite(&entries)?;
+        Ok(entries)
+    }
+
+    /// Drops all entries after the given block. Returns false if the block
+    /// is not covered by the journal, in which case nothing is changed.
+    pub fn rewind(&mut self, to: &ChainAnchor) -> Result<bool> {
+        let mut entries = self.read()?;
+        let keep = match entries.first() {
+            Some(first) if &first.parent == to => 0,
+            _ => match entries.iter().position(|entry| &entry.block == to) {
+                Some(position) => position + 1,
+                None => return Ok(false),
+            },
+        };
+        entries.truncate(keep);
+        self.rewrite(&entries)?;
+        Ok(true)
+    }
+
+    pub fn append(&mut self, entry: &JournalEntry) -> Result<()> {
+        let payload = bincode::encode_to_vec(entry, config::standard())?;
+        let mut frame = Vec::with_capacity(payload.len() + 8);
+        frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        frame.extend_from_slice(&Self::checksum(&payload));
+        frame.extend_from_slice(&payload);
+
+        if self.file.is_none() {
+            self.file = Some(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.path)?,
+            );
+        }
+        let file = self.file.as_mut().expect("journal file");
+        file.write_all(&frame)?;
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Flushes appended entries to disk
+    pub fn sync(&self) -> Result<()> {
+        if let Some(file) = self.file.as_ref() {
+            file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Clears the journal once its changes are committed
+    pub fn truncate(&mut self) -> Result<()> {
+        self.file = None;
+        self.len = 0;
+        if self.path.exists() {
+            fs::remove_file(&self.path)?;
+        }
+        Ok(())
+    }
+
+    /// Decodes all intact entries stopping at the first incomplete or corrupted frame
+    fn read(&self) -> Result<Vec<JournalEntry>> {
+        if !self.path.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut data = Vec::new();
+        BufReader::new(File::open(&self.path)?).read_to_end(&mut data)?;
+
+        let mut entries = Vec::new();
+        let mut offset = 0;
+        while offset + 8 <= data.len() {
+            let len = u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap()) as usize;
+            let checksum = &data[offset + 4..offset + 8];
+            let start = offset + 8;
+            if start + len > data.len() {
+                warn!(target: STORE, offset; "Discarding incomplete journal entry");
+                break;
+            }
+            let payload = &data[start..start + len];
+            if Self::checksum(payload) != checksum {
+                warn!(target: STORE, offset; "Discarding corrupted journal entry");
+                break;
+            }
+            let (entry, _): (JournalEntry, _) =
+                bincode::decode_from_slice(payload, config::standard())?;
+            entries.push(entry);
+            offset = start + len;
+        }
+        Ok(entries)
+    }
+
+    fn rewrite(&mut self, entries: &[JournalEntry]) -> Result<()> {
+        self.truncate()?;
+        for entry in entries {
+            self.append(entry)?;
+        }
+        self.sync()
+    }
+
+    fn checksum(payload: &[u8]) -> [u8; 4] {
+        let hash: Hash = Sha256Hasher::hash(payload);
+        [hash[0], hash[1], hash[2], hash[3]]
+    }
+}
@@ -4,11 +4,29 @@ extern crate core;
 pub extern crate jsonrpsee;
 pub extern crate log;
 
+pub mod backend;
+pub mod blockfile;
+pub mod cache;
 pub mod config;
+pub mod esplora;
+pub mod filter;
+pub mod headers;
+pub mod health;
+pub mod journal;
+pub mod logging;
+pub mod metrics;
 pub mod node;
+pub mod progress;
+pub mod prune;
+pub mod replay;
 pub mod rpc;
+pub mod snapshot;
+pub mod stats;
 pub mod source;
 pub mod store;
 pub mod sync;
+pub mod undo;
+pub mod verify;
 pub mod wallets;
+pub mod zmq;
 mod checker;
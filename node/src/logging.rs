@@ -0,0 +1,199 @@
+use std::{io::Write, sync::RwLock};
+
+use clap::ValueEnum;
+use env_logger::{fmt::Formatter, Env, Logger};
+use log::{
+    kv::{self, Key, Value, VisitSource},
+    Log, Metadata, Record,
+};
+use serde_json::{Map, Number};
+
+/// Log target of the protocol sync
+pub const SYNC: &str = "sync";
+/// Log target of block sources and the block fetcher
+pub const FETCHER: &str = "fetcher";
+/// Log target of the JSON-RPC server
+pub const RPC: &str = "rpc";
+/// Log target of the protocol stores including the journal, undo log,
+/// snapshots, pruning and verification
+pub const STORE: &str = "store";
+/// Log target of the wallet service, see [wallet_target] for individual wallets
+pub const WALLET: &str = "wallet";
+
+/// Log target of the wallet with the given name. Filtering on `wallet`
+/// matches every wallet since targets are matched by prefix.
+pub fn wallet_target(name: &str) -> String {
+    format!("{}:{}", WALLET, name)
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default, ValueEnum)]
+pub enum LogFormat {
+    /// Human readable lines
+    #[default]
+    Text,
+    /// One JSON object per line with the subsystem and key-value fields
+    Json,
+}
+
+/// Installed once at startup so that logging works while the configuration
+/// is still being read, then replaced by [configure] with the user's settings
+struct ReloadableLogger(RwLock<Option<Logger>>);
+
+static LOGGER: ReloadableLogger = ReloadableLogger(RwLock::new(None));
+
+impl Log for ReloadableLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        match self.0.read().expect("read logger").as_ref() {
+            None => false,
+            Some(logger) => logger.enabled(metadata),
+        }
+    }
+
+    fn log(&self, record: &Record) {
+        if let Some(logger) = self.0.read().expect("read logger").as_ref() {
+            logger.log(record)
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(logger) = self.0.read().expect("read logger").as_ref() {
+            logger.flush()
+        }
+    }
+}
+
+/// Logs text at the `info` level or as set by `RUST_LOG`
+pub fn init() {
+    if log::set_logger(&LOGGER).is_ok() {
+        configure(LogFormat::Text, None);
+    }
+}
+
+/// Switches the log format and applies `levels` on top of `RUST_LOG`.
+///
+/// Levels use the `RUST_LOG` syntax with subsystems as targets
+/// e.g. `info,sync=debug,fetcher=warn,store=debug,wallet:alice=trace`.
+pub fn configure(format: LogFormat, levels: Option<&str>) {
+    let mut builder = env_logger::Builder::from_env(Env::default().default_filter_or("info"));
+    if let Some(levels) = levels {
+        builder.parse_filters(levels);
+    }
+    if format == LogFormat::Json {
+        builder.format(format_json);
+    }
+
+    let logger = builder.build();
+    log::set_max_level(logger.filter());
+    *LOGGER.0.write().expect("write logger") = Some(logger);
+}
+
+fn format_json(buf: &mut Formatter, record: &Record) -> std::io::Result<()> {
+    let mut entry = Map::new();
+    entry.insert("ts".into(), buf.timestamp_millis().to_string().into());
+    entry.insert("level".into(), record.level().as_str().into());
+    entry.insert("subsystem".into(), record.target().into());
+    entry.insert("message".into(), record.args().to_string().into());
+
+    let mut fields = JsonFields(&mut entry);
+    if let Err(e) = record.key_values().visit(&mut fields) {
+        entry.insert("fields_error".into(), e.to_string().into());
+    }
+    writeln!(buf, "{}", serde_json::Value::Object(entry))
+}
+
+struct JsonFields<'a>(&'a mut Map<String, serde_json::Value>);
+
+impl<'kvs> VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let value = if let Some(n) = value.to_u64() {
+            Number::from(n).into()
+        } else if let Some(n) = value.to_i64() {
+            Number::from(n).into()
+        } else if let Some(b) = value.to_bool() {
+            b.into()
+        } else {
+            value.to_string().into()
+        };
+        self.0.insert(key.as_str().to_string(), value);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use env_logger::Target;
+    use log::Level;
+
+    use super::*;
+
+    /// Collects everything written by the logger
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_format_json() {
+        let captured = Captured::default();
+        let logger = env_logger::Builder::new()
+            .filter_level(log::LevelFilter::Trace)
+            .format(format_json)
+            .target(Target::Pipe(Box::new(captured.clone())))
+            .build();
+
+        let fields = [
+            ("height", Value::from(5u64)),
+            ("offset", Value::from(-3i64)),
+            ("reorg", Value::from(true)),
+            ("block", Value::from("00ff")),
+        ];
+        logger.log(
+            &Record::builder()
+                .args(format_args!("Applied {} blocks", 2))
+                .level(Level::Info)
+                .target(SYNC)
+                .key_values(&fields)
+                .build(),
+        );
+        logger.log(
+            &Record::builder()
+                .args(format_args!("No fields"))
+                .level(Level::Warn)
+                .target(STORE)
+                .build(),
+        );
+
+        let output = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<serde_json::Value> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).expect("one json object per line"))
+            .collect();
+        assert_eq!(lines.len(), 2);
+
+        let entry = &lines[0];
+        assert!(entry["ts"].is_string());
+        assert_eq!(entry["level"], "INFO");
+        assert_eq!(entry["subsystem"], "sync");
+        assert_eq!(entry["message"], "Applied 2 blocks");
+        assert_eq!(entry["height"], 5);
+        assert_eq!(entry["offset"], -3);
+        assert_eq!(entry["reorg"], true);
+        assert_eq!(entry["block"], "00ff");
+        assert!(entry.get("fields_error").is_none());
+
+        let entry = lines[1].as_object().unwrap();
+        assert_eq!(entry["level"], "WARN");
+        assert_eq!(entry["subsystem"], "store");
+        assert_eq!(entry.len(), 4, "only the standard keys without fields");
+    }
+}
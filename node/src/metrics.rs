@@ -0,0 +1,460 @@
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fmt::Write,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use futures::{future::BoxFuture, FutureExt};
+use hyper::{header, Body, Method, Request, Response};
+use jsonrpsee::{server::middleware::rpc::RpcServiceT, types, MethodResponse};
+use log::debug;
+use protocol::{
+    validate::{TxChangeSet, UpdateKind},
+    RevokeReason,
+};
+use tower::{Layer, Service};
+
+use crate::{
+    logging::RPC,
+    progress::{SyncProgress, SyncStatus},
+};
+
+/// Upper bounds in seconds of the RPC latency histogram buckets
+const RPC_BUCKETS: &[f64] = &[0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+/// Upper bounds in seconds of the commit duration histogram buckets
+const COMMIT_BUCKETS: &[f64] = &[0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0];
+
+/// Counters exposed in the Prometheus text format at `/metrics`
+#[derive(Debug, Clone, Default)]
+pub struct Metrics(Arc<Mutex<MetricsState>>);
+
+#[derive(Debug, Default)]
+struct MetricsState {
+    blocks_applied: u64,
+    /// Transactions [protocol::prepare::TxContext::from_tx] found relevant
+    txs_matched: u64,
+    updates: BTreeMap<&'static str, u64>,
+    revocations: BTreeMap<&'static str, u64>,
+    rpc_requests: BTreeMap<String, Histogram>,
+    wallet_heights: BTreeMap<String, u32>,
+    /// Approximate bytes of uncommitted changes by store
+    staged_bytes: BTreeMap<&'static str, usize>,
+    commits: Option<Histogram>,
+    /// Reorgs by how they were handled
+    reorgs: BTreeMap<&'static str, u64>,
+}
+
+#[derive(Debug, Clone)]
+struct Histogram {
+    bounds: &'static [f64],
+    /// Observations per bucket, not cumulative
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Metrics {
+    pub fn block_applied(&self) {
+        self.lock().blocks_applied += 1;
+    }
+
+    pub fn tx_matched(&self) {
+        self.lock().txs_matched += 1;
+    }
+
+    pub fn changeset_applied(&self, changeset: &TxChangeSet) {
+        if changeset.updates.is_empty() {
+            return;
+        }
+        let mut state = self.lock();
+        for update in changeset.updates.iter() {
+            let kind = match &update.kind {
+                UpdateKind::Revoke(reason) => {
+                    *state.revocations.entry(revoke_reason(reason)).or_default() += 1;
+                    "revoke"
+                }
+                UpdateKind::Rollout(_) => "rollout",
+                UpdateKind::Bid => "bid",
+            };
+            *state.updates.entry(kind).or_default() += 1;
+        }
+    }
+
+    pub fn rpc_request(&self, method: &str, elapsed: Duration) {
+        let mut state = self.lock();
+        match state.rpc_requests.get_mut(method) {
+            Some(histogram) => histogram.observe(elapsed),
+            None => {
+                let mut histogram = Histogram::new(RPC_BUCKETS);
+                histogram.observe(elapsed);
+                state.rpc_requests.insert(method.to_string(), histogram);
+            }
+        }
+    }
+
+    pub fn set_wallet_height(&self, wallet: &str, height: u32) {
+        self.lock()
+            .wallet_heights
+            .insert(wallet.to_string(), height);
+    }
+
+    pub fn remove_wallet(&self, wallet: &str) {
+        self.lock().wallet_heights.remove(wallet);
+    }
+
+    pub fn set_staged_bytes(&self, store: &'static str, bytes: usize) {
+        self.lock().staged_bytes.insert(store, bytes);
+    }
+
+    pub fn commit_finished(&self, elapsed: Duration) {
+        self.lock()
+            .commits
+            .get_or_insert_with(|| Histogram::new(COMMIT_BUCKETS))
+            .observe(elapsed);
+    }
+
+    /// Counts a reorg handled by disconnecting blocks (`rewind`)
+    /// or by restoring a snapshot (`restore`)
+    pub fn reorg_handled(&self, method: &'static str) {
+        *self.lock().reorgs.entry(method).or_default() += 1;
+    }
+
+    /// Renders all metrics in the Prometheus text exposition format
+    pub fn render(&self, sync: &SyncStatus) -> String {
+        let state = self.lock();
+        let mut out = String::new();
+
+        gauge(
+            &mut out,
+            "spaced_tip_height",
+            "Height of the last applied block",
+        );
+        sample(&mut out, "spaced_tip_height", "", sync.tip);
+        if let Some(bitcoin_tip) = sync.bitcoin_tip {
+            gauge(
+                &mut out,
+                "spaced_bitcoin_tip_height",
+                "Height of bitcoind's best chain",
+            );
+            sample(&mut out, "spaced_bitcoin_tip_height", "", bitcoin_tip);
+            gauge(
+                &mut out,
+                "spaced_tip_lag_blocks",
+                "Blocks spaced is behind bitcoind",
+            );
+            sample(
+                &mut out,
+                "spaced_tip_lag_blocks",
+                "",
+                bitcoin_tip.saturating_sub(sync.tip),
+            );
+        }
+
+        counter(&mut out, "spaced_blocks_applied_total", "Blocks applied");
+        sample(
+            &mut out,
+            "spaced_blocks_applied_total",
+            "",
+            state.blocks_applied,
+        );
+        counter(
+            &mut out,
+            "spaced_txs_matched_total",
+            "Transactions relevant to the protocol",
+        );
+        sample(&mut out, "spaced_txs_matched_total", "", state.txs_matched);
+
+        counter(
+            &mut out,
+            "spaced_updates_total",
+            "Applied changeset updates by kind",
+        );
+        for (kind, count) in state.updates.iter() {
+            sample(
+                &mut out,
+                "spaced_updates_total",
+                &label("kind", kind),
+                count,
+            );
+        }
+        counter(
+            &mut out,
+            "spaced_revocations_total",
+            "Revoked spaces by reason",
+        );
+        for (reason, count) in state.revocations.iter() {
+            sample(
+                &mut out,
+                "spaced_revocations_total",
+                &label("reason", reason),
+                count,
+            );
+        }
+
+        if !state.rpc_requests.is_empty() {
+            histogram_header(
+                &mut out,
+                "spaced_rpc_request_duration_seconds",
+                "JSON-RPC request latency by method",
+            );
+            for (method, histogram) in state.rpc_requests.iter() {
+                histogram.render(
+                    &mut out,
+                    "spaced_rpc_request_duration_seconds",
+                    &label("method", method),
+                );
+            }
+        }
+
+        gauge(
+            &mut out,
+            "spaced_wallet_tip_height",
+            "Height each loaded wallet is synced to",
+        );
+        for (wallet, height) in state.wallet_heights.iter() {
+            sample(
+                &mut out,
+                "spaced_wallet_tip_height",
+                &label("wallet", wallet),
+                height,
+            );
+        }
+
+        gauge(
+            &mut out,
+            "spaced_staged_bytes",
+            "Approximate size of changes staged in memory until the next commit",
+        );
+        for (store, bytes) in state.staged_bytes.iter() {
+            sample(
+                &mut out,
+                "spaced_staged_bytes",
+                &label("store", store),
+                bytes,
+            );
+        }
+
+        counter(
+            &mut out,
+            "spaced_reorgs_total",
+            "Reorgs by how they were handled",
+        );
+        for (method, count) in state.reorgs.iter() {
+            sample(
+                &mut out,
+                "spaced_reorgs_total",
+                &label("method", method),
+                count,
+            );
+        }
+
+        if let Some(commits) = state.commits.as_ref() {
+            histogram_header(
+                &mut out,
+                "spaced_commit_duration_seconds",
+                "Time taken to commit staged changes to disk",
+            );
+            commits.render(&mut out, "spaced_commit_duration_seconds", "");
+        }
+
+        out
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, MetricsState> {
+        self.0.lock().expect("metrics lock")
+    }
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            counts: vec![0; bounds.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        if let Some(bucket) = self.bounds.iter().position(|bound| seconds <= *bound) {
+            self.counts[bucket] += 1;
+        }
+        self.sum += seconds;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let bucket_name = format!("{}_bucket", name);
+        let mut cumulative = 0;
+        for (bound, count) in self.bounds.iter().zip(self.counts.iter()) {
+            cumulative += count;
+            let le = label("le", &bound.to_string());
+            sample(out, &bucket_name, &join_labels(labels, &le), cumulative);
+        }
+        let le = label("le", "+Inf");
+        sample(out, &bucket_name, &join_labels(labels, &le), self.count);
+        sample(out, &format!("{}_sum", name), labels, self.sum);
+        sample(out, &format!("{}_count", name), labels, self.count);
+    }
+}
+
+fn revoke_reason(reason: &RevokeReason) -> &'static str {
+    match reason {
+        RevokeReason::PrematureClaim => "premature_claim",
+        RevokeReason::BadSpend => "bad_spend",
+        RevokeReason::Expired => "expired",
+        RevokeReason::BidPsbt(_) => "bid_psbt",
+    }
+}
+
+fn counter(out: &mut String, name: &str, help: &str) {
+    _ = writeln!(out, "# HELP {} {}\n# TYPE {} counter", name, help, name);
+}
+
+fn gauge(out: &mut String, name: &str, help: &str) {
+    _ = writeln!(out, "# HELP {} {}\n# TYPE {} gauge", name, help, name);
+}
+
+fn histogram_header(out: &mut String, name: &str, help: &str) {
+    _ = writeln!(out, "# HELP {} {}\n# TYPE {} histogram", name, help, name);
+}
+
+fn sample(out: &mut String, name: &str, labels: &str, value: impl std::fmt::Display) {
+    if labels.is_empty() {
+        _ = writeln!(out, "{} {}", name, value);
+    } else {
+        _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+    }
+}
+
+fn label(name: &str, value: &str) -> String {
+    let value = value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n");
+    format!("{}=\"{}\"", name, value)
+}
+
+fn join_labels(labels: &str, label: &str) -> String {
+    match labels.is_empty() {
+        true => label.to_string(),
+        false => format!("{},{}", labels, label),
+    }
+}
+
+/// HTTP middleware answering `GET /metrics` on the RPC server
+#[derive(Clone)]
+pub struct MetricsLayer {
+    metrics: Option<Metrics>,
+    progress: SyncProgress,
+}
+
+impl MetricsLayer {
+    /// Serves `metrics` if set, passing every request through otherwise
+    pub fn new(metrics: Option<Metrics>, progress: SyncProgress) -> Self {
+        Self { metrics, progress }
+    }
+}
+
+impl<S> Layer<S> for MetricsLayer {
+    type Service = MetricsService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        MetricsService {
+            inner,
+            metrics: self.metrics.clone(),
+            progress: self.progress.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct MetricsService<S> {
+    inner: S,
+    metrics: Option<Metrics>,
+    progress: SyncProgress,
+}
+
+impl<S> Service<Request<Body>> for MetricsService<S>
+where
+    S: Service<Request<Body>, Response = Response<Body>>,
+    S::Error: Into<Box<dyn Error + Send + Sync>> + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = Response<Body>;
+    type Error = Box<dyn Error + Send + Sync>;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        match &self.metrics {
+            Some(metrics)
+                if request.method() == Method::GET && request.uri().path() == "/metrics" =>
+            {
+                let body = metrics.render(&self.progress.status());
+                let response = Response::builder()
+                    .header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+                    .body(Body::from(body))
+                    .map_err(Into::into);
+                Box::pin(async move { response })
+            }
+            _ => {
+                let response = self.inner.call(request);
+                Box::pin(async move { response.await.map_err(Into::into) })
+            }
+        }
+    }
+}
+
+/// RPC middleware timing and logging each method call
+#[derive(Clone)]
+pub struct RpcMetrics<S> {
+    service: S,
+    metrics: Option<Metrics>,
+}
+
+impl<S> RpcMetrics<S> {
+    pub fn new(service: S, metrics: Option<Metrics>) -> Self {
+        Self { service, metrics }
+    }
+}
+
+impl<'a, S> RpcServiceT<'a> for RpcMetrics<S>
+where
+    S: RpcServiceT<'a> + Send + Sync,
+    S::Future: 'a,
+{
+    type Future = BoxFuture<'a, MethodResponse>;
+
+    fn call(&self, request: types::Request<'a>) -> Self::Future {
+        let metrics = self.metrics.clone();
+        let method = request.method_name().to_string();
+        let started = Instant::now();
+        let response = self.service.call(request);
+        async move {
+            let response = response.await;
+            let elapsed = started.elapsed();
+            debug!(
+                target: RPC,
+                method:% = method,
+                elapsed_ms = elapsed.as_millis() as u64,
+                success = response.is_success();
+                "Request"
+            );
+            if let Some(metrics) = metrics {
+                metrics.rpc_request(&method, elapsed);
+            }
+            response
+        }
+        .boxed()
+    }
+}
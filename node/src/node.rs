@@ -5,11 +5,12 @@ use std::{error::Error, fmt};
 
 use anyhow::{anyhow, Result};
 use bincode::{Decode, Encode};
+use log::debug;
 use protocol::{
-    bitcoin::{Amount, Block, BlockHash, OutPoint},
+    bitcoin::{block::Header, Amount, Block, BlockHash, OutPoint, Work},
     constants::{ChainAnchor, ROLLOUT_BATCH_SIZE, ROLLOUT_BLOCK_INTERVAL},
     hasher::{BidKey, KeyHasher, OutpointKey, SpaceKey},
-    prepare::TxContext,
+    prepare::{DataSource, TxContext},
     validate::{TxChangeSet, UpdateKind, Validator},
     Bytes, Covenant, FullSpaceOut, RevokeReason, SpaceOut,
 };
@@ -17,22 +18,37 @@ use serde::{Deserialize, Serialize};
 use wallet::bitcoin::Transaction;
 
 use crate::{
+    logging::SYNC,
+    metrics::Metrics,
     source::BitcoinRpcError,
+    stats::ChainStats,
     store::{ChainState, ChainStore, LiveSnapshot, LiveStore, Sha256},
 };
 
 pub trait BlockSource {
     fn get_block_hash(&self, height: u32) -> Result<BlockHash, BitcoinRpcError>;
     fn get_block(&self, hash: &BlockHash) -> Result<Block, BitcoinRpcError>;
+
+    /// Header of a block. Sources that can fetch headers on their own
+    /// should, since only headers are needed to verify difficulty.
+    fn get_block_header(&self, hash: &BlockHash) -> Result<Header, BitcoinRpcError> {
+        Ok(self.get_block(hash)?.header)
+    }
     fn get_median_time(&self) -> Result<u64, BitcoinRpcError>;
     fn get_block_count(&self) -> Result<u64, BitcoinRpcError>;
     fn get_best_chain(&self) -> Result<ChainAnchor, BitcoinRpcError>;
+
+    /// Total work of the best chain if the source reports it
+    fn get_best_chain_work(&self) -> Result<Option<Work>, BitcoinRpcError> {
+        Ok(None)
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct Node {
     validator: Validator,
     tx_data: bool,
+    metrics: Metrics,
 }
 
 /// A block structure containing validated transaction metadata
@@ -80,9 +96,16 @@ impl Node {
         Self {
             validator: Validator::new(),
             tx_data,
+            metrics: Metrics::default(),
         }
     }
 
+    /// Counts matched transactions and applied updates in `metrics`
+    pub fn with_metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
     pub fn apply_block(
         &mut self,
         chain: &mut LiveStore,
@@ -106,6 +129,7 @@ impl Node {
             height,
             tx_meta: vec![],
         };
+        let mut stats = chain.state.get_stats()?;
 
         if (height - 1) % ROLLOUT_BLOCK_INTERVAL == 0 {
             let batch = Self::get_rollout_batch(ROLLOUT_BATCH_SIZE, chain)?;
@@ -130,7 +154,7 @@ impl Node {
                     },
                 });
             }
-            self.apply_tx(&mut chain.state, &coinbase, validated);
+            self.apply_tx(&mut chain.state, &mut stats, height, &coinbase, validated)?;
         }
 
         for (position, tx) in block.txdata.into_iter().enumerate() {
@@ -138,6 +162,13 @@ impl Node {
                 { TxContext::from_tx::<LiveSnapshot, Sha256>(&mut chain.state, &tx)? };
 
             if let Some(prepared_tx) = prepared_tx {
+                self.metrics.tx_matched();
+                debug!(
+                    target: SYNC,
+                    txid:% = tx.compute_txid(),
+                    height = height;
+                    "Spaces transaction"
+                );
                 let validated_tx = self.validator.process(height, &tx, prepared_tx);
 
                 if get_block_data {
@@ -155,9 +186,10 @@ impl Node {
                         },
                     });
                 }
-                self.apply_tx(&mut chain.state, &tx, validated_tx);
+                self.apply_tx(&mut chain.state, &mut stats, height, &tx, validated_tx)?;
             }
         }
+        chain.state.set_stats(&stats);
         let mut tip = chain.state.tip.write().expect("write tip");
         tip.height = height;
         tip.hash = block_hash;
@@ -168,12 +200,26 @@ impl Node {
         Ok(None)
     }
 
-    fn apply_tx(&self, state: &mut LiveSnapshot, tx: &Transaction, changeset: TxChangeSet) {
+    fn apply_tx(
+        &self,
+        state: &mut LiveSnapshot,
+        stats: &mut ChainStats,
+        height: u32,
+        tx: &Transaction,
+        changeset: TxChangeSet,
+    ) -> Result<()> {
+        self.metrics.changeset_applied(&changeset);
+
         // Remove spends
+        let mut spent = Vec::with_capacity(changeset.spends.len());
         for spend in changeset.spends.into_iter() {
             let previous = tx.input[spend.n].previous_output;
+            spent.push(previous);
+            if let Some(spaceout) = state.get_spaceout(&previous)? {
+                stats.remove_space(&spaceout);
+            }
             let spend = OutpointKey::from_outpoint::<Sha256>(previous);
-            state.remove(spend);
+            state.remove(spend)?;
         }
 
         // Apply outputs
@@ -195,6 +241,7 @@ impl Node {
                 state.insert_space(space_key, outpoint.into());
             }
             // Outpoint => SpaceOut
+            stats.add_space(&create);
             let outpoint_key = OutpointKey::from_outpoint::<Sha256>(outpoint);
             state.insert_spaceout(outpoint_key, create);
         }
@@ -203,6 +250,11 @@ impl Node {
         for update in changeset.updates {
             match update.kind {
                 UpdateKind::Revoke(params) => {
+                    // Spent outputs were already counted out above
+                    stats.revoke(&params);
+                    if !spent.contains(&update.output.outpoint()) {
+                        stats.remove_space(&update.output.spaceout);
+                    }
                     match params {
                         RevokeReason::BidPsbt(_)
                         | RevokeReason::PrematureClaim
@@ -214,7 +266,7 @@ impl Node {
 
                             // Remove Space -> Outpoint
                             let space_key = SpaceKey::from(base_hash);
-                            state.remove(space_key);
+                            state.remove(space_key)?;
 
                             // Remove any bids from pre-auction pool
                             match space.covenant {
@@ -225,7 +277,7 @@ impl Node {
                                 } => {
                                     if claim_height.is_none() {
                                         let bid_key = BidKey::from_bid(total_burned, base_hash);
-                                        state.remove(bid_key);
+                                        state.remove(bid_key)?;
                                     }
                                 }
                                 _ => {}
@@ -238,7 +290,7 @@ impl Node {
                             // No bids here so only remove Outpoint -> Spaceout
                             let hash =
                                 OutpointKey::from_outpoint::<Sha256>(update.output.outpoint());
-                            state.remove(hash);
+                            state.remove(hash)?;
                         }
                     }
                 }
@@ -258,7 +310,13 @@ impl Node {
                     let outpoint_key =
                         OutpointKey::from_outpoint::<Sha256>(update.output.outpoint());
 
-                    state.remove(bid_key);
+                    // Rolled out spaces move from pre-auction into auction
+                    // without their output being spent
+                    stats.pre_auction = stats.pre_auction.saturating_sub(1);
+                    stats.add_space(&update.output.spaceout);
+                    stats.rollout();
+
+                    state.remove(bid_key)?;
                     state.insert_spaceout(outpoint_key, update.output.spaceout);
                 }
                 UpdateKind::Bid => {
@@ -275,6 +333,8 @@ impl Node {
                     );
 
                     let (bid_value, previous_bid) = unwrap_bid_value(&update.output.spaceout);
+                    stats.burn(height, bid_value - previous_bid);
+                    stats.add_space(&update.output.spaceout);
 
                     let bid_hash = BidKey::from_bid(bid_value, base_hash);
                     let space_key = SpaceKey::from(base_hash);
@@ -304,6 +364,7 @@ impl Node {
                 }
             }
         }
+        Ok(())
     }
 
     fn get_rollout_batch(size: usize, chain: &mut LiveStore) -> Result<Vec<FullSpaceOut>> {
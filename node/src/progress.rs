@@ -0,0 +1,250 @@
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// How often the sync rate is sampled
+const RATE_SAMPLE_INTERVAL: Duration = Duration::from_secs(5);
+/// How often a progress summary is logged while catching up
+const SUMMARY_INTERVAL: Duration = Duration::from_secs(30);
+/// Blocks spaced may trail bitcoind by and still count as synced since
+/// bitcoind's tip is usually seen before its block has been applied
+const SYNCED_MAX_LAG: u32 = 1;
+
+/// Sync progress shared between the block fetcher, protocol sync
+/// and the RPC server
+#[derive(Clone, Default)]
+pub struct SyncProgress(Arc<Mutex<ProgressState>>);
+
+#[derive(Default)]
+struct ProgressState {
+    tip: u32,
+    bitcoin_tip: Option<u32>,
+    /// Height of the last block handed over by the fetcher
+    emitted: u32,
+    /// Blocks requested from the source and not yet received
+    in_flight: usize,
+    /// Blocks received out of order waiting for their parents
+    buffered: usize,
+    /// Exponential moving average of applied blocks per second
+    blocks_per_second: f64,
+    sample: Option<(Instant, u32)>,
+    last_summary: Option<Instant>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncStatus {
+    /// Height of the last block applied by spaced
+    pub tip: u32,
+    /// Height of bitcoind's best chain once known
+    pub bitcoin_tip: Option<u32>,
+    pub blocks_per_second: f64,
+    /// Estimated seconds until caught up
+    pub eta_seconds: Option<u64>,
+    /// Blocks being fetched or waiting to be applied
+    pub queue_depth: usize,
+    /// Whether spaced is within a block of bitcoind's tip, always
+    /// false while that tip is unknown
+    pub synced: bool,
+}
+
+impl SyncProgress {
+    /// Sets the tip without counting towards the sync rate e.g. on
+    /// start or after a reorg
+    pub fn set_tip(&self, height: u32) {
+        let mut state = self.lock();
+        state.tip = height;
+        state.emitted = height;
+        state.sample = None;
+    }
+
+    pub fn block_applied(&self, height: u32) {
+        let mut state = self.lock();
+        state.tip = height;
+
+        let now = Instant::now();
+        match state.sample {
+            None => state.sample = Some((now, height)),
+            Some((at, sampled)) if now.duration_since(at) >= RATE_SAMPLE_INTERVAL => {
+                let rate =
+                    height.saturating_sub(sampled) as f64 / now.duration_since(at).as_secs_f64();
+                state.blocks_per_second = if state.blocks_per_second == 0.0 {
+                    rate
+                } else {
+                    0.7 * state.blocks_per_second + 0.3 * rate
+                };
+                state.sample = Some((now, height));
+            }
+            Some(_) => {}
+        }
+    }
+
+    pub fn set_bitcoin_tip(&self, height: u32) {
+        self.lock().bitcoin_tip = Some(height);
+    }
+
+    pub fn set_fetch_queue(&self, emitted: u32, in_flight: usize, buffered: usize) {
+        let mut state = self.lock();
+        state.emitted = emitted;
+        state.in_flight = in_flight;
+        state.buffered = buffered;
+    }
+
+    pub fn status(&self) -> SyncStatus {
+        self.lock().status()
+    }
+
+    /// Returns the status if spaced is behind and no summary
+    /// was logged recently
+    pub fn summary_due(&self) -> Option<SyncStatus> {
+        let mut state = self.lock();
+        let status = state.status();
+        if status.synced
+            || state
+                .last_summary
+                .is_some_and(|last| last.elapsed() < SUMMARY_INTERVAL)
+        {
+            return None;
+        }
+        state.last_summary = Some(Instant::now());
+        Some(status)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, ProgressState> {
+        self.0.lock().expect("sync progress lock")
+    }
+}
+
+impl ProgressState {
+    fn status(&self) -> SyncStatus {
+        let remaining = self.bitcoin_tip.map(|tip| tip.saturating_sub(self.tip));
+        let synced = remaining.is_some_and(|remaining| remaining <= SYNCED_MAX_LAG);
+        let eta_seconds = match remaining {
+            _ if synced => Some(0),
+            Some(remaining) if self.blocks_per_second > 0.0 => {
+                Some((remaining as f64 / self.blocks_per_second).ceil() as u64)
+            }
+            _ => None,
+        };
+
+        SyncStatus {
+            tip: self.tip,
+            bitcoin_tip: self.bitcoin_tip,
+            blocks_per_second: self.blocks_per_second,
+            eta_seconds,
+            queue_depth: self.in_flight
+                + self.buffered
+                + self.emitted.saturating_sub(self.tip) as usize,
+            synced,
+        }
+    }
+}
+
+impl fmt::Display for SyncStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.bitcoin_tip {
+            Some(bitcoin_tip) if bitcoin_tip > 0 => write!(
+                f,
+                "height={} of {} ({:.1}%)",
+                self.tip,
+                bitcoin_tip,
+                (self.tip as f64 * 100.0 / bitcoin_tip as f64).min(100.0)
+            )?,
+            Some(_) => write!(f, "height={}", self.tip)?,
+            None => write!(f, "height={} of unknown", self.tip)?,
+        }
+        write!(f, " {:.1} blocks/s", self.blocks_per_second)?;
+        if let Some(eta) = self.eta_seconds {
+            write!(f, " eta={}", format_eta(eta))?;
+        }
+        write!(f, " queue={}", self.queue_depth)
+    }
+}
+
+fn format_eta(seconds: u64) -> String {
+    let (hours, minutes) = (seconds / 3600, seconds / 60 % 60);
+    if hours > 0 {
+        format!("{}h{:02}m", hours, minutes)
+    } else if minutes > 0 {
+        format!("{}m{:02}s", minutes, seconds % 60)
+    } else {
+        format!("{}s", seconds)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_synced_within_tolerance() {
+        let progress = SyncProgress::default();
+        progress.set_tip(100);
+        progress.set_bitcoin_tip(102);
+        let status = progress.status();
+        assert!(!status.synced);
+        assert_eq!(status.eta_seconds, None, "no rate sampled yet");
+
+        progress.set_bitcoin_tip(101);
+        let status = progress.status();
+        assert!(status.synced, "a block behind still counts as synced");
+        assert_eq!(status.eta_seconds, Some(0));
+
+        // bitcoind may briefly report a stale tip
+        progress.set_bitcoin_tip(99);
+        assert!(progress.status().synced);
+        assert_eq!(
+            progress.status().to_string(),
+            "height=100 of 99 (100.0%) 0.0 blocks/s eta=0s queue=0"
+        );
+    }
+
+    #[test]
+    fn test_unknown_bitcoin_tip() {
+        let progress = SyncProgress::default();
+        progress.set_tip(10);
+        let status = progress.status();
+        assert!(!status.synced);
+        assert_eq!(status.eta_seconds, None);
+        assert_eq!(
+            status.to_string(),
+            "height=10 of unknown 0.0 blocks/s queue=0"
+        );
+    }
+
+    #[test]
+    fn test_eta_and_queue_depth() {
+        let progress = SyncProgress::default();
+        progress.set_tip(100);
+        progress.set_bitcoin_tip(1100);
+        progress.set_fetch_queue(120, 4, 2);
+        progress.lock().blocks_per_second = 50.0;
+
+        let status = progress.status();
+        assert_eq!(status.eta_seconds, Some(20));
+        assert_eq!(status.queue_depth, 26);
+        assert_eq!(format_eta(3725), "1h02m");
+        assert_eq!(format_eta(65), "1m05s");
+    }
+
+    #[test]
+    fn test_summary_is_throttled() {
+        let progress = SyncProgress::default();
+        progress.set_bitcoin_tip(50);
+        assert!(progress.summary_due().is_some());
+        assert!(progress.summary_due().is_none(), "logged recently");
+
+        progress.lock().last_summary = Some(Instant::now() - SUMMARY_INTERVAL);
+        assert!(progress.summary_due().is_some());
+
+        progress.lock().last_summary = None;
+        progress.set_tip(50);
+        assert!(
+            progress.summary_due().is_none(),
+            "nothing to report once synced"
+        );
+    }
+}
@@ -0,0 +1,207 @@
+use std::{
+    cmp::Ordering,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+use log::info;
+use protocol::constants::ChainAnchor;
+use spacedb::Hash;
+
+use crate::{
+    backend::DEFAULT_CACHE_SIZE,
+    logging::STORE,
+    store::{ReadTx, Store, WriteTx},
+};
+
+/// Which committed snapshots survive compaction
+#[derive(Debug, Clone, Copy)]
+pub struct RetentionPolicy {
+    /// Number of most recent snapshots to keep
+    pub keep_last: u32,
+    /// Additionally keep snapshots committed at multiples of this height (0 disables)
+    pub keep_every: u32,
+}
+
+impl RetentionPolicy {
+    /// Whether the snapshot at position (0 being the most recent) committed
+    /// at the given anchor is retained
+    pub fn retains(&self, position: usize, anchor: &ChainAnchor) -> bool {
+        position < self.keep_last as usize
+            || (self.keep_every != 0 && anchor.height % self.keep_every == 0)
+    }
+}
+
+/// Outcome of compacting a spacedb file
+#[derive(Debug, Clone)]
+pub struct Compaction {
+    pub retained: usize,
+    pub pruned: usize,
+    pub size_before: u64,
+    pub size_after: u64,
+}
+
+/// Rewrites the spacedb file at path keeping only the snapshots retained by
+/// the policy, which deletes the others from disk. Retained snapshots are
+/// re-committed oldest first as diffs of each other so unchanged entries remain
+/// shared. Every rewritten snapshot must reproduce its original root before the
+/// new file replaces the old one.
+///
+/// Must be called before the file is opened elsewhere. Returns `None` if
+/// nothing could be pruned.
+pub fn compact(path: &Path, policy: &RetentionPolicy) -> Result<Option<Compaction>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let size_before = fs::metadata(path)?.len();
+    let store = Store::open(path.to_path_buf(), DEFAULT_CACHE_SIZE)?;
+
+    let mut snapshots = Vec::new();
+    let mut pruned = 0;
+    // Only snapshots holding chain state count towards the most recent ones kept
+    let mut position = 0;
+    for snapshot in store.iter() {
+        let snapshot = snapshot?;
+        if snapshot.metadata().is_empty() {
+            continue;
+        }
+        let anchor: ChainAnchor = snapshot.metadata().try_into()?;
+        if policy.retains(position, &anchor) {
+            snapshots.push(snapshot);
+        } else {
+            pruned += 1;
+        }
+        position += 1;
+    }
+    if pruned == 0 {
+        return Ok(None);
+    }
+
+    let tmp_path = compaction_path(path);
+    if tmp_path.exists() {
+        fs::remove_file(&tmp_path)?;
+    }
+
+    let retained = match rewrite(&tmp_path, &mut snapshots) {
+        Ok(count) => count,
+        Err(e) => {
+            _ = fs::remove_file(&tmp_path);
+            return Err(e);
+        }
+    };
+    drop(snapshots);
+    drop(store);
+
+    fs::rename(&tmp_path, path)?;
+    let compaction = Compaction {
+        retained,
+        pruned,
+        size_before,
+        size_after: fs::metadata(path)?.len(),
+    };
+    info!(
+        target: STORE,
+        pruned = compaction.pruned,
+        kept = compaction.retained,
+        size_before = compaction.size_before,
+        size_after = compaction.size_after;
+        "Compacted {}",
+        path.display()
+    );
+    Ok(Some(compaction))
+}
+
+/// Commits the snapshots, given newest first, to a new store oldest first
+fn rewrite(path: &Path, snapshots: &mut [ReadTx]) -> Result<usize> {
+    let target = Store::open(path.to_path_buf(), DEFAULT_CACHE_SIZE)?;
+    for position in (0..snapshots.len()).rev() {
+        let (newer, older) = snapshots.split_at_mut(position + 1);
+        let snapshot = &mut newer[position];
+
+        let mut tx = target.write()?;
+        commit_diff(&mut tx, older.first(), snapshot)?;
+        tx.metadata(snapshot.metadata().to_vec())?;
+        tx.commit()?;
+
+        let mut written = target
+            .iter()
+            .next()
+            .ok_or_else(|| anyhow!("missing compacted snapshot"))??;
+        if written.compute_root()? != snapshot.compute_root()? {
+            return Err(anyhow!(
+                "compacted snapshot root does not match the original"
+            ));
+        }
+    }
+    Ok(snapshots.len())
+}
+
+/// Writes the changes from `previous` to `snapshot` by merging their
+/// entries in key order so neither is loaded into memory
+fn commit_diff(tx: &mut WriteTx, previous: Option<&ReadTx>, snapshot: &ReadTx) -> Result<()> {
+    let mut old = previous.map(|previous| previous.iter());
+    let mut new = snapshot.iter();
+    let mut next_old = || -> Result<Option<(Hash, Vec<u8>)>> {
+        old.as_mut().and_then(|old| old.next()).transpose()
+    };
+    let mut old_entry = next_old()?;
+    let mut new_entry = new.next().transpose()?;
+
+    loop {
+        match (old_entry.take(), new_entry.take()) {
+            (None, None) => return Ok(()),
+            (Some((key, _)), None) => {
+                tx.remove(key)?;
+                old_entry = next_old()?;
+            }
+            (None, Some((key, value))) => {
+                tx.insert(key, value)?;
+                new_entry = new.next().transpose()?;
+            }
+            (Some((old_key, old_value)), Some((key, value))) => match old_key.cmp(&key) {
+                Ordering::Less => {
+                    tx.remove(old_key)?;
+                    old_entry = next_old()?;
+                    new_entry = Some((key, value));
+                }
+                Ordering::Greater => {
+                    tx.insert(key, value)?;
+                    old_entry = Some((old_key, old_value));
+                    new_entry = new.next().transpose()?;
+                }
+                Ordering::Equal => {
+                    if old_value != value {
+                        tx.insert(key, value)?;
+                    }
+                    old_entry = next_old()?;
+                    new_entry = new.next().transpose()?;
+                }
+            },
+        }
+    }
+}
+
+fn compaction_path(path: &Path) -> PathBuf {
+    path.with_extension("compact")
+}
+
+/// Total size in bytes of all files under path
+pub fn disk_usage(path: &Path) -> u64 {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(_) => return 0,
+    };
+    if !metadata.is_dir() {
+        return metadata.len();
+    }
+    fs::read_dir(path)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| disk_usage(&entry.path()))
+                .sum()
+        })
+        .unwrap_or(0)
+}
@@ -0,0 +1,330 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write},
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use anyhow::{anyhow, Result};
+use bincode::{config, Decode, Encode};
+use log::error;
+use protocol::{
+    bitcoin::{
+        block::Header,
+        consensus::encode::{deserialize, deserialize_partial, serialize},
+        Block, BlockHash, Work,
+    },
+    constants::ChainAnchor,
+};
+
+use crate::{node::BlockSource, source::BitcoinRpcError};
+
+/// An answer given by a block source
+#[derive(Encode, Decode)]
+enum Record {
+    BestChain(ChainAnchor),
+    BlockHash(ChainAnchor),
+    /// Consensus encoded block
+    Block(Vec<u8>),
+    MedianTime(u64),
+}
+
+/// Wraps a block source logging its answers to a file which can be fed
+/// back through [ReplayBlockSource] to reproduce a sync without bitcoind.
+///
+/// Records are framed as `[length: u32 LE][record]`. Blocks are only written
+/// the first time they're fetched and repeated answers are skipped.
+#[derive(Clone)]
+pub struct RecordingBlockSource<S> {
+    inner: S,
+    recorder: Arc<Mutex<Recorder>>,
+}
+
+struct Recorder {
+    writer: BufWriter<File>,
+    tip: Option<ChainAnchor>,
+    /// Hashes recorded since the best chain last changed
+    hashes: HashMap<u32, BlockHash>,
+    blocks: HashSet<BlockHash>,
+    median_time: Option<u64>,
+}
+
+/// Serves blocks from a file written by [RecordingBlockSource].
+///
+/// Each call to `get_best_chain` moves to the next recorded tip, and block
+/// hashes are answered as they were while that tip was the best chain, so
+/// reorgs play out in the order they were observed. Only the position of
+/// each block is kept in memory and blocks are read from the file on request.
+#[derive(Clone)]
+pub struct ReplayBlockSource(Arc<Replay>);
+
+struct Replay {
+    file: Mutex<File>,
+    tips: Vec<ChainAnchor>,
+    /// Hashes by height along with the index of the tip they were seen at
+    hashes: BTreeMap<u32, Vec<(usize, BlockHash)>>,
+    blocks: HashMap<BlockHash, RecordPosition>,
+    median_times: Vec<(usize, u64)>,
+    /// Index of the next tip returned by `get_best_chain`
+    next_tip: AtomicUsize,
+    current_tip: AtomicUsize,
+}
+
+/// Where a record's payload is found in the recording
+#[derive(Clone, Copy)]
+struct RecordPosition {
+    offset: u64,
+    len: usize,
+}
+
+impl<S: BlockSource> RecordingBlockSource<S> {
+    /// Starts a new recording, refusing to replace an existing one
+    /// unless `overwrite` is set
+    pub fn create(path: &Path, inner: S, overwrite: bool) -> Result<Self> {
+        let file = if overwrite {
+            File::create(path)?
+        } else {
+            File::options()
+                .write(true)
+                .create_new(true)
+                .open(path)
+                .map_err(|e| match e.kind() {
+                    ErrorKind::AlreadyExists => {
+                        anyhow!("{} already exists and would be overwritten", path.display())
+                    }
+                    _ => e.into(),
+                })?
+        };
+        Ok(Self {
+            inner,
+            recorder: Arc::new(Mutex::new(Recorder {
+                writer: BufWriter::new(file),
+                tip: None,
+                hashes: HashMap::new(),
+                blocks: Default::default(),
+                median_time: None,
+            })),
+        })
+    }
+
+    fn record(&self, record: impl FnOnce(&mut Recorder) -> Option<Record>) {
+        let mut recorder = self.recorder.lock().expect("recorder lock");
+        if let Some(record) = record(&mut recorder) {
+            if let Err(e) = recorder.write(&record) {
+                error!("Could not record block source: {}", e);
+            }
+        }
+    }
+}
+
+impl Recorder {
+    fn write(&mut self, record: &Record) -> Result<()> {
+        let payload = bincode::encode_to_vec(record, config::standard())?;
+        self.writer
+            .write_all(&(payload.len() as u32).to_le_bytes())?;
+        self.writer.write_all(&payload)?;
+        // Flushed on every record so the file is usable after a crash
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+impl<S: BlockSource> BlockSource for RecordingBlockSource<S> {
+    fn get_block_hash(&self, height: u32) -> Result<BlockHash, BitcoinRpcError> {
+        let hash = self.inner.get_block_hash(height)?;
+        self.record(|recorder| {
+            (recorder.hashes.insert(height, hash) != Some(hash))
+                .then_some(Record::BlockHash(ChainAnchor { hash, height }))
+        });
+        Ok(hash)
+    }
+
+    fn get_block(&self, hash: &BlockHash) -> Result<Block, BitcoinRpcError> {
+        let block = self.inner.get_block(hash)?;
+        self.record(|recorder| {
+            recorder
+                .blocks
+                .insert(*hash)
+                .then(|| Record::Block(serialize(&block)))
+        });
+        Ok(block)
+    }
+
+    fn get_median_time(&self) -> Result<u64, BitcoinRpcError> {
+        let time = self.inner.get_median_time()?;
+        self.record(|recorder| {
+            (recorder.median_time.replace(time) != Some(time)).then_some(Record::MedianTime(time))
+        });
+        Ok(time)
+    }
+
+    fn get_block_count(&self) -> Result<u64, BitcoinRpcError> {
+        self.inner.get_block_count()
+    }
+
+    fn get_best_chain(&self) -> Result<ChainAnchor, BitcoinRpcError> {
+        let tip = self.inner.get_best_chain()?;
+        self.record(|recorder| {
+            if recorder.tip == Some(tip) {
+                return None;
+            }
+            recorder.tip = Some(tip);
+            recorder.hashes.clear();
+            Some(Record::BestChain(tip))
+        });
+        Ok(tip)
+    }
+
+    fn get_best_chain_work(&self) -> Result<Option<Work>, BitcoinRpcError> {
+        self.inner.get_best_chain_work()
+    }
+}
+
+impl ReplayBlockSource {
+    /// Indexes the recording without keeping blocks in memory
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = File::open(path)?;
+        let mut reader = BufReader::new(file.try_clone()?);
+        let mut replay = Replay {
+            file: Mutex::new(file),
+            tips: Vec::new(),
+            hashes: BTreeMap::new(),
+            blocks: HashMap::new(),
+            median_times: Vec::new(),
+            next_tip: AtomicUsize::new(0),
+            current_tip: AtomicUsize::new(0),
+        };
+        let mut offset = 0;
+        let mut payload = Vec::new();
+        while !reader.fill_buf()?.is_empty() {
+            let truncated = || anyhow!("Truncated recording at offset {}", offset);
+            let mut len = [0u8; 4];
+            reader.read_exact(&mut len).map_err(|_| truncated())?;
+            let len = u32::from_le_bytes(len) as usize;
+            payload.resize(len, 0);
+            reader.read_exact(&mut payload).map_err(|_| truncated())?;
+            let (record, _): (Record, _) =
+                bincode::decode_from_slice(&payload, config::standard())?;
+
+            // Answers given before the first tip was fetched belong to it
+            let tip_index = replay.tips.len().saturating_sub(1);
+            match record {
+                Record::BestChain(tip) => replay.tips.push(tip),
+                Record::BlockHash(id) => replay
+                    .hashes
+                    .entry(id.height)
+                    .or_default()
+                    .push((tip_index, id.hash)),
+                Record::Block(raw) => {
+                    let (header, _): (Header, _) = deserialize_partial(&raw)?;
+                    let position = RecordPosition {
+                        offset: offset + 4,
+                        len,
+                    };
+                    replay.blocks.insert(header.block_hash(), position);
+                }
+                Record::MedianTime(time) => replay.median_times.push((tip_index, time)),
+            }
+            offset += 4 + len as u64;
+        }
+
+        if replay.tips.is_empty() {
+            return Err(anyhow!("Recording has no best chain"));
+        }
+        Ok(Self(Arc::new(replay)))
+    }
+
+    /// Hash of the block at `height` as first recorded regardless of the
+    /// current tip e.g. to resolve the activation block before syncing
+    pub fn recorded_hash(&self, height: u32) -> Option<BlockHash> {
+        self.0
+            .hashes
+            .get(&height)
+            .and_then(|answers| answers.first())
+            .map(|(_, hash)| *hash)
+    }
+
+    /// Whether every recorded best chain has been served
+    pub fn is_finished(&self) -> bool {
+        self.0.next_tip.load(Ordering::SeqCst) >= self.0.tips.len()
+    }
+
+    fn current(&self) -> usize {
+        self.0.current_tip.load(Ordering::SeqCst)
+    }
+
+    fn read_block(&self, position: RecordPosition) -> Result<Block> {
+        let mut payload = vec![0; position.len];
+        {
+            let mut file = self.0.file.lock().expect("replay file lock");
+            file.seek(SeekFrom::Start(position.offset))?;
+            file.read_exact(&mut payload)?;
+        }
+        match bincode::decode_from_slice(&payload, config::standard())?.0 {
+            Record::Block(raw) => Ok(deserialize(&raw)?),
+            _ => Err(anyhow!("No block recorded at offset {}", position.offset)),
+        }
+    }
+}
+
+/// Latest answer seen at or before `tip`, or the earliest one after it
+fn seen_at<T: Copy>(answers: &[(usize, T)], tip: usize) -> Option<T> {
+    answers
+        .iter()
+        .rev()
+        .find(|(seen, _)| *seen <= tip)
+        .or(answers.first())
+        .map(|(_, answer)| *answer)
+}
+
+fn not_recorded(what: String) -> BitcoinRpcError {
+    BitcoinRpcError::Other(format!("Replay: {} was not recorded", what))
+}
+
+impl BlockSource for ReplayBlockSource {
+    fn get_block_hash(&self, height: u32) -> Result<BlockHash, BitcoinRpcError> {
+        let current = self.current();
+        if height > self.0.tips[current].height {
+            return Err(BitcoinRpcError::Other(format!(
+                "Replay: block height {} out of range",
+                height
+            )));
+        }
+        self.0
+            .hashes
+            .get(&height)
+            .and_then(|answers| seen_at(answers, current))
+            .ok_or_else(|| not_recorded(format!("block hash at height {}", height)))
+    }
+
+    fn get_block(&self, hash: &BlockHash) -> Result<Block, BitcoinRpcError> {
+        let position = self
+            .0
+            .blocks
+            .get(hash)
+            .ok_or_else(|| not_recorded(format!("block {}", hash)))?;
+        self.read_block(*position).map_err(|e| {
+            BitcoinRpcError::Other(format!("Replay: could not read block {}: {}", hash, e))
+        })
+    }
+
+    fn get_median_time(&self) -> Result<u64, BitcoinRpcError> {
+        seen_at(&self.0.median_times, self.current())
+            .ok_or_else(|| not_recorded("median time".to_string()))
+    }
+
+    fn get_block_count(&self) -> Result<u64, BitcoinRpcError> {
+        Ok(self.0.tips[self.current()].height as u64)
+    }
+
+    fn get_best_chain(&self) -> Result<ChainAnchor, BitcoinRpcError> {
+        let next = self.0.next_tip.fetch_add(1, Ordering::SeqCst);
+        let current = next.min(self.0.tips.len() - 1);
+        self.0.current_tip.store(current, Ordering::SeqCst);
+        Ok(self.0.tips[current])
+    }
+}
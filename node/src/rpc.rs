@@ -1,5 +1,6 @@
 use std::{
     collections::BTreeMap, fs, io::Write, net::SocketAddr, path::PathBuf, str::FromStr, sync::Arc,
+    time::Duration,
 };
 
 use anyhow::{anyhow, Context};
@@ -13,7 +14,12 @@ use bdk::{
     miniscript::Tap,
     KeychainKind,
 };
-use jsonrpsee::{core::async_trait, proc_macros::rpc, server::Server, types::ErrorObjectOwned};
+use jsonrpsee::{
+    core::async_trait,
+    proc_macros::rpc,
+    server::{middleware::rpc::RpcServiceBuilder, Server},
+    types::ErrorObjectOwned,
+};
 use log::info;
 use protocol::{bitcoin, bitcoin::{
     bip32::Xpriv,
@@ -33,10 +39,16 @@ use wallet::{
 };
 
 use crate::{
-    config::ExtendedNetwork,
+    config::{CustomChain, ExtendedNetwork},
+    health::HealthLayer,
+    logging::RPC,
+    metrics::{Metrics, MetricsLayer, RpcMetrics},
     node::{BlockMeta, TxEntry},
-    source::BitcoinRpc,
-    store::{ChainState, LiveSnapshot, RolloutEntry, Sha256},
+    progress::{SyncProgress, SyncStatus},
+    prune,
+    source::{BitcoinRpc, BlockFetchMethod},
+    stats::ChainStats,
+    store::{ChainState, LiveSnapshot, ReadHandle, RolloutEntry, Sha256, StateDiff},
     wallets::{
         AddressKind, Balance, RpcWallet, TxInfo, TxResponse, WalletCommand, WalletOutput,
         WalletResponse,
@@ -46,10 +58,83 @@ use crate::checker::TxChecker;
 
 pub(crate) type Responder<T> = oneshot::Sender<T>;
 
+/// How long bitcoind and wallets are given to answer status checks
+const STATUS_TIMEOUT: Duration = Duration::from_secs(5);
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ServerInfo {
     pub chain: ExtendedNetwork,
     pub tip: ChainAnchor,
+    pub disk_usage: DiskUsage,
+    /// How blocks are fetched from bitcoind
+    pub block_source: BlockFetchMethod,
+}
+
+/// Sizes in bytes of the files in the data directory
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DiskUsage {
+    pub protocol: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub block_index: Option<u64>,
+    pub total: u64,
+}
+
+/// State of the node checked by load balancers through /health and /ready
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NodeStatus {
+    pub chain: ExtendedNetwork,
+    /// Whether bitcoind is reachable
+    pub healthy: bool,
+    /// Whether spaced is synced within the allowed lag and its stores agree
+    pub ready: bool,
+    pub bitcoind: BitcoindStatus,
+    pub sync: SyncStatus,
+    pub store: StoreStatus,
+    /// Loaded wallets, only reported by getnodestatus to keep /health
+    /// and /ready independent of the wallet service
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub wallets: Vec<WalletStatus>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BitcoindStatus {
+    pub reachable: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tip: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoreStatus {
+    /// Tip of the protocol state including blocks not committed yet
+    pub tip: ChainAnchor,
+    /// Tip of the last committed protocol state
+    pub committed: Option<ChainAnchor>,
+    /// Tip of the last committed block index if enabled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub block_index: Option<ChainAnchor>,
+    /// Whether the block index is committed at the same tip as the protocol state
+    pub consistent: bool,
+}
+
+/// A wallet loaded into the wallet service
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WalletStatus {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tip: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Protocol-wide statistics at the current tip
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Stats {
+    pub tip: ChainAnchor,
+    pub next_rollout_height: u32,
+    #[serde(flatten)]
+    pub counters: ChainStats,
 }
 
 pub enum ChainStateCommand {
@@ -57,22 +142,6 @@ pub enum ChainStateCommand {
         txs: Vec<String>,
         resp: Responder<anyhow::Result<Vec<Option<TxChangeSet>>>>,
     },
-    GetTip {
-        resp: Responder<anyhow::Result<ChainAnchor>>,
-    },
-    GetSpace {
-        hash: SpaceKey,
-        resp: Responder<anyhow::Result<Option<FullSpaceOut>>>,
-    },
-
-    GetSpaceout {
-        outpoint: OutPoint,
-        resp: Responder<anyhow::Result<Option<SpaceOut>>>,
-    },
-    GetSpaceOutpoint {
-        hash: SpaceKey,
-        resp: Responder<anyhow::Result<Option<OutPoint>>>,
-    },
     GetTxMeta {
         txid: Txid,
         resp: Responder<anyhow::Result<Option<TxEntry>>>,
@@ -89,11 +158,21 @@ pub enum ChainStateCommand {
         target: usize,
         resp: Responder<anyhow::Result<Vec<RolloutEntry>>>,
     },
+    GetStateDiff {
+        from: u32,
+        to: u32,
+        resp: Responder<anyhow::Result<StateDiff>>,
+    },
+    GetStoreStatus {
+        resp: Responder<anyhow::Result<StoreStatus>>,
+    },
 }
 
 #[derive(Clone)]
 pub struct AsyncChainState {
     sender: mpsc::Sender<ChainStateCommand>,
+    /// Serves space and spaceout lookups concurrently instead of queueing them
+    reader: ReadHandle,
 }
 
 #[rpc(server, client)]
@@ -101,6 +180,12 @@ pub trait Rpc {
     #[method(name = "getserverinfo")]
     async fn get_server_info(&self) -> Result<ServerInfo, ErrorObjectOwned>;
 
+    #[method(name = "getsyncstatus")]
+    async fn get_sync_status(&self) -> Result<SyncStatus, ErrorObjectOwned>;
+
+    #[method(name = "getnodestatus")]
+    async fn get_node_status(&self) -> Result<NodeStatus, ErrorObjectOwned>;
+
     #[method(name = "getspace")]
     async fn get_space(
         &self,
@@ -125,6 +210,12 @@ pub trait Rpc {
     #[method(name = "getrollout")]
     async fn get_rollout(&self, target: usize) -> Result<Vec<RolloutEntry>, ErrorObjectOwned>;
 
+    #[method(name = "getstatediff")]
+    async fn get_state_diff(&self, from: u32, to: u32) -> Result<StateDiff, ErrorObjectOwned>;
+
+    #[method(name = "getstats")]
+    async fn get_stats(&self) -> Result<Stats, ErrorObjectOwned>;
+
     #[method(name = "getblockmeta")]
     async fn get_block_meta(
         &self,
@@ -282,12 +373,20 @@ pub struct RpcServerImpl {
     wallet_manager: WalletManager,
     store: AsyncChainState,
     client: reqwest::Client,
+    data_dir: PathBuf,
+    block_source: BlockFetchMethod,
+    sync_progress: SyncProgress,
+    /// Served at /metrics if set
+    metrics: Option<Metrics>,
+    /// Blocks spaced may be behind bitcoind while still reported as ready
+    ready_max_lag: u32,
 }
 
 #[derive(Clone)]
 pub struct WalletManager {
     pub data_dir: PathBuf,
     pub network: ExtendedNetwork,
+    pub custom_chain: Option<CustomChain>,
     pub rpc: BitcoinRpc,
     pub wallet_loader: mpsc::Sender<LoadedWallet>,
     pub wallets: Arc<RwLock<BTreeMap<String, RpcWallet>>>,
@@ -421,6 +520,11 @@ impl WalletManager {
             _ => self.network.fallback_network(),
         };
 
+        // Wallets on a custom chain are checked against its own genesis block
+        if let Some(custom) = &self.custom_chain {
+            genesis_hash = Some(custom.genesis_hash);
+        }
+
         (network, genesis_hash)
     }
 
@@ -434,6 +538,10 @@ impl WalletManager {
 
         let (network, genesis_hash) = self.fallback_network();
         let export: WalletExport = serde_json::from_reader(file)?;
+        let space_hrp = match self.custom_chain.as_ref() {
+            Some(custom) => custom.space_hrp(network)?,
+            None => None,
+        };
 
         let mut wallet = SpacesWallet::new(WalletConfig {
             start_block: export.blockheight,
@@ -441,6 +549,7 @@ impl WalletManager {
             name: name.to_string(),
             network,
             genesis_hash,
+            space_hrp,
             space_descriptors: WalletDescriptors {
                 external: export.descriptor(),
                 internal: export
@@ -509,14 +618,94 @@ impl WalletManager {
 }
 
 impl RpcServerImpl {
-    pub fn new(store: AsyncChainState, wallet_manager: WalletManager) -> Self {
+    pub fn new(
+        store: AsyncChainState,
+        wallet_manager: WalletManager,
+        data_dir: PathBuf,
+        block_source: BlockFetchMethod,
+        sync_progress: SyncProgress,
+        metrics: Option<Metrics>,
+        ready_max_lag: u32,
+    ) -> Self {
         RpcServerImpl {
             wallet_manager,
             store,
             client: reqwest::Client::new(),
+            data_dir,
+            block_source,
+            sync_progress,
+            metrics,
+            ready_max_lag,
         }
     }
 
+    /// Aggregates the state reported by /health and /ready, getnodestatus
+    /// adds the wallets
+    pub async fn node_status(&self) -> anyhow::Result<NodeStatus> {
+        let bitcoind = self.bitcoind_status().await;
+        let store = self.store.get_store_status().await?;
+        let sync = self.sync_progress.status();
+
+        let bitcoin_tip = bitcoind.tip.or(sync.bitcoin_tip);
+        let synced = bitcoin_tip
+            .is_some_and(|tip| tip.saturating_sub(store.tip.height) <= self.ready_max_lag);
+        Ok(NodeStatus {
+            chain: self.wallet_manager.network,
+            healthy: bitcoind.reachable,
+            ready: bitcoind.reachable && synced && store.consistent,
+            bitcoind,
+            sync,
+            store,
+            wallets: Vec::new(),
+        })
+    }
+
+    async fn bitcoind_status(&self) -> BitcoindStatus {
+        let rpc = &self.wallet_manager.rpc;
+        let count = rpc.send_json::<u32>(&self.client, &rpc.get_block_count());
+        let (tip, error) = match tokio::time::timeout(STATUS_TIMEOUT, count).await {
+            Ok(Ok(tip)) => (Some(tip), None),
+            Ok(Err(e)) => (None, Some(e.to_string())),
+            Err(_) => (None, Some("timed out".to_string())),
+        };
+        BitcoindStatus {
+            reachable: tip.is_some(),
+            tip,
+            error,
+        }
+    }
+
+    async fn wallet_statuses(&self) -> Vec<WalletStatus> {
+        let wallets = self.wallet_manager.wallets.read().await.clone();
+        let mut statuses = Vec::with_capacity(wallets.len());
+        for (name, wallet) in wallets {
+            let info = tokio::time::timeout(STATUS_TIMEOUT, wallet.send_get_info());
+            let (tip, error) = match info.await {
+                Ok(Ok(info)) => (Some(info.tip), None),
+                Ok(Err(e)) => (None, Some(e.to_string())),
+                Err(_) => (None, Some("timed out".to_string())),
+            };
+            statuses.push(WalletStatus { name, tip, error });
+        }
+        statuses
+    }
+
+    /// Walks the data directory on the blocking thread pool
+    async fn disk_usage(&self) -> anyhow::Result<DiskUsage> {
+        let data_dir = self.data_dir.clone();
+        Ok(tokio::task::spawn_blocking(move || {
+            let block_index = data_dir.join("block_index.sdb");
+            DiskUsage {
+                protocol: prune::disk_usage(&data_dir.join("protocol.sdb")),
+                block_index: block_index
+                    .exists()
+                    .then(|| prune::disk_usage(&block_index)),
+                total: prune::disk_usage(&data_dir),
+            }
+        })
+        .await?)
+    }
+
     async fn wallet(&self, wallet: &str) -> Result<RpcWallet, ErrorObjectOwned> {
         let wallets = self.wallet_manager.wallets.read().await;
         wallets.get(wallet).cloned().ok_or_else(|| {
@@ -533,16 +722,26 @@ impl RpcServerImpl {
         addrs: Vec<SocketAddr>,
         signal: broadcast::Sender<()>,
     ) -> anyhow::Result<()> {
-        let mut listeners: Vec<Server> = Vec::with_capacity(addrs.len());
+        let mut listeners = Vec::with_capacity(addrs.len());
         for addr in addrs.iter() {
-            let server = Server::builder().build(addr).await?;
+            let metrics = self.metrics.clone();
+            let http_middleware = tower::ServiceBuilder::new()
+                .layer(HealthLayer::new(self.clone()))
+                .layer(MetricsLayer::new(metrics.clone(), self.sync_progress.clone()));
+            let rpc_middleware = RpcServiceBuilder::new()
+                .layer_fn(move |service| RpcMetrics::new(service, metrics.clone()));
+            let server = Server::builder()
+                .set_http_middleware(http_middleware)
+                .set_rpc_middleware(rpc_middleware)
+                .build(addr)
+                .await?;
             listeners.push(server);
         }
 
         let mut set = JoinSet::new();
         for listener in listeners {
             let addr = listener.local_addr()?;
-            info!("Listening at {addr}");
+            info!(target: RPC, "Listening at {addr}");
 
             let handle = listener.start(self.clone().into_rpc());
 
@@ -554,7 +753,7 @@ impl RpcServerImpl {
                     },
                     _ = signal.recv() => {
                         // Shutdown signal received
-                        info!("Shutting down listener {addr}...");
+                        info!(target: RPC, "Shutting down listener {addr}...");
                         _ = handle.stop();
                     }
                 }
@@ -581,7 +780,29 @@ impl RpcServer for RpcServerImpl {
             .get_tip()
             .await
             .map_err(|error| ErrorObjectOwned::owned(-1, error.to_string(), None::<String>))?;
-        Ok(ServerInfo { chain, tip })
+        let disk_usage = self
+            .disk_usage()
+            .await
+            .map_err(|error| ErrorObjectOwned::owned(-1, error.to_string(), None::<String>))?;
+        Ok(ServerInfo {
+            chain,
+            tip,
+            disk_usage,
+            block_source: self.block_source,
+        })
+    }
+
+    async fn get_sync_status(&self) -> Result<SyncStatus, ErrorObjectOwned> {
+        Ok(self.sync_progress.status())
+    }
+
+    async fn get_node_status(&self) -> Result<NodeStatus, ErrorObjectOwned> {
+        let mut status = self
+            .node_status()
+            .await
+            .map_err(|error| ErrorObjectOwned::owned(-1, error.to_string(), None::<String>))?;
+        status.wallets = self.wallet_statuses().await;
+        Ok(status)
     }
 
     async fn get_space(
@@ -648,6 +869,24 @@ impl RpcServer for RpcServerImpl {
         Ok(rollouts)
     }
 
+    async fn get_state_diff(&self, from: u32, to: u32) -> Result<StateDiff, ErrorObjectOwned> {
+        let diff = self
+            .store
+            .get_state_diff(from, to)
+            .await
+            .map_err(|error| ErrorObjectOwned::owned(-1, error.to_string(), None::<String>))?;
+        Ok(diff)
+    }
+
+    async fn get_stats(&self) -> Result<Stats, ErrorObjectOwned> {
+        let stats = self
+            .store
+            .get_stats()
+            .await
+            .map_err(|error| ErrorObjectOwned::owned(-1, error.to_string(), None::<String>))?;
+        Ok(stats)
+    }
+
     async fn get_block_meta(
         &self,
         block_hash: BlockHash,
@@ -819,8 +1058,8 @@ impl RpcServer for RpcServerImpl {
 }
 
 impl AsyncChainState {
-    pub fn new(sender: mpsc::Sender<ChainStateCommand>) -> Self {
-        Self { sender }
+    pub fn new(sender: mpsc::Sender<ChainStateCommand>, reader: ReadHandle) -> Self {
+        Self { sender, reader }
     }
 
     async fn get_indexed_tx(
@@ -915,26 +1154,6 @@ impl AsyncChainState {
                 let result = emulator.apply_package(tip.height+1, txs);
                 let _ = resp.send(result);
             },
-            ChainStateCommand::GetTip { resp } => {
-                let tip = chain_state.tip.read().expect("read meta").clone();
-                _ = resp.send(Ok(tip))
-            }
-            ChainStateCommand::GetSpace { hash, resp } => {
-                let result = chain_state.get_space_info(&hash);
-                let _ = resp.send(result);
-            }
-            ChainStateCommand::GetSpaceout { outpoint, resp } => {
-                let result = chain_state
-                    .get_spaceout(&outpoint)
-                    .context("could not fetch spaceout");
-                let _ = resp.send(result);
-            }
-            ChainStateCommand::GetSpaceOutpoint { hash, resp } => {
-                let result = chain_state
-                    .get_space_outpoint(&hash)
-                    .context("could not fetch spaceout");
-                let _ = resp.send(result);
-            }
             ChainStateCommand::GetBlockMeta { block_hash, resp } => {
                 let res =
                     Self::get_indexed_block(block_index, &block_hash, client, rpc, chain_state)
@@ -953,9 +1172,34 @@ impl AsyncChainState {
                 let rollouts = chain_state.get_rollout(target);
                 _ = resp.send(rollouts);
             }
+            ChainStateCommand::GetStateDiff { from, to, resp } => {
+                let diff = chain_state.state_diff(from, to);
+                _ = resp.send(diff);
+            }
+            ChainStateCommand::GetStoreStatus { resp } => {
+                _ = resp.send(Self::store_status(chain_state, block_index));
+            }
         }
     }
 
+    fn store_status(
+        chain_state: &LiveSnapshot,
+        block_index: &Option<LiveSnapshot>,
+    ) -> anyhow::Result<StoreStatus> {
+        let tip = *chain_state.tip.read().expect("read tip");
+        let committed = chain_state.committed_tip()?;
+        let index = match block_index {
+            None => None,
+            Some(index) => Some(index.committed_tip()?),
+        };
+        Ok(StoreStatus {
+            tip,
+            committed,
+            block_index: index.flatten(),
+            consistent: index.map_or(true, |index| index == committed),
+        })
+    }
+
     pub async fn handler(
         client: &reqwest::Client,
         rpc: BitcoinRpc,
@@ -975,7 +1219,7 @@ impl AsyncChainState {
             }
         }
 
-        info!("Shutting down chain state...");
+        info!(target: RPC, "Shutting down chain state...");
     }
 
     pub async fn estimate_bid(&self, target: usize) -> anyhow::Result<u64> {
@@ -994,20 +1238,48 @@ impl AsyncChainState {
         resp_rx.await?
     }
 
-    pub async fn get_space(&self, hash: SpaceKey) -> anyhow::Result<Option<FullSpaceOut>> {
+    pub async fn get_store_status(&self) -> anyhow::Result<StoreStatus> {
+        let mut attempts = 0;
+        loop {
+            let (resp, resp_rx) = oneshot::channel();
+            self.sender
+                .send(ChainStateCommand::GetStoreStatus { resp })
+                .await?;
+            let status = resp_rx.await??;
+
+            // The block index is committed right after the protocol state
+            // so a mismatch is only reported if it doesn't resolve shortly.
+            // Waiting here keeps the chain state free for other commands.
+            attempts += 1;
+            if status.consistent || attempts == 3 {
+                return Ok(status);
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+
+    pub async fn get_state_diff(&self, from: u32, to: u32) -> anyhow::Result<StateDiff> {
         let (resp, resp_rx) = oneshot::channel();
         self.sender
-            .send(ChainStateCommand::GetSpace { hash, resp })
+            .send(ChainStateCommand::GetStateDiff { from, to, resp })
             .await?;
         resp_rx.await?
     }
 
+    pub async fn get_space(&self, hash: SpaceKey) -> anyhow::Result<Option<FullSpaceOut>> {
+        self.reader
+            .spawn(move |chain_state| chain_state.get_space_info(&hash))
+            .await?
+    }
+
     pub async fn get_space_outpoint(&self, hash: SpaceKey) -> anyhow::Result<Option<OutPoint>> {
-        let (resp, resp_rx) = oneshot::channel();
-        self.sender
-            .send(ChainStateCommand::GetSpaceOutpoint { hash, resp })
-            .await?;
-        resp_rx.await?
+        self.reader
+            .spawn(move |chain_state| {
+                chain_state
+                    .get_space_outpoint(&hash)
+                    .context("could not fetch spaceout")
+            })
+            .await?
     }
 
     pub async fn check_package(&self, txs: Vec<String>) -> anyhow::Result<Vec<Option<TxChangeSet>>> {
@@ -1019,17 +1291,30 @@ impl AsyncChainState {
     }
 
     pub async fn get_tip(&self) -> anyhow::Result<ChainAnchor> {
-        let (resp, resp_rx) = oneshot::channel();
-        self.sender.send(ChainStateCommand::GetTip { resp }).await?;
-        resp_rx.await?
+        Ok(self.reader.tip())
+    }
+
+    pub async fn get_stats(&self) -> anyhow::Result<Stats> {
+        self.reader
+            .spawn(|chain_state| -> anyhow::Result<Stats> {
+                let tip = *chain_state.tip.read().expect("read tip");
+                Ok(Stats {
+                    tip,
+                    next_rollout_height: ChainStats::next_rollout_height(tip.height),
+                    counters: chain_state.get_stats()?,
+                })
+            })
+            .await?
     }
 
     pub async fn get_spaceout(&self, outpoint: OutPoint) -> anyhow::Result<Option<SpaceOut>> {
-        let (resp, resp_rx) = oneshot::channel();
-        self.sender
-            .send(ChainStateCommand::GetSpaceout { outpoint, resp })
-            .await?;
-        resp_rx.await?
+        self.reader
+            .spawn(move |chain_state| {
+                chain_state
+                    .get_spaceout(&outpoint)
+                    .context("could not fetch spaceout")
+            })
+            .await?
     }
 
     pub async fn get_block_meta(&self, block_hash: BlockHash) -> anyhow::Result<Option<BlockMeta>> {
@@ -0,0 +1,384 @@
+use std::{
+    fs,
+    fs::{File, OpenOptions},
+    io::{BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Context, Result};
+use bincode::{config, Decode, Encode};
+use log::info;
+use protocol::{
+    bitcoin::{
+        hashes::{sha256, Hash as _, HashEngine},
+        secp256k1::{schnorr, Keypair, Message, Secp256k1, XOnlyPublicKey},
+    },
+    constants::ChainAnchor,
+};
+use spacedb::Hash;
+
+use crate::{
+    logging::STORE,
+    store::{ReadTx, Store},
+    sync::Spaced,
+};
+
+const SNAPSHOT_MAGIC: &[u8; 8] = b"SPACESNP";
+const SNAPSHOT_VERSION: u8 = 3;
+
+/// Upper bound of the encoded header or a single entry. Lengths read from
+/// the file are untrusted so nothing larger is ever allocated.
+const MAX_RECORD_SIZE: usize = 1 << 20;
+
+/// Describes the committed chain state contained in a snapshot file
+#[derive(Encode, Decode)]
+pub struct SnapshotHeader {
+    /// Network the snapshot was exported from
+    pub network: String,
+    /// Block at which the state was committed
+    pub anchor: ChainAnchor,
+    /// Raw snapshot metadata as stored in spacedb
+    pub metadata: Vec<u8>,
+    /// Root hash of the state at the anchor
+    pub root: Hash,
+}
+
+/// The header is followed by `Some((key, value))` entries, a `None` and the
+/// number of entries so that exports don't need to count them up front.
+/// The trailer comes last: its digest covers everything before it and the
+/// optional signature commits to the digest.
+#[derive(Encode, Decode)]
+struct SnapshotTrailer {
+    digest: [u8; 32],
+    signature: Option<[u8; 64]>,
+}
+
+/// Writes the latest committed state of the chain store to the given path
+pub fn export(
+    spaced: &Spaced,
+    path: &Path,
+    signing_key: Option<&Keypair>,
+) -> Result<SnapshotHeader> {
+    let (header, entries) =
+        write_snapshot(&spaced.chain.store, &spaced.chain_name(), path, signing_key)?;
+    info!(
+        target: STORE,
+        block:% = header.anchor.hash,
+        height = header.anchor.height,
+        entries;
+        "Exported snapshot to {}",
+        path.display()
+    );
+    Ok(header)
+}
+
+/// Loads a snapshot into an empty chain store. The digest, the signature if
+/// a trusted key is given and the state root are verified in memory, and the
+/// anchor against the configured block source, before anything is written.
+pub async fn import(
+    spaced: &Spaced,
+    path: &Path,
+    trusted_key: Option<&XOnlyPublicKey>,
+) -> Result<ChainAnchor> {
+    if spaced.block_index.is_some() {
+        return Err(anyhow!(
+            "Block index must be enabled from the initial sync and cannot be used with snapshot import"
+        ));
+    }
+    if spaced.chain.store.iter().next().is_some() {
+        return Err(anyhow!(
+            "Snapshots can only be imported into an empty data directory"
+        ));
+    }
+
+    let (header, verified) = read_snapshot(path, &spaced.chain_name(), trusted_key)?;
+
+    let genesis = Spaced::genesis(
+        &spaced.rpc,
+        spaced.esplora_url.as_deref(),
+        spaced.replay.as_ref(),
+        spaced.network,
+        spaced.custom_chain.as_ref(),
+    )
+    .await?;
+    if header.anchor.height < genesis.height {
+        return Err(anyhow!(
+            "Snapshot height {} is below the activation height {}",
+            header.anchor.height,
+            genesis.height
+        ));
+    }
+
+    let best_hash = Spaced::fetch_block_hash(
+        &spaced.rpc,
+        spaced.esplora_url.as_deref(),
+        spaced.replay.as_ref(),
+        header.anchor.height,
+    )
+    .await
+    .map_err(|e| anyhow!("Could not verify snapshot anchor: {}", e))?;
+    if best_hash != header.anchor.hash {
+        return Err(anyhow!(
+            "Snapshot block={} height={} is not in the best chain of the block source",
+            header.anchor.hash,
+            header.anchor.height
+        ));
+    }
+
+    let root = copy_state(&verified, &spaced.chain.store, &header.metadata)?;
+    if root != header.root {
+        return Err(anyhow!(
+            "Imported state root does not match the snapshot root"
+        ));
+    }
+
+    spaced.chain.state.restore(header.anchor.clone())?;
+    info!(
+        target: STORE,
+        block:% = header.anchor.hash,
+        height = header.anchor.height,
+        root:% = hex::encode(root);
+        "Imported snapshot"
+    );
+    Ok(header.anchor)
+}
+
+/// Streams the latest committed snapshot of `store` to `path` in a single
+/// pass, signing it if a key is given. The file only appears at `path` once
+/// complete. Returns the header along with the number of entries written.
+pub fn write_snapshot(
+    store: &Store,
+    network: &str,
+    path: &Path,
+    signing_key: Option<&Keypair>,
+) -> Result<(SnapshotHeader, u64)> {
+    let mut snapshot = store
+        .iter()
+        .next()
+        .ok_or_else(|| anyhow!("Nothing to export: no committed state found"))??;
+
+    let header = SnapshotHeader {
+        network: network.to_string(),
+        anchor: snapshot.metadata().try_into()?,
+        metadata: snapshot.metadata().to_vec(),
+        root: snapshot.compute_root()?,
+    };
+
+    let tmp_path = temp_path(path);
+    let written = write_file(&tmp_path, &header, &snapshot, signing_key).and_then(|entries| {
+        fs::rename(&tmp_path, path)?;
+        Ok(entries)
+    });
+    if written.is_err() {
+        _ = fs::remove_file(&tmp_path);
+    }
+    Ok((header, written?))
+}
+
+fn write_file(
+    path: &Path,
+    header: &SnapshotHeader,
+    snapshot: &ReadTx,
+    signing_key: Option<&Keypair>,
+) -> Result<u64> {
+    let file = OpenOptions::new().write(true).create_new(true).open(path)?;
+    let mut writer = Digesting::new(BufWriter::new(file));
+    writer.write_all(SNAPSHOT_MAGIC)?;
+    writer.write_all(&[SNAPSHOT_VERSION])?;
+    bincode::encode_into_std_write(header, &mut writer, config::standard())?;
+    let mut entries = 0u64;
+    for entry in snapshot.iter() {
+        bincode::encode_into_std_write(Some(entry?), &mut writer, config::standard())?;
+        entries += 1;
+    }
+    bincode::encode_into_std_write(None::<(Hash, Vec<u8>)>, &mut writer, config::standard())?;
+    bincode::encode_into_std_write(entries, &mut writer, config::standard())?;
+
+    let (mut writer, digest) = writer.finish();
+    let trailer = SnapshotTrailer {
+        digest,
+        signature: signing_key.map(|key| {
+            Secp256k1::new()
+                .sign_schnorr_no_aux_rand(&Message::from_digest(digest), key)
+                .serialize()
+        }),
+    };
+    bincode::encode_into_std_write(&trailer, &mut writer, config::standard())?;
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    Ok(entries)
+}
+
+/// Reads and verifies a snapshot file into a memory store. Fails unless the
+/// file is intact, was exported for `network` and its entries hash to the
+/// root in the header. If `trusted_key` is given the file must be signed by it.
+pub fn read_snapshot(
+    path: &Path,
+    network: &str,
+    trusted_key: Option<&XOnlyPublicKey>,
+) -> Result<(SnapshotHeader, Store)> {
+    let file = File::open(path).with_context(|| format!("could not open {}", path.display()))?;
+    let mut reader = Digesting::new(BufReader::new(file));
+    let limited = config::standard().with_limit::<MAX_RECORD_SIZE>();
+
+    let mut magic = [0u8; 9];
+    reader.read_exact(&mut magic)?;
+    if &magic[..8] != SNAPSHOT_MAGIC {
+        return Err(anyhow!("{} is not a spaces snapshot", path.display()));
+    }
+    if magic[8] != SNAPSHOT_VERSION {
+        return Err(anyhow!("Unsupported snapshot version {}", magic[8]));
+    }
+
+    let header: SnapshotHeader = bincode::decode_from_std_read(&mut reader, limited)
+        .map_err(|e| anyhow!("Could not read snapshot header: {}", e))?;
+    if header.network != network {
+        return Err(anyhow!(
+            "Snapshot is for {} but node is configured for {}",
+            header.network,
+            network
+        ));
+    }
+    let metadata_anchor: ChainAnchor = header.metadata.as_slice().try_into()?;
+    if metadata_anchor != header.anchor {
+        return Err(anyhow!("Snapshot metadata does not match its anchor"));
+    }
+
+    // Entries are written to memory as they're read so that only the
+    // decoded state is held in memory
+    let verified = Store::memory()?;
+    {
+        let mut tx = verified.write()?;
+        let mut entries = 0u64;
+        while let Some((key, value)) =
+            bincode::decode_from_std_read::<Option<(Hash, Vec<u8>)>, _, _>(&mut reader, limited)
+                .map_err(|e| anyhow!("Could not read snapshot entry: {}", e))?
+        {
+            tx.insert(key, value)?;
+            entries += 1;
+        }
+        let expected: u64 = bincode::decode_from_std_read(&mut reader, limited)
+            .map_err(|e| anyhow!("Could not read snapshot entry count: {}", e))?;
+        if entries != expected {
+            return Err(anyhow!(
+                "Snapshot has {} entries but claims {}",
+                entries,
+                expected
+            ));
+        }
+        tx.metadata(header.metadata.clone())?;
+        tx.commit()?;
+    }
+
+    let (mut reader, digest) = reader.finish();
+    let trailer: SnapshotTrailer = bincode::decode_from_std_read(&mut reader, limited)
+        .map_err(|e| anyhow!("Could not read snapshot trailer: {}", e))?;
+    if trailer.digest != digest {
+        return Err(anyhow!("Snapshot is corrupted: digest mismatch"));
+    }
+    if reader.read(&mut [0u8; 1])? != 0 {
+        return Err(anyhow!("Snapshot has unexpected trailing data"));
+    }
+    match (trusted_key, trailer.signature) {
+        (None, Some(_)) => info!(target: STORE, "Snapshot is signed but no trusted key was given"),
+        (None, None) => {}
+        (Some(_), None) => return Err(anyhow!("Snapshot is not signed")),
+        (Some(key), Some(signature)) => {
+            let signature = schnorr::Signature::from_slice(&signature)?;
+            Secp256k1::verification_only()
+                .verify_schnorr(&signature, &Message::from_digest(digest), key)
+                .map_err(|_| anyhow!("Snapshot signature is not valid for the trusted key"))?;
+        }
+    }
+
+    let root = verified
+        .iter()
+        .next()
+        .ok_or_else(|| anyhow!("missing committed snapshot"))??
+        .compute_root()?;
+    if root != header.root {
+        return Err(anyhow!(
+            "Snapshot root {} does not match expected root {}",
+            hex::encode(root),
+            hex::encode(header.root)
+        ));
+    }
+    Ok((header, verified))
+}
+
+/// Commits the latest snapshot of `from` to `to` and returns the resulting state root
+fn copy_state(from: &Store, to: &Store, metadata: &[u8]) -> Result<Hash> {
+    let snapshot = from
+        .iter()
+        .next()
+        .ok_or_else(|| anyhow!("missing verified snapshot"))??;
+    let mut tx = to.write()?;
+    for entry in snapshot.iter() {
+        let (key, value) = entry?;
+        tx.insert(key, value)?;
+    }
+    tx.metadata(metadata.to_vec())?;
+    tx.commit()?;
+
+    let mut snapshot = to
+        .iter()
+        .next()
+        .ok_or_else(|| anyhow!("missing committed snapshot"))??;
+    snapshot.compute_root()
+}
+
+/// A file next to `path` that no other export uses at the same time
+fn temp_path(path: &Path) -> PathBuf {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.subsec_nanos())
+        .unwrap_or_default();
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    path.with_file_name(format!(".{}.{}.{}.tmp", name, std::process::id(), nanos))
+}
+
+/// Hashes all bytes passing through a reader or writer
+struct Digesting<T> {
+    inner: T,
+    engine: sha256::HashEngine,
+}
+
+impl<T> Digesting<T> {
+    fn new(inner: T) -> Self {
+        Self {
+            inner,
+            engine: sha256::Hash::engine(),
+        }
+    }
+
+    fn finish(self) -> (T, [u8; 32]) {
+        (
+            self.inner,
+            sha256::Hash::from_engine(self.engine).to_byte_array(),
+        )
+    }
+}
+
+impl<R: Read> Read for Digesting<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.engine.input(&buf[..read]);
+        Ok(read)
+    }
+}
+
+impl<W: Write> Write for Digesting<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.engine.input(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
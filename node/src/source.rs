@@ -1,45 +1,157 @@
 use std::{
     collections::BTreeMap,
     fmt,
+    path::PathBuf,
     sync::{
-        atomic::{AtomicU64, AtomicUsize, Ordering},
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
         mpsc::Receiver,
-        Arc,
+        Arc, Condvar, Mutex,
     },
-    time::Duration,
+    time::{Duration, SystemTime},
 };
 
 use base64::Engine;
-use bitcoin::{Block, BlockHash, Txid};
+use bitcoin::{block::Header, Block, BlockHash, Txid, Work};
+use futures::future::join_all;
 use hex::FromHexError;
-use log::{error, info};
+use log::{error, info, warn};
 use reqwest::StatusCode;
 use protocol::constants::ChainAnchor;
+use rand::Rng;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use threadpool::ThreadPool;
 use tokio::time::Instant;
 use wallet::{bdk_wallet::chain::ConfirmationTime, bitcoin, bitcoin::Transaction};
 
-use crate::node::BlockSource;
+use crate::{
+    headers::{HeaderRules, HeaderVerifier},
+    logging::FETCHER,
+    node::BlockSource,
+    progress::SyncProgress,
+};
 
 const BITCOIN_RPC_IN_WARMUP: i32 = -28; // Client still warming up
 const BITCOIN_RPC_CLIENT_NOT_CONNECTED: i32 = -9; // Bitcoin is not connected
 const BITCOIN_RPC_CLIENT_IN_INITIAL_DOWNLOAD: i32 = -10; // Still downloading initial blocks
+const BITCOIN_RPC_INVALID_ADDRESS_OR_KEY: i32 = -5; // Invalid address or key
 
 const RPC_PARSE_ERROR: i32 = -32700;
 
+/// How often the best chain is polled when no notifications are received
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Polling is kept as a fallback while notifications are received
+/// in case some are dropped by the publisher
+const NOTIFIED_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
 #[derive(Clone)]
 pub struct BitcoinRpc {
     id: Arc<AtomicU64>,
-    auth_token: Option<String>,
+    endpoints: Arc<RpcEndpoints>,
+    retry: Arc<RetryState>,
+}
+
+/// How requests failing with temporary errors are retried
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub initial_delay: Duration,
+    /// Upper bound of the exponentially growing delay between attempts
+    pub max_delay: Duration,
+    /// Requests are given up on once retried for this long
+    pub max_elapsed: Duration,
+    /// Fraction of each delay that's randomized so that concurrent
+    /// requests don't retry in lockstep
+    pub jitter: f64,
+    /// Consecutive requests that must fail before the circuit opens (0 disables)
+    pub breaker_threshold: u32,
+    /// How long requests fail immediately once the circuit is open
+    pub breaker_cooldown: Duration,
+}
+
+struct RetryState {
+    policy: RetryPolicy,
+    breaker: Mutex<CircuitBreaker>,
+}
+
+#[derive(Default)]
+struct CircuitBreaker {
+    /// Requests given up on since the last success
+    failures: u32,
+    open_until: Option<Instant>,
+}
+
+/// How often endpoints are compared when more than one is configured
+const ENDPOINT_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+/// Failing endpoints are tried last for this long
+const ENDPOINT_FAILURE_COOLDOWN: Duration = Duration::from_secs(30);
+
+/// Bitcoin RPC endpoints requests fail over between
+struct RpcEndpoints {
+    list: Vec<RpcEndpoint>,
+    last_check: Mutex<Option<Instant>>,
+}
+
+struct RpcEndpoint {
     url: String,
+    credentials: Arc<RpcCredentials>,
+    health: Mutex<EndpointHealth>,
 }
 
-pub struct BlockFetcher {
-    src: BitcoinBlockSource,
+/// How often the cookie file is checked for changes
+const COOKIE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Credentials shared by all endpoints. Tokens from a cookie file are
+/// reloaded when bitcoind rotates it on restart.
+struct RpcCredentials {
+    cookie_file: Option<PathBuf>,
+    state: Mutex<CredentialsState>,
+}
+
+#[derive(Default)]
+struct CredentialsState {
+    token: Option<String>,
+    /// Modification time of the cookie file the token was read from
+    modified: Option<SystemTime>,
+    last_check: Option<Instant>,
+    /// Read errors are only logged once until the cookie can be read again
+    warned: bool,
+}
+
+#[derive(Default)]
+struct EndpointHealth {
+    /// Consecutive failed requests
+    failures: u32,
+    last_failure: Option<Instant>,
+    /// Moving average of request latency
+    latency: Option<Duration>,
+    /// Best chain as of the last check
+    tip: Option<ChainAnchor>,
+    /// Best chain disagrees with the majority of endpoints
+    diverged: bool,
+}
+
+pub struct BlockFetcher<S = BitcoinBlockSource> {
+    src: S,
     job_id: Arc<AtomicUsize>,
     sender: std::sync::mpsc::SyncSender<BlockEvent>,
     num_workers: usize,
+    notifier: TipNotifier,
+    /// Blocks are verified before being emitted if set
+    rules: Option<HeaderRules>,
+    progress: SyncProgress,
+}
+
+/// Wakes block fetchers as soon as a new block is announced
+/// instead of waiting for the next poll
+#[derive(Clone, Default)]
+pub struct TipNotifier(Arc<NotifierState>);
+
+#[derive(Default)]
+struct NotifierState {
+    /// Incremented on every notification
+    generation: Mutex<u64>,
+    cvar: Condvar,
+    /// Whether notifications are currently being received
+    connected: AtomicBool,
 }
 
 pub enum BlockEvent {
@@ -50,6 +162,8 @@ pub enum BlockEvent {
 pub enum BitcoinRpcAuth {
     UserPass(String, String),
     Cookie(String),
+    /// Cookie file written by bitcoind, re-read whenever it changes
+    CookieFile(PathBuf),
     None,
 }
 
@@ -57,6 +171,8 @@ pub enum BitcoinRpcAuth {
 pub enum BitcoinRpcError {
     Rpc(JsonRpcError),
     Transport(reqwest::Error),
+    /// Requests are paused for the remaining duration after repeated failures
+    CircuitOpen(Duration),
     Other(String),
 }
 
@@ -65,6 +181,10 @@ pub enum BlockFetchError {
     RpcError(BitcoinRpcError),
     BlockMismatch,
     ChannelClosed,
+    /// The source returned a block failing header or merkle root checks
+    InvalidBlock(ChainAnchor, String),
+    /// The source follows a chain that doesn't include a checkpoint
+    CheckpointMismatch(ChainAnchor),
 }
 
 impl fmt::Display for BlockFetchError {
@@ -73,6 +193,16 @@ impl fmt::Display for BlockFetchError {
             BlockFetchError::RpcError(e) => write!(f, "RPC error: {}", e),
             BlockFetchError::BlockMismatch => write!(f, "Block mismatch detected"),
             BlockFetchError::ChannelClosed => write!(f, "Channel closed"),
+            BlockFetchError::InvalidBlock(id, reason) => write!(
+                f,
+                "Invalid block={} height={}: {}",
+                id.hash, id.height, reason
+            ),
+            BlockFetchError::CheckpointMismatch(checkpoint) => write!(
+                f,
+                "Chain does not include checkpoint block={} height={}",
+                checkpoint.hash, checkpoint.height
+            ),
         }
     }
 }
@@ -86,6 +216,13 @@ impl std::error::Error for BlockFetchError {
     }
 }
 
+impl BlockFetchError {
+    /// Whether syncing may succeed later without intervention
+    pub fn is_temporary(&self) -> bool {
+        matches!(self, BlockFetchError::RpcError(e) if e.is_temporary())
+    }
+}
+
 impl From<BitcoinRpcError> for BlockFetchError {
     fn from(err: BitcoinRpcError) -> Self {
         BlockFetchError::RpcError(err)
@@ -120,11 +257,105 @@ trait ErrorForRpcBlocking {
 
 impl BitcoinRpc {
     pub fn new(url: &str, auth: BitcoinRpcAuth) -> Self {
+        Self::with_endpoints(&[url.to_string()], auth)
+    }
+
+    /// Requests are routed to the healthiest endpoint and fail over to the
+    /// others on transport errors. All endpoints share the same credentials
+    /// unless they're embedded in the url.
+    pub fn with_endpoints(urls: &[String], auth: BitcoinRpcAuth) -> Self {
+        assert!(!urls.is_empty(), "at least one rpc url is required");
+        let credentials = Arc::new(RpcCredentials::new(auth));
         Self {
             id: Default::default(),
-            auth_token: auth.to_token(),
-            url: url.to_string(),
+            retry: Arc::new(RetryState::new(RetryPolicy::default())),
+            endpoints: Arc::new(RpcEndpoints {
+                list: urls
+                    .iter()
+                    .map(|url| RpcEndpoint {
+                        url: url.clone(),
+                        credentials: credentials.clone(),
+                        health: Default::default(),
+                    })
+                    .collect(),
+                last_check: Mutex::new(None),
+            }),
+        }
+    }
+
+    pub fn with_retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry = Arc::new(RetryState::new(policy));
+        self
+    }
+
+    /// Compares the best chain of all endpoints so that those disagreeing
+    /// with the majority are no longer followed. This is a no-op with a
+    /// single endpoint or if endpoints were checked recently.
+    pub fn check_endpoints_blocking(&self, client: &reqwest::blocking::Client) {
+        if !self.endpoints.check_due() {
+            return;
+        }
+        let tips: Vec<_> = self
+            .endpoints
+            .list
+            .iter()
+            .map(|endpoint| {
+                let info: EndpointInfo = endpoint
+                    .send_json_blocking(client, &self.get_blockchain_info())
+                    .ok()?;
+                Some(endpoint.record_tip(info))
+            })
+            .collect();
+        let common_height = match common_height(&tips) {
+            None => return,
+            Some(height) => height,
+        };
+        let hashes = self
+            .endpoints
+            .list
+            .iter()
+            .zip(tips.iter())
+            .map(|(endpoint, tip)| {
+                tip.as_ref()?;
+                endpoint
+                    .send_json_blocking(client, &self.get_block_hash(common_height))
+                    .ok()
+            })
+            .collect();
+        self.endpoints.vote(common_height, hashes);
+    }
+
+    /// Same as [BitcoinRpc::check_endpoints_blocking] for async clients,
+    /// run before each request so that all requests honor the majority
+    pub async fn check_endpoints(&self, client: &reqwest::Client) {
+        if !self.endpoints.check_due() {
+            return;
         }
+        let info_request = self.get_blockchain_info();
+        let tips: Vec<_> = join_all(self.endpoints.list.iter().map(|endpoint| {
+            let request = &info_request;
+            async move {
+                let info: EndpointInfo = endpoint.send_json(client, request).await.ok()?;
+                Some(endpoint.record_tip(info))
+            }
+        }))
+        .await;
+        let common_height = match common_height(&tips) {
+            None => return,
+            Some(height) => height,
+        };
+        let hash_request = self.get_block_hash(common_height);
+        let hashes = join_all(self.endpoints.list.iter().zip(tips.iter()).map(
+            |(endpoint, tip)| {
+                let request = &hash_request;
+                async move {
+                    tip.as_ref()?;
+                    endpoint.send_json(client, request).await.ok()
+                }
+            },
+        ))
+        .await;
+        self.endpoints.vote(common_height, hashes);
     }
 
     pub fn make_request(&self, method: &str, params: serde_json::Value) -> BitcoinRpcRequest {
@@ -149,6 +380,11 @@ impl BitcoinRpc {
         self.make_request("getblockheader", params)
     }
 
+    pub fn get_raw_block_header(&self, hash: &BlockHash) -> BitcoinRpcRequest {
+        let params = serde_json::json!([hash, /* verbose */ false]);
+        self.make_request("getblockheader", params)
+    }
+
     pub fn get_raw_transaction(&self, hash: &Txid, verbose: bool) -> BitcoinRpcRequest {
         let params = serde_json::json!([hash, verbose]);
         self.make_request("getrawtransaction", params)
@@ -236,29 +472,132 @@ impl BitcoinRpc {
         client: &reqwest::Client,
         request: &BitcoinRpcRequest,
     ) -> Result<reqwest::Response, BitcoinRpcError> {
-        let mut delay = Duration::from_millis(1000);
-        let max_retries = 10;
+        self.retry.check_circuit()?;
+        self.check_endpoints(client).await;
+        let started = Instant::now();
+        let mut attempt = 0;
         let mut last_error = None;
 
-        for attempt in 0..max_retries {
-            let mut builder = client.post(&self.url);
-            if let Some(auth) = self.auth_token.as_ref() {
-                builder = builder.header("Authorization", format!("Basic {}", auth));
+        loop {
+            for endpoint in self.endpoints.ranked() {
+                let sent = Instant::now();
+                match endpoint.send(client, request).await {
+                    Ok(res) => {
+                        endpoint.record_success(sent.elapsed());
+                        match Self::clean_rpc_response(res).await {
+                            // e.g. bitcoind warming up after a restart
+                            Err(e) if e.is_temporary() => last_error = Some(e),
+                            res => {
+                                self.retry.record_success();
+                                return res;
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        endpoint.record_failure();
+                        if !e.should_fail_over() {
+                            return Err(e);
+                        }
+                        if self.endpoints.list.len() > 1 {
+                            error!(
+                                target: FETCHER,
+                                "Rpc {}: {} - trying next endpoint",
+                                endpoint.url,
+                                e
+                            );
+                        }
+                        last_error = Some(e);
+                    }
+                }
             }
 
-            match builder.json(&request.body).send().await.map_err(BitcoinRpcError::from) {
-                Ok(res) => return Self::clean_rpc_response(res).await,
-                Err(e) if e.is_temporary() && attempt < max_retries - 1 => {
-                    error!("Rpc: {} - retrying in {:?}...", e, delay);
-                    last_error = Some(e.into());
-                    tokio::time::sleep(delay).await;
-                    delay *= 2;
+            let e = last_error.take().expect("an error");
+            let delay = match self.retry.next_delay(&e, attempt, started) {
+                Some(delay) => delay,
+                None => return self.retry.give_up(e),
+            };
+            error!(target: FETCHER, "Rpc: {} - retrying in {:?}...", e, delay);
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    /// Performs a GET against bitcoind's REST interface returning the raw
+    /// response body. REST must be enabled in bitcoind with `-rest`.
+    /// Failures are retried and failed over like JSON-RPC requests.
+    pub fn send_rest_blocking(
+        &self,
+        client: &reqwest::blocking::Client,
+        path: &str,
+    ) -> Result<Vec<u8>, BitcoinRpcError> {
+        self.retry.check_circuit()?;
+        let started = Instant::now();
+        let mut attempt = 0;
+        let mut last_error = None;
+
+        loop {
+            for endpoint in self.endpoints.ranked() {
+                let url = format!("{}/rest/{}", endpoint.url.trim_end_matches('/'), path);
+                let sent = Instant::now();
+                match client.get(&url).send() {
+                    Ok(res) => {
+                        endpoint.record_success(sent.elapsed());
+                        match Self::clean_rest_response_blocking(res, path) {
+                            // e.g. bitcoind warming up after a restart
+                            Err(e) if e.is_temporary() => last_error = Some(e),
+                            res => {
+                                self.retry.record_success();
+                                return res;
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        endpoint.record_failure();
+                        if self.endpoints.list.len() > 1 {
+                            error!(
+                                target: FETCHER,
+                                "Rest {}: {} - trying next endpoint",
+                                endpoint.url,
+                                e
+                            );
+                        }
+                        last_error = Some(BitcoinRpcError::Transport(e));
+                    }
                 }
-                Err(e) => return Err(e),
             }
+
+            let e = last_error.take().expect("an error");
+            let delay = match self.retry.next_delay(&e, attempt, started) {
+                Some(delay) => delay,
+                None => return self.retry.give_up(e),
+            };
+            error!(target: FETCHER, "Rest: {} - retrying in {:?}...", e, delay);
+            std::thread::sleep(delay);
+            attempt += 1;
         }
+    }
 
-        Err(last_error.expect("an error"))
+    /// Unknown blocks are reported like their JSON-RPC counterpart and other
+    /// HTTP errors as transport errors so that temporary ones are retried
+    fn clean_rest_response_blocking(
+        res: reqwest::blocking::Response,
+        path: &str,
+    ) -> Result<Vec<u8>, BitcoinRpcError> {
+        let status = res.status();
+        if status.is_success() {
+            return Ok(res.bytes()?.to_vec());
+        }
+        let error = res
+            .error_for_status_ref()
+            .expect_err("unsuccessful status");
+        if status == StatusCode::NOT_FOUND {
+            let message = res.text().unwrap_or_default();
+            return Err(BitcoinRpcError::Rpc(JsonRpcError {
+                code: BITCOIN_RPC_INVALID_ADDRESS_OR_KEY,
+                message: format!("REST /rest/{}: {}", path, message.trim()),
+            }));
+        }
+        Err(BitcoinRpcError::Transport(error))
     }
 
     fn send_request_blocking(
@@ -266,29 +605,53 @@ impl BitcoinRpc {
         client: &reqwest::blocking::Client,
         request: &BitcoinRpcRequest,
     ) -> Result<reqwest::blocking::Response, BitcoinRpcError> {
-        let mut delay = Duration::from_millis(1000);
-        let max_retries = 10;
+        self.retry.check_circuit()?;
+        let started = Instant::now();
+        let mut attempt = 0;
         let mut last_error = None;
 
-        for attempt in 0..max_retries {
-            let mut builder = client.post(&self.url);
-            if let Some(auth) = self.auth_token.as_ref() {
-                builder = builder.header("Authorization", format!("Basic {}", auth));
-            }
-
-            match builder.json(&request.body).send().map_err(BitcoinRpcError::from) {
-                Ok(res) => return Self::clean_rpc_response_blocking(res),
-                Err(e) if e.is_temporary() && attempt < max_retries - 1 => {
-                    error!("Rpc: {} - retrying in {:?}...", e, delay);
-                    last_error = Some(e.into());
-                    std::thread::sleep(delay);
-                    delay *= 2;
+        loop {
+            for endpoint in self.endpoints.ranked() {
+                let sent = Instant::now();
+                match endpoint.send_blocking(client, request) {
+                    Ok(res) => {
+                        endpoint.record_success(sent.elapsed());
+                        match Self::clean_rpc_response_blocking(res) {
+                            // e.g. bitcoind warming up after a restart
+                            Err(e) if e.is_temporary() => last_error = Some(e),
+                            res => {
+                                self.retry.record_success();
+                                return res;
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        endpoint.record_failure();
+                        if !e.should_fail_over() {
+                            return Err(e);
+                        }
+                        if self.endpoints.list.len() > 1 {
+                            error!(
+                                target: FETCHER,
+                                "Rpc {}: {} - trying next endpoint",
+                                endpoint.url,
+                                e
+                            );
+                        }
+                        last_error = Some(e);
+                    }
                 }
-                Err(e) => return Err(e),
             }
-        }
 
-        Err(last_error.expect("an error"))
+            let e = last_error.take().expect("an error");
+            let delay = match self.retry.next_delay(&e, attempt, started) {
+                Some(delay) => delay,
+                None => return self.retry.give_up(e),
+            };
+            error!(target: FETCHER, "Rpc: {} - retrying in {:?}...", e, delay);
+            std::thread::sleep(delay);
+            attempt += 1;
+        }
     }
 
     pub async fn clean_rpc_response(res: reqwest::Response) -> Result<reqwest::Response, BitcoinRpcError> {
@@ -350,22 +713,465 @@ impl BitcoinRpc {
     }
 }
 
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(30),
+            max_elapsed: Duration::from_secs(300),
+            jitter: 0.2,
+            breaker_threshold: 5,
+            breaker_cooldown: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Delay before retrying for the given attempt starting at 0
+    fn delay(&self, attempt: u32) -> Duration {
+        let delay = self
+            .initial_delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_delay);
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        if jitter == 0.0 {
+            return delay;
+        }
+        delay.mul_f64(1.0 - jitter * rand::thread_rng().gen_range(0.0..1.0))
+    }
+}
+
+impl RetryState {
+    fn new(policy: RetryPolicy) -> Self {
+        Self {
+            policy,
+            breaker: Default::default(),
+        }
+    }
+
+    fn breaker(&self) -> std::sync::MutexGuard<'_, CircuitBreaker> {
+        self.breaker.lock().expect("circuit breaker lock")
+    }
+
+    /// Fails fast while bitcoind is considered unavailable
+    fn check_circuit(&self) -> Result<(), BitcoinRpcError> {
+        let breaker = self.breaker();
+        match breaker.open_until {
+            Some(until) if until > Instant::now() => Err(BitcoinRpcError::CircuitOpen(
+                until.saturating_duration_since(Instant::now()),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns how long to wait before retrying or `None` to give up
+    fn next_delay(&self, e: &BitcoinRpcError, attempt: u32, started: Instant) -> Option<Duration> {
+        if !e.is_temporary() {
+            return None;
+        }
+        let delay = self.policy.delay(attempt);
+        if started.elapsed() + delay > self.policy.max_elapsed {
+            return None;
+        }
+        Some(delay)
+    }
+
+    fn give_up<T>(&self, e: BitcoinRpcError) -> Result<T, BitcoinRpcError> {
+        if e.is_temporary() {
+            self.record_failure();
+        }
+        Err(e)
+    }
+
+    fn record_success(&self) {
+        let mut breaker = self.breaker();
+        breaker.failures = 0;
+        breaker.open_until = None;
+    }
+
+    fn record_failure(&self) {
+        if self.policy.breaker_threshold == 0 {
+            return;
+        }
+        let mut breaker = self.breaker();
+        breaker.failures += 1;
+        if breaker.failures >= self.policy.breaker_threshold {
+            let reopening = breaker.open_until.is_some();
+            breaker.open_until = Some(Instant::now() + self.policy.breaker_cooldown);
+            if !reopening {
+                warn!(
+                    target: FETCHER,
+                    "Bitcoin RPC failed {} times in a row - pausing requests for {:?}",
+                    breaker.failures, self.policy.breaker_cooldown
+                );
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct EndpointInfo {
+    blocks: u32,
+    #[serde(rename = "bestblockhash")]
+    best_block_hash: BlockHash,
+}
+
+/// Endpoints may lag behind each other so chains are compared
+/// at the lowest height all of them have
+fn common_height(tips: &[Option<ChainAnchor>]) -> Option<u32> {
+    tips.iter().flatten().map(|tip| tip.height).min()
+}
+
+impl RpcEndpoints {
+    /// Whether endpoints should be compared, claiming the check if so
+    fn check_due(&self) -> bool {
+        if self.list.len() < 2 {
+            return false;
+        }
+        let mut last_check = self.last_check.lock().expect("endpoints lock");
+        if last_check.is_some_and(|last| last.elapsed() < ENDPOINT_CHECK_INTERVAL) {
+            return false;
+        }
+        *last_check = Some(Instant::now());
+        true
+    }
+
+    /// Marks endpoints whose block at `height` disagrees with the majority
+    /// of those that responded as diverged
+    fn vote(&self, height: u32, hashes: Vec<Option<BlockHash>>) {
+        let responded = hashes.iter().flatten().count();
+        let mut votes: Vec<(BlockHash, usize)> = Vec::new();
+        for hash in hashes.iter().flatten() {
+            match votes.iter_mut().find(|(h, _)| h == hash) {
+                Some((_, count)) => *count += 1,
+                None => votes.push((*hash, 1)),
+            }
+        }
+        let majority = votes
+            .iter()
+            .find(|(_, count)| *count * 2 > responded)
+            .map(|(hash, _)| *hash);
+        let majority = match majority {
+            None => {
+                warn!(
+                    target: FETCHER,
+                    "Rpc endpoints disagree on block {} with no majority",
+                    height
+                );
+                return;
+            }
+            Some(majority) => majority,
+        };
+
+        for (endpoint, hash) in self.list.iter().zip(hashes) {
+            let hash = match hash {
+                None => continue,
+                Some(hash) => hash,
+            };
+            let diverged = hash != majority;
+            let mut health = endpoint.health();
+            if diverged && !health.diverged {
+                warn!(
+                    target: FETCHER,
+                    "Rpc endpoint {} disagrees with the majority at height {} - no longer following it",
+                    endpoint.url, height
+                );
+            } else if !diverged && health.diverged {
+                info!(
+                    target: FETCHER,
+                    "Rpc endpoint {} agrees with the majority again",
+                    endpoint.url
+                );
+            }
+            health.diverged = diverged;
+        }
+    }
+
+    /// Endpoints to try in order of health leaving out those
+    /// that disagree with the majority
+    fn ranked(&self) -> Vec<&RpcEndpoint> {
+        if self.list.len() == 1 {
+            return vec![&self.list[0]];
+        }
+
+        let mut ranked: Vec<_> = self
+            .list
+            .iter()
+            .map(|endpoint| (endpoint, endpoint.score()))
+            .filter(|(_, score)| score.is_some())
+            .collect();
+        ranked.sort_by(|(_, a), (_, b)| a.cmp(b));
+        if ranked.is_empty() {
+            return self.list.iter().collect();
+        }
+        ranked.into_iter().map(|(endpoint, _)| endpoint).collect()
+    }
+}
+
+impl RpcEndpoint {
+    fn health(&self) -> std::sync::MutexGuard<'_, EndpointHealth> {
+        self.health.lock().expect("endpoint health lock")
+    }
+
+    /// Lower is healthier, `None` if the endpoint shouldn't be followed
+    fn score(&self) -> Option<(bool, std::cmp::Reverse<u32>, u32, Duration)> {
+        let health = self.health();
+        if health.diverged {
+            return None;
+        }
+        let cooling_down = health
+            .last_failure
+            .is_some_and(|last| health.failures > 0 && last.elapsed() < ENDPOINT_FAILURE_COOLDOWN);
+        Some((
+            cooling_down,
+            std::cmp::Reverse(health.tip.map(|tip| tip.height).unwrap_or(0)),
+            health.failures,
+            health.latency.unwrap_or(Duration::MAX),
+        ))
+    }
+
+    fn record_success(&self, latency: Duration) {
+        let mut health = self.health();
+        health.failures = 0;
+        health.latency = Some(match health.latency {
+            None => latency,
+            Some(average) => (average * 7 + latency) / 8,
+        });
+    }
+
+    fn record_failure(&self) {
+        let mut health = self.health();
+        health.failures += 1;
+        health.last_failure = Some(Instant::now());
+    }
+
+    async fn send(
+        &self,
+        client: &reqwest::Client,
+        request: &BitcoinRpcRequest,
+    ) -> Result<reqwest::Response, BitcoinRpcError> {
+        let send = |token: Option<String>| {
+            let mut builder = client.post(&self.url);
+            if let Some(auth) = token {
+                builder = builder.header("Authorization", format!("Basic {}", auth));
+            }
+            builder.json(&request.body).send()
+        };
+        let res = send(self.credentials.token()).await?;
+        // The cookie may have been rotated since it was last checked
+        if res.status() == StatusCode::UNAUTHORIZED && self.credentials.reload(true) {
+            return Ok(send(self.credentials.token()).await?);
+        }
+        Ok(res)
+    }
+
+    fn send_blocking(
+        &self,
+        client: &reqwest::blocking::Client,
+        request: &BitcoinRpcRequest,
+    ) -> Result<reqwest::blocking::Response, BitcoinRpcError> {
+        let send = |token: Option<String>| {
+            let mut builder = client.post(&self.url);
+            if let Some(auth) = token {
+                builder = builder.header("Authorization", format!("Basic {}", auth));
+            }
+            builder.json(&request.body).send()
+        };
+        let res = send(self.credentials.token())?;
+        if res.status() == StatusCode::UNAUTHORIZED && self.credentials.reload(true) {
+            return Ok(send(self.credentials.token())?);
+        }
+        Ok(res)
+    }
+
+    fn record_tip(&self, info: EndpointInfo) -> ChainAnchor {
+        let tip = ChainAnchor {
+            hash: info.best_block_hash,
+            height: info.blocks,
+        };
+        self.health().tip = Some(tip);
+        tip
+    }
+
+    /// Sends a request to this endpoint only without retrying
+    async fn send_json<T: DeserializeOwned>(
+        &self,
+        client: &reqwest::Client,
+        request: &BitcoinRpcRequest,
+    ) -> Result<T, BitcoinRpcError> {
+        let started = Instant::now();
+        match self.send(client, request).await {
+            Ok(res) => {
+                self.record_success(started.elapsed());
+                BitcoinRpc::clean_rpc_response(res).await?.error_for_rpc().await
+            }
+            Err(e) => {
+                self.record_failure();
+                Err(e)
+            }
+        }
+    }
+
+    /// Sends a request to this endpoint only without retrying
+    fn send_json_blocking<T: DeserializeOwned>(
+        &self,
+        client: &reqwest::blocking::Client,
+        request: &BitcoinRpcRequest,
+    ) -> Result<T, BitcoinRpcError> {
+        let started = Instant::now();
+        match self.send_blocking(client, request) {
+            Ok(res) => {
+                self.record_success(started.elapsed());
+                BitcoinRpc::clean_rpc_response_blocking(res)?.error_for_rpc()
+            }
+            Err(e) => {
+                self.record_failure();
+                Err(e)
+            }
+        }
+    }
+}
+
 impl BitcoinRpcAuth {
     fn to_token(&self) -> Option<String> {
         match self {
             BitcoinRpcAuth::UserPass(user, pass) => {
                 Some(base64::prelude::BASE64_STANDARD.encode(format!("{user}:{pass}")))
             }
-            BitcoinRpcAuth::Cookie(cookie) => Some(cookie.clone()),
-            BitcoinRpcAuth::None => None,
+            BitcoinRpcAuth::Cookie(cookie) => Some(cookie_token(cookie)),
+            BitcoinRpcAuth::CookieFile(_) | BitcoinRpcAuth::None => None,
         }
     }
 }
 
-impl BlockFetcher {
+/// The cookie holds `__cookie__:<password>` which is sent as is with basic auth
+fn cookie_token(cookie: &str) -> String {
+    base64::prelude::BASE64_STANDARD.encode(cookie.trim())
+}
+
+impl RpcCredentials {
+    fn new(auth: BitcoinRpcAuth) -> Self {
+        let credentials = Self {
+            state: Mutex::new(CredentialsState {
+                token: auth.to_token(),
+                ..Default::default()
+            }),
+            cookie_file: match auth {
+                BitcoinRpcAuth::CookieFile(path) => Some(path),
+                _ => None,
+            },
+        };
+        credentials.reload(false);
+        credentials
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, CredentialsState> {
+        self.state.lock().expect("credentials lock")
+    }
+
+    /// Current token picking up a rotated cookie file
+    fn token(&self) -> Option<String> {
+        if self.cookie_file.is_some()
+            && !self
+                .state()
+                .last_check
+                .is_some_and(|last| last.elapsed() < COOKIE_CHECK_INTERVAL)
+        {
+            self.reload(false);
+        }
+        self.state().token.clone()
+    }
+
+    /// Re-reads the cookie file if it changed returning whether the token changed.
+    /// A `forced` reload reads it even if its modification time is the same
+    /// since bitcoind may rotate it within the resolution of the file system.
+    fn reload(&self, forced: bool) -> bool {
+        let Some(path) = self.cookie_file.as_ref() else {
+            return false;
+        };
+        let mut state = self.state();
+        state.last_check = Some(Instant::now());
+
+        // bitcoind removes the cookie while shutting down so the last
+        // token is kept until a new one is written
+        let read = std::fs::metadata(path)
+            .and_then(|meta| meta.modified())
+            .and_then(|modified| {
+                if !forced && state.token.is_some() && state.modified == Some(modified) {
+                    return Ok(None);
+                }
+                Ok(Some((modified, std::fs::read_to_string(path)?)))
+            });
+        let (modified, cookie) = match read {
+            Ok(Some(read)) => read,
+            Ok(None) => return false,
+            Err(e) => {
+                if !state.warned {
+                    warn!(target: FETCHER, "Could not read rpc cookie {}: {}", path.display(), e);
+                    state.warned = true;
+                }
+                return false;
+            }
+        };
+        state.warned = false;
+        state.modified = Some(modified);
+        let token = Some(cookie_token(&cookie));
+        if token == state.token {
+            return false;
+        }
+        if state.token.is_some() {
+            info!(target: FETCHER, "Reloaded rotated rpc cookie {}", path.display());
+        }
+        state.token = token;
+        true
+    }
+}
+
+impl TipNotifier {
+    pub fn notify(&self) {
+        let mut generation = self.0.generation.lock().expect("notifier lock");
+        *generation += 1;
+        self.0.cvar.notify_all();
+    }
+
+    pub fn set_connected(&self, connected: bool) {
+        self.0.connected.store(connected, Ordering::Relaxed);
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.0.connected.load(Ordering::Relaxed)
+    }
+
+    fn generation(&self) -> u64 {
+        *self.0.generation.lock().expect("notifier lock")
+    }
+
+    /// Waits up to `timeout` for a notification newer than `seen`
+    fn wait(&self, seen: &mut u64, timeout: Duration) -> bool {
+        let generation = self.0.generation.lock().expect("notifier lock");
+        let (generation, _) = self
+            .0
+            .cvar
+            .wait_timeout_while(generation, timeout, |generation| *generation == *seen)
+            .expect("notifier lock");
+        let notified = *generation != *seen;
+        *seen = *generation;
+        notified
+    }
+}
+
+impl<S: BlockSource + Clone + Send + 'static> BlockFetcher<S> {
     pub fn new(
-        src: BitcoinBlockSource,
+        src: S,
         num_workers: usize,
+    ) -> (Self, std::sync::mpsc::Receiver<BlockEvent>) {
+        Self::with_notifier(src, num_workers, TipNotifier::default())
+    }
+
+    pub fn with_notifier(
+        src: S,
+        num_workers: usize,
+        notifier: TipNotifier,
     ) -> (Self, std::sync::mpsc::Receiver<BlockEvent>) {
         let (tx, rx) = std::sync::mpsc::sync_channel(12);
         (
@@ -374,20 +1180,43 @@ impl BlockFetcher {
                 job_id: Arc::new(AtomicUsize::new(0)),
                 sender: tx,
                 num_workers,
+                notifier,
+                rules: None,
+                progress: SyncProgress::default(),
             },
             rx,
         )
     }
 
+    pub fn with_rules(mut self, rules: HeaderRules) -> Self {
+        self.rules = Some(rules);
+        self
+    }
+
+    /// Reports the source tip and fetch queue depth to `progress`
+    pub fn with_progress(mut self, progress: SyncProgress) -> Self {
+        self.progress = progress;
+        self
+    }
+
     pub fn stop(&self) {
         self.job_id.fetch_add(1, Ordering::SeqCst);
     }
 
     fn should_sync(
-        source: &BitcoinBlockSource,
+        source: &S,
+        verifier: Option<&mut HeaderVerifier>,
+        progress: &SyncProgress,
         start: ChainAnchor,
     ) -> Result<Option<ChainAnchor>, BlockFetchError> {
+        if let Some(verifier) = verifier {
+            if !verifier.has_min_chain_work(source)? {
+                return Ok(None);
+            }
+        }
+
         let tip = source.get_best_chain()?;
+        progress.set_bitcoin_tip(tip.height);
         if start.height > tip.height {
             return Err(BlockFetchError::BlockMismatch);
         }
@@ -414,50 +1243,75 @@ impl BlockFetcher {
         let current_task = self.job_id.clone();
         let task_sender = self.sender.clone();
         let num_workers = self.num_workers;
+        let notifier = self.notifier.clone();
+        let mut verifier = self.rules.clone().map(HeaderVerifier::new);
+        let progress = self.progress.clone();
 
         _ = std::thread::spawn(move || {
-            let mut last_check = Instant::now() - Duration::from_secs(2);
+            let mut seen = notifier.generation();
+            let mut last_check: Option<Instant> = None;
+            // Temporary errors are only logged once until the source recovers
+            let mut unavailable = false;
 
             loop {
                 if current_task.load(Ordering::SeqCst) != job_id {
-                    info!("Shutting down block fetcher");
+                    info!(target: FETCHER, "Shutting down block fetcher");
                     return;
                 }
-                if last_check.elapsed() < Duration::from_secs(1) {
-                    std::thread::sleep(Duration::from_millis(10));
-                    continue;
+                if let Some(last_check) = last_check {
+                    let interval = if notifier.is_connected() {
+                        NOTIFIED_POLL_INTERVAL
+                    } else {
+                        POLL_INTERVAL
+                    };
+                    // Wake up at least every POLL_INTERVAL to notice the job was stopped
+                    let notified = notifier.wait(&mut seen, POLL_INTERVAL);
+                    if !notified && last_check.elapsed() < interval {
+                        continue;
+                    }
                 }
-                last_check = Instant::now();
-
-                let tip = match BlockFetcher::should_sync(&task_src, checkpoint) {
-                    Ok(t) => t,
+                last_check = Some(Instant::now());
+
+                let res = Self::should_sync(&task_src, verifier.as_mut(), &progress, checkpoint)
+                    .and_then(|tip| match tip {
+                        Some(tip) => Self::run_workers(
+                            job_id,
+                            current_task.clone(),
+                            task_src.clone(),
+                            task_sender.clone(),
+                            verifier.as_mut(),
+                            &progress,
+                            &mut checkpoint,
+                            tip.height,
+                            num_workers,
+                        ),
+                        None => Ok(()),
+                    });
+
+                match res {
+                    Ok(()) => {
+                        if unavailable {
+                            info!(target: FETCHER, "Block source is available again");
+                            unavailable = false;
+                        }
+                    }
+                    // Keep polling through bitcoind restarts resuming from the
+                    // last emitted block instead of failing the sync
+                    Err(e) if e.is_temporary() => {
+                        if !unavailable {
+                            warn!(
+                                target: FETCHER,
+                                "Block source unavailable: {} - will keep retrying",
+                                e
+                            );
+                            unavailable = true;
+                        }
+                    }
                     Err(e) => {
                         _ = task_sender.send(BlockEvent::Error(e));
+                        current_task.fetch_add(1, Ordering::SeqCst);
                         return;
                     }
-                };
-
-                if let Some(tip) = tip {
-                    let res = Self::run_workers(
-                        job_id,
-                        current_task.clone(),
-                        task_src.clone(),
-                        task_sender.clone(),
-                        checkpoint,
-                        tip.height,
-                        num_workers,
-                    );
-
-                    match res {
-                        Ok(new_tip) => {
-                            checkpoint = new_tip;
-                        }
-                        Err(e) => {
-                            _ = task_sender.send(BlockEvent::Error(e));
-                            current_task.fetch_add(1, Ordering::SeqCst);
-                            return;
-                        }
-                    }
                 }
             }
         });
@@ -466,78 +1320,37 @@ impl BlockFetcher {
     fn run_workers(
         job_id: usize,
         current_job: Arc<AtomicUsize>,
-        src: BitcoinBlockSource,
+        src: S,
         sender: std::sync::mpsc::SyncSender<BlockEvent>,
-        start_block: ChainAnchor,
+        verifier: Option<&mut HeaderVerifier>,
+        progress: &SyncProgress,
+        checkpoint: &mut ChainAnchor,
         end_height: u32,
         num_workers: usize,
-    ) -> Result<ChainAnchor, BlockFetchError> {
+    ) -> Result<(), BlockFetchError> {
         let mut workers = Workers {
             current_job,
             job_id,
             out_of_order: Default::default(),
-            last_emitted: start_block,
-            queued_height: start_block.height + 1,
+            last_emitted: *checkpoint,
+            queued_height: checkpoint.height + 1,
             end_height,
             ordered_sender: sender,
             src,
+            verifier,
+            progress,
             num_workers,
             pool: ThreadPool::new(num_workers),
         };
 
-        workers.run()
-    }
-
-    pub fn fetch_block(
-        source: &BitcoinBlockSource,
-        hash: &BlockHash,
-    ) -> Result<Block, BitcoinRpcError> {
-        let block_req = source.rpc.get_block(&hash);
-        let id = block_req.id;
-        let response = source
-            .rpc
-            .send_request_blocking(&source.client, &block_req)?;
-
-        let mut raw = response.bytes()?.to_vec();
-
-        let start_needle = "{\"result\":\"";
-        let end_needle = format!("\",\"error\":null,\"id\":\"{}\"}}\n", id.to_string());
-
-        // Check if we can quickly extract block
-        let hex_block =
-            if raw.starts_with(start_needle.as_bytes()) && raw.ends_with(end_needle.as_bytes()) {
-                raw.drain(0..start_needle.len());
-                raw.truncate(raw.len() - end_needle.len());
-                raw
-            } else {
-                // fallback to decoding json
-                let hex_block: JsonRpcResponse<String> = serde_json::from_slice(raw.as_slice())
-                    .map_err(|e| BitcoinRpcError::Other(e.to_string()))?;
-                if let Some(e) = hex_block.error {
-                    return Err(BitcoinRpcError::Rpc(e));
-                }
-                hex_block.result.unwrap().into_bytes()
-            };
-
-        if hex_block.len() % 2 != 0 {
-            return Err(BitcoinRpcError::Other(
-                "Parse error: could not hex decode block".to_string(),
-            ));
-        }
-
-        let raw_block = hex_to_bytes(hex_block).map_err(|e| {
-            BitcoinRpcError::Other(format!("Hex deserialize error: {}", e.to_string()))
-        })?;
-
-        let block: Block =
-            bitcoin::consensus::encode::deserialize(raw_block.as_slice()).map_err(|e| {
-                BitcoinRpcError::Other(format!("Block Deserialize error: {}", e.to_string()))
-            })?;
-        Ok(block)
+        // Blocks emitted before an error must not be emitted again on retry
+        let res = workers.run();
+        *checkpoint = workers.last_emitted;
+        res.map(|_| ())
     }
 }
 
-struct Workers {
+struct Workers<'a, S> {
     current_job: Arc<AtomicUsize>,
     job_id: usize,
     out_of_order: BTreeMap<u32, (ChainAnchor, Block)>,
@@ -545,14 +1358,16 @@ struct Workers {
     queued_height: u32,
     end_height: u32,
     ordered_sender: std::sync::mpsc::SyncSender<BlockEvent>,
-    src: BitcoinBlockSource,
+    src: S,
+    verifier: Option<&'a mut HeaderVerifier>,
+    progress: &'a SyncProgress,
     num_workers: usize,
     pool: ThreadPool,
 }
 
 type RpcBlockReceiver = Receiver<Result<(ChainAnchor, Block), BitcoinRpcError>>;
 
-impl Workers {
+impl<S: BlockSource + Clone + Send + 'static> Workers<'_, S> {
     fn try_emit_next_block(
         &mut self,
         unordered: &RpcBlockReceiver,
@@ -564,6 +1379,7 @@ impl Workers {
             let (id, block) = unordered_block?;
             self.out_of_order.insert(id.height, (id, block));
         }
+        self.report_progress();
 
         let next = self.last_emitted.height + 1;
         if next > self.end_height {
@@ -578,6 +1394,9 @@ impl Workers {
             if block.header.prev_blockhash != self.last_emitted.hash {
                 return Err(BlockFetchError::BlockMismatch);
             }
+            if let Some(verifier) = self.verifier.as_mut() {
+                verifier.verify(&self.src, self.last_emitted, id, &block)?;
+            }
 
             self.last_emitted = id;
             self.ordered_sender
@@ -588,6 +1407,15 @@ impl Workers {
         Ok(false)
     }
 
+    fn report_progress(&self) {
+        let requested = self.queued_height - self.last_emitted.height - 1;
+        self.progress.set_fetch_queue(
+            self.last_emitted.height,
+            requested as usize - self.out_of_order.len(),
+            self.out_of_order.len(),
+        );
+    }
+
     #[inline(always)]
     fn can_add_workers(&self) -> bool {
         self.out_of_order.len() < self.num_workers
@@ -624,7 +1452,7 @@ impl Workers {
                     }
                     let result: Result<_, BitcoinRpcError> = (move || {
                         let hash: BlockHash = rpc.get_block_hash(height)?;
-                        let block = BlockFetcher::fetch_block(&rpc, &hash)?;
+                        let block = rpc.get_block(&hash)?;
                         Ok((ChainAnchor { height, hash }, block))
                     })();
                     _ = tx.send(result);
@@ -684,7 +1512,12 @@ fn val(c: u8, idx: usize) -> Result<u8, FromHexError> {
 }
 
 impl BitcoinRpcError {
-    fn is_temporary(&self) -> bool {
+    /// Whether the request may succeed on another endpoint
+    fn should_fail_over(&self) -> bool {
+        matches!(self, BitcoinRpcError::Transport(_)) || self.is_temporary()
+    }
+
+    pub fn is_temporary(&self) -> bool {
         match self {
             BitcoinRpcError::Transport(e) => {
                 if e.is_timeout() || e.is_connect() {
@@ -711,6 +1544,7 @@ impl BitcoinRpcError {
                         | BITCOIN_RPC_CLIENT_NOT_CONNECTED
                 )
             }
+            BitcoinRpcError::CircuitOpen(_) => true,
             _ => false,
         }
     }
@@ -731,6 +1565,11 @@ impl fmt::Display for BitcoinRpcError {
             BitcoinRpcError::Transport(transport_error) => {
                 write!(f, "Transport: {}", transport_error)
             }
+            BitcoinRpcError::CircuitOpen(remaining) => write!(
+                f,
+                "Bitcoin RPC unavailable - requests paused for another {}s",
+                remaining.as_secs()
+            ),
             BitcoinRpcError::Other(message) => write!(f, "{}", message),
         }
     }
@@ -767,30 +1606,139 @@ impl ErrorForRpcBlocking for reqwest::blocking::Response {
     }
 }
 
+/// How blocks are retrieved from bitcoind
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BlockFetchMethod {
+    /// Hex encoded blocks over JSON-RPC
+    Rpc,
+    /// Binary blocks over the REST interface
+    Rest,
+    /// Read from the `blk*.dat` files with RPC as a fallback
+    BlockFiles,
+    /// From an Esplora HTTP API
+    Esplora,
+    /// From a recording made with `--record-blocks`
+    Replay,
+}
+
 #[derive(Clone)]
 pub struct BitcoinBlockSource {
     pub client: reqwest::blocking::Client,
     pub rpc: BitcoinRpc,
+    /// Fetch blocks and block hashes through REST instead of JSON-RPC
+    pub rest: bool,
 }
 
 impl BitcoinBlockSource {
     pub fn new(rpc: BitcoinRpc) -> Self {
         let client = reqwest::blocking::Client::new();
-        Self { client, rpc }
+        Self {
+            client,
+            rpc,
+            rest: false,
+        }
+    }
+
+    pub fn with_rest(mut self, rest: bool) -> Self {
+        self.rest = rest;
+        self
+    }
+
+    /// Binary blocks over REST skip both JSON and hex decoding
+    fn fetch_block_rest(&self, hash: &BlockHash) -> Result<Block, BitcoinRpcError> {
+        let raw = self
+            .rpc
+            .send_rest_blocking(&self.client, &format!("block/{}.bin", hash))?;
+        bitcoin::consensus::encode::deserialize(&raw)
+            .map_err(|e| BitcoinRpcError::Other(format!("Block Deserialize error: {}", e)))
+    }
+
+    /// Fetches a raw block extracting it from the response without
+    /// decoding the whole JSON when possible
+    fn fetch_block(&self, hash: &BlockHash) -> Result<Block, BitcoinRpcError> {
+        let block_req = self.rpc.get_block(&hash);
+        let id = block_req.id;
+        let response = self
+            .rpc
+            .send_request_blocking(&self.client, &block_req)?;
+
+        let mut raw = response.bytes()?.to_vec();
+
+        let start_needle = "{\"result\":\"";
+        let end_needle = format!("\",\"error\":null,\"id\":\"{}\"}}\n", id.to_string());
+
+        // Check if we can quickly extract block
+        let hex_block =
+            if raw.starts_with(start_needle.as_bytes()) && raw.ends_with(end_needle.as_bytes()) {
+                raw.drain(0..start_needle.len());
+                raw.truncate(raw.len() - end_needle.len());
+                raw
+            } else {
+                // fallback to decoding json
+                let hex_block: JsonRpcResponse<String> = serde_json::from_slice(raw.as_slice())
+                    .map_err(|e| BitcoinRpcError::Other(e.to_string()))?;
+                if let Some(e) = hex_block.error {
+                    return Err(BitcoinRpcError::Rpc(e));
+                }
+                hex_block.result.unwrap().into_bytes()
+            };
+
+        if hex_block.len() % 2 != 0 {
+            return Err(BitcoinRpcError::Other(
+                "Parse error: could not hex decode block".to_string(),
+            ));
+        }
+
+        let raw_block = hex_to_bytes(hex_block).map_err(|e| {
+            BitcoinRpcError::Other(format!("Hex deserialize error: {}", e.to_string()))
+        })?;
+
+        let block: Block =
+            bitcoin::consensus::encode::deserialize(raw_block.as_slice()).map_err(|e| {
+                BitcoinRpcError::Other(format!("Block Deserialize error: {}", e.to_string()))
+            })?;
+        Ok(block)
     }
 }
 
 impl BlockSource for BitcoinBlockSource {
     fn get_block_hash(&self, height: u32) -> Result<BlockHash, BitcoinRpcError> {
+        if self.rest {
+            let raw = self.rpc.send_rest_blocking(
+                &self.client,
+                &format!("blockhashbyheight/{}.bin", height),
+            )?;
+            return bitcoin::consensus::encode::deserialize(&raw).map_err(|e| {
+                BitcoinRpcError::Other(format!("Block hash deserialize error: {}", e))
+            });
+        }
         Ok(self
             .rpc
             .send_json_blocking(&self.client, &self.rpc.get_block_hash(height))?)
     }
 
     fn get_block(&self, hash: &BlockHash) -> Result<Block, BitcoinRpcError> {
-        Ok(self
-            .rpc
-            .send_json_blocking(&self.client, &self.rpc.get_block(hash))?)
+        if self.rest {
+            return self.fetch_block_rest(hash);
+        }
+        self.fetch_block(hash)
+    }
+
+    fn get_block_header(&self, hash: &BlockHash) -> Result<Header, BitcoinRpcError> {
+        let raw = if self.rest {
+            self.rpc
+                .send_rest_blocking(&self.client, &format!("headers/1/{}.bin", hash))?
+        } else {
+            let hex: String = self
+                .rpc
+                .send_json_blocking(&self.client, &self.rpc.get_raw_block_header(hash))?;
+            hex_to_bytes(hex.into_bytes()).map_err(|e| {
+                BitcoinRpcError::Other(format!("Hex deserialize error: {}", e))
+            })?
+        };
+        bitcoin::consensus::encode::deserialize(&raw)
+            .map_err(|e| BitcoinRpcError::Other(format!("Header deserialize error: {}", e)))
     }
 
     fn get_median_time(&self) -> Result<u64, BitcoinRpcError> {
@@ -812,6 +1760,8 @@ impl BlockSource for BitcoinBlockSource {
     }
 
     fn get_best_chain(&self) -> Result<ChainAnchor, BitcoinRpcError> {
+        self.rpc.check_endpoints_blocking(&self.client);
+
         #[derive(Deserialize)]
         struct Info {
             #[serde(rename = "blocks")]
@@ -828,4 +1778,220 @@ impl BlockSource for BitcoinBlockSource {
             height: info.height as _,
         })
     }
+
+    fn get_best_chain_work(&self) -> Result<Option<Work>, BitcoinRpcError> {
+        let info: serde_json::Value = self
+            .rpc
+            .send_json_blocking(&self.client, &self.rpc.get_blockchain_info())?;
+        let work = info
+            .get("chainwork")
+            .and_then(|work| work.as_str())
+            .ok_or_else(|| BitcoinRpcError::Other("Could not fetch chain work".to_string()))?;
+        Work::from_unprefixed_hex(work)
+            .map(Some)
+            .map_err(|e| BitcoinRpcError::Other(format!("Invalid chain work: {}", e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::*;
+
+    /// Answers getblockcount with 1 after failing the given number of
+    /// requests as a warming up bitcoind would
+    struct FlakyBitcoind {
+        url: String,
+        failures_left: Arc<AtomicUsize>,
+        requests: Arc<AtomicUsize>,
+    }
+
+    impl FlakyBitcoind {
+        fn start(failures: usize) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+            let url = format!("http://{}", listener.local_addr().expect("addr"));
+            let failures_left = Arc::new(AtomicUsize::new(failures));
+            let requests = Arc::new(AtomicUsize::new(0));
+            let (left, count) = (failures_left.clone(), requests.clone());
+            std::thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let Ok(mut stream) = stream else { continue };
+                    if read_request(&mut stream).is_err() {
+                        continue;
+                    }
+                    count.fetch_add(1, Ordering::SeqCst);
+                    let failing = left
+                        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                        .is_ok();
+                    let (status, body) = if failing {
+                        (
+                            "503 Service Unavailable",
+                            r#"{"result":null,"error":{"code":-28,"message":"Loading"},"id":"0"}"#,
+                        )
+                    } else {
+                        ("200 OK", r#"{"result":1,"error":null,"id":"0"}"#)
+                    };
+                    let _ = write!(
+                        stream,
+                        "HTTP/1.1 {}\r\nContent-Type: application/json\r\n\
+                         Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        status,
+                        body.len(),
+                        body
+                    );
+                }
+            });
+            Self {
+                url,
+                failures_left,
+                requests,
+            }
+        }
+
+        fn fail_next(&self, failures: usize) {
+            self.failures_left.store(failures, Ordering::SeqCst);
+        }
+
+        fn requests(&self) -> usize {
+            self.requests.load(Ordering::SeqCst)
+        }
+    }
+
+    fn read_request(stream: &mut TcpStream) -> std::io::Result<()> {
+        let mut reader = BufReader::new(stream);
+        let mut length = 0;
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line)? == 0 || line == "\r\n" {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    length = value.trim().parse().unwrap_or(0);
+                }
+            }
+        }
+        reader.read_exact(&mut vec![0; length])
+    }
+
+    async fn block_count(rpc: &BitcoinRpc) -> Result<u32, BitcoinRpcError> {
+        rpc.send_json(&reqwest::Client::new(), &rpc.get_block_count())
+            .await
+    }
+
+    /// Lets requests through again as if the cooldown had passed
+    fn expire_cooldown(rpc: &BitcoinRpc) {
+        let mut breaker = rpc.retry.breaker();
+        assert!(breaker.open_until.is_some(), "circuit should be open");
+        breaker.open_until = Some(Instant::now());
+    }
+
+    #[test]
+    fn test_retry_delay_bounds() {
+        let policy = RetryPolicy {
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(1),
+            jitter: 0.0,
+            ..Default::default()
+        };
+        let delays: Vec<_> = (0..6).map(|attempt| policy.delay(attempt).as_millis()).collect();
+        assert_eq!(delays, vec![100, 200, 400, 800, 1000, 1000]);
+        assert_eq!(policy.delay(u32::MAX), policy.max_delay, "no overflow");
+
+        let jittered = RetryPolicy {
+            jitter: 0.5,
+            ..policy.clone()
+        };
+        for attempt in 0..10 {
+            let (base, delay) = (policy.delay(attempt), jittered.delay(attempt));
+            assert!(delay <= base && delay >= base / 2, "{:?} of {:?}", delay, base);
+        }
+
+        // out of range jitter is clamped
+        let clamped = RetryPolicy {
+            jitter: 5.0,
+            ..policy.clone()
+        };
+        assert!(clamped.delay(3) <= policy.delay(3));
+        let negative = RetryPolicy {
+            jitter: -1.0,
+            ..policy.clone()
+        };
+        assert_eq!(negative.delay(3), policy.delay(3));
+    }
+
+    #[tokio::test]
+    async fn test_retries_until_bitcoind_recovers() {
+        let bitcoind = FlakyBitcoind::start(3);
+        let rpc = BitcoinRpc::new(&bitcoind.url, BitcoinRpcAuth::None).with_retry_policy(
+            RetryPolicy {
+                initial_delay: Duration::from_millis(1),
+                max_delay: Duration::from_millis(1),
+                max_elapsed: Duration::from_secs(30),
+                jitter: 0.0,
+                breaker_threshold: 1,
+                ..Default::default()
+            },
+        );
+        assert_eq!(block_count(&rpc).await.expect("recovered"), 1);
+        assert_eq!(bitcoind.requests(), 4, "three failures then success");
+        assert_eq!(rpc.retry.breaker().failures, 0, "retried requests don't trip it");
+    }
+
+    #[tokio::test]
+    async fn test_circuit_breaker() {
+        let bitcoind = FlakyBitcoind::start(usize::MAX);
+        // every request gives up after its first attempt
+        let rpc = BitcoinRpc::new(&bitcoind.url, BitcoinRpcAuth::None).with_retry_policy(
+            RetryPolicy {
+                initial_delay: Duration::from_millis(1),
+                max_delay: Duration::from_millis(1),
+                max_elapsed: Duration::ZERO,
+                jitter: 0.0,
+                breaker_threshold: 2,
+                breaker_cooldown: Duration::from_secs(60),
+            },
+        );
+
+        // closed: requests reach bitcoind until the threshold is hit
+        let e = block_count(&rpc).await.expect_err("warming up");
+        assert!(matches!(e, BitcoinRpcError::Rpc(ref e) if e.code == BITCOIN_RPC_IN_WARMUP));
+        assert!(rpc.retry.breaker().open_until.is_none());
+        block_count(&rpc).await.expect_err("warming up");
+        assert_eq!(bitcoind.requests(), 2);
+
+        // open: requests fail fast without reaching bitcoind
+        let e = block_count(&rpc).await.expect_err("circuit open");
+        assert!(matches!(e, BitcoinRpcError::CircuitOpen(_)));
+        assert!(e.is_temporary());
+        assert_eq!(bitcoind.requests(), 2);
+
+        // half-open: a single failing request reopens it
+        expire_cooldown(&rpc);
+        block_count(&rpc).await.expect_err("still warming up");
+        assert_eq!(bitcoind.requests(), 3);
+        let e = block_count(&rpc).await.expect_err("reopened");
+        assert!(matches!(e, BitcoinRpcError::CircuitOpen(_)));
+        assert_eq!(bitcoind.requests(), 3);
+
+        // half-open: a successful request closes it
+        expire_cooldown(&rpc);
+        bitcoind.fail_next(0);
+        assert_eq!(block_count(&rpc).await.expect("recovered"), 1);
+        {
+            let breaker = rpc.retry.breaker();
+            assert_eq!(breaker.failures, 0);
+            assert!(breaker.open_until.is_none());
+        }
+
+        // closed again: a single failure stays below the threshold
+        bitcoind.fail_next(1);
+        block_count(&rpc).await.expect_err("warming up");
+        assert_eq!(block_count(&rpc).await.expect("recovered"), 1);
+        assert_eq!(bitcoind.requests(), 6);
+    }
 }
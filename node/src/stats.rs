@@ -0,0 +1,120 @@
+use bincode::{Decode, Encode};
+use protocol::{
+    bitcoin::Amount, constants::ROLLOUT_BLOCK_INTERVAL, Covenant, RevokeReason, SpaceOut,
+};
+use serde::{Deserialize, Serialize};
+
+/// Protocol-wide counters updated incrementally as blocks are applied.
+///
+/// Counters are staged alongside the chain state and committed in the
+/// snapshot metadata, so they are disconnected and replayed along with blocks.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, Encode, Decode)]
+pub struct ChainStats {
+    /// Height from which burns, revocations and rollouts were counted.
+    /// Space counts are complete regardless.
+    pub since: u32,
+    /// Spaces owned by a winning bidder
+    pub registered: u64,
+    /// Spaces rolled out and accepting bids
+    pub in_auction: u64,
+    /// Opened spaces waiting to be rolled out
+    pub pre_auction: u64,
+    #[bincode(with_serde)]
+    pub burned: Amount,
+    /// Amounts burned per rollout period ordered by period
+    pub burned_by_period: Vec<PeriodBurn>,
+    pub revocations: Revocations,
+    /// Number of spaces rolled out into auctions
+    pub rollouts: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Encode, Decode)]
+pub struct PeriodBurn {
+    /// Index of the rollout period starting at `period * ROLLOUT_BLOCK_INTERVAL + 1`
+    pub period: u32,
+    #[bincode(with_serde)]
+    pub burned: Amount,
+}
+
+/// Revoked spaces by [RevokeReason]
+#[derive(Debug, Clone, Default, Serialize, Deserialize, Encode, Decode)]
+pub struct Revocations {
+    pub premature_claim: u64,
+    pub bad_spend: u64,
+    pub expired: u64,
+    pub bid_psbt: u64,
+}
+
+impl ChainStats {
+    pub fn new(since: u32) -> Self {
+        Self {
+            since,
+            ..Default::default()
+        }
+    }
+
+    /// Rollout period a block at the given height belongs to
+    pub fn period(height: u32) -> u32 {
+        height.saturating_sub(1) / ROLLOUT_BLOCK_INTERVAL
+    }
+
+    /// Height of the first rollout after the given tip
+    pub fn next_rollout_height(tip: u32) -> u32 {
+        let next = tip / ROLLOUT_BLOCK_INTERVAL * ROLLOUT_BLOCK_INTERVAL + 1;
+        if next <= tip {
+            next + ROLLOUT_BLOCK_INTERVAL
+        } else {
+            next
+        }
+    }
+
+    /// Counts a space output added to the state
+    pub fn add_space(&mut self, spaceout: &SpaceOut) {
+        if let Some(counter) = self.counter(spaceout) {
+            *counter += 1;
+        }
+    }
+
+    /// Counts a space output removed from the state
+    pub fn remove_space(&mut self, spaceout: &SpaceOut) {
+        if let Some(counter) = self.counter(spaceout) {
+            *counter = counter.saturating_sub(1);
+        }
+    }
+
+    pub fn burn(&mut self, height: u32, amount: Amount) {
+        self.burned += amount;
+        let period = Self::period(height);
+        match self.burned_by_period.last_mut() {
+            Some(last) if last.period == period => last.burned += amount,
+            _ => self.burned_by_period.push(PeriodBurn {
+                period,
+                burned: amount,
+            }),
+        }
+    }
+
+    pub fn revoke(&mut self, reason: &RevokeReason) {
+        let counter = match reason {
+            RevokeReason::PrematureClaim => &mut self.revocations.premature_claim,
+            RevokeReason::BadSpend => &mut self.revocations.bad_spend,
+            RevokeReason::Expired => &mut self.revocations.expired,
+            RevokeReason::BidPsbt(_) => &mut self.revocations.bid_psbt,
+        };
+        *counter += 1;
+    }
+
+    pub fn rollout(&mut self) {
+        self.rollouts += 1;
+    }
+
+    fn counter(&mut self, spaceout: &SpaceOut) -> Option<&mut u64> {
+        Some(match spaceout.space.as_ref()?.covenant {
+            Covenant::Bid {
+                claim_height: None, ..
+            } => &mut self.pre_auction,
+            Covenant::Bid { .. } => &mut self.in_auction,
+            Covenant::Transfer { .. } | Covenant::Reserved => &mut self.registered,
+        })
+    }
+}
@@ -1,44 +1,82 @@
 use std::{
     collections::{BTreeMap, BTreeSet},
-    fs::OpenOptions,
-    io,
-    io::ErrorKind,
     mem,
     path::PathBuf,
-    sync::{Arc, RwLock},
+    sync::{Arc, Mutex, RwLock},
 };
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use bincode::{config, Decode, Encode};
 use jsonrpsee::core::Serialize;
 use protocol::{
-    bitcoin::OutPoint,
+    bitcoin::{OutPoint, Txid},
     constants::{ChainAnchor, ROLLOUT_BATCH_SIZE},
-    hasher::{BidKey, KeyHash, OutpointKey, SpaceKey},
+    hasher::{BidKey, KeyHash, KeyHasher, OutpointKey, SpaceKey},
     prepare::DataSource,
+    slabel::SLabel,
     Covenant, FullSpaceOut, SpaceOut,
 };
 use serde::Deserialize;
-use spacedb::{
-    db::{Database, SnapshotIterator},
-    fs::FileBackend,
-    tx::{KeyIterator, ReadTransaction, WriteTransaction},
-    Configuration, Hash, NodeHasher, Sha256Hasher,
+use spacedb::{Hash, NodeHasher, Sha256Hasher};
+
+use crate::{
+    backend::{Entries, Snapshots, SpaceDbBackend, StateBackend, StateSnapshot, StateWrite},
+    cache::SpaceoutCache,
+    filter::OutpointFilter,
+    stats::ChainStats,
+    undo::UndoEntry,
 };
 
+/// Version of the fields following the anchor in snapshot metadata
+const METADATA_VERSION: u8 = 1;
+
+/// Metadata committed with each snapshot.
+///
+/// Encoded as the [ChainAnchor] followed by a version byte and the fields
+/// of that version, so the anchor can be decoded from any snapshot.
+/// Snapshots committed before versioning hold only the anchor.
+#[derive(Debug, Clone)]
+pub struct SnapshotMetadata {
+    pub anchor: ChainAnchor,
+    /// Protocol-wide counters at the anchor if they were recorded
+    pub stats: Option<ChainStats>,
+}
+
+impl SnapshotMetadata {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut raw = self.anchor.to_vec();
+        raw.push(METADATA_VERSION);
+        raw.extend(bincode::encode_to_vec(&self.stats, config::standard()).expect("encodes stats"));
+        raw
+    }
+
+    pub fn decode(raw: &[u8]) -> Result<Self> {
+        let (anchor, len): (ChainAnchor, _) = bincode::decode_from_slice(raw, config::standard())?;
+        let stats = match raw[len..].split_first() {
+            None => None,
+            Some((&METADATA_VERSION, fields)) => {
+                bincode::decode_from_slice(fields, config::standard())?.0
+            }
+            Some((version, _)) => {
+                return Err(anyhow!("Unsupported snapshot metadata version {}", version))
+            }
+        };
+        Ok(Self { anchor, stats })
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RolloutEntry {
     pub space: String,
     pub value: u32,
 }
 
-type SpaceDb = Database<Sha256Hasher>;
-type ReadTx = ReadTransaction<Sha256Hasher>;
-pub type WriteTx<'db> = WriteTransaction<'db, Sha256Hasher>;
+pub type ReadTx = Box<dyn StateSnapshot>;
+pub type WriteTx<'db> = Box<dyn StateWrite + 'db>;
 type WriteMemory = BTreeMap<Hash, Option<Vec<u8>>>;
 
 #[derive(Clone)]
-pub struct Store(SpaceDb);
+pub struct Store(Arc<dyn StateBackend>);
 
 pub struct Sha256;
 
@@ -50,10 +88,74 @@ pub struct LiveStore {
 
 #[derive(Clone)]
 pub struct LiveSnapshot {
-    db: SpaceDb,
+    db: Store,
     pub tip: Arc<RwLock<ChainAnchor>>,
     staged: Arc<RwLock<Staged>>,
     snapshot: (u32, ReadTx),
+    /// Filter of all tracked outpoint keys, committed and staged
+    filter: Option<Arc<RwLock<OutpointFilter>>>,
+    cache: Option<Arc<Mutex<SpaceoutCache>>>,
+    /// Held for writing while a block is being applied so
+    /// that readers observe the state at a single tip
+    block_lock: Arc<RwLock<()>>,
+}
+
+/// A cloneable handle for reading the chain state concurrently with other
+/// readers. Each read runs on its own backend snapshot.
+#[derive(Clone)]
+pub struct ReadHandle {
+    snapshot: LiveSnapshot,
+}
+
+/// Spaces, spaceouts and auction bids inserted, updated or removed
+/// between two committed snapshots
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StateDiff {
+    pub from: ChainAnchor,
+    pub to: ChainAnchor,
+    pub inserted: Vec<StateDiffEntry>,
+    pub updated: Vec<StateDiffEntry>,
+    pub removed: Vec<StateDiffEntry>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum StateDiffEntry {
+    /// A `SpaceKey` -> outpoint mapping
+    Space {
+        key: String,
+        outpoint: OutPoint,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        spaceout: Option<FullSpaceOut>,
+    },
+    /// An `OutpointKey` -> spaceout mapping. The txid can only be recovered
+    /// for spaceouts carrying a space since outpoint keys are hashed.
+    Outpoint {
+        key: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        txid: Option<Txid>,
+        #[serde(flatten)]
+        spaceout: SpaceOut,
+    },
+    /// A `BidKey` -> `SpaceKey` entry ordering spaces in the auction by their
+    /// current bid. The name is resolved through the space key if it still exists.
+    Bid {
+        key: String,
+        priority: u32,
+        space_key: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        name: Option<SLabel>,
+    },
+}
+
+/// Keys written by a block along with the values they held before and after
+pub struct TrackedChanges {
+    pub prior: Vec<UndoEntry>,
+    pub written: Vec<UndoEntry>,
+    /// Stats before the block if it read or wrote them
+    pub prior_stats: Option<ChainStats>,
+    /// Stats after the block if it read or wrote them
+    pub stats: Option<ChainStats>,
 }
 
 pub struct Staged {
@@ -61,55 +163,68 @@ pub struct Staged {
     snapshot_version: u32,
     /// Stores changes until committed
     memory: WriteMemory,
+    /// Staged values of keys touched by the block being applied
+    /// before they were first written. `None` if the key wasn't staged.
+    tracked: Option<BTreeMap<Hash, Option<Option<Vec<u8>>>>>,
+    /// Protocol-wide counters at the tip. Kept outside the keyspace and
+    /// committed in the snapshot metadata.
+    stats: Option<ChainStats>,
+    /// Stats before the block being applied, set once it reads or
+    /// writes them
+    tracked_stats: Option<Option<ChainStats>>,
 }
 
 impl Store {
-    pub fn open(path: PathBuf) -> Result<Self> {
-        let db = Self::open_db(path)?;
-        Ok(Self(db))
+    /// Opens a spacedb file keeping up to cache_size bytes of nodes in memory
+    pub fn open(path: PathBuf, cache_size: usize) -> Result<Self> {
+        Ok(Self::new(SpaceDbBackend::open(path, cache_size)?))
     }
 
     pub fn memory() -> Result<Self> {
-        let db = Database::memory()?;
-        Ok(Self(db))
+        Ok(Self::new(SpaceDbBackend::memory()?))
     }
 
-    fn open_db(path_buf: PathBuf) -> anyhow::Result<Database<Sha256Hasher>> {
-        let file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(path_buf)?;
+    pub fn new(backend: impl StateBackend + 'static) -> Self {
+        Self(Arc::new(backend))
+    }
 
-        let config = Configuration::new().with_cache_size(1000000 /* 1MB */);
-        Ok(Database::new(Box::new(FileBackend::new(file)?), config)?)
+    /// Committed snapshots newest first
+    pub fn iter(&self) -> Snapshots<'_> {
+        self.0.snapshots()
     }
 
-    pub fn iter(&self) -> SnapshotIterator<Sha256Hasher> {
-        return self.0.iter();
+    pub fn read(&self) -> Result<ReadTx> {
+        self.0.read()
     }
 
     pub fn write(&self) -> Result<WriteTx> {
-        Ok(self.0.begin_write()?)
+        self.0.write()
     }
 
     pub fn begin(&self, genesis_block: &ChainAnchor) -> Result<LiveSnapshot> {
-        let snapshot = self.0.begin_read()?;
-        let anchor: ChainAnchor = if snapshot.metadata().len() == 0 {
-            genesis_block.clone()
+        let snapshot = self.0.read()?;
+        let (anchor, stats) = if snapshot.metadata().len() == 0 {
+            (genesis_block.clone(), None)
         } else {
-            snapshot.metadata().try_into()?
+            let metadata = SnapshotMetadata::decode(snapshot.metadata())?;
+            (metadata.anchor, metadata.stats)
         };
 
         let version = anchor.height;
         let live = LiveSnapshot {
-            db: self.0.clone(),
+            db: self.clone(),
             tip: Arc::new(RwLock::new(anchor)),
             staged: Arc::new(RwLock::new(Staged {
                 snapshot_version: version,
                 memory: BTreeMap::new(),
+                tracked: None,
+                stats,
+                tracked_stats: None,
             })),
             snapshot: (version, snapshot),
+            filter: None,
+            cache: None,
+            block_lock: Arc::new(RwLock::new(())),
         };
 
         Ok(live)
@@ -122,7 +237,7 @@ pub trait ChainStore {
 
 impl ChainStore for Store {
     fn rollout_iter(&self) -> Result<(RolloutIterator, ReadTx)> {
-        let snapshot = self.0.begin_read()?;
+        let snapshot = self.0.read()?;
         Ok((
             RolloutIterator {
                 inner: snapshot.iter(),
@@ -171,7 +286,8 @@ impl ChainState for LiveSnapshot {
 
     fn update_bid(&self, previous: Option<BidKey>, bid: BidKey, space: SpaceKey) {
         if let Some(previous) = previous {
-            self.remove(previous);
+            // Bid keys are never in the outpoint filter
+            self.stage_removal(&previous.into(), None);
         }
         self.insert(bid, space)
     }
@@ -191,14 +307,63 @@ impl ChainState for LiveSnapshot {
     }
 }
 
+impl Staged {
+    #[inline]
+    fn track(&mut self, key: &Hash) {
+        if let Some(tracked) = self.tracked.as_mut() {
+            if !tracked.contains_key(key) {
+                tracked.insert(*key, self.memory.get(key).cloned());
+            }
+        }
+    }
+
+    /// Tracks a key whose current value was already read so that it
+    /// doesn't have to be read again as the prior value
+    #[inline]
+    fn track_read(&mut self, key: &Hash, current: Option<Vec<u8>>) {
+        if let Some(tracked) = self.tracked.as_mut() {
+            tracked.entry(*key).or_insert(Some(current));
+        }
+    }
+}
+
 impl LiveSnapshot {
     #[inline]
     pub fn is_dirty(&self) -> bool {
         self.staged.read().expect("read").memory.len() > 0
     }
 
-    pub fn restore(&self, checkpoint: ChainAnchor) {
+    /// Anchor of the latest committed snapshot if any
+    pub fn committed_tip(&self) -> Result<Option<ChainAnchor>> {
+        let snapshot = self.db.read()?;
+        if snapshot.metadata().is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(snapshot.metadata().try_into()?))
+    }
+
+    /// Stats committed with the latest snapshot if any
+    fn committed_stats(&self) -> Result<Option<ChainStats>> {
+        let snapshot = self.db.read()?;
+        if snapshot.metadata().is_empty() {
+            return Ok(None);
+        }
+        Ok(SnapshotMetadata::decode(snapshot.metadata())?.stats)
+    }
+
+    /// Approximate bytes held by staged changes
+    pub fn staged_size(&self) -> usize {
+        let staged = self.staged.read().expect("read");
+        staged
+            .memory
+            .values()
+            .map(|value| std::mem::size_of::<Hash>() + value.as_ref().map_or(0, Vec::len))
+            .sum()
+    }
+
+    pub fn restore(&self, checkpoint: ChainAnchor) -> Result<()> {
         let snapshot_version = checkpoint.height;
+        let stats = self.committed_stats()?;
         let mut meta_lock = self.tip.write().expect("write lock");
         *meta_lock = checkpoint;
 
@@ -207,9 +372,78 @@ impl LiveSnapshot {
         *staged_lock = Staged {
             snapshot_version,
             memory: BTreeMap::new(),
+            tracked: None,
+            stats,
+            tracked_stats: None,
+        };
+        drop(staged_lock);
+        drop(meta_lock);
+
+        if let Some(cache) = self.cache.as_ref() {
+            cache.lock().expect("cache lock").clear();
+        }
+        self.rebuild_filter()
+    }
+
+    /// Keeps up to `capacity` recently read spaceouts decoded in memory
+    pub fn enable_spaceout_cache(&mut self, capacity: usize) {
+        self.cache = match capacity {
+            0 => None,
+            capacity => Some(Arc::new(Mutex::new(SpaceoutCache::new(capacity)))),
         };
     }
 
+    /// Lock that must be held for writing while a block is applied or reverted
+    pub fn block_lock(&self) -> Arc<RwLock<()>> {
+        self.block_lock.clone()
+    }
+
+    pub fn read_handle(&self) -> ReadHandle {
+        ReadHandle {
+            snapshot: self.clone(),
+        }
+    }
+
+    /// Builds a filter of all outpoint keys so that lookups of untracked
+    /// outpoints can skip spacedb entirely
+    pub fn enable_outpoint_filter(&mut self) -> Result<()> {
+        self.filter = Some(Arc::new(RwLock::new(OutpointFilter::with_capacity(0))));
+        self.rebuild_filter()
+    }
+
+    fn rebuild_filter(&self) -> Result<()> {
+        let filter = match self.filter.as_ref() {
+            None => return Ok(()),
+            Some(filter) => filter,
+        };
+
+        let mut committed = Vec::new();
+        let snapshot = self.db.read()?;
+        for entry in snapshot.iter() {
+            let (key, _) = entry?;
+            if is_outpoint_key(&key) {
+                committed.push(key);
+            }
+        }
+        committed.sort_unstable();
+
+        let staged = self.staged.read().expect("read lock");
+        let mut rebuilt =
+            OutpointFilter::with_capacity((committed.len() + staged.memory.len()) * 2);
+        for key in committed.iter() {
+            rebuilt.insert(key);
+        }
+        for (key, value) in staged.memory.iter().filter(|(key, _)| is_outpoint_key(key)) {
+            match value {
+                Some(_) => rebuilt.insert(key),
+                None if committed.binary_search(key).is_ok() => rebuilt.remove(key),
+                None => {}
+            }
+        }
+        *filter.write().expect("write lock") = rebuilt;
+        Ok(())
+    }
+
     pub fn inner(&mut self) -> anyhow::Result<&ReadTx> {
         {
             let rlock = self.staged.read().expect("acquire lock");
@@ -226,50 +460,181 @@ impl LiveSnapshot {
         self.insert_raw(key.into(), value);
     }
 
-    pub fn get<K: KeyHash + Into<Hash>, T: Decode>(
-        &mut self,
-        key: K,
-    ) -> spacedb::Result<Option<T>> {
+    pub fn get<K: KeyHash + Into<Hash>, T: Decode>(&mut self, key: K) -> Result<Option<T>> {
         match self.get_raw(&key.into())? {
             Some(value) => {
-                let (decoded, _): (T, _) = bincode::decode_from_slice(&value, config::standard())
-                    .map_err(|e| {
-                    spacedb::Error::IO(io::Error::new(ErrorKind::Other, e.to_string()))
-                })?;
+                let (decoded, _): (T, _) = bincode::decode_from_slice(&value, config::standard())?;
                 Ok(Some(decoded))
             }
             None => Ok(None),
         }
     }
 
-    pub fn remove<K: KeyHash + Into<Hash>>(&self, key: K) {
+    pub fn remove<K: KeyHash + Into<Hash>>(&mut self, key: K) -> Result<()> {
         self.remove_raw(&key.into())
     }
 
     #[inline]
-    fn remove_raw(&self, key: &Hash) {
-        self.staged
-            .write()
-            .expect("write lock")
-            .memory
-            .insert(*key, None);
+    fn remove_raw(&mut self, key: &Hash) -> Result<()> {
+        let current = match self.filter_may_contain(key) {
+            true => Some(self.get_raw(key)?),
+            false => None,
+        };
+        self.stage_removal(key, current);
+        Ok(())
+    }
+
+    /// Stages the removal of a key. If its `current` value was read, it is
+    /// tracked as the prior value and an existing key is also removed from
+    /// the outpoint filter.
+    #[inline]
+    fn stage_removal(&self, key: &Hash, current: Option<Option<Vec<u8>>>) {
+        let mut staged = self.staged.write().expect("write lock");
+        let filtered = matches!(current, Some(Some(_)));
+        match current {
+            Some(current) => staged.track_read(key, current),
+            None => staged.track(key),
+        }
+        staged.memory.insert(*key, None);
+        if filtered {
+            self.update_filter(key, false);
+        }
+        self.invalidate_cached(key);
+    }
+
+    /// Whether the key may be in the outpoint filter. Only keys that also
+    /// exist may be removed from the filter since removing an absent key
+    /// could evict the fingerprint of another key.
+    fn filter_may_contain(&self, key: &Hash) -> bool {
+        match self.filter.as_ref() {
+            Some(filter) if is_outpoint_key(key) => filter.read().expect("read lock").contains(key),
+            _ => false,
+        }
     }
 
     #[inline]
     fn insert_raw(&self, key: Hash, value: Vec<u8>) {
-        self.staged
-            .write()
-            .expect("write lock")
-            .memory
-            .insert(key, Some(value));
+        let mut staged = self.staged.write().expect("write lock");
+        staged.track(&key);
+        staged.memory.insert(key, Some(value));
+        self.update_filter(&key, true);
+        self.invalidate_cached(&key);
+    }
+
+    /// Must be called after a write becomes visible in the staged memory
+    #[inline]
+    fn invalidate_cached(&self, key: &Hash) {
+        if let Some(cache) = self.cache.as_ref() {
+            if is_outpoint_key(key) {
+                cache.lock().expect("cache lock").invalidate(key);
+            }
+        }
+    }
+
+    #[inline]
+    fn update_filter(&self, key: &Hash, exists: bool) {
+        if let Some(filter) = self.filter.as_ref() {
+            if is_outpoint_key(key) {
+                let mut filter = filter.write().expect("write lock");
+                match exists {
+                    true => filter.insert(key),
+                    false => filter.remove(key),
+                }
+            }
+        }
+    }
+
+    /// Starts tracking all keys written until [LiveSnapshot::take_changes] is
+    /// called. Marks the start of a block for the spaceout cache.
+    pub fn begin_tracking(&self) {
+        let mut staged = self.staged.write().expect("write lock");
+        staged.tracked = Some(BTreeMap::new());
+        staged.tracked_stats = None;
+        drop(staged);
+        self.advance_cache();
+    }
+
+    #[inline]
+    fn advance_cache(&self) {
+        if let Some(cache) = self.cache.as_ref() {
+            cache.lock().expect("cache lock").advance();
+        }
+    }
+
+    /// Stops tracking and returns the values keys held before
+    /// and after they were written
+    pub fn take_changes(&mut self) -> Result<TrackedChanges> {
+        let mut staged = self.staged.write().expect("write lock");
+        let tracked = staged.tracked.take().unwrap_or_default();
+        let (prior_stats, stats) = match staged.tracked_stats.take() {
+            Some(prior) => (prior, staged.stats.clone()),
+            None => (None, None),
+        };
+        drop(staged);
+
+        let mut changes = TrackedChanges {
+            prior: Vec::with_capacity(tracked.len()),
+            written: Vec::with_capacity(tracked.len()),
+            prior_stats,
+            stats,
+        };
+        for (key, staged) in tracked {
+            let prior = match staged {
+                Some(value) => value,
+                None => self.get_committed(&key)?,
+            };
+            let written = self
+                .staged
+                .read()
+                .expect("read lock")
+                .memory
+                .get(&key)
+                .cloned()
+                .expect("tracked key must be staged");
+            changes.prior.push((key, prior));
+            changes.written.push((key, written));
+        }
+        Ok(changes)
+    }
+
+    /// Stages the given values and stats and moves the tip. Used to revert
+    /// blocks using their prior values or to replay journaled blocks.
+    /// Stats are left unchanged if `None`.
+    pub fn apply_changes(
+        &mut self,
+        entries: Vec<UndoEntry>,
+        stats: Option<ChainStats>,
+        tip: ChainAnchor,
+    ) -> Result<()> {
+        self.advance_cache();
+        if let Some(stats) = stats {
+            self.staged.write().expect("write lock").stats = Some(stats);
+        }
+        for (key, value) in entries {
+            // A removed key may be absent if it was created and spent
+            // within the same block
+            let exists =
+                value.is_none() && self.filter_may_contain(&key) && self.get_raw(&key)?.is_some();
+            let mut staged = self.staged.write().expect("write lock");
+            if value.is_some() || exists {
+                self.update_filter(&key, value.is_some());
+            }
+            staged.memory.insert(key, value);
+            self.invalidate_cached(&key);
+        }
+        *self.tip.write().expect("write lock") = tip;
+        Ok(())
     }
 
     fn update_snapshot(&mut self, version: u32) -> Result<()> {
         if self.snapshot.0 != version {
-            self.snapshot.1 = self.db.begin_read()?;
-            let anchor: ChainAnchor = self.snapshot.1.metadata().try_into().map_err(|_| {
-                std::io::Error::new(std::io::ErrorKind::Other, "could not parse metdata")
-            })?;
+            self.snapshot.1 = self.db.read()?;
+            let anchor: ChainAnchor = self
+                .snapshot
+                .1
+                .metadata()
+                .try_into()
+                .map_err(|_| anyhow!("could not parse metdata"))?;
 
             assert_eq!(version, anchor.height, "inconsistent db state");
             self.snapshot.0 = version;
@@ -277,7 +642,7 @@ impl LiveSnapshot {
         Ok(())
     }
 
-    pub fn get_raw(&mut self, key: &Hash) -> spacedb::Result<Option<Vec<u8>>> {
+    pub fn get_raw(&mut self, key: &Hash) -> Result<Option<Vec<u8>>> {
         let rlock = self.staged.read().expect("acquire lock");
 
         if let Some(value) = rlock.memory.get(key) {
@@ -287,42 +652,253 @@ impl LiveSnapshot {
             };
         }
 
-        let version = rlock.snapshot_version;
         drop(rlock);
+        self.get_committed(key)
+    }
 
-        self.update_snapshot(version).map_err(|error| {
-            spacedb::Error::IO(std::io::Error::new(std::io::ErrorKind::Other, error))
-        })?;
+    fn get_committed(&mut self, key: &Hash) -> Result<Option<Vec<u8>>> {
+        let version = self.staged.read().expect("read lock").snapshot_version;
+        self.update_snapshot(version)?;
         self.snapshot.1.get(key)
     }
 
+    /// Protocol-wide counters at the current tip. If they were never
+    /// recorded, space counts are rebuilt from the state.
+    pub fn get_stats(&mut self) -> Result<ChainStats> {
+        let staged = self.staged.read().expect("read lock").stats.clone();
+        let stats = match staged {
+            Some(stats) => stats,
+            None => self.rebuild_stats()?,
+        };
+
+        let mut staged = self.staged.write().expect("write lock");
+        if staged.tracked.is_some() && staged.tracked_stats.is_none() {
+            staged.tracked_stats = Some(Some(stats.clone()));
+        }
+        Ok(stats)
+    }
+
+    fn rebuild_stats(&mut self) -> Result<ChainStats> {
+        let mut stats = ChainStats::new(self.tip.read().expect("read tip").height);
+        let committed = self.inner()?.iter();
+        let staged = self.staged.read().expect("read lock");
+        for entry in committed {
+            let (key, value) = entry?;
+            if is_outpoint_key(&key) && !staged.memory.contains_key(&key) {
+                let (spaceout, _): (SpaceOut, _) =
+                    bincode::decode_from_slice(&value, config::standard())?;
+                stats.add_space(&spaceout);
+            }
+        }
+        for (key, value) in staged.memory.iter() {
+            if let (true, Some(value)) = (is_outpoint_key(key), value) {
+                let (spaceout, _): (SpaceOut, _) =
+                    bincode::decode_from_slice(value, config::standard())?;
+                stats.add_space(&spaceout);
+            }
+        }
+        Ok(stats)
+    }
+
+    pub fn set_stats(&self, stats: &ChainStats) {
+        let mut staged = self.staged.write().expect("write lock");
+        if staged.tracked.is_some() && staged.tracked_stats.is_none() {
+            staged.tracked_stats = Some(staged.stats.clone());
+        }
+        staged.stats = Some(stats.clone());
+    }
+
     pub fn commit(&self, metadata: ChainAnchor, mut tx: WriteTx) -> Result<()> {
         let mut staged = self.staged.write().expect("write");
+        let stats = staged.stats.clone();
         let changes = mem::replace(
             &mut *staged,
             Staged {
                 snapshot_version: metadata.height,
                 memory: BTreeMap::new(),
+                tracked: None,
+                stats: stats.clone(),
+                tracked_stats: None,
             },
         );
 
         for (key, value) in changes.memory {
             match value {
-                None => {
-                    _ = {
-                        tx = tx.delete(key)?;
-                    }
-                }
-                Some(value) => tx = tx.insert(key, value)?,
+                None => tx.remove(key)?,
+                Some(value) => tx.insert(key, value)?,
             }
         }
 
-        tx.metadata(metadata.to_vec())?;
+        let metadata = SnapshotMetadata {
+            anchor: metadata,
+            stats,
+        };
+        tx.metadata(metadata.encode())?;
         tx.commit()?;
         drop(staged);
+
+        let rebuild = self
+            .filter
+            .as_ref()
+            .is_some_and(|filter| filter.read().expect("read lock").needs_rebuild());
+        if rebuild {
+            self.rebuild_filter()?;
+        }
         Ok(())
     }
 
+    /// Returns the committed snapshot at the given height if it still exists
+    pub fn get_snapshot(&self, height: u32) -> Result<Option<ReadTx>> {
+        for snapshot in self.db.iter() {
+            let snapshot = snapshot?;
+            let anchor: ChainAnchor = snapshot.metadata().try_into()?;
+            if anchor.height == height {
+                return Ok(Some(snapshot));
+            }
+            // snapshots are iterated newest first
+            if anchor.height < height {
+                break;
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn state_diff(&self, from: u32, to: u32) -> Result<StateDiff> {
+        if from > to {
+            return Err(anyhow!(
+                "from height {} must not be greater than to height {}",
+                from,
+                to
+            ));
+        }
+        let mut from_snapshot = self
+            .get_snapshot(from)?
+            .ok_or_else(|| anyhow!("No committed snapshot at height {}", from))?;
+        let mut to_snapshot = self
+            .get_snapshot(to)?
+            .ok_or_else(|| anyhow!("No committed snapshot at height {}", to))?;
+
+        let mut diff = StateDiff {
+            from: from_snapshot.metadata().try_into()?,
+            to: to_snapshot.metadata().try_into()?,
+            inserted: vec![],
+            updated: vec![],
+            removed: vec![],
+        };
+
+        let mut inserted = Vec::new();
+        let mut updated = Vec::new();
+        for entry in to_snapshot.iter() {
+            let (key, value) = entry?;
+            match from_snapshot.get(&key)? {
+                None => inserted.push((key, value)),
+                Some(previous) if previous != value => updated.push((key, value)),
+                _ => {}
+            }
+        }
+
+        let mut removed = Vec::new();
+        for entry in from_snapshot.iter() {
+            let (key, value) = entry?;
+            if to_snapshot.get(&key)?.is_none() {
+                removed.push((key, value));
+            }
+        }
+
+        for (key, value) in inserted {
+            diff.inserted
+                .push(Self::decode_diff_entry(&mut to_snapshot, key, value)?);
+        }
+        for (key, value) in updated {
+            diff.updated
+                .push(Self::decode_diff_entry(&mut to_snapshot, key, value)?);
+        }
+        for (key, value) in removed {
+            diff.removed
+                .push(Self::decode_diff_entry(&mut from_snapshot, key, value)?);
+        }
+        Ok(diff)
+    }
+
+    fn decode_diff_entry(
+        snapshot: &mut ReadTx,
+        key: Hash,
+        value: Vec<u8>,
+    ) -> Result<StateDiffEntry> {
+        if BidKey::is_valid(&key) {
+            let space_key = SpaceKey::from_slice_unchecked(value.as_slice());
+            let name = match snapshot.get(&space_key.into())? {
+                Some(raw) => {
+                    let (outpoint, _): (EncodableOutpoint, _) =
+                        bincode::decode_from_slice(&raw, config::standard())?;
+                    Self::get_snapshot_spaceout(snapshot, outpoint.into())?
+                        .and_then(|spaceout| spaceout.space)
+                        .map(|space| space.name)
+                }
+                None => None,
+            };
+            return Ok(StateDiffEntry::Bid {
+                key: hex::encode(key),
+                priority: BidKey::from_slice_unchecked(key.as_slice()).priority(),
+                space_key: hex::encode(value),
+                name,
+            });
+        }
+        if SpaceKey::from_raw(key).is_ok() {
+            let (outpoint, _): (EncodableOutpoint, _) =
+                bincode::decode_from_slice(&value, config::standard())?;
+            let outpoint: OutPoint = outpoint.into();
+            let spaceout =
+                Self::get_snapshot_spaceout(snapshot, outpoint)?.map(|spaceout| FullSpaceOut {
+                    txid: outpoint.txid,
+                    spaceout,
+                });
+            return Ok(StateDiffEntry::Space {
+                key: hex::encode(key),
+                outpoint,
+                spaceout,
+            });
+        }
+
+        let (spaceout, _): (SpaceOut, _) = bincode::decode_from_slice(&value, config::standard())?;
+
+        // Recover the txid through the space pointing at this outpoint
+        let mut txid = None;
+        if let Some(space) = spaceout.space.as_ref() {
+            let space_key = SpaceKey::from(Sha256::hash(space.name.as_ref()));
+            if let Some(raw) = snapshot.get(&space_key.into())? {
+                let (outpoint, _): (EncodableOutpoint, _) =
+                    bincode::decode_from_slice(&raw, config::standard())?;
+                let outpoint: OutPoint = outpoint.into();
+                let outpoint_key: Hash = OutpointKey::from_outpoint::<Sha256>(outpoint).into();
+                if outpoint_key == key {
+                    txid = Some(outpoint.txid);
+                }
+            }
+        }
+
+        Ok(StateDiffEntry::Outpoint {
+            key: hex::encode(key),
+            txid,
+            spaceout,
+        })
+    }
+
+    fn get_snapshot_spaceout(
+        snapshot: &mut ReadTx,
+        outpoint: OutPoint,
+    ) -> Result<Option<SpaceOut>> {
+        let key: Hash = OutpointKey::from_outpoint::<Sha256>(outpoint).into();
+        match snapshot.get(&key)? {
+            Some(raw) => {
+                let (spaceout, _): (SpaceOut, _) =
+                    bincode::decode_from_slice(&raw, config::standard())?;
+                Ok(Some(spaceout))
+            }
+            None => Ok(None),
+        }
+    }
+
     pub fn estimate_bid(&mut self, target: usize) -> Result<u64> {
         let rollout = self.get_rollout(target)?;
         if rollout.is_empty() {
@@ -436,13 +1012,68 @@ impl DataSource for LiveSnapshot {
 
     fn get_spaceout(&mut self, outpoint: &OutPoint) -> protocol::errors::Result<Option<SpaceOut>> {
         let h = OutpointKey::from_outpoint::<Sha256>(*outpoint);
-        let result = self
+        if let Some(filter) = self.filter.as_ref() {
+            if !filter.read().expect("read lock").contains(&h.into()) {
+                return Ok(None);
+            }
+        }
+
+        let generation = match self.cache.as_ref() {
+            None => None,
+            Some(cache) => {
+                let mut cache = cache.lock().expect("cache lock");
+                if let Some(spaceout) = cache.get(&h.into()) {
+                    return Ok(Some(spaceout));
+                }
+                Some(cache.generation())
+            }
+        };
+        let result: Option<SpaceOut> = self
             .get(h)
             .map_err(|err| protocol::errors::Error::IO(err.to_string()))?;
+
+        if let (Some(cache), Some(generation), Some(spaceout)) =
+            (self.cache.as_ref(), generation, result.as_ref())
+        {
+            cache
+                .lock()
+                .expect("cache lock")
+                .insert(h.into(), spaceout.clone(), generation);
+        }
         Ok(result)
     }
 }
 
+impl ReadHandle {
+    /// Runs a read against the state at the current tip. Blocks
+    /// while a block is being applied.
+    pub fn read<T>(&self, f: impl FnOnce(&mut LiveSnapshot) -> T) -> T {
+        let mut snapshot = self.snapshot.clone();
+        let _guard = self.snapshot.block_lock.read().expect("block lock");
+        f(&mut snapshot)
+    }
+
+    pub fn tip(&self) -> ChainAnchor {
+        *self.snapshot.tip.read().expect("read tip")
+    }
+
+    /// Runs a read on the blocking thread pool
+    pub async fn spawn<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut LiveSnapshot) -> T + Send + 'static,
+    {
+        let handle = self.clone();
+        Ok(tokio::task::spawn_blocking(move || handle.read(f)).await?)
+    }
+}
+
+/// Outpoint keys have their first bit unset and their last bit set
+#[inline]
+fn is_outpoint_key(key: &Hash) -> bool {
+    key[0] & 0b1000_0000 == 0 && key[31] & 0b0000_0001 == 1
+}
+
 impl protocol::hasher::KeyHasher for Sha256 {
     fn hash(data: &[u8]) -> protocol::hasher::Hash {
         Sha256Hasher::hash(data)
@@ -450,12 +1081,12 @@ impl protocol::hasher::KeyHasher for Sha256 {
 }
 
 pub struct RolloutIterator {
-    inner: KeyIterator<Sha256Hasher>,
+    inner: Entries,
     n: usize,
 }
 
 impl Iterator for RolloutIterator {
-    type Item = spacedb::Result<(Hash, Vec<u8>)>;
+    type Item = Result<(Hash, Vec<u8>)>;
 
     fn next(&mut self) -> Option<Self::Item> {
         if let Some(result) = self.inner.next() {
@@ -477,7 +1108,7 @@ impl Iterator for RolloutIterator {
 }
 
 struct KeyRolloutIterator {
-    iter: KeyIterator<Sha256Hasher>,
+    iter: Entries,
 }
 
 impl Iterator for KeyRolloutIterator {
@@ -493,7 +1124,7 @@ impl Iterator for KeyRolloutIterator {
                 Ok(_) => {
                     continue;
                 }
-                Err(error) => return Some(Err(error.into())),
+                Err(error) => return Some(Err(error)),
             }
         }
         None
@@ -546,3 +1177,239 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use protocol::bitcoin::{hashes::Hash as _, Amount, BlockHash};
+
+    use super::*;
+
+    fn anchor(height: u32) -> ChainAnchor {
+        ChainAnchor {
+            hash: BlockHash::from_byte_array([height as u8; 32]),
+            height,
+        }
+    }
+
+    fn live_snapshot(store: &Store) -> Result<LiveSnapshot> {
+        let mut state = store.begin(&anchor(0))?;
+        state.enable_outpoint_filter()?;
+        Ok(state)
+    }
+
+    /// A key in the outpoint key class derived from `i`
+    fn outpoint_key(i: u32) -> Hash {
+        let mut key = Sha256Hasher::hash(&i.to_le_bytes());
+        key[0] &= 0b0111_1111;
+        key[31] |= 1;
+        key
+    }
+
+    fn filter_contains(state: &LiveSnapshot, key: &Hash) -> bool {
+        let filter = state.filter.as_ref().expect("filter enabled");
+        filter.read().expect("read lock").contains(key)
+    }
+
+    #[test]
+    fn test_filter_tracks_inserts_and_removes() -> Result<()> {
+        let store = Store::memory()?;
+        let mut state = live_snapshot(&store)?;
+        let key = outpoint_key(1);
+        assert!(!filter_contains(&state, &key));
+
+        state.insert_raw(key, vec![1]);
+        assert!(filter_contains(&state, &key));
+        state.remove_raw(&key)?;
+        assert!(!filter_contains(&state, &key));
+        Ok(())
+    }
+
+    #[test]
+    fn test_removal_tracks_the_value_read_for_the_filter() -> Result<()> {
+        let store = Store::memory()?;
+        let mut state = live_snapshot(&store)?;
+        let key = outpoint_key(1);
+        state.insert_raw(key, vec![1]);
+        state.commit(anchor(1), store.write()?)?;
+
+        state.begin_tracking();
+        state.remove_raw(&key)?;
+        assert!(!filter_contains(&state, &key));
+        let tracked = state.staged.read().expect("read lock").tracked.clone();
+        assert_eq!(
+            tracked.and_then(|tracked| tracked.get(&key).cloned()),
+            Some(Some(Some(vec![1]))),
+            "the committed value is tracked without another read"
+        );
+
+        let changes = state.take_changes()?;
+        assert_eq!(changes.prior, vec![(key, Some(vec![1]))]);
+        assert_eq!(changes.written, vec![(key, None)]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_removing_absent_key_keeps_filter_entries() -> Result<()> {
+        let store = Store::memory()?;
+        let mut state = live_snapshot(&store)?;
+        let key = outpoint_key(1);
+        // shares the fingerprint and bucket of `key`
+        let mut absent = key;
+        absent[20] ^= 0xff;
+
+        state.insert_raw(key, vec![1]);
+        state.remove_raw(&absent)?;
+        assert!(
+            filter_contains(&state, &key),
+            "removing an absent key must not evict another key"
+        );
+        assert_eq!(state.get_raw(&key)?, Some(vec![1]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_filter_false_positive_falls_through() -> Result<()> {
+        let store = Store::memory()?;
+        let mut state = live_snapshot(&store)?;
+        let outpoint = OutPoint::new(Txid::all_zeros(), 7);
+        let key: Hash = OutpointKey::from_outpoint::<Sha256>(outpoint).into();
+
+        state
+            .filter
+            .as_ref()
+            .expect("filter enabled")
+            .write()
+            .expect("write lock")
+            .insert(&key);
+        assert!(filter_contains(&state, &key));
+        assert!(
+            state.get_spaceout(&outpoint)?.is_none(),
+            "a false positive must be answered by the store"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_filter_rebuilds() -> Result<()> {
+        let store = Store::memory()?;
+        let mut state = live_snapshot(&store)?;
+        let keys: Vec<_> = (0..4000).map(outpoint_key).collect();
+        for key in keys.iter() {
+            state.insert_raw(*key, vec![1]);
+        }
+        state.commit(anchor(1), store.write()?)?;
+
+        // outgrew the initial capacity and was rebuilt from the committed state
+        let filter = state.filter.as_ref().expect("filter enabled");
+        assert!(!filter.read().expect("read lock").needs_rebuild());
+        assert!(keys.iter().all(|key| filter_contains(&state, key)));
+
+        // staged removals are dropped along with the staged state on restore
+        for key in keys.iter().take(10) {
+            state.remove_raw(key)?;
+        }
+        assert!(keys
+            .iter()
+            .take(10)
+            .all(|key| !filter_contains(&state, key)));
+        state.restore(anchor(1))?;
+        assert!(keys.iter().all(|key| filter_contains(&state, key)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_state_diff_includes_bids() -> Result<()> {
+        let store = Store::memory()?;
+        let mut state = live_snapshot(&store)?;
+        let space_key = SpaceKey::from(Sha256Hasher::hash(b"@example"));
+        let outbid = BidKey::from_bid(Amount::from_sat(1000), Sha256Hasher::hash(b"a"));
+        let bid = BidKey::from_bid(Amount::from_sat(2000), Sha256Hasher::hash(b"b"));
+
+        state.insert_raw(outbid.into(), space_key.as_slice().to_vec());
+        state.commit(anchor(1), store.write()?)?;
+        state.remove_raw(&outbid.into())?;
+        state.insert_raw(bid.into(), space_key.as_slice().to_vec());
+        state.commit(anchor(2), store.write()?)?;
+
+        let diff = state.state_diff(1, 2)?;
+        assert!(diff.updated.is_empty());
+        let bid_entry = |entries: &[StateDiffEntry]| match entries {
+            [StateDiffEntry::Bid {
+                priority,
+                space_key: key,
+                name,
+                ..
+            }] => {
+                assert_eq!(key, &hex::encode(space_key.as_slice()));
+                assert!(name.is_none(), "the space key has no outpoint");
+                *priority
+            }
+            entries => panic!("expected a single bid, got {:?}", entries),
+        };
+        assert_eq!(bid_entry(&diff.inserted), 2000);
+        assert_eq!(bid_entry(&diff.removed), 1000);
+        Ok(())
+    }
+
+    #[test]
+    fn test_snapshot_metadata_versions() -> Result<()> {
+        let mut stats = ChainStats::new(10);
+        stats.registered = 3;
+        let metadata = SnapshotMetadata {
+            anchor: anchor(36),
+            stats: Some(stats),
+        };
+        let raw = metadata.encode();
+        let decoded = SnapshotMetadata::decode(&raw)?;
+        assert_eq!(decoded.anchor, anchor(36));
+        assert_eq!(decoded.stats.map(|stats| stats.registered), Some(3));
+        let prefix: ChainAnchor = raw.as_slice().try_into()?;
+        assert_eq!(prefix, anchor(36), "the anchor stays readable on its own");
+
+        let legacy = SnapshotMetadata::decode(&anchor(72).to_vec())?;
+        assert_eq!(legacy.anchor, anchor(72));
+        assert!(legacy.stats.is_none());
+
+        let mut unknown = anchor(72).to_vec();
+        unknown.push(METADATA_VERSION + 1);
+        assert!(SnapshotMetadata::decode(&unknown).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_stats_are_staged_outside_the_keyspace() -> Result<()> {
+        let store = Store::memory()?;
+        let mut state = live_snapshot(&store)?;
+        let mut stats = ChainStats::new(0);
+        stats.registered = 1;
+        state.set_stats(&stats);
+        assert!(!state.is_dirty());
+        assert_eq!(state.staged_size(), 0);
+
+        state.commit(anchor(1), store.write()?)?;
+        let committed = SnapshotMetadata::decode(store.read()?.metadata())?;
+        assert_eq!(committed.stats.map(|stats| stats.registered), Some(1));
+
+        // a block that changes stats records their prior value
+        state.begin_tracking();
+        stats.registered = 2;
+        state.set_stats(&stats);
+        let changes = state.take_changes()?;
+        assert_eq!(changes.prior_stats.map(|stats| stats.registered), Some(1));
+        assert_eq!(changes.stats.map(|stats| stats.registered), Some(2));
+
+        // a block that doesn't touch them leaves them out
+        state.begin_tracking();
+        let untouched = state.take_changes()?;
+        assert!(untouched.prior_stats.is_none() && untouched.stats.is_none());
+
+        state.apply_changes(Vec::new(), changes.prior_stats, anchor(1))?;
+        assert_eq!(state.get_stats()?.registered, 1);
+
+        // committed stats are restored along with the snapshot
+        state.set_stats(&stats);
+        state.restore(anchor(1))?;
+        assert_eq!(state.get_stats()?.registered, 1);
+        Ok(())
+    }
+}
@@ -1,7 +1,11 @@
-use std::{net::SocketAddr, path::PathBuf, time::Duration};
+use std::{
+    net::SocketAddr,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
 
 use anyhow::{anyhow, Context};
-use log::info;
+use log::{info, warn};
 use protocol::{
     bitcoin::{hashes::Hash, Block, BlockHash},
     constants::ChainAnchor,
@@ -10,10 +14,21 @@ use protocol::{
 use tokio::sync::broadcast;
 
 use crate::{
-    config::ExtendedNetwork,
+    config::{CustomChain, ExtendedNetwork},
+    esplora::EsploraBlockSource,
+    headers::HeaderRules,
+    journal::{Journal, JournalEntry},
+    logging::SYNC,
+    metrics::Metrics,
     node::{BlockMeta, BlockSource, Node},
-    source::{BitcoinBlockSource, BitcoinRpc, BlockEvent, BlockFetchError, BlockFetcher},
-    store::LiveStore,
+    progress::SyncProgress,
+    prune::RetentionPolicy,
+    replay::{RecordingBlockSource, ReplayBlockSource},
+    source::{
+        BitcoinRpc, BlockEvent, BlockFetchError, BlockFetchMethod, BlockFetcher, TipNotifier,
+    },
+    store::{LiveStore, TrackedChanges},
+    undo::{BlockUndo, UndoLog},
 };
 
 // https://internals.rust-lang.org/t/nicer-static-assertions/15986
@@ -31,51 +46,133 @@ const_assert!(
 
 pub struct Spaced {
     pub network: ExtendedNetwork,
+    /// Signet or test chain defined in the configuration file
+    pub custom_chain: Option<CustomChain>,
     pub chain: LiveStore,
     pub block_index: Option<LiveStore>,
     pub block_index_full: bool,
     pub rpc: BitcoinRpc,
+    /// Bitcoin Core blocks directory to read blocks from if set
+    pub blocks_dir: Option<PathBuf>,
+    /// Fetch blocks over bitcoind's REST interface
+    pub rest: bool,
+    /// bitcoind ZMQ endpoint publishing block hashes
+    pub zmq_hashblock: Option<String>,
+    /// Woken on new blocks to skip waiting for the next poll
+    pub tip_notifier: TipNotifier,
+    /// Esplora API to sync blocks from instead of bitcoind
+    pub esplora_url: Option<String>,
+    /// Checks applied to synced blocks
+    pub header_rules: HeaderRules,
+    /// Progress reported by getsyncstatus
+    pub sync_progress: SyncProgress,
+    pub metrics: Metrics,
+    /// Serve metrics at /metrics on the RPC server
+    pub metrics_endpoint: bool,
+    /// Blocks behind bitcoind at which the node is still reported ready
+    pub ready_max_lag: u32,
+    /// Log block source answers to this file for replaying the sync
+    pub record_blocks: Option<PathBuf>,
+    /// Replace an existing recording instead of failing
+    pub record_blocks_overwrite: bool,
+    /// Sync from a recording instead of bitcoind
+    pub replay: Option<ReplayBlockSource>,
     pub data_dir: PathBuf,
     pub bind: Vec<SocketAddr>,
     pub num_workers: usize,
+    pub undo: UndoLog,
+    pub journal: Journal,
+    /// Snapshots kept by compaction if pruning is enabled
+    pub retention: Option<RetentionPolicy>,
 }
 
 impl Spaced {
+    /// Name of the chain being followed
+    pub fn chain_name(&self) -> String {
+        match &self.custom_chain {
+            Some(custom) => custom.name.clone(),
+            None => self.network.to_string(),
+        }
+    }
+
+    pub fn block_fetch_method(&self) -> BlockFetchMethod {
+        if self.replay.is_some() {
+            return BlockFetchMethod::Replay;
+        }
+        if self.esplora_url.is_some() {
+            return BlockFetchMethod::Esplora;
+        }
+        match self.blocks_dir {
+            Some(_) => BlockFetchMethod::BlockFiles,
+            None if self.rest => BlockFetchMethod::Rest,
+            None => BlockFetchMethod::Rpc,
+        }
+    }
+
     // Restores state to a valid checkpoint
-    pub fn restore(&self, source: &BitcoinBlockSource) -> anyhow::Result<()> {
-        let chain_iter = self.chain.store.iter();
-        for (snapshot_index, snapshot) in chain_iter.enumerate() {
+    pub fn restore(&mut self, source: &impl BlockSource) -> anyhow::Result<()> {
+        let mut newer_checkpoint: Option<ChainAnchor> = None;
+        for snapshot in self.chain.store.iter() {
             let chain_snapshot = snapshot?;
+            if chain_snapshot.metadata().is_empty() {
+                continue;
+            }
             let chain_checkpoint: ChainAnchor = chain_snapshot.metadata().try_into()?;
+            if let Some(newer) = newer_checkpoint.replace(chain_checkpoint) {
+                // Snapshots may be sparse if older ones were pruned
+                if newer.height > chain_checkpoint.height + COMMIT_BLOCK_INTERVAL {
+                    info!(
+                        target: SYNC,
+                        from = chain_checkpoint.height,
+                        to = newer.height;
+                        "No snapshots between heights"
+                    );
+                }
+            }
             let required_hash = source.get_block_hash(chain_checkpoint.height)?;
 
             if required_hash != chain_checkpoint.hash {
                 info!(
-                    "Could not restore to block={} height={}",
-                    chain_checkpoint.hash, chain_checkpoint.height
+                    target: SYNC,
+                    block:% = chain_checkpoint.hash,
+                    height = chain_checkpoint.height;
+                    "Could not restore"
                 );
                 continue;
             }
 
             info!(
-                "Restoring block={} height={}",
-                chain_checkpoint.hash, chain_checkpoint.height
+                target: SYNC,
+                block:% = chain_checkpoint.hash,
+                height = chain_checkpoint.height;
+                "Restoring"
             );
 
+            // Readers must not see the stores rolled back under the staged state
+            let block_lock = self.chain.state.block_lock();
+            let _block_guard = block_lock.write().expect("block lock");
+
             if let Some(block_index) = self.block_index.as_ref() {
-                let index_snapshot = block_index.store.iter().skip(snapshot_index).next();
-                if index_snapshot.is_none() {
-                    return Err(anyhow!(
-                        "Could not restore block index due to missing snapshot"
-                    ));
-                }
-                let index_snapshot = index_snapshot.unwrap()?;
-                let index_checkpoint: ChainAnchor = index_snapshot.metadata().try_into()?;
-                if index_checkpoint != chain_checkpoint {
-                    return Err(anyhow!(
-                        "block index checkpoint does not match the chain's checkpoint"
-                    ));
+                // Pair snapshots by anchor since both stores may be pruned differently
+                let mut index_snapshot = None;
+                for snapshot in block_index.store.iter() {
+                    let snapshot = snapshot?;
+                    if ChainAnchor::try_from(snapshot.metadata())
+                        .is_ok_and(|anchor| anchor == chain_checkpoint)
+                    {
+                        index_snapshot = Some(snapshot);
+                        break;
+                    }
                 }
+                let Some(index_snapshot) = index_snapshot else {
+                    info!(
+                        target: SYNC,
+                        block:% = chain_checkpoint.hash,
+                        height = chain_checkpoint.height;
+                        "Block index has no snapshot"
+                    );
+                    continue;
+                };
                 index_snapshot
                     .rollback()
                     .context("could not rollback block index snapshot")?;
@@ -84,16 +181,156 @@ impl Spaced {
             chain_snapshot
                 .rollback()
                 .context("could not rollback chain snapshot")?;
-
-            self.chain.state.restore(chain_checkpoint.clone());
+            self.chain.state.restore(chain_checkpoint.clone())?;
 
             if let Some(block_index) = self.block_index.as_ref() {
-                block_index.state.restore(chain_checkpoint)
+                block_index.state.restore(chain_checkpoint)?;
             }
+
+            // Undo records and journaled blocks no longer lead up to the restored
+            // state. They're kept until then so that a failed restore can be retried.
+            self.undo.clear();
+            self.journal.truncate()?;
             return Ok(());
         }
 
-        Err(anyhow!("Unable to restore to a valid state"))
+        match self.retention {
+            Some(policy) => Err(anyhow!(
+                "Unable to restore to a valid state: the reorg is deeper than the snapshots \
+                 retained by pruning (--prune-keep-last {}, --prune-keep-every {})",
+                policy.keep_last,
+                policy.keep_every
+            )),
+            None => Err(anyhow!("Unable to restore to a valid state")),
+        }
+    }
+
+    /// Disconnects blocks using the undo log until the tip is part of the
+    /// source's best chain, which may be shorter than the current tip.
+    /// Returns false without making any changes if the fork point is
+    /// deeper than the undo log.
+    pub fn rewind(&mut self, source: &impl BlockSource) -> anyhow::Result<bool> {
+        let tip = self.chain.state.tip.read().expect("read").clone();
+        let best_height = source.get_best_chain()?.height;
+        let mut fork_point = tip;
+        let mut depth = 0;
+        let mut records = self.undo.iter();
+        while fork_point.height > best_height
+            || source.get_block_hash(fork_point.height)? != fork_point.hash
+        {
+            match records.next() {
+                Some(record) if record.block == fork_point => {
+                    fork_point = record.parent;
+                    depth += 1;
+                }
+                _ => return Ok(false),
+            }
+        }
+
+        let block_lock = self.chain.state.block_lock();
+        let block_guard = block_lock.write().expect("block lock");
+        for _ in 0..depth {
+            let record = self.undo.pop().expect("undo record");
+            info!(
+                target: SYNC,
+                block:% = record.block.hash,
+                height = record.block.height;
+                "Disconnecting"
+            );
+            self.chain
+                .state
+                .apply_changes(record.chain, record.stats, record.parent)?;
+            if let Some(index) = self.block_index.as_mut() {
+                index
+                    .state
+                    .apply_changes(record.index, None, record.parent)?;
+            }
+        }
+        drop(block_guard);
+
+        // Rollouts read from the latest snapshot so the state
+        // must be committed if the fork point is on a commit boundary.
+        // A fork point before the journaled blocks also requires a commit,
+        // usually off the boundaries, since the journal can only replay on
+        // top of the committed state.
+        if depth > 0
+            && (fork_point.height % COMMIT_BLOCK_INTERVAL == 0
+                || !self.journal.rewind(&fork_point)?)
+        {
+            self.commit(fork_point)?;
+        }
+        Ok(true)
+    }
+
+    /// Replays blocks journaled since the last commit after an unclean shutdown
+    pub fn recover(&mut self) -> anyhow::Result<()> {
+        let committed_tip = self.chain.state.tip.read().expect("read").clone();
+        if let Some(index) = self.block_index.as_ref() {
+            let index_tip = index.state.tip.read().expect("read").clone();
+            if index_tip != committed_tip {
+                warn!(
+                    target: SYNC,
+                    chain_tip:% = committed_tip.hash,
+                    chain_height = committed_tip.height,
+                    index_tip:% = index_tip.hash,
+                    index_height = index_tip.height;
+                    "Block index and chain state were committed at different tips, \
+                     discarding journaled blocks"
+                );
+                self.journal.truncate()?;
+                return Ok(());
+            }
+        }
+
+        let entries = self.journal.recover(&committed_tip)?;
+        let Some(last) = entries.last().map(|entry| entry.block) else {
+            return Ok(());
+        };
+        info!(
+            target: SYNC,
+            block:% = last.hash,
+            height = last.height;
+            "Replaying {} journaled blocks",
+            entries.len()
+        );
+        for entry in entries {
+            self.chain
+                .state
+                .apply_changes(entry.chain, entry.stats, entry.block)?;
+            if let Some(index) = self.block_index.as_mut() {
+                index.state.apply_changes(entry.index, None, entry.block)?;
+            }
+            if let Some(undo) = entry.undo {
+                self.undo.push(undo);
+            }
+        }
+
+        // The journal may end on a commit boundary if the node stopped before
+        // committing and the next block could be a rollout that expects a clean state
+        if last.height % COMMIT_BLOCK_INTERVAL == 0 {
+            self.commit(last)?;
+        }
+        Ok(())
+    }
+
+    /// Commits the chain state and block index at the given tip.
+    ///
+    /// Blocks on commit boundaries are always committed as they're applied.
+    /// Shutdown and [Spaced::rewind] may also commit between boundaries, which
+    /// is safe since rollouts only require the boundary before them to be
+    /// committed, and restoring or pruning snapshots goes by their anchors.
+    fn commit(&mut self, tip: ChainAnchor) -> anyhow::Result<()> {
+        let started = Instant::now();
+        let tx = self.chain.store.write().expect("write handle");
+        self.chain.state.commit(tip, tx)?;
+        if let Some(index) = self.block_index.as_ref() {
+            let tx = index.store.write().expect("write handle");
+            index.state.commit(tip, tx)?;
+        }
+        self.undo.save()?;
+        self.journal.truncate()?;
+        self.metrics.commit_finished(started.elapsed());
+        Ok(())
     }
 
     pub fn save_block(
@@ -113,6 +350,17 @@ impl Spaced {
         id: ChainAnchor,
         block: Block,
     ) -> anyhow::Result<()> {
+        let parent = ChainAnchor {
+            hash: block.header.prev_blockhash,
+            height: id.height - 1,
+        };
+        let block_lock = self.chain.state.block_lock();
+        let block_guard = block_lock.write().expect("block lock");
+        self.chain.state.begin_tracking();
+        if let Some(index) = self.block_index.as_ref() {
+            index.state.begin_tracking();
+        }
+
         let index_blocks = self.block_index.is_some();
         let block_result =
             node.apply_block(&mut self.chain, id.height, id.hash, block, index_blocks)?;
@@ -123,39 +371,94 @@ impl Spaced {
             }
         }
 
-        if id.height % COMMIT_BLOCK_INTERVAL == 0 {
-            let block_index_writer = self.block_index.clone();
+        let chain = self.chain.state.take_changes()?;
+        let index = match self.block_index.as_mut() {
+            Some(index) => index.state.take_changes()?,
+            None => TrackedChanges {
+                prior: Vec::new(),
+                written: Vec::new(),
+                prior_stats: None,
+                stats: None,
+            },
+        };
+        drop(block_guard);
 
-            let tx = self.chain.store.write().expect("write handle");
-            let state_meta = ChainAnchor {
-                height: id.height,
-                hash: id.hash,
-            };
+        let undo = self.undo.is_enabled().then(|| BlockUndo {
+            block: id,
+            parent,
+            chain: chain.prior,
+            index: index.prior,
+            stats: chain.prior_stats,
+        });
 
-            self.chain.state.commit(state_meta.clone(), tx)?;
-            if let Some(index) = block_index_writer {
-                let tx = index.store.write().expect("write handle");
-                index.state.commit(state_meta, tx)?;
-            }
+        // Journal the block before it can be lost with the staged state
+        self.journal.append(&JournalEntry {
+            block: id,
+            parent,
+            chain: chain.written,
+            index: index.written,
+            stats: chain.stats,
+            undo: undo.clone(),
+        })?;
+        if let Some(undo) = undo {
+            self.undo.push(undo);
+        }
+
+        if id.height % COMMIT_BLOCK_INTERVAL == 0 {
+            self.commit(id)?;
         }
 
+        self.metrics.block_applied();
+        self.metrics
+            .set_staged_bytes("chain", self.chain.state.staged_size());
+        if let Some(index) = self.block_index.as_ref() {
+            self.metrics
+                .set_staged_bytes("block_index", index.state.staged_size());
+        }
         Ok(())
     }
 
-    pub fn protocol_sync(
+    pub fn protocol_sync<S: BlockSource + Clone + Send + 'static>(
         &mut self,
-        source: BitcoinBlockSource,
+        source: S,
+        shutdown: broadcast::Sender<()>,
+    ) -> anyhow::Result<()> {
+        match self.record_blocks.clone() {
+            Some(path) => {
+                info!(target: SYNC, "Recording block source to {}", path.display());
+                let source =
+                    RecordingBlockSource::create(&path, source, self.record_blocks_overwrite)
+                        .with_context(|| format!("Could not create {}", path.display()))?;
+                self.sync_blocks(source, shutdown)
+            }
+            None => self.sync_blocks(source, shutdown),
+        }
+    }
+
+    fn sync_blocks<S: BlockSource + Clone + Send + 'static>(
+        &mut self,
+        source: S,
         shutdown: broadcast::Sender<()>,
     ) -> anyhow::Result<()> {
         let start_block: ChainAnchor = { self.chain.state.tip.read().expect("read").clone() };
-        let mut node = Node::new(self.block_index_full);
+        let mut node = Node::new(self.block_index_full).with_metrics(self.metrics.clone());
 
         info!(
-            "Start block={} height={}",
-            start_block.hash, start_block.height
+            target: SYNC,
+            block:% = start_block.hash,
+            height = start_block.height;
+            "Start"
         );
 
-        let (fetcher, receiver) = BlockFetcher::new(source.clone(), self.num_workers);
+        let (fetcher, receiver) = BlockFetcher::with_notifier(
+            source.clone(),
+            self.num_workers,
+            self.tip_notifier.clone(),
+        );
+        let fetcher = fetcher
+            .with_rules(self.header_rules.clone())
+            .with_progress(self.sync_progress.clone());
+        self.sync_progress.set_tip(start_block.height);
         fetcher.start(start_block);
 
         let mut shutdown_signal = shutdown.subscribe();
@@ -163,65 +466,123 @@ impl Spaced {
             if shutdown_signal.try_recv().is_ok() {
                 break;
             }
-            match receiver.try_recv() {
+            match receiver.recv_timeout(Duration::from_millis(100)) {
                 Ok(event) => match event {
                     BlockEvent::Block(id, block) => {
                         self.handle_block(&mut node, id, block)?;
-                        info!("block={} height={}", id.hash, id.height);
+                        self.sync_progress.block_applied(id.height);
+                        info!(target: SYNC, block:% = id.hash, height = id.height; "Applied block");
                     }
                     BlockEvent::Error(e) if matches!(e, BlockFetchError::BlockMismatch) => {
-                        self.restore(&source)?;
+                        if self.rewind(&source)? {
+                            self.metrics.reorg_handled("rewind");
+                        } else {
+                            self.restore(&source)?;
+                            self.metrics.reorg_handled("restore");
+                        }
                         let new_tip = self.chain.state.tip.read().expect("read").clone();
+                        self.sync_progress.set_tip(new_tip.height);
                         fetcher.start(new_tip);
                     }
                     BlockEvent::Error(e) => return Err(e.into()),
                 },
-                Err(e) if matches!(e, std::sync::mpsc::TryRecvError::Empty) => {
-                    std::thread::sleep(Duration::from_millis(10));
-                }
+                Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {}
                 Err(_) => {
                     break;
                 }
             }
+            if let Some(status) = self.sync_progress.summary_due() {
+                info!(target: SYNC, "Syncing {}", status);
+            }
         }
 
-        info!("Shutting down protocol sync");
+        info!(target: SYNC, "Shutting down protocol sync");
         fetcher.stop();
 
+        // Persist staged blocks so they don't have to be synced or replayed
+        // from the journal again. This snapshot is off the commit boundaries,
+        // see [Spaced::commit].
+        if !self.journal.is_empty() {
+            let tip = self.chain.state.tip.read().expect("read").clone();
+            info!(target: SYNC, block:% = tip.hash, height = tip.height; "Committing");
+            self.commit(tip)?;
+        }
+
         Ok(())
     }
 
     pub async fn genesis(
         rpc: &BitcoinRpc,
+        esplora_url: Option<&str>,
+        replay: Option<&ReplayBlockSource>,
         network: ExtendedNetwork,
+        custom_chain: Option<&CustomChain>,
     ) -> anyhow::Result<ChainAnchor> {
-        let mut anchor = match network {
-            ExtendedNetwork::Testnet => ChainAnchor::TESTNET(),
-            ExtendedNetwork::Testnet4 => ChainAnchor::TESTNET4(),
-            ExtendedNetwork::Regtest => ChainAnchor::REGTEST(),
-            ExtendedNetwork::Mainnet => ChainAnchor::MAINNET(),
-            ExtendedNetwork::MainnetAlpha => ChainAnchor::MAINNET_ALPHA(),
-            _ => panic!("unsupported network"),
+        let mut anchor = match (custom_chain, network) {
+            (Some(custom), _) => custom.activation,
+            (None, ExtendedNetwork::Testnet) => ChainAnchor::TESTNET(),
+            (None, ExtendedNetwork::Testnet4) => ChainAnchor::TESTNET4(),
+            (None, ExtendedNetwork::Signet) => ChainAnchor::SIGNET(),
+            (None, ExtendedNetwork::Regtest) => ChainAnchor::REGTEST(),
+            (None, ExtendedNetwork::Mainnet) => ChainAnchor::MAINNET(),
+            (None, ExtendedNetwork::MainnetAlpha) => ChainAnchor::MAINNET_ALPHA(),
         };
 
-        if anchor.hash == BlockHash::all_zeros() {
-            let client = reqwest::Client::new();
+        if let Some(custom) = custom_chain {
+            let genesis_hash = Self::fetch_block_hash(rpc, esplora_url, replay, 0)
+                .await
+                .map_err(|e| anyhow!("Could not retrieve genesis block: {}", e))?;
+            if genesis_hash != custom.genesis_hash {
+                return Err(anyhow!(
+                    "Bitcoin node is not following chain '{}': expected genesis {} got {}",
+                    custom.name,
+                    custom.genesis_hash,
+                    genesis_hash
+                ));
+            }
+        }
 
-            anchor.hash = match rpc
-                .send_json(&client, &rpc.get_block_hash(anchor.height))
+        if anchor.hash == BlockHash::all_zeros() {
+            anchor.hash = Self::fetch_block_hash(rpc, esplora_url, replay, anchor.height)
                 .await
-            {
-                Ok(hash) => hash,
-                Err(e) => {
-                    return Err(anyhow!(
+                .map_err(|e| {
+                    anyhow!(
                         "Could not retrieve activation block at height {}: {}",
                         anchor.height,
                         e
-                    ));
-                }
-            }
+                    )
+                })?;
         }
 
         Ok(anchor)
     }
+
+    /// Looks up the hash of the block at `height` through the configured block source
+    pub(crate) async fn fetch_block_hash(
+        rpc: &BitcoinRpc,
+        esplora_url: Option<&str>,
+        replay: Option<&ReplayBlockSource>,
+        height: u32,
+    ) -> anyhow::Result<BlockHash> {
+        if let Some(replay) = replay {
+            return replay
+                .recorded_hash(height)
+                .ok_or_else(|| anyhow!("block hash at height {} was not recorded", height));
+        }
+        let hash = match esplora_url {
+            Some(url) => {
+                // The blocking client must not be used or dropped in async context
+                let url = url.to_string();
+                tokio::task::spawn_blocking(move || {
+                    EsploraBlockSource::new(&url).get_block_hash(height)
+                })
+                .await?
+            }
+            None => {
+                let client = reqwest::Client::new();
+                rpc.send_json(&client, &rpc.get_block_hash(height)).await
+            }
+        };
+        Ok(hash?)
+    }
 }
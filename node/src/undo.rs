@@ -0,0 +1,147 @@
+use std::{
+    collections::VecDeque,
+    fs,
+    fs::File,
+    io::{BufReader, BufWriter, Write},
+    path::PathBuf,
+};
+
+use anyhow::Result;
+use bincode::{config, Decode, Encode};
+use log::warn;
+use protocol::constants::ChainAnchor;
+use spacedb::Hash;
+
+use crate::{logging::STORE, stats::ChainStats};
+
+/// A key and the value it held before a block was applied.
+/// `None` means the key did not exist.
+pub type UndoEntry = (Hash, Option<Vec<u8>>);
+
+/// Everything needed to disconnect a single block
+#[derive(Clone, Encode, Decode)]
+pub struct BlockUndo {
+    pub block: ChainAnchor,
+    pub parent: ChainAnchor,
+    pub chain: Vec<UndoEntry>,
+    pub index: Vec<UndoEntry>,
+    /// Stats before the block if it changed them
+    pub stats: Option<ChainStats>,
+}
+
+/// Undo records for the most recent blocks, bounded by the max reorg depth.
+/// Records are persisted on every commit so that blocks which already made it
+/// into a spacedb snapshot can still be disconnected after a restart.
+pub struct UndoLog {
+    path: Option<PathBuf>,
+    max_depth: u32,
+    records: VecDeque<BlockUndo>,
+}
+
+impl UndoLog {
+    /// Loads the undo log at path keeping only records that lead
+    /// up to the committed tip
+    pub fn open(path: PathBuf, max_depth: u32, committed_tip: &ChainAnchor) -> Result<Self> {
+        let mut log = Self {
+            path: Some(path.clone()),
+            max_depth,
+            records: VecDeque::new(),
+        };
+        if max_depth == 0 || !path.exists() {
+            return Ok(log);
+        }
+
+        let mut reader = BufReader::new(File::open(&path)?);
+        let records: Vec<BlockUndo> =
+            match bincode::decode_from_std_read(&mut reader, config::standard()) {
+                Ok(records) => records,
+                Err(e) => {
+                    warn!(target: STORE, "Discarding unreadable undo log: {}", e);
+                    return Ok(log);
+                }
+            };
+
+        let connected = records
+            .last()
+            .is_some_and(|last| &last.block == committed_tip)
+            && records
+                .windows(2)
+                .all(|pair| pair[1].parent == pair[0].block);
+        if connected {
+            log.records = records.into();
+            log.truncate();
+        }
+        Ok(log)
+    }
+
+    /// An undo log that is never written to disk
+    pub fn memory(max_depth: u32) -> Self {
+        Self {
+            path: None,
+            max_depth,
+            records: VecDeque::new(),
+        }
+    }
+
+    #[inline]
+    pub fn is_enabled(&self) -> bool {
+        self.max_depth > 0
+    }
+
+    pub fn push(&mut self, record: BlockUndo) {
+        if !self.is_enabled() {
+            return;
+        }
+        if self
+            .records
+            .back()
+            .is_some_and(|last| last.block != record.parent)
+        {
+            self.records.clear();
+        }
+        self.records.push_back(record);
+        self.truncate();
+    }
+
+    pub fn pop(&mut self) -> Option<BlockUndo> {
+        self.records.pop_back()
+    }
+
+    /// Iterates records starting from the most recent block
+    pub fn iter(&self) -> impl Iterator<Item = &BlockUndo> {
+        self.records.iter().rev()
+    }
+
+    pub fn clear(&mut self) {
+        self.records.clear();
+    }
+
+    /// Persists all records. Must only be called right after a commit
+    /// so that the last record matches the committed tip.
+    pub fn save(&self) -> Result<()> {
+        let path = match self.path.as_ref() {
+            None => return Ok(()),
+            Some(path) => path,
+        };
+        if !self.is_enabled() {
+            return Ok(());
+        }
+
+        let tmp_path = path.with_extension("tmp");
+        {
+            let mut writer = BufWriter::new(File::create(&tmp_path)?);
+            let records: Vec<&BlockUndo> = self.records.iter().collect();
+            bincode::encode_into_std_write(records, &mut writer, config::standard())?;
+            writer.flush()?;
+            writer.get_ref().sync_all()?;
+        }
+        fs::rename(tmp_path, path)?;
+        Ok(())
+    }
+
+    fn truncate(&mut self) {
+        while self.records.len() > self.max_depth as usize {
+            self.records.pop_front();
+        }
+    }
+}
@@ -0,0 +1,600 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use anyhow::{anyhow, Result};
+use bincode::{config, Decode};
+use log::info;
+use protocol::{
+    bitcoin::OutPoint,
+    constants::{ChainAnchor, ROLLOUT_BLOCK_INTERVAL},
+    hasher::{BidKey, KeyHasher, OutpointKey, SpaceKey},
+    validate::UpdateKind,
+    Covenant, SpaceOut,
+};
+use serde::{Deserialize, Serialize};
+use spacedb::Hash;
+
+use crate::{
+    blockfile::BlockFileSource,
+    esplora::EsploraBlockSource,
+    logging::STORE,
+    node::{BlockMeta, BlockSource, Node},
+    source::BitcoinBlockSource,
+    store::{EncodableOutpoint, LiveStore, Sha256, Store},
+    sync::Spaced,
+};
+
+/// Result of checking the committed state of a data directory
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VerifyReport {
+    pub tip: ChainAnchor,
+    pub root: String,
+    pub spaces: usize,
+    pub spaceouts: usize,
+    pub bids: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub indexed_blocks: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rederived: Option<Rederived>,
+    pub issues: Vec<Issue>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repaired: Option<Repaired>,
+}
+
+/// State re-derived by replaying blocks from the configured block source
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Rederived {
+    pub tip: ChainAnchor,
+    pub root: String,
+    pub matches: bool,
+}
+
+/// Derived index entries rewritten by `--repair`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Repaired {
+    pub inserted: usize,
+    pub removed: usize,
+    pub root: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Issue {
+    pub kind: IssueKind,
+    pub key: String,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IssueKind {
+    /// A value could not be decoded for its key class
+    InvalidValue,
+    /// A space points to an outpoint that has no spaceout
+    MissingSpaceout,
+    /// A space points to a spaceout carrying a different or no space
+    SpaceMismatch,
+    /// A spaceout carries a space that doesn't point back to it
+    UnreferencedSpaceout,
+    /// A bid points to a space that doesn't exist
+    DanglingBid,
+    /// A bid points to a space that isn't a pre-auction bid of the same priority
+    InvalidBid,
+    /// A pre-auction bid is missing from the bid index
+    MissingBid,
+    /// The block index isn't committed at the same block as the chain state
+    IndexMismatch,
+    /// A space outpoint isn't recorded by any block in the block index
+    UnindexedOutpoint,
+    /// Replaying the blocks didn't produce the committed state
+    RootMismatch,
+}
+
+/// Committed key/value entries grouped by key class
+#[derive(Default)]
+struct Entries {
+    spaces: BTreeMap<Hash, Vec<u8>>,
+    spaceouts: BTreeMap<Hash, Vec<u8>>,
+    bids: BTreeMap<Hash, Vec<u8>>,
+}
+
+/// Checks the latest committed chain state and block index. The state is
+/// re-derived from the configured block source, which may be a recording
+/// given with `--replay-blocks`, if `rederive` is set and derived indexes
+/// are rebuilt if `repair` is set.
+pub async fn verify(spaced: &mut Spaced, rederive: bool, repair: bool) -> Result<VerifyReport> {
+    let mut snapshot = spaced
+        .chain
+        .store
+        .iter()
+        .next()
+        .ok_or_else(|| anyhow!("Nothing to verify: no committed state found"))??;
+    let tip: ChainAnchor = snapshot.metadata().try_into()?;
+
+    let mut entries = Entries::default();
+    for entry in snapshot.iter() {
+        let (key, value) = entry?;
+        if BidKey::is_valid(&key) {
+            entries.bids.insert(key, value);
+        } else if SpaceKey::from_raw(key).is_ok() {
+            entries.spaces.insert(key, value);
+        } else {
+            entries.spaceouts.insert(key, value);
+        }
+    }
+
+    let mut report = VerifyReport {
+        tip,
+        root: hex::encode(snapshot.compute_root()?),
+        spaces: entries.spaces.len(),
+        spaceouts: entries.spaceouts.len(),
+        bids: entries.bids.len(),
+        indexed_blocks: None,
+        rederived: None,
+        issues: Vec::new(),
+        repaired: None,
+    };
+
+    let expected = check_state(&entries, &mut report.issues);
+    if let Some(index) = spaced.block_index.as_ref() {
+        report.indexed_blocks = Some(check_block_index(
+            index,
+            &tip,
+            &expected.space_outpoints,
+            &mut report.issues,
+        )?);
+    }
+    if rederive {
+        let genesis = Spaced::genesis(
+            &spaced.rpc,
+            spaced.esplora_url.as_deref(),
+            spaced.replay.as_ref(),
+            spaced.network,
+            spaced.custom_chain.as_ref(),
+        )
+        .await?;
+        let rederived = rederive_from_source(spaced, genesis, tip, report.root.clone()).await?;
+        if !rederived.matches {
+            report.issues.push(Issue {
+                kind: IssueKind::RootMismatch,
+                key: rederived.root.clone(),
+                message: format!(
+                    "replayed state at height {} does not match the committed root",
+                    rederived.tip.height
+                ),
+            });
+        }
+        report.rederived = Some(rederived);
+    }
+
+    if repair {
+        report.repaired = Some(repair_indexes(
+            spaced,
+            &entries,
+            &expected,
+            snapshot.metadata().to_vec(),
+        )?);
+    }
+
+    info!(
+        target: STORE,
+        block:% = tip.hash,
+        height = tip.height,
+        issues = report.issues.len();
+        "Verified stores"
+    );
+    Ok(report)
+}
+
+/// Derived index entries as they should be according to the committed spaceouts
+struct ExpectedIndexes {
+    /// Space key -> encoded outpoint of the spaceout carrying the space
+    spaces: BTreeMap<Hash, Vec<u8>>,
+    /// Bid key -> encoded space key for spaces in pre-auctions
+    bids: BTreeMap<Hash, Vec<u8>>,
+    /// Outpoints of all spaceouts carrying a space
+    space_outpoints: BTreeSet<OutPoint>,
+}
+
+fn check_state(entries: &Entries, issues: &mut Vec<Issue>) -> ExpectedIndexes {
+    let mut expected = ExpectedIndexes {
+        spaces: BTreeMap::new(),
+        bids: BTreeMap::new(),
+        space_outpoints: BTreeSet::new(),
+    };
+
+    let mut spaceouts = BTreeMap::new();
+    for (key, value) in entries.spaceouts.iter() {
+        match decode::<SpaceOut>(value) {
+            Some(spaceout) => {
+                spaceouts.insert(*key, spaceout);
+            }
+            None => issue(issues, IssueKind::InvalidValue, key, "not a spaceout"),
+        }
+    }
+
+    // SpaceKey -> outpoint -> SpaceOut
+    for (key, value) in entries.spaces.iter() {
+        let outpoint: OutPoint = match decode::<EncodableOutpoint>(value) {
+            Some(outpoint) => outpoint.into(),
+            None => {
+                issue(issues, IssueKind::InvalidValue, key, "not an outpoint");
+                continue;
+            }
+        };
+        let outpoint_key: Hash = OutpointKey::from_outpoint::<Sha256>(outpoint).into();
+        let space = match spaceouts.get(&outpoint_key) {
+            None => {
+                issue(
+                    issues,
+                    IssueKind::MissingSpaceout,
+                    key,
+                    &format!("no spaceout for outpoint {}", outpoint),
+                );
+                continue;
+            }
+            Some(spaceout) => spaceout.space.as_ref(),
+        };
+        let space_key = space.map(|space| Hash::from(space_key(space.name.as_ref())));
+        if space_key != Some(*key) {
+            issue(
+                issues,
+                IssueKind::SpaceMismatch,
+                key,
+                &format!("spaceout {} carries a different space", outpoint),
+            );
+            continue;
+        }
+        expected.spaces.insert(*key, value.clone());
+        expected.space_outpoints.insert(outpoint);
+    }
+
+    // Every spaceout carrying a space must be its space's current outpoint
+    for (key, spaceout) in spaceouts.iter() {
+        let space = match spaceout.space.as_ref() {
+            None => continue,
+            Some(space) => space,
+        };
+        let space_key = space_key(space.name.as_ref());
+        let points_back = entries
+            .spaces
+            .get(&Hash::from(space_key))
+            .and_then(|value| decode::<EncodableOutpoint>(value))
+            .is_some_and(|outpoint| {
+                let outpoint_key: Hash =
+                    OutpointKey::from_outpoint::<Sha256>(outpoint.into()).into();
+                outpoint_key == *key
+            });
+        if !points_back {
+            issue(
+                issues,
+                IssueKind::UnreferencedSpaceout,
+                key,
+                &format!("@{} does not point to this spaceout", space.name),
+            );
+            continue;
+        }
+
+        if let Covenant::Bid {
+            total_burned,
+            claim_height: None,
+            ..
+        } = space.covenant
+        {
+            let bid_key = BidKey::from_bid(total_burned, space_key.into());
+            let value =
+                bincode::encode_to_vec(space_key, config::standard()).expect("encodes space key");
+            expected.bids.insert(bid_key.into(), value);
+        }
+    }
+
+    // BidKey -> pre-auction bid
+    for (key, value) in entries.bids.iter() {
+        let space_key = match decode::<SpaceKey>(value) {
+            Some(space_key) => Hash::from(space_key),
+            None => {
+                issue(issues, IssueKind::InvalidValue, key, "not a space key");
+                continue;
+            }
+        };
+        if !expected.spaces.contains_key(&space_key) {
+            issue(
+                issues,
+                IssueKind::DanglingBid,
+                key,
+                &format!("space {} does not exist", hex::encode(space_key)),
+            );
+            continue;
+        }
+        if expected.bids.get(key) != Some(value) {
+            issue(
+                issues,
+                IssueKind::InvalidBid,
+                key,
+                &format!(
+                    "space {} is not a pre-auction bid with priority {}",
+                    hex::encode(space_key),
+                    BidKey::from_slice_unchecked(key).priority()
+                ),
+            );
+        }
+    }
+    for key in expected.bids.keys() {
+        if !entries.bids.contains_key(key) {
+            issue(
+                issues,
+                IssueKind::MissingBid,
+                key,
+                "pre-auction bid is not in the bid index",
+            );
+        }
+    }
+
+    expected
+}
+
+/// Checks that the block index is committed at the same block as the chain state
+/// and that it recorded every space outpoint. Returns the number of indexed blocks.
+fn check_block_index(
+    index: &LiveStore,
+    tip: &ChainAnchor,
+    space_outpoints: &BTreeSet<OutPoint>,
+    issues: &mut Vec<Issue>,
+) -> Result<usize> {
+    let mut snapshot = index
+        .store
+        .iter()
+        .next()
+        .ok_or_else(|| anyhow!("Block index has no committed state"))??;
+    let index_tip: ChainAnchor = snapshot.metadata().try_into()?;
+    if &index_tip != tip {
+        issue(
+            issues,
+            IssueKind::IndexMismatch,
+            index_tip.hash.as_ref(),
+            &format!(
+                "block index is at height {} but chain state is at height {}",
+                index_tip.height, tip.height
+            ),
+        );
+        return Ok(0);
+    }
+
+    let mut indexed = BTreeSet::new();
+    let mut blocks = 0;
+    for entry in snapshot.iter() {
+        let (key, value) = entry?;
+        let meta = match decode::<BlockMeta>(&value) {
+            Some(meta) => meta,
+            None => {
+                issue(issues, IssueKind::InvalidValue, &key, "not a block");
+                continue;
+            }
+        };
+        blocks += 1;
+        for entry in meta.tx_meta {
+            let changeset = entry.changeset;
+            for create in changeset.creates.iter().filter(|c| c.space.is_some()) {
+                indexed.insert(OutPoint {
+                    txid: changeset.txid,
+                    vout: create.n as u32,
+                });
+            }
+            for update in changeset.updates {
+                if matches!(update.kind, UpdateKind::Bid | UpdateKind::Rollout(_)) {
+                    indexed.insert(update.output.outpoint());
+                }
+            }
+        }
+    }
+
+    for outpoint in space_outpoints.difference(&indexed) {
+        let key: Hash = OutpointKey::from_outpoint::<Sha256>(*outpoint).into();
+        issue(
+            issues,
+            IssueKind::UnindexedOutpoint,
+            &key,
+            &format!("outpoint {} is not recorded by the block index", outpoint),
+        );
+    }
+    Ok(blocks)
+}
+
+/// Re-derives the state on a blocking thread from the block source the
+/// node syncs from
+async fn rederive_from_source(
+    spaced: &Spaced,
+    genesis: ChainAnchor,
+    target: ChainAnchor,
+    expected_root: String,
+) -> Result<Rederived> {
+    let replay = spaced.replay.clone();
+    let esplora_url = spaced.esplora_url.clone();
+    let blocks_dir = spaced.blocks_dir.clone();
+    let (rpc, rest) = (spaced.rpc.clone(), spaced.rest);
+
+    // Blocking clients must not be created or dropped in async context
+    tokio::task::spawn_blocking(move || {
+        if let Some(replay) = replay {
+            return rederive(&replay, genesis, target, &expected_root);
+        }
+        if let Some(url) = esplora_url {
+            return rederive(
+                &EsploraBlockSource::new(&url),
+                genesis,
+                target,
+                &expected_root,
+            );
+        }
+        let source = BitcoinBlockSource::new(rpc).with_rest(rest);
+        match blocks_dir {
+            None => rederive(&source, genesis, target, &expected_root),
+            Some(dir) => rederive(
+                &BlockFileSource::new(dir, source)?,
+                genesis,
+                target,
+                &expected_root,
+            ),
+        }
+    })
+    .await?
+}
+
+/// Replays the blocks following the activation block into memory up to the
+/// committed tip
+pub fn rederive(
+    source: &impl BlockSource,
+    genesis: ChainAnchor,
+    target: ChainAnchor,
+    expected_root: &str,
+) -> Result<Rederived> {
+    let store = Store::memory()?;
+    let mut chain = LiveStore {
+        state: store.begin(&genesis)?,
+        store,
+    };
+    let mut node = Node::new(false);
+    let mut tip = genesis;
+
+    while tip.height < target.height {
+        let height = tip.height + 1;
+        let hash = source
+            .get_block_hash(height)
+            .map_err(|e| anyhow!("could not get block hash at height {}: {}", height, e))?;
+        let block = source
+            .get_block(&hash)
+            .map_err(|e| anyhow!("could not get block at height {}: {}", height, e))?;
+        if block.header.prev_blockhash != tip.hash {
+            return Err(anyhow!(
+                "block {} at height {} does not connect to {}",
+                hash,
+                height,
+                tip.hash
+            ));
+        }
+        node.apply_block(&mut chain, height, hash, block, false)?;
+        tip = ChainAnchor { hash, height };
+
+        // Rollouts expect a clean state committed right before them
+        if tip.height % ROLLOUT_BLOCK_INTERVAL == 0 {
+            let tx = chain.store.write()?;
+            chain.state.commit(tip, tx)?;
+        }
+    }
+
+    let tx = chain.store.write()?;
+    chain.state.commit(tip, tx)?;
+    let mut snapshot = chain
+        .store
+        .iter()
+        .next()
+        .ok_or_else(|| anyhow!("missing replayed snapshot"))??;
+    let root = hex::encode(snapshot.compute_root()?);
+
+    Ok(Rederived {
+        tip,
+        matches: tip == target && root == expected_root,
+        root,
+    })
+}
+
+/// Commits rebuilt indexes on top of the verified snapshot, keeping the
+/// chain state and block index paired and the node's recovery data consistent
+fn repair_indexes(
+    spaced: &mut Spaced,
+    entries: &Entries,
+    expected: &ExpectedIndexes,
+    metadata: Vec<u8>,
+) -> Result<Repaired> {
+    let tip: ChainAnchor = metadata.as_slice().try_into()?;
+    let repaired = rebuild_indexes(&spaced.chain.store, entries, expected, metadata.clone())?;
+
+    // Snapshots are restored in pairs by anchor so the block index gets a
+    // snapshot at the same block if it was committed there
+    if let Some(index) = spaced.block_index.as_ref() {
+        let index_tip = index
+            .store
+            .iter()
+            .next()
+            .transpose()?
+            .and_then(|snapshot| ChainAnchor::try_from(snapshot.metadata()).ok());
+        if index_tip == Some(tip) {
+            let mut tx = index.store.write()?;
+            tx.metadata(metadata)?;
+            tx.commit()?;
+            index.state.restore(tip)?;
+        }
+    }
+
+    // Undo records and journaled blocks hold index values from before the
+    // repair, so the node continues from the repaired snapshot instead
+    spaced.undo.clear();
+    spaced.undo.save()?;
+    spaced.journal.truncate()?;
+    spaced.chain.state.restore(tip)?;
+    Ok(repaired)
+}
+
+/// Rewrites the space and bid indexes from the committed spaceouts and
+/// commits them at the same block. Space mappings can only be removed since
+/// outpoints can't be recovered from their hashed keys.
+fn rebuild_indexes(
+    store: &Store,
+    entries: &Entries,
+    expected: &ExpectedIndexes,
+    metadata: Vec<u8>,
+) -> Result<Repaired> {
+    let mut tx = store.write()?;
+    let mut repaired = Repaired {
+        inserted: 0,
+        removed: 0,
+        root: String::new(),
+    };
+
+    for (current, wanted) in [
+        (&entries.spaces, &expected.spaces),
+        (&entries.bids, &expected.bids),
+    ] {
+        for key in current.keys().filter(|key| !wanted.contains_key(*key)) {
+            tx.remove(*key)?;
+            repaired.removed += 1;
+        }
+        for (key, value) in wanted {
+            if current.get(key) != Some(value) {
+                tx.insert(*key, value.clone())?;
+                repaired.inserted += 1;
+            }
+        }
+    }
+    tx.metadata(metadata)?;
+    tx.commit()?;
+
+    let mut snapshot = store
+        .iter()
+        .next()
+        .ok_or_else(|| anyhow!("missing repaired snapshot"))??;
+    repaired.root = hex::encode(snapshot.compute_root()?);
+    info!(
+        target: STORE,
+        inserted = repaired.inserted,
+        removed = repaired.removed;
+        "Repaired indexes"
+    );
+    Ok(repaired)
+}
+
+fn space_key(name: &[u8]) -> SpaceKey {
+    SpaceKey::from(Sha256::hash(name))
+}
+
+fn decode<T: Decode>(value: &[u8]) -> Option<T> {
+    bincode::decode_from_slice(value, config::standard())
+        .ok()
+        .map(|(value, _)| value)
+}
+
+fn issue(issues: &mut Vec<Issue>, kind: IssueKind, key: &[u8], message: &str) {
+    issues.push(Issue {
+        kind,
+        key: hex::encode(key),
+        message: message.to_string(),
+    });
+}
@@ -1,4 +1,4 @@
-use std::{collections::BTreeMap, str::FromStr, time::Duration};
+use std::{collections::BTreeMap, str::FromStr, sync::mpsc::RecvTimeoutError, time::Duration};
 
 use anyhow::anyhow;
 use clap::ValueEnum;
@@ -17,10 +17,10 @@ use serde::{Deserialize, Serialize};
 use serde_json::json;
 use tokio::{
     select,
-    sync::{broadcast, mpsc, mpsc::Receiver, oneshot},
+    sync::{broadcast, mpsc, mpsc::{error::TryRecvError, Receiver}, oneshot},
 };
 use wallet::{
-    address::SpaceAddress,
+    address::{SpaceAddress, SpaceHrp},
     bdk_wallet,
     bdk_wallet::{
         chain::{local_chain::CheckPoint, BlockId},
@@ -38,15 +38,22 @@ use wallet::{
 use wallet::bdk_wallet::chain::ConfirmationTime;
 use crate::{
     config::ExtendedNetwork,
+    logging,
+    metrics::Metrics,
     node::BlockSource,
     rpc::{LoadedWallet, RpcWalletRequest, RpcWalletTxBuilder},
     source::{
         BitcoinBlockSource, BitcoinRpc, BitcoinRpcError, BlockEvent, BlockFetchError, BlockFetcher,
+        TipNotifier,
     },
     store::{ChainState, LiveSnapshot, Sha256},
 };
 use crate::checker::TxChecker;
 
+/// How long a wallet sync thread waits for a block before checking for
+/// commands and shutdown again
+const WALLET_SYNC_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TxResponse {
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -314,7 +321,12 @@ impl RpcWallet {
                         .next_unused_address(KeychainKind::External)
                         .address
                         .to_string(),
-                    AddressKind::Space => wallet.next_unused_space_address().to_string(),
+                    AddressKind::Space => {
+                        let address = wallet.next_unused_space_address();
+                        address
+                            .display_with(wallet.config.space_hrp.as_ref())
+                            .to_string()
+                    }
                 };
                 _ = resp.send(Ok(address));
             }
@@ -349,7 +361,12 @@ impl RpcWallet {
                 _ = resp.send(balance);
             }
             WalletCommand::UnloadWallet => {
-                info!("Unloading wallet '{}' ...", wallet.name());
+                info!(
+                    target: &logging::wallet_target(wallet.name()),
+                    wallet:% = wallet.name();
+                    "Unloading wallet '{}' ...",
+                    wallet.name()
+                );
             }
         }
         Ok(())
@@ -363,8 +380,12 @@ impl RpcWallet {
         mut commands: Receiver<WalletCommand>,
         mut shutdown: broadcast::Receiver<()>,
         num_workers: usize,
+        notifier: TipNotifier,
+        metrics: Metrics,
     ) -> anyhow::Result<()> {
-        let (fetcher, receiver) = BlockFetcher::new(source.clone(), num_workers);
+        let (fetcher, receiver) = BlockFetcher::with_notifier(source.clone(), num_workers, notifier);
+        let wallet_name = wallet.name().to_string();
+        let target = logging::wallet_target(&wallet_name);
 
         let mut wallet_tip = {
             let tip = wallet.spaces.local_chain().tip();
@@ -375,101 +396,129 @@ impl RpcWallet {
         };
 
         fetcher.start(wallet_tip);
+        metrics.set_wallet_height(&wallet_name, wallet_tip.height);
 
         loop {
             if shutdown.try_recv().is_ok() {
-                info!("Shutting down wallet sync");
+                info!(target: &target, wallet:% = wallet_name; "Shutting down wallet sync");
                 break;
             }
-            if let Ok(command) = commands.try_recv() {
-                Self::wallet_handle_commands(network, &source, &mut state, &mut wallet, command)?;
+            match commands.try_recv() {
+                Ok(command) => {
+                    Self::wallet_handle_commands(
+                        network,
+                        &source,
+                        &mut state,
+                        &mut wallet,
+                        command,
+                    )?;
+                    continue;
+                }
+                Err(TryRecvError::Disconnected) => {
+                    info!(
+                        target: &target,
+                        wallet:% = wallet_name;
+                        "Wallet was dropped, stopping sync"
+                    );
+                    break;
+                }
+                Err(TryRecvError::Empty) => {}
             }
-            if let Ok(event) = receiver.try_recv() {
-                match event {
-                    BlockEvent::Block(id, block) => {
-                        wallet.apply_block_connected_to(
-                            id.height,
-                            &block,
-                            wallet::bdk_wallet::chain::BlockId {
-                                height: wallet_tip.height,
-                                hash: wallet_tip.hash,
-                            },
-                        )?;
-
-                        // Temporary fix for https://github.com/bitcoindevkit/bdk/issues/1740
-                        for tx in block.txdata {
-                            for input in tx.input.iter() {
-                                if wallet.watch_bid_spends.contains(&input.previous_output) {
-                                    if wallet.spaces.get_tx(tx.compute_txid()).is_some() {
-                                        break;
-                                    }
-                                    wallet.insert_tx(tx, ConfirmationTime::Confirmed {
-                                        height: id.height,
-                                        time: block.header.time as _,
-                                    })?;
+            let event = match receiver.recv_timeout(WALLET_SYNC_POLL_INTERVAL) {
+                Ok(event) => event,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(anyhow!("Block fetcher of wallet `{}` stopped", wallet_name))
+                }
+            };
+            match event {
+                BlockEvent::Block(id, block) => {
+                    wallet.apply_block_connected_to(
+                        id.height,
+                        &block,
+                        wallet::bdk_wallet::chain::BlockId {
+                            height: wallet_tip.height,
+                            hash: wallet_tip.hash,
+                        },
+                    )?;
+
+                    // Temporary fix for https://github.com/bitcoindevkit/bdk/issues/1740
+                    for tx in block.txdata {
+                        for input in tx.input.iter() {
+                            if wallet.watch_bid_spends.contains(&input.previous_output) {
+                                if wallet.spaces.get_tx(tx.compute_txid()).is_some() {
                                     break;
                                 }
+                                wallet.insert_tx(tx, ConfirmationTime::Confirmed {
+                                    height: id.height,
+                                    time: block.header.time as _,
+                                })?;
+                                break;
                             }
                         }
+                    }
 
-                        wallet_tip.height = id.height;
-                        wallet_tip.hash = id.hash;
+                    wallet_tip.height = id.height;
+                    wallet_tip.hash = id.hash;
+                    metrics.set_wallet_height(&wallet_name, id.height);
 
-                        if id.height % 12 == 0 {
-                            wallet.commit()?;
-                        }
+                    if id.height % 12 == 0 {
+                        wallet.commit()?;
                     }
-                    BlockEvent::Error(e) if matches!(e, BlockFetchError::BlockMismatch) => {
-                        let mut checkpoint_in_chain = None;
-                        let best_chain = source.get_best_chain()?;
-                        for cp in wallet.spaces.local_chain().iter_checkpoints() {
-                            if cp.height() > best_chain.height {
-                                continue;
-                            }
-
-                            let hash = source.get_block_hash(cp.height())?;
-                            if cp.height() != 0 && hash == cp.hash() {
-                                checkpoint_in_chain = Some(cp);
-                                break;
-                            }
+                }
+                BlockEvent::Error(e) if matches!(e, BlockFetchError::BlockMismatch) => {
+                    let mut checkpoint_in_chain = None;
+                    let best_chain = source.get_best_chain()?;
+                    for cp in wallet.spaces.local_chain().iter_checkpoints() {
+                        if cp.height() > best_chain.height {
+                            continue;
                         }
 
-                        let restore_point = match checkpoint_in_chain {
-                            None => {
-                                // We couldn't find a restore point
-                                warn!("Rebuilding wallet `{}`", wallet.config.name);
-                                let birthday = wallet.config.start_block;
-                                let hash = source.get_block_hash(birthday)?;
-                                let cp = CheckPoint::new(BlockId {
-                                    height: birthday,
-                                    hash,
-                                });
-                                wallet = wallet.rebuild()?;
-                                wallet.spaces.insert_checkpoint(cp.block_id())?;
-                                cp
-                            }
-                            Some(cp) => cp,
-                        };
+                        let hash = source.get_block_hash(cp.height())?;
+                        if cp.height() != 0 && hash == cp.hash() {
+                            checkpoint_in_chain = Some(cp);
+                            break;
+                        }
+                    }
 
-                        wallet_tip.height = restore_point.block_id().height;
-                        wallet_tip.hash = restore_point.block_id().hash;
+                    let restore_point = match checkpoint_in_chain {
+                        None => {
+                            // We couldn't find a restore point
+                            warn!(
+                                target: &target,
+                                wallet:% = wallet_name;
+                                "Rebuilding wallet `{}`",
+                                wallet.config.name
+                            );
+                            let birthday = wallet.config.start_block;
+                            let hash = source.get_block_hash(birthday)?;
+                            let cp = CheckPoint::new(BlockId {
+                                height: birthday,
+                                hash,
+                            });
+                            wallet = wallet.rebuild()?;
+                            wallet.spaces.insert_checkpoint(cp.block_id())?;
+                            cp
+                        }
+                        Some(cp) => cp,
+                    };
 
-                        info!(
-                            "Restore wallet `{}` to block={} height={}",
-                            wallet.name(),
-                            wallet_tip.hash,
-                            wallet_tip.height
-                        );
-                        fetcher.start(wallet_tip);
-                    }
-                    BlockEvent::Error(e) => return Err(e.into()),
+                    wallet_tip.height = restore_point.block_id().height;
+                    wallet_tip.hash = restore_point.block_id().hash;
+                    metrics.set_wallet_height(&wallet_name, wallet_tip.height);
+
+                    info!(
+                        target: &target,
+                        wallet:% = wallet_name,
+                        block:% = wallet_tip.hash,
+                        height = wallet_tip.height;
+                        "Restore wallet `{}`",
+                        wallet_name
+                    );
+                    fetcher.start(wallet_tip);
                 }
-
-                continue;
+                BlockEvent::Error(e) => return Err(e.into()),
             }
-
-            // TODO: update wallet mempool
-            std::thread::sleep(Duration::from_millis(10));
         }
 
         fetcher.stop();
@@ -558,6 +607,7 @@ impl RpcWallet {
 
     fn resolve(
         network: ExtendedNetwork,
+        space_hrp: Option<SpaceHrp>,
         store: &mut LiveSnapshot,
         to: &str,
         require_space_address: bool,
@@ -568,7 +618,7 @@ impl RpcWallet {
             }
             return Ok(Some(address.require_network(network.fallback_network())?));
         }
-        if let Ok(space_address) = SpaceAddress::from_str(to) {
+        if let Ok(space_address) = SpaceAddress::parse(to, space_hrp.as_ref()) {
             return Ok(Some(space_address.0));
         }
 
@@ -635,7 +685,14 @@ impl RpcWallet {
             },
             Some(r) => r.clone(),
         };
-        info!("Using fee rate: {} sat/vB", fee_rate.to_sat_per_vb_ceil());
+        let wallet_name = wallet.name().to_string();
+        let target = logging::wallet_target(&wallet_name);
+        info!(
+            target: &target,
+            wallet:% = wallet_name;
+            "Using fee rate: {} sat/vB",
+            fee_rate.to_sat_per_vb_ceil()
+        );
 
         let mut builder = wallet::builder::Builder::new();
         builder = builder.fee_rate(fee_rate);
@@ -646,11 +703,13 @@ impl RpcWallet {
 
         builder = builder.force(tx.force);
         let mut bid_replacement = tx.confirmed_only;
+        let space_hrp = wallet.config.space_hrp;
 
         for req in tx.requests {
             match req {
                 RpcWalletRequest::SendCoins(params) => {
-                    let recipient = match Self::resolve(network, store, &params.to, false)? {
+                    let resolved = Self::resolve(network, space_hrp, store, &params.to, false)?;
+                    let recipient = match resolved {
                         None => {
                             return Err(anyhow!("sendcoins: could not resolve '{}'", params.to))
                         }
@@ -670,7 +729,8 @@ impl RpcWallet {
                     if spaces.len() != params.spaces.len() {
                         return Err(anyhow!("sendspaces: some names were malformed"));
                     }
-                    let recipient = match Self::resolve(network, store, &params.to, true)? {
+                    let resolved = Self::resolve(network, space_hrp, store, &params.to, true)?;
+                    let recipient = match resolved {
                         None => {
                             return Err(anyhow!("sendspaces: could not resolve '{}'", params.to))
                         }
@@ -765,7 +825,7 @@ impl RpcWallet {
 
                     let address = match params.to {
                         None => wallet.next_unused_space_address(),
-                        Some(address) => match SpaceAddress::from_str(&address) {
+                        Some(address) => match SpaceAddress::parse(&address, space_hrp.as_ref()) {
                             Ok(addr) => addr,
                             Err(_) => {
                                 return Err(anyhow!(
@@ -832,8 +892,9 @@ impl RpcWallet {
             let tagged = tx_result?;
 
             let is_bid = tagged.tags.iter().any(|tag| *tag == TransactionTag::Bid);
+            let txid = tagged.tx.compute_txid();
             result_set.push(TxResponse {
-                txid: tagged.tx.compute_txid(),
+                txid,
                 tags: tagged.tags,
                 error: None,
                 raw: None,
@@ -847,6 +908,12 @@ impl RpcWallet {
             let result = source.rpc.broadcast_tx(&source.client, &tagged.tx);
             match result {
                 Ok(confirmation) => {
+                    info!(
+                        target: &target,
+                        wallet:% = wallet_name,
+                        txid:% = txid;
+                        "Broadcast transaction"
+                    );
                     tx_iter.wallet.insert_tx(tagged.tx, confirmation)?;
                     tx_iter.wallet.commit()?;
                 }
@@ -899,6 +966,8 @@ impl RpcWallet {
         mut channel: Receiver<LoadedWallet>,
         shutdown: broadcast::Sender<()>,
         num_workers: usize,
+        notifier: TipNotifier,
+        metrics: Metrics,
     ) -> anyhow::Result<()> {
         let mut shutdown_signal = shutdown.subscribe();
         let mut wallet_results = FuturesUnordered::new();
@@ -906,17 +975,24 @@ impl RpcWallet {
         loop {
             select! {
                 _ = shutdown_signal.recv() => {
-                    info!("Shutting down wallet service...");
+                    info!(target: logging::WALLET, "Shutting down wallet service...");
                     break;
                 }
                 wallet = channel.recv() => {
                     if let Some( loaded ) = wallet {
                         let wallet_name = loaded.wallet.name().to_string();
-                        info!("Loaded wallet: {}", wallet_name);
+                        info!(
+                            target: &logging::wallet_target(&wallet_name),
+                            wallet:% = wallet_name;
+                            "Loaded wallet: {}",
+                            wallet_name
+                        );
 
                         let wallet_chain = store.clone();
                         let rpc = rpc.clone();
                         let wallet_shutdown = shutdown.subscribe();
+                        let notifier = notifier.clone();
+                        let metrics = metrics.clone();
                         let (tx, rx) = oneshot::channel();
 
                         std::thread::spawn(move || {
@@ -928,16 +1004,24 @@ impl RpcWallet {
                                 loaded.wallet,
                                 loaded.rx,
                                 wallet_shutdown,
-                                num_workers
+                                num_workers,
+                                notifier,
+                                metrics,
                             ));
                         });
                         wallet_results.push(named_future(wallet_name, rx));
                     }
                 }
                 Some((name, res)) = wallet_results.next() => {
+                    metrics.remove_wallet(&name);
                     if let Ok(res) = res {
                         match res {
-                        Ok(_) => info!("Wallet `{}` shutdown normally", name),
+                        Ok(_) => info!(
+                            target: &logging::wallet_target(&name),
+                            wallet:% = name;
+                            "Wallet `{}` shutdown normally",
+                            name
+                        ),
                             Err(e) => {
                                 return Err(anyhow!("An error occurred with wallet `{}`: {}", name, e))
                             }
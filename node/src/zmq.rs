@@ -0,0 +1,234 @@
+//! A minimal ZMQ subscriber for bitcoind's `-zmqpub*` notifications.
+//!
+//! Only what's needed to talk to a single bitcoind publisher is implemented:
+//! ZMTP 3.0 over TCP with the NULL security mechanism.
+
+use std::{
+    io,
+    io::{Read, Write},
+    net::TcpStream,
+    time::Duration,
+};
+
+use log::{info, warn};
+use tokio::sync::broadcast;
+
+use crate::{logging::FETCHER, source::TipNotifier};
+
+/// Block hashes published by `-zmqpubhashblock`
+pub const TOPIC_HASHBLOCK: &str = "hashblock";
+
+const FLAG_MORE: u8 = 0x01;
+const FLAG_LONG: u8 = 0x02;
+const FLAG_COMMAND: u8 = 0x04;
+
+/// Large enough for any raw transaction or block
+const MAX_FRAME_LEN: u64 = 32 * 1024 * 1024;
+/// How long a frame may take to arrive once it has started
+const FRAME_TIMEOUT: Duration = Duration::from_secs(30);
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// A SUB socket connected to a single publisher
+pub struct Subscriber {
+    stream: TcpStream,
+}
+
+impl Subscriber {
+    /// Connects to a `tcp://host:port` endpoint and subscribes to the given topics
+    pub fn connect(endpoint: &str, topics: &[&str]) -> io::Result<Self> {
+        let addr = endpoint
+            .strip_prefix("tcp://")
+            .ok_or_else(|| invalid_data(format!("unsupported zmq endpoint '{}'", endpoint)))?;
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(Some(FRAME_TIMEOUT))?;
+
+        let mut subscriber = Self { stream };
+        subscriber.handshake()?;
+        for topic in topics {
+            let mut subscribe = Vec::with_capacity(topic.len() + 1);
+            subscribe.push(1);
+            subscribe.extend_from_slice(topic.as_bytes());
+            subscriber.write_frame(0, &subscribe)?;
+        }
+        Ok(subscriber)
+    }
+
+    fn handshake(&mut self) -> io::Result<()> {
+        let mut greeting = [0u8; 64];
+        greeting[0] = 0xff;
+        greeting[9] = 0x7f;
+        // version 3.0
+        greeting[10] = 3;
+        greeting[12..16].copy_from_slice(b"NULL");
+        self.stream.write_all(&greeting)?;
+
+        let mut peer = [0u8; 64];
+        self.stream.read_exact(&mut peer)?;
+        if peer[0] != 0xff || peer[9] != 0x7f || peer[10] < 3 {
+            return Err(invalid_data("peer does not speak ZMTP 3".to_string()));
+        }
+        if &peer[12..16] != b"NULL" {
+            return Err(invalid_data("unsupported security mechanism".to_string()));
+        }
+
+        let mut ready = Vec::new();
+        ready.push(5);
+        ready.extend_from_slice(b"READY");
+        ready.push(11);
+        ready.extend_from_slice(b"Socket-Type");
+        ready.extend_from_slice(&3u32.to_be_bytes());
+        ready.extend_from_slice(b"SUB");
+        self.write_frame(FLAG_COMMAND, &ready)?;
+
+        let (flags, command) = self.read_frame()?;
+        if flags & FLAG_COMMAND == 0 || !command.starts_with(b"\x05READY") {
+            return Err(invalid_data("expected READY from publisher".to_string()));
+        }
+        Ok(())
+    }
+
+    /// Waits up to `timeout` for the next message to start arriving
+    pub fn poll(&self, timeout: Duration) -> io::Result<bool> {
+        self.stream.set_read_timeout(Some(timeout))?;
+        let mut byte = [0u8; 1];
+        let result = match self.stream.peek(&mut byte) {
+            Ok(0) => Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+            Ok(_) => Ok(true),
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                Ok(false)
+            }
+            Err(e) => Err(e),
+        };
+        self.stream.set_read_timeout(Some(FRAME_TIMEOUT))?;
+        result
+    }
+
+    /// Receives the next multipart message
+    pub fn recv(&mut self) -> io::Result<Vec<Vec<u8>>> {
+        let mut parts = Vec::new();
+        loop {
+            let (flags, body) = self.read_frame()?;
+            if flags & FLAG_COMMAND != 0 {
+                continue;
+            }
+            parts.push(body);
+            if flags & FLAG_MORE == 0 {
+                return Ok(parts);
+            }
+        }
+    }
+
+    fn read_frame(&mut self) -> io::Result<(u8, Vec<u8>)> {
+        let mut flags = [0u8; 1];
+        self.stream.read_exact(&mut flags)?;
+        let flags = flags[0];
+
+        let len = if flags & FLAG_LONG != 0 {
+            let mut len = [0u8; 8];
+            self.stream.read_exact(&mut len)?;
+            u64::from_be_bytes(len)
+        } else {
+            let mut len = [0u8; 1];
+            self.stream.read_exact(&mut len)?;
+            len[0] as u64
+        };
+        if len > MAX_FRAME_LEN {
+            return Err(invalid_data(format!("frame of {} bytes is too large", len)));
+        }
+
+        let mut body = vec![0u8; len as usize];
+        self.stream.read_exact(&mut body)?;
+        Ok((flags, body))
+    }
+
+    fn write_frame(&mut self, flags: u8, body: &[u8]) -> io::Result<()> {
+        let mut frame = Vec::with_capacity(body.len() + 9);
+        if body.len() > u8::MAX as usize {
+            frame.push(flags | FLAG_LONG);
+            frame.extend_from_slice(&(body.len() as u64).to_be_bytes());
+        } else {
+            frame.push(flags);
+            frame.push(body.len() as u8);
+        }
+        frame.extend_from_slice(body);
+        self.stream.write_all(&frame)
+    }
+}
+
+/// Subscribes to `hashblock` notifications in the background waking the
+/// notifier on every new block. The connection is retried until shutdown
+/// and the notifier is marked disconnected meanwhile so fetchers keep polling.
+pub fn listen_blocks(endpoint: String, notifier: TipNotifier, shutdown: broadcast::Sender<()>) {
+    let mut shutdown_signal = shutdown.subscribe();
+    std::thread::spawn(move || {
+        // Connection failures are only logged once until reconnected
+        let mut warned = false;
+        while shutdown_signal.try_recv().is_err() {
+            let mut subscriber = match Subscriber::connect(&endpoint, &[TOPIC_HASHBLOCK]) {
+                Ok(subscriber) => subscriber,
+                Err(e) => {
+                    if !warned {
+                        warn!(
+                            target: FETCHER,
+                            "Could not subscribe to {}: {} - polling for blocks",
+                            endpoint, e
+                        );
+                        warned = true;
+                    }
+                    std::thread::sleep(RECONNECT_DELAY);
+                    continue;
+                }
+            };
+            info!(target: FETCHER, "Receiving block notifications from {}", endpoint);
+            warned = false;
+            notifier.set_connected(true);
+            // Catch up on anything published before the subscription was active
+            notifier.notify();
+
+            let result: io::Result<()> = loop {
+                if shutdown_signal.try_recv().is_ok() {
+                    break Ok(());
+                }
+                match subscriber.poll(Duration::from_secs(1)) {
+                    Ok(false) => continue,
+                    Ok(true) => {}
+                    Err(e) => break Err(e),
+                }
+                match subscriber.recv() {
+                    Ok(message)
+                        if message.first().map(Vec::as_slice)
+                            == Some(TOPIC_HASHBLOCK.as_bytes()) =>
+                    {
+                        notifier.notify()
+                    }
+                    Ok(_) => {}
+                    Err(e) => break Err(e),
+                }
+            };
+
+            notifier.set_connected(false);
+            match result {
+                Ok(()) => break,
+                Err(e) => {
+                    warn!(
+                        target: FETCHER,
+                        "Lost block notifications from {}: {} - polling for blocks",
+                        endpoint, e
+                    );
+                    warned = true;
+                    std::thread::sleep(RECONNECT_DELAY);
+                }
+            }
+        }
+    });
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
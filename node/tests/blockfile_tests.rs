@@ -0,0 +1,112 @@
+use std::{fs, path::Path};
+
+use spaced::{
+    blockfile::BlockFileSource,
+    node::{
+        protocol::bitcoin::{consensus::encode::serialize, Block, Network, ScriptBuf},
+        BlockSource,
+    },
+    source::{BitcoinBlockSource, BitcoinRpc, BitcoinRpcAuth},
+};
+use testutil::{bitcoind::tempfile::tempdir, mock::MockChain};
+
+const XOR_KEY: [u8; 8] = [0x1f, 0x2e, 0x3d, 0x4c, 0x5b, 0x6a, 0x79, 0x88];
+
+/// Writes blocks as `[magic][length][block]` records obfuscated with the
+/// key followed by unobfuscated zero padding like a preallocated file
+fn write_block_file(dir: &Path, file: u32, blocks: &[Block], xor: [u8; 8]) {
+    let mut raw = Vec::new();
+    for block in blocks {
+        let block = serialize(block);
+        raw.extend(Network::Regtest.magic().to_bytes());
+        raw.extend((block.len() as u32).to_le_bytes());
+        raw.extend(block);
+    }
+    for (i, byte) in raw.iter_mut().enumerate() {
+        *byte ^= xor[i % xor.len()];
+    }
+    raw.extend([0u8; 256]);
+    fs::write(dir.join(format!("blk{:05}.dat", file)), raw).expect("write block file");
+}
+
+/// Blocks that the RPC of the given chain doesn't know about so they can
+/// only be read from disk
+fn offline_blocks(count: usize) -> Vec<Block> {
+    let other = MockChain::new();
+    (1..=count)
+        .map(|height| {
+            other.mine_block_to(ScriptBuf::new());
+            other.block_at(height as u32).expect("mined")
+        })
+        .collect()
+}
+
+fn block_file_source(dir: &Path, chain: &MockChain) -> anyhow::Result<BlockFileSource> {
+    let rpc = BitcoinRpc::new(&chain.serve()?, BitcoinRpcAuth::None);
+    BlockFileSource::new(dir.to_path_buf(), BitcoinBlockSource::new(rpc))
+}
+
+#[test]
+fn it_should_read_obfuscated_block_files() -> anyhow::Result<()> {
+    let dir = tempdir()?;
+    let chain = MockChain::new();
+    chain.mine_blocks(2);
+    let blocks = offline_blocks(4);
+    fs::write(dir.path().join("xor.dat"), XOR_KEY)?;
+    write_block_file(dir.path(), 0, &blocks[..2], XOR_KEY);
+    write_block_file(dir.path(), 1, &blocks[2..], XOR_KEY);
+
+    let source = block_file_source(dir.path(), &chain)?;
+    // scanned out of order across both files
+    for block in blocks.iter().rev() {
+        assert_eq!(source.get_block(&block.block_hash())?, *block);
+    }
+
+    // blocks missing from disk are fetched over rpc
+    let missing = chain.block_at(2).expect("mined");
+    assert_eq!(source.get_block(&missing.block_hash())?, missing);
+    Ok(())
+}
+
+#[test]
+fn it_should_reject_a_malformed_obfuscation_key() -> anyhow::Result<()> {
+    let dir = tempdir()?;
+    let chain = MockChain::new();
+    fs::write(dir.path().join("xor.dat"), [1u8; 4])?;
+    assert!(block_file_source(dir.path(), &chain).is_err());
+    Ok(())
+}
+
+#[test]
+fn it_should_start_at_the_oldest_file_of_a_pruned_node() -> anyhow::Result<()> {
+    let dir = tempdir()?;
+    let chain = MockChain::new();
+    let blocks = offline_blocks(2);
+    write_block_file(dir.path(), 3, &blocks[..1], [0u8; 8]);
+    write_block_file(dir.path(), 4, &blocks[1..], [0u8; 8]);
+
+    let source = block_file_source(dir.path(), &chain)?;
+    assert_eq!(source.get_block(&blocks[1].block_hash())?, blocks[1]);
+
+    // a file pruned after it was indexed falls back to rpc
+    chain.mine_blocks(1);
+    let block = chain.block_at(1).expect("mined");
+    write_block_file(dir.path(), 5, &[block.clone()], [0u8; 8]);
+    assert_eq!(source.get_block(&block.block_hash())?, block);
+    fs::remove_file(dir.path().join("blk00005.dat"))?;
+    assert_eq!(source.get_block(&block.block_hash())?, block);
+    Ok(())
+}
+
+#[test]
+fn it_should_fall_back_to_rpc_without_block_files() -> anyhow::Result<()> {
+    let dir = tempdir()?;
+    let chain = MockChain::new();
+    chain.mine_blocks(1);
+    let source = block_file_source(dir.path(), &chain)?;
+    let block = chain.block_at(1).expect("mined");
+    assert_eq!(source.get_block(&block.block_hash())?, block);
+
+    assert!(block_file_source(&dir.path().join("missing"), &chain).is_err());
+    Ok(())
+}
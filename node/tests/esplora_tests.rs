@@ -0,0 +1,54 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use spaced::{esplora::EsploraBlockSource, node::BlockSource, source::BlockFetcher};
+use testutil::{mock::MockChain, wait_for_blocks};
+
+#[test]
+fn test_esplora_block_source() -> Result<()> {
+    let chain = MockChain::new();
+    chain.mine_blocks(4);
+    let source = EsploraBlockSource::new(&chain.serve()?);
+
+    let best = source.get_best_chain()?;
+    assert_eq!(best, chain.tip());
+    assert_eq!(source.get_block_count()?, 4);
+    assert_eq!(source.get_median_time()?, chain.get_median_time()?);
+
+    for height in 0..=4 {
+        let block = chain.block_at(height).expect("mined");
+        let hash = source.get_block_hash(height)?;
+        assert_eq!(hash, block.block_hash());
+        assert_eq!(source.get_block(&hash)?, block);
+    }
+
+    assert!(
+        source.get_block_hash(5).is_err(),
+        "should not find a block above the tip"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_block_fetching_from_esplora() -> Result<()> {
+    const GENERATED_BLOCKS: u32 = 20;
+
+    let chain = MockChain::new();
+    let genesis = chain.tip();
+    chain.mine_blocks(GENERATED_BLOCKS as usize);
+    let source = EsploraBlockSource::new(&chain.serve()?);
+
+    let (fetcher, receiver) = BlockFetcher::new(source, 4);
+    fetcher.start(genesis);
+
+    let mut height = 0;
+    wait_for_blocks(&receiver, Duration::from_secs(5), |id, block| {
+        height += 1;
+        assert_eq!(id.height, height, "blocks should be emitted in order");
+        assert_eq!(Some(block), chain.block_at(height));
+        id.height == GENERATED_BLOCKS
+    })?;
+
+    fetcher.stop();
+    Ok(())
+}
@@ -1,12 +1,33 @@
 use std::{
-    sync::mpsc::TryRecvError,
+    net::TcpListener,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
     time::{Duration, Instant},
 };
 
 use anyhow::Result;
-use protocol::{bitcoin::BlockHash, constants::ChainAnchor};
-use spaced::source::{BitcoinBlockSource, BitcoinRpc, BitcoinRpcAuth, BlockEvent, BlockFetcher};
-use testutil::TestRig;
+use protocol::{
+    bitcoin::{Block, BlockHash, Sequence},
+    constants::ChainAnchor,
+};
+use spaced::{
+    config::ExtendedNetwork,
+    headers::HeaderRules,
+    node::BlockSource,
+    source::{
+        BitcoinBlockSource, BitcoinRpc, BitcoinRpcAuth, BitcoinRpcError, BlockFetchError,
+        BlockFetcher, TipNotifier,
+    },
+    zmq,
+};
+use testutil::{
+    bitcoind::{bitcoincore_rpc::RpcApi, tempfile::tempdir, BitcoinD, Conf},
+    mock::MockChain,
+    wait_for_blocks, TestRig,
+};
+use tokio::sync::broadcast;
 
 async fn setup(blocks: u64) -> Result<(TestRig, u64, BlockHash)> {
     let rig = TestRig::new().await?;
@@ -29,28 +50,314 @@ fn test_block_fetching_from_bitcoin_rpc() -> Result<()> {
     let (fetcher, receiver) = BlockFetcher::new(fetcher_rpc.clone(), 8);
     fetcher.start(ChainAnchor { hash, height: 0 });
 
-    let timeout = Duration::from_secs(5);
+    wait_for_blocks(&receiver, Duration::from_secs(5), |id, _| {
+        height += 1;
+        id.height == GENERATED_BLOCKS as u32
+    })?;
+
+    assert_eq!(height, GENERATED_BLOCKS, "Not all blocks were received");
+    Ok(())
+}
+
+#[test]
+fn test_block_fetcher_wakes_on_notification() -> Result<()> {
+    let runtime = tokio::runtime::Runtime::new()?;
+    let (rig, _, hash) = runtime.block_on(setup(2))?;
+    let fetcher_rpc = BitcoinBlockSource::new(BitcoinRpc::new(
+        &rig.bitcoind.rpc_url(),
+        BitcoinRpcAuth::UserPass("user".to_string(), "password".to_string()),
+    ));
+
+    // While notifications are received polling falls back to a long interval
+    let notifier = TipNotifier::default();
+    notifier.set_connected(true);
+    let (fetcher, receiver) = BlockFetcher::with_notifier(fetcher_rpc, 8, notifier.clone());
+    fetcher.start(ChainAnchor { hash, height: 0 });
+
+    let wait_for = |height: u32| {
+        wait_for_blocks(&receiver, Duration::from_secs(5), |id, _| {
+            id.height == height
+        })
+    };
+
+    wait_for(2)?;
+    runtime.block_on(rig.mine_blocks(1, None))?;
+    notifier.notify();
+    wait_for(3)?;
+
+    fetcher.stop();
+    Ok(())
+}
+
+#[test]
+fn test_block_fetcher_wakes_on_zmq_hashblock() -> Result<()> {
+    let port = TcpListener::bind("127.0.0.1:0")?.local_addr()?.port();
+    let endpoint = format!("tcp://127.0.0.1:{}", port);
+    let mut conf = Conf::default();
+    conf.args = vec![
+        "-regtest",
+        "-rpcauth=user:70dbb4f60ccc95e154da97a43b7a9d06$00c10a3849edf2f10173e80d0bdadbde793ad9a80e6e6f9f71f978fb5c797343",
+        Box::leak(format!("-zmqpubhashblock={}", endpoint).into_boxed_str()),
+    ];
+    let bitcoind = BitcoinD::from_downloaded_with_conf(&conf)?;
+    let address = bitcoind
+        .client
+        .get_new_address(None, None)?
+        .assume_checked();
+    let genesis = ChainAnchor {
+        hash: bitcoind.client.get_block_hash(0)?,
+        height: 0,
+    };
+
+    let notifier = TipNotifier::default();
+    let (shutdown, _) = broadcast::channel(1);
+    zmq::listen_blocks(endpoint, notifier.clone(), shutdown.clone());
     let start_time = Instant::now();
+    while !notifier.is_connected() {
+        assert!(
+            start_time.elapsed() < Duration::from_secs(5),
+            "Timed out subscribing"
+        );
+        std::thread::sleep(Duration::from_millis(10));
+    }
 
-    loop {
-        if start_time.elapsed() > timeout {
-            panic!("Test timed out after {:?}", timeout);
+    // Polling falls back to a long interval while subscribed so
+    // the block can only be fetched in time if it was published
+    let source = BitcoinBlockSource::new(BitcoinRpc::new(
+        &bitcoind.rpc_url(),
+        BitcoinRpcAuth::UserPass("user".to_string(), "password".to_string()),
+    ));
+    let (fetcher, receiver) = BlockFetcher::with_notifier(source, 2, notifier);
+    fetcher.start(genesis);
+    // Give the publisher time to apply the subscription
+    std::thread::sleep(Duration::from_millis(500));
+    let mined = bitcoind.client.generate_to_address(1, &address)?;
+
+    wait_for_blocks(&receiver, Duration::from_secs(5), |id, _| {
+        assert_eq!(id.hash, mined[0]);
+        true
+    })?;
+
+    fetcher.stop();
+    shutdown.send(())?;
+    Ok(())
+}
+
+#[test]
+fn test_block_fetching_fails_over_to_healthy_endpoint() -> Result<()> {
+    const GENERATED_BLOCKS: u64 = 5;
+
+    let (rig, _, hash) = tokio::runtime::Runtime::new()?.block_on(setup(GENERATED_BLOCKS))?;
+    // Nothing listens on the first endpoint
+    let fetcher_rpc = BitcoinBlockSource::new(BitcoinRpc::with_endpoints(
+        &["http://127.0.0.1:1".to_string(), rig.bitcoind.rpc_url()],
+        BitcoinRpcAuth::UserPass("user".to_string(), "password".to_string()),
+    ));
+    let (fetcher, receiver) = BlockFetcher::new(fetcher_rpc, 8);
+    fetcher.start(ChainAnchor { hash, height: 0 });
+
+    wait_for_blocks(&receiver, Duration::from_secs(10), |id, _| {
+        id.height == GENERATED_BLOCKS as u32
+    })?;
+
+    fetcher.stop();
+    Ok(())
+}
+
+/// Serves blocks from bitcoind altering the coinbase of one of them
+#[derive(Clone)]
+struct TamperedSource {
+    inner: BitcoinBlockSource,
+    height: u32,
+}
+
+impl BlockSource for TamperedSource {
+    fn get_block_hash(&self, height: u32) -> Result<BlockHash, BitcoinRpcError> {
+        self.inner.get_block_hash(height)
+    }
+
+    fn get_block(&self, hash: &BlockHash) -> Result<Block, BitcoinRpcError> {
+        let mut block = self.inner.get_block(hash)?;
+        if *hash == self.inner.get_block_hash(self.height)? {
+            block.txdata[0].input[0].sequence = Sequence::ZERO;
         }
-        match receiver.try_recv() {
-            Ok(BlockEvent::Block(id, _)) => {
-                height += 1;
-                if id.height == GENERATED_BLOCKS as u32 {
-                    break;
-                }
-            }
-            Ok(BlockEvent::Error(e)) => panic!("Unexpected error: {}", e),
-            Err(TryRecvError::Empty) => {
-                std::thread::sleep(Duration::from_millis(10));
-            }
-            Err(TryRecvError::Disconnected) => panic!("Disconnected unexpectedly"),
+        Ok(block)
+    }
+
+    fn get_median_time(&self) -> Result<u64, BitcoinRpcError> {
+        self.inner.get_median_time()
+    }
+
+    fn get_block_count(&self) -> Result<u64, BitcoinRpcError> {
+        self.inner.get_block_count()
+    }
+
+    fn get_best_chain(&self) -> Result<ChainAnchor, BitcoinRpcError> {
+        self.inner.get_best_chain()
+    }
+}
+
+#[test]
+fn test_block_fetcher_rejects_invalid_blocks() -> Result<()> {
+    const GENERATED_BLOCKS: u64 = 5;
+    const TAMPERED_HEIGHT: u32 = 3;
+
+    let (rig, _, hash) = tokio::runtime::Runtime::new()?.block_on(setup(GENERATED_BLOCKS))?;
+    let source = TamperedSource {
+        inner: BitcoinBlockSource::new(BitcoinRpc::new(
+            &rig.bitcoind.rpc_url(),
+            BitcoinRpcAuth::UserPass("user".to_string(), "password".to_string()),
+        )),
+        height: TAMPERED_HEIGHT,
+    };
+    let (fetcher, receiver) = BlockFetcher::new(source, 8);
+    let fetcher = fetcher.with_rules(HeaderRules::new(ExtendedNetwork::Regtest));
+    fetcher.start(ChainAnchor { hash, height: 0 });
+
+    let result = wait_for_blocks(&receiver, Duration::from_secs(5), |id, _| {
+        assert!(
+            id.height < TAMPERED_HEIGHT,
+            "block {} should not be emitted",
+            id.height
+        );
+        false
+    });
+    match result {
+        Err(BlockFetchError::InvalidBlock(id, _)) => assert_eq!(id.height, TAMPERED_HEIGHT),
+        other => panic!("Expected an invalid block, got {:?}", other.err()),
+    }
+
+    fetcher.stop();
+    Ok(())
+}
+
+/// Fails fetching a block once as if bitcoind was restarting
+#[derive(Clone)]
+struct FlakySource {
+    inner: BitcoinBlockSource,
+    height: u32,
+    failed: Arc<AtomicBool>,
+}
+
+impl BlockSource for FlakySource {
+    fn get_block_hash(&self, height: u32) -> Result<BlockHash, BitcoinRpcError> {
+        if height == self.height && !self.failed.swap(true, Ordering::SeqCst) {
+            return Err(BitcoinRpcError::CircuitOpen(Duration::from_secs(1)));
         }
+        self.inner.get_block_hash(height)
     }
 
-    assert_eq!(height, GENERATED_BLOCKS, "Not all blocks were received");
+    fn get_block(&self, hash: &BlockHash) -> Result<Block, BitcoinRpcError> {
+        self.inner.get_block(hash)
+    }
+
+    fn get_median_time(&self) -> Result<u64, BitcoinRpcError> {
+        self.inner.get_median_time()
+    }
+
+    fn get_block_count(&self) -> Result<u64, BitcoinRpcError> {
+        self.inner.get_block_count()
+    }
+
+    fn get_best_chain(&self) -> Result<ChainAnchor, BitcoinRpcError> {
+        self.inner.get_best_chain()
+    }
+}
+
+#[test]
+fn test_block_fetcher_recovers_from_temporary_errors() -> Result<()> {
+    const GENERATED_BLOCKS: u64 = 10;
+
+    let (rig, _, hash) = tokio::runtime::Runtime::new()?.block_on(setup(GENERATED_BLOCKS))?;
+    let source = FlakySource {
+        inner: BitcoinBlockSource::new(BitcoinRpc::new(
+            &rig.bitcoind.rpc_url(),
+            BitcoinRpcAuth::UserPass("user".to_string(), "password".to_string()),
+        )),
+        height: 5,
+        failed: Default::default(),
+    };
+    let (fetcher, receiver) = BlockFetcher::new(source, 2);
+    fetcher.start(ChainAnchor { hash, height: 0 });
+
+    let mut height = 0;
+    wait_for_blocks(&receiver, Duration::from_secs(10), |id, _| {
+        height += 1;
+        assert_eq!(id.height, height, "blocks should be emitted once in order");
+        id.height == GENERATED_BLOCKS as u32
+    })?;
+
+    fetcher.stop();
+    Ok(())
+}
+
+#[test]
+fn test_rotated_cookie_is_reloaded_when_unauthorized() -> Result<()> {
+    let chain = MockChain::new();
+    chain.mine_blocks(3);
+    chain.set_credentials(Some("user:stale"));
+    let dir = tempdir()?;
+    let cookie = dir.path().join(".cookie");
+    std::fs::write(&cookie, "user:stale")?;
+    let modified = std::fs::metadata(&cookie)?.modified()?;
+
+    let source = BitcoinBlockSource::new(BitcoinRpc::new(
+        &chain.serve()?,
+        BitcoinRpcAuth::CookieFile(cookie.clone()),
+    ));
+    assert_eq!(source.get_block_count()?, 3);
+
+    // bitcoind restarts rotating the cookie within the resolution of the
+    // modification time so the rotation is only noticed through the 401
+    chain.set_credentials(Some("user:password"));
+    std::fs::write(&cookie, "user:password")?;
+    std::fs::File::options()
+        .write(true)
+        .open(&cookie)?
+        .set_modified(modified)?;
+    assert_eq!(source.get_block_count()?, 3);
+    assert_eq!(
+        chain.unauthorized_requests(),
+        1,
+        "the request should be retried once with the new cookie"
+    );
+
+    // A cookie that's still stale after reloading isn't retried
+    chain.set_credentials(Some("user:other"));
+    assert!(
+        source.get_block_count().is_err(),
+        "stale cookie should be rejected"
+    );
+    assert_eq!(chain.unauthorized_requests(), 2);
+    Ok(())
+}
+
+#[test]
+fn test_rotated_cookie_is_polled() -> Result<()> {
+    let chain = MockChain::new();
+    chain.mine_blocks(3);
+    chain.set_credentials(Some("user:stale"));
+    let dir = tempdir()?;
+    let cookie = dir.path().join(".cookie");
+    std::fs::write(&cookie, "user:stale")?;
+    let modified = std::fs::metadata(&cookie)?.modified()?;
+
+    let source = BitcoinBlockSource::new(BitcoinRpc::new(
+        &chain.serve()?,
+        BitcoinRpcAuth::CookieFile(cookie.clone()),
+    ));
+    assert_eq!(source.get_block_count()?, 3);
+
+    // A cookie with a new modification time is picked up before
+    // sending once it's due to be checked again
+    chain.set_credentials(Some("user:password"));
+    std::fs::write(&cookie, "user:password")?;
+    std::fs::File::options()
+        .write(true)
+        .open(&cookie)?
+        .set_modified(modified + Duration::from_secs(1))?;
+    std::thread::sleep(Duration::from_millis(1100));
+    assert_eq!(source.get_block_count()?, 3);
+    assert_eq!(chain.unauthorized_requests(), 0);
     Ok(())
 }
@@ -0,0 +1,63 @@
+use spaced::rpc::{NodeStatus, RpcClient};
+use testutil::TestRig;
+
+async fn check(rig: &TestRig, path: &str) -> anyhow::Result<(u16, NodeStatus)> {
+    let response = reqwest::get(format!("{}{path}", rig.spaced.rpc_url())).await?;
+    Ok((response.status().as_u16(), response.json().await?))
+}
+
+#[tokio::test]
+async fn it_should_report_health_and_readiness() -> anyhow::Result<()> {
+    let rig = TestRig::new().await?;
+    rig.mine_blocks(5, None).await?;
+    rig.wait_until_synced().await?;
+    let height = rig.get_block_count().await? as u32;
+
+    let (code, health) = check(&rig, "/health").await?;
+    assert_eq!(code, 200, "bitcoind should be reachable");
+    assert!(health.healthy && health.bitcoind.reachable);
+    assert_eq!(health.bitcoind.tip, Some(height));
+
+    let (code, ready) = check(&rig, "/ready").await?;
+    assert_eq!(code, 200, "spaced should be ready once synced");
+    assert!(ready.ready && ready.store.consistent);
+    assert_eq!(ready.store.tip.height, height);
+
+    let status = rig.spaced.client.get_node_status().await?;
+    assert!(status.ready);
+    assert_eq!(status.sync.tip, height);
+    assert!(status.wallets.is_empty(), "no wallets were loaded");
+
+    rig.spaced.client.wallet_create("alice").await?;
+    rig.wait_until_wallet_synced("alice").await?;
+    let status = rig.spaced.client.get_node_status().await?;
+    assert_eq!(status.wallets.len(), 1);
+    assert_eq!(status.wallets[0].name, "alice");
+    assert_eq!(status.wallets[0].tip, Some(height));
+
+    // wallets are left out of the probes
+    let (code, health) = check(&rig, "/health").await?;
+    assert_eq!(code, 200);
+    assert!(health.wallets.is_empty());
+    let body = reqwest::get(format!("{}/ready", rig.spaced.rpc_url()))
+        .await?
+        .text()
+        .await?;
+    assert!(!body.contains("wallets"), "unexpected wallets in {}", body);
+    Ok(())
+}
+
+#[tokio::test]
+async fn it_should_report_sync_status() -> anyhow::Result<()> {
+    let rig = TestRig::new().await?;
+    rig.mine_blocks(3, None).await?;
+    rig.wait_until_synced().await?;
+    let height = rig.get_block_count().await? as u32;
+
+    let status = rig.spaced.client.get_sync_status().await?;
+    assert!(status.synced, "spaced should be synced: {}", status);
+    assert_eq!(status.tip, height);
+    assert_eq!(status.bitcoin_tip, Some(height));
+    assert_eq!(status.eta_seconds, Some(0));
+    Ok(())
+}
@@ -4,7 +4,9 @@ use protocol::bitcoin::{Amount, FeeRate};
 use protocol::constants::RENEWAL_INTERVAL;
 use protocol::{Covenant};
 use protocol::script::SpaceScript;
-use spaced::rpc::{BidParams, ExecuteParams, OpenParams, RegisterParams, RpcClient, RpcWalletRequest, RpcWalletTxBuilder, TransferSpacesParams};
+use protocol::constants::ROLLOUT_BLOCK_INTERVAL;
+use spaced::rpc::{BidParams, ExecuteParams, OpenParams, RegisterParams, RpcClient, RpcWalletRequest, RpcWalletTxBuilder, Stats, TransferSpacesParams};
+use spaced::store::StateDiffEntry;
 use spaced::wallets::{AddressKind, WalletResponse};
 use testutil::{TestRig};
 use wallet::export::WalletExport;
@@ -470,6 +472,49 @@ async fn it_should_maintain_locktime_when_fee_bumping(rig: &TestRig) -> anyhow::
     Ok(())
 }
 
+async fn it_should_diff_committed_snapshots(rig: &TestRig, start_height: u32) -> anyhow::Result<()> {
+    // snapshots are committed every 36 blocks
+    rig.mine_blocks(36, None).await?;
+    rig.wait_until_synced().await?;
+
+    let tip = rig.spaced.client.get_server_info().await?.tip;
+    let from = start_height - (start_height % 36);
+    let to = tip.height - (tip.height % 36);
+
+    let diff = rig.spaced.client.get_state_diff(from, to).await?;
+    assert_eq!(diff.from.height, from, "from snapshot height");
+    assert_eq!(diff.to.height, to, "to snapshot height");
+
+    // @test9880 was registered in between so its outpoint must have changed
+    let registered = diff.updated.iter().any(|entry| match entry {
+        StateDiffEntry::Space { spaceout: Some(full), outpoint, .. } => {
+            assert_eq!(full.txid, outpoint.txid, "spaceout must be resolved from its outpoint");
+            full.spaceout.space.as_ref().is_some_and(|space| space.name.to_string() == "@test9880")
+        }
+        _ => false,
+    });
+    assert!(registered, "expected @test9880 to be updated");
+
+    let empty = rig.spaced.client.get_state_diff(to, to).await?;
+    assert!(empty.inserted.is_empty() && empty.updated.is_empty() && empty.removed.is_empty(),
+            "diff of a snapshot with itself must be empty");
+
+    assert!(rig.spaced.client.get_state_diff(to, from).await.is_err(), "from must not be after to");
+    Ok(())
+}
+
+async fn it_should_count_burns_and_revocations(rig: &TestRig, before: &Stats) -> anyhow::Result<()> {
+    let stats = rig.spaced.client.get_stats().await?;
+    assert_eq!(stats.tip, rig.spaced.client.get_server_info().await?.tip, "stats must be at the tip");
+    assert!(stats.counters.burned >= before.counters.burned + Amount::from_sat(TEST_INITIAL_BID),
+            "opening and bidding must burn at least the initial bid");
+    assert_eq!(stats.counters.revocations.bid_psbt, before.counters.revocations.bid_psbt + 1,
+               "the zero value bid must be revoked");
+    assert!(stats.next_rollout_height > stats.tip.height, "next rollout must be after the tip");
+    assert_eq!((stats.next_rollout_height - 1) % ROLLOUT_BLOCK_INTERVAL, 0, "rollouts happen every interval");
+    Ok(())
+}
+
 #[tokio::test]
 async fn run_auction_tests() -> anyhow::Result<()> {
     let rig = TestRig::new_with_regtest_preset().await?;
@@ -482,6 +527,8 @@ async fn run_auction_tests() -> anyhow::Result<()> {
     load_wallet(&rig, wallets_path.clone(), ALICE).await?;
     load_wallet(&rig, wallets_path.clone(), BOB).await?;
     load_wallet(&rig, wallets_path, EVE).await?;
+    let start_height = rig.spaced.client.get_server_info().await?.tip.height;
+    let stats = rig.spaced.client.get_stats().await?;
 
     it_should_open_a_space_for_auction(&rig).await?;
     it_should_allow_outbidding(&rig).await?;
@@ -491,6 +538,8 @@ async fn run_auction_tests() -> anyhow::Result<()> {
     it_should_allow_applying_script_in_batch(&rig).await?;
     it_should_replace_mempool_bids(&rig).await?;
     it_should_maintain_locktime_when_fee_bumping(&rig).await?;
+    it_should_diff_committed_snapshots(&rig, start_height).await?;
+    it_should_count_burns_and_revocations(&rig, &stats).await?;
 
     Ok(())
 }
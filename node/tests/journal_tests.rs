@@ -0,0 +1,122 @@
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use spaced::{node::protocol::constants::ChainAnchor, sync::Spaced, undo::UndoLog};
+use testutil::{bitcoind::tempfile::tempdir, mock::MockChain, tip};
+use tokio::sync::broadcast;
+
+/// Drops staged state as if the process was restarted
+fn restart(spaced: &mut Spaced, chain: &MockChain) -> anyhow::Result<()> {
+    let genesis = ChainAnchor {
+        hash: chain.block_at(0).expect("genesis").block_hash(),
+        height: 0,
+    };
+    spaced.chain.state = spaced.chain.store.begin(&genesis)?;
+    spaced.undo = UndoLog::memory(6);
+    spaced.recover()
+}
+
+#[test]
+fn it_should_replay_journaled_blocks() -> anyhow::Result<()> {
+    let dir = tempdir()?;
+    let chain = MockChain::new();
+    let mut spaced = chain.spaced(dir.path(), 6)?;
+    chain.mine_blocks(40);
+    chain.apply_blocks(&mut spaced)?;
+    assert_eq!(
+        spaced.chain.state.committed_tip()?.map(|t| t.height),
+        Some(36)
+    );
+    assert_eq!(spaced.journal.len(), 4);
+
+    restart(&mut spaced, &chain)?;
+    assert_eq!(
+        tip(&spaced),
+        chain.tip(),
+        "journaled blocks should be replayed"
+    );
+    assert_eq!(
+        spaced.chain.state.committed_tip()?.map(|t| t.height),
+        Some(36)
+    );
+
+    // the replayed undo records can disconnect blocks
+    chain.reorg(3);
+    assert!(spaced.rewind(&chain)?);
+    chain.apply_blocks(&mut spaced)?;
+    assert_eq!(tip(&spaced), chain.tip());
+    Ok(())
+}
+
+#[test]
+fn it_should_commit_off_boundary_on_shutdown() -> anyhow::Result<()> {
+    let dir = tempdir()?;
+    let chain = MockChain::new();
+    let mut spaced = chain.spaced(dir.path(), 6)?;
+    chain.mine_blocks(140);
+
+    let progress = spaced.sync_progress.clone();
+    let (shutdown, _) = broadcast::channel(1);
+    let sync = {
+        let chain = chain.clone();
+        let shutdown = shutdown.clone();
+        thread::spawn(move || spaced.protocol_sync(chain, shutdown).map(|_| spaced))
+    };
+    let start = Instant::now();
+    while progress.status().tip < 140 {
+        assert!(!sync.is_finished(), "sync stopped early");
+        assert!(start.elapsed() < Duration::from_secs(10), "sync timed out");
+        thread::sleep(Duration::from_millis(50));
+    }
+    shutdown.send(())?;
+    let mut spaced = sync.join().expect("sync thread")?;
+
+    let committed = spaced.chain.state.committed_tip()?;
+    assert_eq!(
+        committed,
+        Some(chain.tip()),
+        "staged blocks should be committed"
+    );
+    assert!(spaced.journal.is_empty());
+
+    // blocks on the following boundaries are still committed so the
+    // rollout at height 145 begins on a clean state
+    chain.mine_blocks(10);
+    restart(&mut spaced, &chain)?;
+    chain.apply_blocks(&mut spaced)?;
+    assert_eq!(tip(&spaced), chain.tip());
+    assert_eq!(
+        spaced.chain.state.committed_tip()?.map(|t| t.height),
+        Some(144)
+    );
+    Ok(())
+}
+
+#[test]
+fn it_should_keep_recovery_data_when_restore_fails() -> anyhow::Result<()> {
+    let dir = tempdir()?;
+    let chain = MockChain::new();
+    let mut spaced = chain.spaced(dir.path(), 6)?;
+    chain.mine_blocks(40);
+    chain.apply_blocks(&mut spaced)?;
+    assert_eq!(spaced.journal.len(), 4);
+    let undo_depth = spaced.undo.iter().count();
+    assert!(undo_depth > 0);
+
+    // a source that can't confirm any snapshot
+    assert!(spaced.restore(&MockChain::new()).is_err());
+    assert_eq!(
+        spaced.journal.len(),
+        4,
+        "journal must survive a failed restore"
+    );
+    assert_eq!(spaced.undo.iter().count(), undo_depth);
+
+    spaced.restore(&chain)?;
+    assert!(spaced.journal.is_empty());
+    assert_eq!(spaced.undo.iter().count(), 0);
+    assert_eq!(tip(&spaced).height, 36);
+    Ok(())
+}
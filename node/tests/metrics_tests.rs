@@ -0,0 +1,72 @@
+use std::{str::FromStr, time::Duration};
+
+use spaced::rpc::RpcClient;
+use testutil::TestRig;
+use wallet::export::WalletExport;
+
+const ALICE: &str = "wallet_99";
+
+async fn scrape(rig: &TestRig) -> anyhow::Result<String> {
+    let response = reqwest::get(format!("{}/metrics", rig.spaced.rpc_url())).await?;
+    assert!(response.status().is_success(), "metrics should be served");
+    Ok(response.text().await?)
+}
+
+/// Value of the sample with the given name and labels
+fn value(metrics: &str, sample: &str) -> Option<f64> {
+    metrics
+        .lines()
+        .find_map(|line| line.strip_prefix(sample)?.strip_prefix(' ')?.parse().ok())
+}
+
+#[tokio::test]
+async fn it_should_serve_metrics() -> anyhow::Result<()> {
+    let rig = TestRig::new_with_regtest_preset().await?;
+    rig.wait_until_synced().await?;
+    let tip = rig.spaced.client.get_server_info().await?.tip.height;
+
+    let json = std::fs::read_to_string(
+        rig.testdata_wallets_path()
+            .await
+            .join(format!("{ALICE}.json")),
+    )?;
+    rig.spaced
+        .client
+        .wallet_import(WalletExport::from_str(&json)?)
+        .await?;
+
+    let wallet_height = format!("spaced_wallet_tip_height{{wallet=\"{ALICE}\"}}");
+    let mut metrics = scrape(&rig).await?;
+    for _ in 0..100 {
+        if value(&metrics, &wallet_height) == Some(tip as f64) {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        metrics = scrape(&rig).await?;
+    }
+
+    assert_eq!(value(&metrics, "spaced_tip_height"), Some(tip as f64));
+    assert_eq!(value(&metrics, "spaced_tip_lag_blocks"), Some(0.0));
+    assert!(value(&metrics, "spaced_blocks_applied_total").is_some_and(|n| n >= 1.0));
+    assert!(
+        value(&metrics, "spaced_txs_matched_total").is_some_and(|n| n > 0.0),
+        "the preset chain contains spaces transactions"
+    );
+    assert!(value(&metrics, "spaced_updates_total{kind=\"bid\"}").is_some_and(|n| n > 0.0));
+    assert!(value(&metrics, "spaced_staged_bytes{store=\"chain\"}").is_some());
+    assert!(value(&metrics, "spaced_commit_duration_seconds_count").is_some_and(|n| n > 0.0));
+    assert!(
+        value(
+            &metrics,
+            "spaced_rpc_request_duration_seconds_count{method=\"getserverinfo\"}"
+        )
+        .is_some_and(|n| n >= 1.0),
+        "rpc calls should be timed"
+    );
+    assert_eq!(
+        value(&metrics, &wallet_height),
+        Some(tip as f64),
+        "wallet should sync to the tip"
+    );
+    Ok(())
+}
@@ -0,0 +1,379 @@
+use std::{
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use protocol::{
+    bitcoin::{hashes::Hash, Address, Amount, BlockHash, Network, ScriptBuf, TxOut},
+    constants::ChainAnchor,
+};
+use serde_json::json;
+use spaced::{
+    config::{CustomChain, ExtendedNetwork},
+    headers::HeaderRules,
+    metrics::Metrics,
+    node::BlockSource,
+    rpc::WalletManager,
+    source::{
+        BitcoinBlockSource, BitcoinRpc, BitcoinRpcAuth, BitcoinRpcError, BlockFetchError,
+        BlockFetcher, RetryPolicy, TipNotifier,
+    },
+    sync::Spaced,
+    wallets::{AddressKind, RpcWallet},
+};
+use testutil::{bitcoind::tempfile::tempdir, mock::MockChain, wait_for_blocks};
+use tokio::sync::{broadcast, mpsc};
+use wallet::{
+    address::{SpaceAddress, SpaceHrp},
+    bdk_wallet::chain::ConfirmationTime,
+};
+
+#[test]
+fn test_block_fetching_from_mock_chain() -> Result<()> {
+    let chain = MockChain::new();
+    let genesis = chain.tip();
+    chain.mine_blocks(10);
+
+    let (fetcher, receiver) = BlockFetcher::new(chain.clone(), 4);
+    let fetcher = fetcher.with_rules(HeaderRules::new(ExtendedNetwork::Regtest));
+    fetcher.start(genesis);
+
+    let mut tip = genesis;
+    let mut reorged = false;
+    let result = wait_for_blocks(&receiver, Duration::from_secs(5), |id, block| {
+        assert_eq!(
+            id.height,
+            tip.height + 1,
+            "blocks should be emitted in order"
+        );
+        assert_eq!(block.header.prev_blockhash, tip.hash);
+        tip = id;
+        if tip.height == 10 && !reorged {
+            let replaced = chain.reorg(2);
+            assert_ne!(replaced[1], tip.hash);
+            reorged = true;
+        }
+        false
+    });
+    match result {
+        Err(BlockFetchError::BlockMismatch) => {
+            assert!(reorged, "mismatch should only follow the reorg")
+        }
+        other => panic!("Expected a block mismatch, got {:?}", other.err()),
+    }
+
+    fetcher.stop();
+    Ok(())
+}
+
+#[test]
+fn test_bitcoin_rpc_against_mock_chain() -> Result<()> {
+    let chain = MockChain::new();
+    chain.mine_blocks(3);
+    let source = BitcoinBlockSource::new(BitcoinRpc::new(&chain.serve()?, BitcoinRpcAuth::None));
+
+    let tip = chain.tip();
+    assert_eq!(source.get_best_chain()?, tip);
+    assert_eq!(source.get_block_count()?, 3);
+    assert_eq!(
+        source.get_block_hash(2)?,
+        chain.block_at(2).unwrap().block_hash()
+    );
+    assert_eq!(source.get_block(&tip.hash)?, chain.block_at(3).unwrap());
+    assert_eq!(source.get_best_chain_work()?, chain.get_best_chain_work()?);
+    assert!(source.get_block_hash(4).is_err(), "no block above the tip");
+
+    chain.set_median_time(Some(1_700_000_000));
+    assert_eq!(source.get_median_time()?, 1_700_000_000);
+
+    let tx = chain
+        .spend_coinbase(
+            1,
+            vec![TxOut {
+                value: Amount::from_sat(10_000),
+                script_pubkey: ScriptBuf::new(),
+            }],
+        )
+        .unwrap();
+    let confirmation = source.rpc.broadcast_tx(&source.client, &tx)?;
+    assert!(matches!(confirmation, ConfirmationTime::Unconfirmed { .. }));
+    assert_eq!(chain.mempool(), vec![tx.clone()]);
+
+    chain.mine_blocks(1);
+    assert!(chain.mempool().is_empty());
+    let block = source.get_block(&source.get_best_chain()?.hash)?;
+    assert_eq!(block.txdata[1], tx, "mempool should be mined");
+
+    // Reorged transactions return to the mempool
+    chain.invalidate_blocks(1);
+    assert_eq!(source.get_best_chain()?, tip);
+    assert_eq!(chain.mempool(), vec![tx]);
+    Ok(())
+}
+
+#[test]
+fn test_block_fetching_over_rest() -> Result<()> {
+    let chain = MockChain::new();
+    chain.mine_blocks(3);
+    let rpc =
+        BitcoinRpc::new(&chain.serve()?, BitcoinRpcAuth::None).with_retry_policy(RetryPolicy {
+            initial_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(20),
+            max_elapsed: Duration::from_millis(100),
+            jitter: 0.0,
+            breaker_threshold: 0,
+            breaker_cooldown: Duration::ZERO,
+        });
+    let rest = BitcoinBlockSource::new(rpc.clone()).with_rest(true);
+    let tip = chain.block_at(3).unwrap();
+    assert_eq!(rest.get_block_hash(3)?, tip.block_hash());
+    assert_eq!(rest.get_block(&tip.block_hash())?, tip);
+
+    // unknown blocks are reported like over JSON-RPC and not retried
+    match rest.get_block(&BlockHash::all_zeros()) {
+        Err(BitcoinRpcError::Rpc(e)) => assert_eq!(e.code, -5),
+        other => panic!("Expected a not found error, got {:?}", other.map(|_| ())),
+    }
+
+    // bitcoind warming up is a temporary error
+    chain.set_rest_status(Some("503 Service Unavailable"));
+    let e = rest.get_block(&tip.block_hash()).unwrap_err();
+    assert!(e.is_temporary(), "{} should be temporary", e);
+
+    // JSON-RPC is used when REST is disabled
+    let json_rpc = BitcoinBlockSource::new(rpc).with_rest(false);
+    assert_eq!(json_rpc.get_block_hash(3)?, tip.block_hash());
+    assert_eq!(json_rpc.get_block(&tip.block_hash())?, tip);
+    Ok(())
+}
+
+#[test]
+fn test_diverging_endpoint_is_excluded() -> Result<()> {
+    let majority = MockChain::new();
+    majority.mine_blocks(2);
+    let diverging = MockChain::new();
+    for _ in 0..3 {
+        diverging.mine_block_to(ScriptBuf::new());
+    }
+
+    // The diverging endpoint is listed first and has the highest tip
+    // so it would be preferred if it was still followed
+    let urls = vec![diverging.serve()?, majority.serve()?, majority.serve()?];
+    let source = BitcoinBlockSource::new(BitcoinRpc::with_endpoints(&urls, BitcoinRpcAuth::None));
+    assert_eq!(source.get_best_chain()?, diverging.tip());
+
+    source.rpc.check_endpoints_blocking(&source.client);
+    for _ in 0..3 {
+        assert_eq!(source.get_best_chain()?, majority.tip());
+    }
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_diverging_endpoint_is_excluded_from_async_requests() -> Result<()> {
+    let majority = MockChain::new();
+    majority.mine_blocks(2);
+    let diverging = MockChain::new();
+    for _ in 0..3 {
+        diverging.mine_block_to(ScriptBuf::new());
+    }
+
+    // Requests made through async clients (e.g. wallets, rpc passthrough)
+    // check endpoints themselves without a block source having to
+    let urls = vec![diverging.serve()?, majority.serve()?, majority.serve()?];
+    let rpc = BitcoinRpc::with_endpoints(&urls, BitcoinRpcAuth::None);
+    let client = reqwest::Client::new();
+    for _ in 0..3 {
+        let info: serde_json::Value = rpc.send_json(&client, &rpc.get_blockchain_info()).await?;
+        assert_eq!(info["bestblockhash"], json!(majority.tip().hash));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_mock_chain_reorg() {
+    let chain = MockChain::new();
+    let mined = chain.mine_blocks(5);
+    let replaced = chain.reorg(2);
+
+    assert_eq!(
+        chain.tip(),
+        ChainAnchor {
+            hash: replaced[1],
+            height: 5
+        }
+    );
+    for height in 1..=3 {
+        assert_eq!(
+            chain.block_at(height).unwrap().block_hash(),
+            mined[height as usize - 1]
+        );
+    }
+    assert_ne!(mined[3..], replaced[..], "reorged blocks should differ");
+}
+
+#[test]
+fn test_stale_blocks_are_served() -> Result<()> {
+    let chain = MockChain::new();
+    let mined = chain.mine_blocks(5);
+    let stale = chain.block_at(5).unwrap();
+    chain.reorg(2);
+
+    let rpc = BitcoinRpc::new(&chain.serve()?, BitcoinRpcAuth::None);
+    let source = BitcoinBlockSource::new(rpc.clone());
+    assert_ne!(source.get_block_hash(5)?, mined[4]);
+    assert_eq!(source.get_block(&mined[4])?, stale);
+    assert_eq!(chain.get_block(&mined[4])?, stale);
+
+    let header: serde_json::Value =
+        rpc.send_json_blocking(&source.client, &rpc.get_block_header(&mined[4]))?;
+    assert_eq!(header["confirmations"], -1);
+    assert_eq!(header["height"], 5);
+    let header: serde_json::Value =
+        rpc.send_json_blocking(&source.client, &rpc.get_block_header(&mined[2]))?;
+    assert_eq!(header["confirmations"], 3);
+    assert_eq!(
+        header["nextblockhash"],
+        json!(chain.block_at(4).unwrap().block_hash())
+    );
+
+    // stale blocks are also served over REST
+    let rest = BitcoinBlockSource::new(rpc).with_rest(true);
+    assert_eq!(rest.get_block(&mined[3])?, chain.get_block(&mined[3])?);
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_wallet_sync_against_mock_chain() -> Result<()> {
+    let dir = tempdir()?;
+    let chain = MockChain::new();
+    chain.mine_blocks(5);
+    let mut spaced = chain.spaced(dir.path(), 6)?;
+    chain.apply_blocks(&mut spaced)?;
+
+    let (wallet_loader, loaded) = mpsc::channel(1);
+    let (shutdown, _) = broadcast::channel(1);
+    let service = tokio::spawn(RpcWallet::service(
+        ExtendedNetwork::Regtest,
+        spaced.rpc.clone(),
+        spaced.chain.state.clone(),
+        loaded,
+        shutdown.clone(),
+        1,
+        TipNotifier::default(),
+        Metrics::default(),
+    ));
+    let manager = WalletManager {
+        data_dir: dir.path().join("wallets"),
+        network: ExtendedNetwork::Regtest,
+        custom_chain: None,
+        rpc: spaced.rpc.clone(),
+        wallet_loader,
+        wallets: Default::default(),
+    };
+    manager
+        .create_wallet(&reqwest::Client::new(), "alice")
+        .await?;
+    let wallet = manager.wallets.read().await["alice"].clone();
+
+    let address = wallet.send_get_new_address(AddressKind::Coin).await?;
+    let address = Address::from_str(&address)?.require_network(Network::Regtest)?;
+    let coinbase = chain.mine_block_to(address.script_pubkey());
+    chain.mine_blocks(1);
+    let reward = Amount::from_btc(50.0)?;
+    wait_for_balance(&wallet, reward).await?;
+
+    // reorging out the coinbase removes it from the wallet
+    chain.reorg(2);
+    chain.mine_blocks(1);
+    assert!(
+        chain.get_block(&coinbase).is_ok(),
+        "stale block is still served"
+    );
+    wait_for_balance(&wallet, Amount::ZERO).await?;
+
+    shutdown.send(())?;
+    service.await??;
+    Ok(())
+}
+
+async fn wait_for_balance(wallet: &RpcWallet, expected: Amount) -> Result<()> {
+    let start = Instant::now();
+    loop {
+        let total = wallet.send_get_balance().await?.details.balance.total();
+        if total == expected {
+            return Ok(());
+        }
+        if start.elapsed() > Duration::from_secs(10) {
+            panic!("Expected a balance of {} got {}", expected, total);
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+}
+
+#[test]
+fn test_custom_chain_activation() -> Result<()> {
+    let chain = MockChain::new();
+    chain.mine_blocks(5);
+    let rpc = BitcoinRpc::new(&chain.serve()?, BitcoinRpcAuth::None);
+    let mut custom = CustomChain {
+        name: "testchain".to_string(),
+        genesis_hash: chain.block_at(0).unwrap().block_hash(),
+        activation: ChainAnchor {
+            hash: BlockHash::all_zeros(),
+            height: 3,
+        },
+        space_hrp: Some("tcs".to_string()),
+        bitcoin_rpc_port: None,
+        rpc_port: None,
+    };
+
+    let runtime = tokio::runtime::Runtime::new()?;
+    let genesis = runtime.block_on(Spaced::genesis(
+        &rpc,
+        None,
+        None,
+        ExtendedNetwork::Regtest,
+        Some(&custom),
+    ))?;
+    assert_eq!(
+        genesis,
+        ChainAnchor {
+            hash: chain.block_at(3).unwrap().block_hash(),
+            height: 3
+        },
+        "activation hash should be looked up by height"
+    );
+
+    custom.genesis_hash = chain.block_at(1).unwrap().block_hash();
+    let wrong_chain = runtime.block_on(Spaced::genesis(
+        &rpc,
+        None,
+        None,
+        ExtendedNetwork::Regtest,
+        Some(&custom),
+    ));
+    assert!(
+        wrong_chain.is_err(),
+        "genesis hash must match the node's chain"
+    );
+
+    let space_address: SpaceAddress =
+        "bcrts1pqqqsyqcyq5rqwzqfpg9scrgwpugpzysnzs23v9ccrydpk8qarc0srrddnz".parse()?;
+    let hrp = custom.space_hrp(Network::Regtest)?;
+    let custom_address = space_address.display_with(hrp.as_ref()).to_string();
+    assert!(custom_address.starts_with("tcs1"));
+    let parsed = SpaceAddress::parse(&custom_address, hrp.as_ref())?;
+    assert_eq!(parsed.script_pubkey(), space_address.script_pubkey());
+
+    // the prefix is only used where it's passed in
+    assert!(space_address.to_string().starts_with("bcrts1"));
+    assert!(custom_address.parse::<SpaceAddress>().is_err());
+    let other = SpaceHrp::new("xyz", Network::Regtest)?;
+    assert!(space_address
+        .display_with(Some(&other))
+        .to_string()
+        .starts_with("xyz1"));
+    Ok(())
+}
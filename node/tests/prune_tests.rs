@@ -0,0 +1,182 @@
+use std::{collections::BTreeMap, fs, path::Path};
+
+use anyhow::Result;
+use protocol::{
+    bitcoin::{hashes::Hash, BlockHash},
+    constants::ChainAnchor,
+};
+use spaced::{
+    backend::DEFAULT_CACHE_SIZE,
+    prune::{compact, disk_usage, RetentionPolicy},
+    store::Store,
+};
+use testutil::{bitcoind::tempfile::tempdir, mock::MockChain};
+
+fn anchor(height: u32) -> ChainAnchor {
+    ChainAnchor {
+        hash: BlockHash::from_byte_array([height as u8; 32]),
+        height,
+    }
+}
+
+fn key(i: u32) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    key[..4].copy_from_slice(&i.to_be_bytes());
+    key
+}
+
+/// Commits a snapshot every 36 blocks up to `count` that inserts, updates
+/// and removes entries, returning the root of each by height
+fn populate(path: &Path, count: u32) -> Result<BTreeMap<u32, [u8; 32]>> {
+    let store = Store::open(path.to_path_buf(), DEFAULT_CACHE_SIZE)?;
+    let mut roots = BTreeMap::new();
+    for n in 1..=count {
+        let mut tx = store.write()?;
+        for i in 0..50 {
+            tx.insert(key(n * 100 + i), vec![n as u8; 64])?;
+        }
+        tx.insert(key(0), n.to_le_bytes().to_vec())?;
+        if n > 1 {
+            tx.remove(key((n - 1) * 100))?;
+        }
+        tx.metadata(anchor(n * 36).to_vec())?;
+        tx.commit()?;
+
+        let mut snapshot = store.iter().next().expect("snapshot")?;
+        roots.insert(n * 36, snapshot.compute_root()?);
+    }
+    Ok(roots)
+}
+
+fn snapshot_roots(path: &Path) -> Result<BTreeMap<u32, [u8; 32]>> {
+    let store = Store::open(path.to_path_buf(), DEFAULT_CACHE_SIZE)?;
+    let mut roots = BTreeMap::new();
+    for snapshot in store.iter() {
+        let mut snapshot = snapshot?;
+        if snapshot.metadata().is_empty() {
+            continue;
+        }
+        let anchor: ChainAnchor = snapshot.metadata().try_into()?;
+        roots.insert(anchor.height, snapshot.compute_root()?);
+    }
+    Ok(roots)
+}
+
+#[test]
+fn test_retention_policy() {
+    let policy = RetentionPolicy {
+        keep_last: 2,
+        keep_every: 144,
+    };
+    assert!(policy.retains(0, &anchor(36)));
+    assert!(policy.retains(1, &anchor(72)));
+    assert!(!policy.retains(2, &anchor(108)));
+    assert!(policy.retains(5, &anchor(288)));
+
+    let keep_last_only = RetentionPolicy {
+        keep_last: 1,
+        keep_every: 0,
+    };
+    assert!(!keep_last_only.retains(1, &anchor(0)));
+}
+
+#[test]
+fn test_compaction_deletes_pruned_snapshots() -> Result<()> {
+    let dir = tempdir()?;
+    let path = dir.path().join("protocol.sdb");
+    let roots = populate(&path, 10)?;
+    let policy = RetentionPolicy {
+        keep_last: 3,
+        keep_every: 144,
+    };
+
+    let compaction = compact(&path, &policy)?.expect("snapshots to prune");
+    assert_eq!(compaction.pruned, 6);
+    assert_eq!(compaction.retained, 4);
+    assert!(compaction.size_after < compaction.size_before);
+    assert_eq!(fs::metadata(&path)?.len(), compaction.size_after);
+
+    let retained = snapshot_roots(&path)?;
+    assert_eq!(
+        retained.keys().copied().collect::<Vec<_>>(),
+        vec![144, 288, 324, 360],
+        "the last 3 and every 144th snapshot should remain"
+    );
+    for (height, root) in retained {
+        assert_eq!(Some(&root), roots.get(&height), "root at {}", height);
+    }
+    assert_eq!(
+        fs::read_dir(dir.path())?.count(),
+        1,
+        "no compaction files should be left behind"
+    );
+
+    assert!(
+        compact(&path, &policy)?.is_none(),
+        "nothing is left to prune"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_snapshots_without_state_keep_no_slot() -> Result<()> {
+    let dir = tempdir()?;
+    let path = dir.path().join("protocol.sdb");
+    populate(&path, 3)?;
+    {
+        let store = Store::open(path.clone(), DEFAULT_CACHE_SIZE)?;
+        let mut tx = store.write()?;
+        tx.insert(key(1), vec![1])?;
+        tx.commit()?;
+    }
+    let policy = RetentionPolicy {
+        keep_last: 2,
+        keep_every: 0,
+    };
+
+    compact(&path, &policy)?.expect("snapshots to prune");
+    assert_eq!(
+        snapshot_roots(&path)?.keys().copied().collect::<Vec<_>>(),
+        vec![72, 108],
+        "two snapshots with chain state should remain"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_reorg_deeper_than_retained_snapshots() -> Result<()> {
+    let dir = tempdir()?;
+    let chain = MockChain::new();
+    let mut spaced = chain.spaced(dir.path(), 6)?;
+    spaced.retention = Some(RetentionPolicy {
+        keep_last: 1,
+        keep_every: 0,
+    });
+    chain.mine_blocks(40);
+    chain.apply_blocks(&mut spaced)?;
+
+    // replaces the block of the only snapshot
+    chain.reorg(10);
+    let error = spaced
+        .restore(&chain)
+        .expect_err("no snapshot is left to restore");
+    assert!(
+        error.to_string().contains("--prune-keep-last 1"),
+        "the error should name pruning: {}",
+        error
+    );
+    Ok(())
+}
+
+#[test]
+fn test_disk_usage() -> Result<()> {
+    let dir = tempdir()?;
+    fs::write(dir.path().join("a"), [0u8; 100])?;
+    fs::create_dir(dir.path().join("nested"))?;
+    fs::write(dir.path().join("nested").join("b"), [0u8; 50])?;
+
+    assert_eq!(disk_usage(dir.path()), 150);
+    assert_eq!(disk_usage(&dir.path().join("a")), 100);
+    assert_eq!(disk_usage(&dir.path().join("missing")), 0);
+    Ok(())
+}
@@ -1,7 +1,26 @@
-use std::time::Duration;
+use std::{
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
 
-use spaced::rpc::RpcClient;
-use testutil::TestRig;
+use spaced::{
+    node::BlockSource, progress::SyncProgress, rpc::RpcClient, source::BitcoinBlockSource,
+};
+use testutil::{bitcoind::tempfile::tempdir, mock::MockChain, tip, TestRig};
+use tokio::sync::broadcast;
+
+async fn reorgs(rig: &TestRig, method: &str) -> anyhow::Result<Option<f64>> {
+    let metrics = reqwest::get(format!("{}/metrics", rig.spaced.rpc_url()))
+        .await?
+        .text()
+        .await?;
+    let sample = format!("spaced_reorgs_total{{method=\"{}\"}}", method);
+    Ok(metrics.lines().find_map(|line| {
+        line.strip_prefix(&sample)
+            .and_then(|value| value.trim().parse().ok())
+    }))
+}
 
 #[tokio::test]
 async fn it_should_resync_after_reorg_at_same_height() -> anyhow::Result<()> {
@@ -20,3 +39,180 @@ async fn it_should_resync_after_reorg_at_same_height() -> anyhow::Result<()> {
         .await?;
     Ok(())
 }
+
+#[tokio::test]
+async fn it_should_disconnect_committed_blocks_on_shallow_reorg() -> anyhow::Result<()> {
+    let rig = TestRig::new().await?;
+    // block 36 is committed to a snapshot
+    rig.mine_blocks(37, None).await?;
+    rig.wait_until_synced().await?;
+
+    let info = rig.spaced.client.get_server_info().await?;
+    assert_eq!(info.tip.height, 37);
+
+    // replaces blocks 36 and 37
+    let reorged = rig.reorg(2).await?;
+    assert_eq!(reorged.len(), 2);
+
+    rig.wait_until_tip(reorged[1], Duration::from_secs(2))
+        .await?;
+    assert_eq!(reorgs(&rig, "rewind").await?, Some(1.0));
+    assert_eq!(
+        reorgs(&rig, "restore").await?,
+        None,
+        "blocks should be disconnected without restoring a snapshot"
+    );
+
+    // keeps syncing on top of the new chain
+    rig.mine_blocks(1, None).await?;
+    rig.wait_until_synced().await?;
+    let info = rig.spaced.client.get_server_info().await?;
+    assert_eq!(info.tip.height, 38);
+    assert_eq!(info.tip.hash, rig.get_best_block_hash().await?);
+    Ok(())
+}
+
+#[test]
+fn it_should_rewind_committed_blocks() -> anyhow::Result<()> {
+    let dir = tempdir()?;
+    let chain = MockChain::new();
+    let mut spaced = chain.spaced(dir.path(), 6)?;
+    chain.mine_blocks(37);
+    chain.apply_blocks(&mut spaced)?;
+    assert_eq!(tip(&spaced), chain.tip());
+
+    // block 36 was committed to a snapshot
+    chain.reorg(2);
+    assert!(spaced.rewind(&chain)?, "fork point is within the undo log");
+    assert_eq!(tip(&spaced).height, 35);
+    assert_eq!(tip(&spaced).hash, chain.get_block_hash(35)?);
+    assert_eq!(
+        spaced.chain.state.committed_tip()?,
+        Some(tip(&spaced)),
+        "the reorged snapshot must be replaced by one at the fork point"
+    );
+
+    // the off-boundary snapshot can be restored
+    spaced.restore(&chain)?;
+    assert_eq!(tip(&spaced).height, 35);
+
+    chain.apply_blocks(&mut spaced)?;
+    assert_eq!(tip(&spaced), chain.tip());
+    Ok(())
+}
+
+fn wait_for_height(progress: &SyncProgress, height: u32, sync: &thread::JoinHandle<impl Sized>) {
+    let start = Instant::now();
+    while progress.status().tip < height {
+        assert!(!sync.is_finished(), "sync stopped early");
+        assert!(start.elapsed() < Duration::from_secs(10), "sync timed out");
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[test]
+fn it_should_follow_reorgs_while_syncing_over_rpc() -> anyhow::Result<()> {
+    let dir = tempdir()?;
+    let chain = MockChain::new();
+    let mut spaced = chain.spaced(dir.path(), 6)?;
+    chain.mine_blocks(10);
+
+    let source = BitcoinBlockSource::new(spaced.rpc.clone());
+    let progress = spaced.sync_progress.clone();
+    let (shutdown, _) = broadcast::channel(1);
+    let sync = {
+        let shutdown = shutdown.clone();
+        thread::spawn(move || spaced.protocol_sync(source, shutdown).map(|_| spaced))
+    };
+    wait_for_height(&progress, 10, &sync);
+
+    let stale = chain.tip();
+    chain.reorg(3);
+    chain.mine_blocks(2);
+    wait_for_height(&progress, 12, &sync);
+    shutdown.send(())?;
+    let spaced = sync.join().expect("sync thread")?;
+
+    assert_eq!(tip(&spaced), chain.tip());
+    let source = BitcoinBlockSource::new(spaced.rpc.clone());
+    assert_eq!(
+        source.get_block(&stale.hash)?.block_hash(),
+        stale.hash,
+        "reorged out blocks are still served"
+    );
+    Ok(())
+}
+
+#[test]
+fn it_should_rewind_to_a_shorter_chain() -> anyhow::Result<()> {
+    let dir = tempdir()?;
+    let chain = MockChain::new();
+    let mut spaced = chain.spaced(dir.path(), 6)?;
+    chain.mine_blocks(10);
+    chain.apply_blocks(&mut spaced)?;
+
+    // the new best chain ends below the node's tip
+    chain.invalidate_blocks(3);
+    assert!(spaced.rewind(&chain)?);
+    assert_eq!(tip(&spaced), chain.tip());
+
+    // and may fork below its tip too
+    chain.invalidate_blocks(2);
+    chain.mine_blocks(1);
+    assert!(spaced.rewind(&chain)?);
+    assert_eq!(tip(&spaced).height, 5);
+    chain.apply_blocks(&mut spaced)?;
+    assert_eq!(tip(&spaced), chain.tip());
+    Ok(())
+}
+
+#[test]
+fn it_should_not_rewind_beyond_the_undo_log() -> anyhow::Result<()> {
+    let dir = tempdir()?;
+    let chain = MockChain::new();
+    let mut spaced = chain.spaced(dir.path(), 2)?;
+    chain.mine_blocks(10);
+    chain.apply_blocks(&mut spaced)?;
+
+    chain.reorg(3);
+    let before = tip(&spaced);
+    assert!(
+        !spaced.rewind(&chain)?,
+        "fork point is deeper than the undo log"
+    );
+    assert_eq!(tip(&spaced), before, "nothing should be disconnected");
+    Ok(())
+}
+
+#[test]
+fn it_should_not_roll_back_stores_under_readers() -> anyhow::Result<()> {
+    let dir = tempdir()?;
+    let chain = MockChain::new();
+    let mut spaced = chain.spaced(dir.path(), 0)?;
+    chain.mine_blocks(73);
+    chain.apply_blocks(&mut spaced)?;
+    let committed = spaced.chain.state.committed_tip()?.expect("committed");
+    assert_eq!(committed.height, 72);
+
+    // a reader holding the block lock keeps seeing the committed state
+    let handle = spaced.chain.state.read_handle();
+    let (started, reading) = mpsc::channel();
+    let reader = thread::spawn(move || {
+        handle.read(|state| {
+            started.send(()).expect("send");
+            thread::sleep(Duration::from_millis(300));
+            state.committed_tip()
+        })
+    });
+    reading.recv()?;
+
+    chain.reorg(2);
+    spaced.restore(&chain)?;
+    assert_eq!(
+        reader.join().expect("reader")?,
+        Some(committed),
+        "rollback must wait for readers"
+    );
+    assert_eq!(tip(&spaced).height, 36);
+    Ok(())
+}
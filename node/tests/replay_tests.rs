@@ -0,0 +1,85 @@
+use std::{sync::mpsc::Receiver, time::Duration};
+
+use anyhow::Result;
+use protocol::constants::ChainAnchor;
+use spaced::{
+    replay::{RecordingBlockSource, ReplayBlockSource},
+    source::{
+        BitcoinBlockSource, BitcoinRpc, BitcoinRpcAuth, BlockEvent, BlockFetchError, BlockFetcher,
+    },
+};
+use testutil::{bitcoind::tempfile::tempdir, wait_for_blocks, TestRig};
+
+/// Collects emitted blocks until one at `height` is received
+/// or a block mismatch is detected if `height` is `None`
+fn collect(receiver: &Receiver<BlockEvent>, height: Option<u32>) -> Vec<ChainAnchor> {
+    let mut emitted = Vec::new();
+    let result = wait_for_blocks(receiver, Duration::from_secs(10), |id, _| {
+        emitted.push(id);
+        Some(id.height) == height
+    });
+    match result {
+        Ok(()) => {}
+        Err(BlockFetchError::BlockMismatch) if height.is_none() => {}
+        Err(e) => panic!("Unexpected error: {}", e),
+    }
+    emitted
+}
+
+#[test]
+fn test_replay_recorded_sync() -> Result<()> {
+    let runtime = tokio::runtime::Runtime::new()?;
+    let rig = runtime.block_on(async {
+        let rig = TestRig::new().await?;
+        rig.mine_blocks(5, None).await?;
+        anyhow::Ok(rig)
+    })?;
+    let genesis = ChainAnchor {
+        hash: runtime.block_on(rig.get_block_hash(0))?,
+        height: 0,
+    };
+    let dir = tempdir()?;
+    let recording = dir.path().join("blocks.rec");
+
+    let bitcoin = BitcoinBlockSource::new(BitcoinRpc::new(
+        &rig.bitcoind.rpc_url(),
+        BitcoinRpcAuth::UserPass("user".to_string(), "password".to_string()),
+    ));
+    let source = RecordingBlockSource::create(&recording, bitcoin.clone(), false)?;
+    let (fetcher, receiver) = BlockFetcher::new(source, 2);
+    fetcher.start(genesis);
+    let mut recorded = collect(&receiver, Some(5));
+
+    runtime.block_on(rig.mine_blocks(2, None))?;
+    recorded.extend(collect(&receiver, Some(7)));
+
+    runtime.block_on(rig.reorg_empty_blocks(1))?;
+    recorded.extend(collect(&receiver, None));
+    fetcher.stop();
+
+    // an existing recording is only replaced when asked to
+    assert!(RecordingBlockSource::create(&recording, bitcoin.clone(), false).is_err());
+    let other = dir.path().join("other.rec");
+    std::fs::write(&other, b"old")?;
+    RecordingBlockSource::create(&other, bitcoin, true)?;
+    assert!(std::fs::read(&other)?.is_empty(), "overwritten");
+    drop(rig);
+
+    // Replays the same blocks and reorg without bitcoind
+    let replay = ReplayBlockSource::open(&recording)?;
+    let (fetcher, receiver) = BlockFetcher::new(replay.clone(), 2);
+    fetcher.start(genesis);
+    let replayed = collect(&receiver, None);
+    fetcher.stop();
+
+    assert_eq!(recorded.len(), 7);
+    assert_eq!(replayed, recorded, "replay should emit the recorded blocks");
+    assert!(replay.is_finished(), "all recorded tips should be served");
+    assert_eq!(replay.recorded_hash(1), Some(recorded[0].hash));
+
+    // a recording cut short while writing is rejected
+    let data = std::fs::read(&recording)?;
+    std::fs::write(&recording, &data[..data.len() - 1])?;
+    assert!(ReplayBlockSource::open(&recording).is_err());
+    Ok(())
+}
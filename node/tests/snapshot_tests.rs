@@ -0,0 +1,156 @@
+use std::{fs, io::Write, path::Path};
+
+use anyhow::Result;
+use bincode::config;
+use protocol::{
+    bitcoin::{
+        hashes::Hash,
+        secp256k1::{Keypair, Secp256k1, SecretKey},
+        BlockHash,
+    },
+    constants::ChainAnchor,
+};
+use spaced::{
+    snapshot::{read_snapshot, write_snapshot, SnapshotHeader},
+    store::Store,
+};
+use testutil::bitcoind::tempfile::tempdir;
+
+const NETWORK: &str = "regtest";
+
+fn anchor() -> ChainAnchor {
+    ChainAnchor {
+        hash: BlockHash::from_byte_array([7u8; 32]),
+        height: 144,
+    }
+}
+
+/// A committed store with a few hundred entries
+fn populated_store() -> Result<Store> {
+    let store = Store::memory()?;
+    let mut tx = store.write()?;
+    for i in 0..300u32 {
+        let mut key = [0u8; 32];
+        key[..4].copy_from_slice(&i.to_be_bytes());
+        key[31] = 1;
+        tx.insert(key, i.to_le_bytes().repeat(i as usize % 7 + 1))?;
+    }
+    tx.metadata(anchor().to_vec())?;
+    tx.commit()?;
+    Ok(store)
+}
+
+fn root(store: &Store) -> Result<[u8; 32]> {
+    store.iter().next().expect("snapshot")?.compute_root()
+}
+
+fn keypair(seed: u8) -> Keypair {
+    let secret = SecretKey::from_slice(&[seed; 32]).expect("valid key");
+    Keypair::from_secret_key(&Secp256k1::new(), &secret)
+}
+
+fn flip_byte(path: &Path, offset: usize) -> Result<()> {
+    let mut data = fs::read(path)?;
+    data[offset] ^= 0xff;
+    fs::write(path, data)?;
+    Ok(())
+}
+
+#[test]
+fn test_snapshot_round_trip() -> Result<()> {
+    let dir = tempdir()?;
+    let path = dir.path().join("state.snapshot");
+    let store = populated_store()?;
+
+    let (header, entries) = write_snapshot(&store, NETWORK, &path, None)?;
+    assert_eq!(entries, 300);
+    assert_eq!(header.anchor, anchor());
+    assert_eq!(
+        fs::read_dir(dir.path())?.count(),
+        1,
+        "no temporary files should be left behind"
+    );
+
+    let (read, imported) = read_snapshot(&path, NETWORK, None)?;
+    assert_eq!(read.root, root(&store)?);
+    assert_eq!(root(&imported)?, root(&store)?);
+    assert!(
+        read_snapshot(&path, "mainnet", None).is_err(),
+        "snapshots are bound to their network"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_snapshot_corruption_is_detected() -> Result<()> {
+    let dir = tempdir()?;
+    let path = dir.path().join("state.snapshot");
+    write_snapshot(&populated_store()?, NETWORK, &path, None)?;
+    let len = fs::metadata(&path)?.len() as usize;
+
+    // A flipped byte within an entry decodes fine but fails the digest
+    flip_byte(&path, len / 2)?;
+    assert!(read_snapshot(&path, NETWORK, None).is_err());
+
+    write_snapshot(&populated_store()?, NETWORK, &path, None)?;
+    let data = fs::read(&path)?;
+    fs::write(&path, &data[..len - 10])?;
+    assert!(
+        read_snapshot(&path, NETWORK, None).is_err(),
+        "truncated files must be rejected"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_snapshot_untrusted_entry_size() -> Result<()> {
+    let dir = tempdir()?;
+    let path = dir.path().join("hostile.snapshot");
+    let header = SnapshotHeader {
+        network: NETWORK.to_string(),
+        anchor: anchor(),
+        metadata: anchor().to_vec(),
+        root: [0u8; 32],
+    };
+    let mut file = fs::File::create(&path)?;
+    file.write_all(b"SPACESNP\x03")?;
+    bincode::encode_into_std_write(&header, &mut file, config::standard())?;
+    // An entry claiming a value of 4 GiB
+    bincode::encode_into_std_write(
+        Some(([1u8; 32], u32::MAX as u64)),
+        &mut file,
+        config::standard(),
+    )?;
+    drop(file);
+
+    assert!(
+        read_snapshot(&path, NETWORK, None).is_err(),
+        "claimed sizes must not be allocated up front"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_snapshot_signature() -> Result<()> {
+    let dir = tempdir()?;
+    let signed = dir.path().join("signed.snapshot");
+    let unsigned = dir.path().join("unsigned.snapshot");
+    let store = populated_store()?;
+    let signer = keypair(1);
+    let trusted = signer.x_only_public_key().0;
+
+    write_snapshot(&store, NETWORK, &signed, Some(&signer))?;
+    write_snapshot(&store, NETWORK, &unsigned, None)?;
+
+    read_snapshot(&signed, NETWORK, Some(&trusted))?;
+    read_snapshot(&signed, NETWORK, None)?;
+    assert!(
+        read_snapshot(&signed, NETWORK, Some(&keypair(2).x_only_public_key().0)).is_err(),
+        "signature by another key must be rejected"
+    );
+    assert!(
+        read_snapshot(&unsigned, NETWORK, Some(&trusted)).is_err(),
+        "a trusted key requires a signature"
+    );
+    Ok(())
+}
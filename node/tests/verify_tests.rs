@@ -0,0 +1,112 @@
+use spaced::{
+    node::{protocol::constants::ChainAnchor, BlockSource},
+    replay::{RecordingBlockSource, ReplayBlockSource},
+    store::{LiveStore, Store},
+    verify,
+};
+use testutil::{bitcoind::tempfile::tempdir, mock::MockChain, tip};
+
+/// Raw key of a space that no spaceout carries
+const BOGUS_SPACE: [u8; 32] = [2u8; 32];
+
+fn latest_anchor(store: &Store) -> anyhow::Result<ChainAnchor> {
+    let snapshot = store.iter().next().expect("snapshot")?;
+    Ok(snapshot.metadata().try_into()?)
+}
+
+#[test]
+fn it_should_verify_rebuild_and_restore() -> anyhow::Result<()> {
+    let dir = tempdir()?;
+    let chain = MockChain::new();
+    let mut spaced = chain.spaced(dir.path(), 6)?;
+    let index_store = Store::memory()?;
+    spaced.block_index = Some(LiveStore {
+        state: index_store.begin(&chain.tip())?,
+        store: index_store,
+    });
+
+    chain.mine_blocks(40);
+    chain.apply_blocks(&mut spaced)?;
+    let committed = spaced.chain.state.committed_tip()?.expect("committed");
+    assert_eq!(committed.height, 36);
+
+    // corrupt the space index at the committed block
+    {
+        let mut tx = spaced.chain.store.write()?;
+        tx.insert(BOGUS_SPACE, vec![0u8; 36])?;
+        tx.metadata(committed.to_vec())?;
+        tx.commit()?;
+    }
+
+    let runtime = tokio::runtime::Runtime::new()?;
+    let report = runtime.block_on(verify::verify(&mut spaced, false, true))?;
+    assert!(
+        !report.issues.is_empty(),
+        "the bogus space should be reported"
+    );
+    let repaired = report.repaired.expect("repaired");
+    assert_eq!(repaired.removed, 1);
+
+    // both stores have a snapshot at the repaired block and journaled
+    // blocks and undo records from before the repair are dropped
+    let index = spaced.block_index.as_ref().expect("block index");
+    assert_eq!(latest_anchor(&spaced.chain.store)?, committed);
+    assert_eq!(latest_anchor(&index.store)?, committed);
+    assert!(spaced.journal.is_empty());
+    assert_eq!(spaced.undo.iter().count(), 0);
+    assert_eq!(tip(&spaced), committed);
+
+    spaced.restore(&chain)?;
+    assert_eq!(tip(&spaced), committed);
+    let mut restored = spaced.chain.store.iter().next().expect("snapshot")?;
+    assert_eq!(hex::encode(restored.compute_root()?), repaired.root);
+    assert_eq!(restored.get(&BOGUS_SPACE)?, None);
+
+    chain.apply_blocks(&mut spaced)?;
+    assert_eq!(tip(&spaced), chain.tip());
+    Ok(())
+}
+
+#[test]
+fn it_should_rederive_from_the_block_source() -> anyhow::Result<()> {
+    let dir = tempdir()?;
+    let chain = MockChain::new();
+    let mut spaced = chain.spaced(dir.path(), 6)?;
+    let genesis = tip(&spaced);
+    chain.mine_blocks(40);
+    chain.apply_blocks(&mut spaced)?;
+
+    let runtime = tokio::runtime::Runtime::new()?;
+    let report = runtime.block_on(verify::verify(&mut spaced, true, false))?;
+    let rederived = report.rederived.expect("rederived over rpc");
+    assert!(rederived.matches);
+    assert_eq!(rederived.tip, report.tip);
+    assert!(report.issues.is_empty());
+
+    // record the blocks once and verify against the recording
+    let path = dir.path().join("blocks.rec");
+    let recorder = RecordingBlockSource::create(&path, chain.clone(), false)?;
+    recorder.get_best_chain()?;
+    verify::rederive(&recorder, genesis, report.tip, &report.root)?;
+    drop(recorder);
+
+    spaced.replay = Some(ReplayBlockSource::open(&path)?);
+    let report = runtime.block_on(verify::verify(&mut spaced, true, false))?;
+    assert!(report.rederived.expect("rederived from recording").matches);
+
+    // a recording that stops early can't reach the committed tip
+    let short = dir.path().join("short.rec");
+    let recorder = RecordingBlockSource::create(&short, chain.clone(), false)?;
+    recorder.get_best_chain()?;
+    let partial = ChainAnchor {
+        hash: chain.block_at(10).expect("mined").block_hash(),
+        height: 10,
+    };
+    verify::rederive(&recorder, genesis, partial, "")?;
+    drop(recorder);
+    spaced.replay = Some(ReplayBlockSource::open(&short)?);
+    assert!(runtime
+        .block_on(verify::verify(&mut spaced, true, false))
+        .is_err());
+    Ok(())
+}
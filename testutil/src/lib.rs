@@ -1,7 +1,15 @@
 pub extern crate bitcoind;
+pub mod mock;
 pub mod spaced;
 
-use std::{fs, io, sync::Arc, time::Duration};
+use std::{
+    fs, io,
+    sync::{
+        mpsc::{Receiver, RecvTimeoutError},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 use std::path::{Path, PathBuf};
 use ::spaced::{
     jsonrpsee::tokio,
@@ -13,8 +21,11 @@ use ::spaced::{
             OutPoint, ScriptBuf, ScriptHash, Sequence, Transaction, TxIn, TxMerkleNode, TxOut,
             Txid,
         },
+        constants::ChainAnchor,
     },
     rpc::RpcClient,
+    source::{BlockEvent, BlockFetchError},
+    sync::Spaced,
 };
 use anyhow::Result;
 use bitcoind::{
@@ -103,6 +114,7 @@ impl TestRig {
                 "--bitcoin-rpc-password",
                 "password",
                 "--block-index-full",
+                "--metrics",
             ],
             view_stdout,
         };
@@ -395,3 +407,32 @@ pub fn copy_dir_all(src: impl AsRef<Path>, dst: impl AsRef<Path>) -> io::Result<
     }
     Ok(())
 }
+
+/// Receives blocks emitted by a block fetcher passing each to `on_block`
+/// until it returns true or the fetcher reports an error. Panics if that
+/// takes longer than `timeout`.
+pub fn wait_for_blocks(
+    receiver: &Receiver<BlockEvent>,
+    timeout: Duration,
+    mut on_block: impl FnMut(ChainAnchor, Block) -> bool,
+) -> std::result::Result<(), BlockFetchError> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        match receiver.recv_timeout(remaining) {
+            Ok(BlockEvent::Block(id, block)) => {
+                if on_block(id, block) {
+                    return Ok(());
+                }
+            }
+            Ok(BlockEvent::Error(e)) => return Err(e),
+            Err(RecvTimeoutError::Timeout) => panic!("Timed out after {:?}", timeout),
+            Err(RecvTimeoutError::Disconnected) => panic!("Disconnected unexpectedly"),
+        }
+    }
+}
+
+/// Tip of the chain state of a node
+pub fn tip(spaced: &Spaced) -> ChainAnchor {
+    spaced.chain.state.tip.read().expect("tip").clone()
+}
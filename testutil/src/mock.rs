@@ -0,0 +1,806 @@
+//! An in-process bitcoin chain for tests that don't need a real bitcoind.
+//!
+//! [MockChain] implements [BlockSource] directly and can serve the subset of
+//! bitcoind's JSON-RPC and REST interfaces and of the Esplora API used by
+//! spaced so that [spaced::source::BitcoinRpc], [spaced::esplora::EsploraBlockSource]
+//! and wallets can be pointed at it.
+
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    path::Path,
+    str::FromStr,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use bitcoind::anyhow::Result;
+use serde_json::{json, Value};
+use spaced::{
+    config::ExtendedNetwork,
+    headers::HeaderRules,
+    journal::Journal,
+    metrics::Metrics,
+    node::{
+        protocol::{
+            bitcoin::{
+                absolute,
+                base64::{prelude::BASE64_STANDARD, Engine},
+                block,
+                blockdata::constants::genesis_block,
+                consensus::encode::{deserialize_hex, serialize, serialize_hex},
+                hashes::Hash,
+                hex::DisplayHex,
+                opcodes::all::OP_PUSHNUM_1,
+                script::{Builder, PushBytesBuf},
+                transaction, Amount, Block, BlockHash, FeeRate, Network, OutPoint, ScriptBuf,
+                Sequence, Transaction, TxIn, TxOut, Txid, Witness, Work,
+            },
+            constants::ChainAnchor,
+        },
+        BlockSource, Node,
+    },
+    progress::SyncProgress,
+    source::{BitcoinRpc, BitcoinRpcAuth, BitcoinRpcError, JsonRpcError, TipNotifier},
+    store::{LiveStore, Store},
+    sync::Spaced,
+    undo::UndoLog,
+};
+
+const RPC_INVALID_PARAMETER: i32 = -8;
+const RPC_INVALID_ADDRESS_OR_KEY: i32 = -5;
+const RPC_DESERIALIZATION_ERROR: i32 = -22;
+const RPC_METHOD_NOT_FOUND: i32 = -32601;
+
+/// A regtest chain with a mempool that can be mined and reorged on demand
+#[derive(Clone)]
+pub struct MockChain(Arc<Mutex<MockState>>);
+
+struct MockState {
+    /// Blocks of the active chain starting at genesis
+    blocks: Vec<Block>,
+    /// Blocks disconnected from the active chain with their height, still
+    /// served by hash as bitcoind does
+    stale: Vec<(u32, Block)>,
+    mempool: Vec<(Transaction, u64)>,
+    /// Reported instead of the median time past of the tip if set
+    median_time: Option<u64>,
+    fee_rate: Option<FeeRate>,
+    /// HTTP status returned by all REST requests if set
+    rest_status: Option<&'static str>,
+    /// `user:password` JSON-RPC requests must be authenticated with if set
+    credentials: Option<String>,
+    /// JSON-RPC requests rejected for not matching the credentials
+    unauthorized: usize,
+    /// Makes coinbases of otherwise identical blocks unique
+    extra_nonce: i64,
+}
+
+impl Default for MockChain {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockChain {
+    pub fn new() -> Self {
+        Self(Arc::new(Mutex::new(MockState {
+            blocks: vec![genesis_block(Network::Regtest)],
+            stale: Vec::new(),
+            mempool: Vec::new(),
+            median_time: None,
+            fee_rate: None,
+            rest_status: None,
+            credentials: None,
+            unauthorized: 0,
+            extra_nonce: 0,
+        })))
+    }
+
+    fn state(&self) -> MutexGuard<'_, MockState> {
+        self.0.lock().expect("mock chain lock")
+    }
+
+    pub fn tip(&self) -> ChainAnchor {
+        let state = self.state();
+        ChainAnchor {
+            hash: state.blocks.last().expect("genesis").block_hash(),
+            height: state.height(),
+        }
+    }
+
+    /// Block of the active chain at `height`
+    pub fn block_at(&self, height: u32) -> Option<Block> {
+        self.state().blocks.get(height as usize).cloned()
+    }
+
+    /// Mines blocks confirming the mempool in the first one
+    pub fn mine_blocks(&self, count: usize) -> Vec<BlockHash> {
+        let mut state = self.state();
+        (0..count).map(|_| state.mine(anyone_can_spend())).collect()
+    }
+
+    /// Mines a block paying the coinbase to the given script
+    pub fn mine_block_to(&self, script_pubkey: ScriptBuf) -> BlockHash {
+        self.state().mine(script_pubkey)
+    }
+
+    /// Disconnects blocks from the tip returning their transactions to the mempool
+    pub fn invalidate_blocks(&self, count: usize) {
+        let mut state = self.state();
+        for _ in 0..count.min(state.blocks.len() - 1) {
+            let height = state.height();
+            let block = state.blocks.pop().expect("block");
+            let time = block.header.time as u64;
+            let txs: Vec<_> = block
+                .txdata
+                .iter()
+                .skip(1)
+                .map(|tx| (tx.clone(), time))
+                .collect();
+            state.mempool.splice(0..0, txs);
+            state.stale.push((height, block));
+        }
+    }
+
+    /// Replaces the last `count` blocks with new ones at the same height
+    pub fn reorg(&self, count: usize) -> Vec<BlockHash> {
+        self.invalidate_blocks(count);
+        self.mine_blocks(count)
+    }
+
+    /// Adds a transaction to the mempool without validating it
+    pub fn add_to_mempool(&self, tx: Transaction) -> Txid {
+        let mut state = self.state();
+        let txid = tx.compute_txid();
+        if !state
+            .mempool
+            .iter()
+            .any(|(entry, _)| entry.compute_txid() == txid)
+        {
+            let time = state.blocks.last().expect("genesis").header.time as u64;
+            state.mempool.push((tx, time));
+        }
+        txid
+    }
+
+    /// Builds a transaction spending the coinbase at `height`, which must
+    /// have been mined to the default anyone-can-spend script
+    pub fn spend_coinbase(&self, height: u32, outputs: Vec<TxOut>) -> Option<Transaction> {
+        let block = self.block_at(height)?;
+        Some(Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(block.txdata[0].compute_txid(), 0),
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::new(),
+            }],
+            output: outputs,
+        })
+    }
+
+    pub fn mempool(&self) -> Vec<Transaction> {
+        self.state()
+            .mempool
+            .iter()
+            .map(|(tx, _)| tx.clone())
+            .collect()
+    }
+
+    /// Overrides the median time past of the tip
+    pub fn set_median_time(&self, time: Option<u64>) {
+        self.state().median_time = time;
+    }
+
+    /// Fee rate returned by `estimatesmartfee`, which fails if unset
+    pub fn set_fee_rate(&self, fee_rate: Option<FeeRate>) {
+        self.state().fee_rate = fee_rate;
+    }
+
+    /// Fails REST requests with the given HTTP status e.g. "503 Service Unavailable"
+    pub fn set_rest_status(&self, status: Option<&'static str>) {
+        self.state().rest_status = status;
+    }
+
+    /// Requires JSON-RPC requests to use basic auth with `user:password`,
+    /// e.g. the contents of a cookie file, answering others with a 401
+    pub fn set_credentials(&self, credentials: Option<&str>) {
+        self.state().credentials = credentials.map(str::to_string);
+    }
+
+    /// Number of JSON-RPC requests rejected with a 401
+    pub fn unauthorized_requests(&self) -> usize {
+        self.state().unauthorized
+    }
+
+    /// Serves the JSON-RPC methods and REST endpoints used by spaced on a
+    /// local port returning its url
+    pub fn serve(&self) -> Result<String> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let url = format!("http://{}", listener.local_addr()?);
+        let chain = self.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else { continue };
+                let chain = chain.clone();
+                std::thread::spawn(move || {
+                    _ = chain.handle_connection(stream);
+                });
+            }
+        });
+        Ok(url)
+    }
+
+    /// Builds a node following this chain from its genesis block with
+    /// in-memory stores, keeping only the journal in `data_dir`
+    pub fn spaced(&self, data_dir: &Path, max_reorg_depth: u32) -> Result<Spaced> {
+        let genesis = ChainAnchor {
+            hash: self.block_at(0).expect("genesis").block_hash(),
+            height: 0,
+        };
+        let store = Store::memory()?;
+        let chain = LiveStore {
+            state: store.begin(&genesis)?,
+            store,
+        };
+        Ok(Spaced {
+            network: ExtendedNetwork::Regtest,
+            custom_chain: None,
+            chain,
+            block_index: None,
+            block_index_full: false,
+            rpc: BitcoinRpc::new(&self.serve()?, BitcoinRpcAuth::None),
+            blocks_dir: None,
+            rest: false,
+            zmq_hashblock: None,
+            tip_notifier: TipNotifier::default(),
+            esplora_url: None,
+            header_rules: HeaderRules::new(ExtendedNetwork::Regtest),
+            sync_progress: SyncProgress::default(),
+            metrics: Metrics::default(),
+            metrics_endpoint: false,
+            ready_max_lag: 0,
+            record_blocks: None,
+            record_blocks_overwrite: false,
+            replay: None,
+            data_dir: data_dir.to_path_buf(),
+            bind: Vec::new(),
+            num_workers: 1,
+            undo: UndoLog::memory(max_reorg_depth),
+            journal: Journal::new(data_dir.join("journal.dat")),
+            retention: None,
+        })
+    }
+
+    /// Applies the blocks above the node's tip as the protocol sync would
+    pub fn apply_blocks(&self, spaced: &mut Spaced) -> Result<()> {
+        let mut node = Node::new(false);
+        let tip = spaced.chain.state.tip.read().expect("tip").clone();
+        for height in tip.height + 1..=self.tip().height {
+            let block = self.block_at(height).expect("block");
+            let id = ChainAnchor {
+                hash: block.block_hash(),
+                height,
+            };
+            spaced.handle_block(&mut node, id, block)?;
+        }
+        Ok(())
+    }
+
+    fn handle_connection(&self, mut stream: TcpStream) -> Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line)?;
+        let mut content_length = 0;
+        let mut authorization = None;
+        loop {
+            let mut header = String::new();
+            if reader.read_line(&mut header)? == 0 || header == "\r\n" {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    content_length = value.trim().parse()?;
+                } else if name.eq_ignore_ascii_case("authorization") {
+                    authorization = Some(value.trim().to_string());
+                }
+            }
+        }
+        let mut body = vec![0u8; content_length];
+        reader.read_exact(&mut body)?;
+
+        if let Some(path) = request_line.strip_prefix("GET ") {
+            let path = path.split_whitespace().next().unwrap_or_default();
+            let result = match path.strip_prefix("/rest/") {
+                Some(path) => self.handle_rest(path),
+                None => self.handle_esplora(path),
+            };
+            let (status, body) = match result {
+                Ok(body) => ("200 OK", body),
+                Err((status, message)) => (status, message.into_bytes()),
+            };
+            write!(
+                stream,
+                "HTTP/1.1 {}\r\nContent-Type: application/octet-stream\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                status,
+                body.len()
+            )?;
+            stream.write_all(&body)?;
+            return Ok(());
+        }
+
+        if !self.is_authorized(authorization.as_deref()) {
+            self.state().unauthorized += 1;
+            write!(
+                stream,
+                "HTTP/1.1 401 Unauthorized\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+            )?;
+            return Ok(());
+        }
+
+        let request: Value = serde_json::from_slice(&body)?;
+        let params = request["params"].as_array().cloned().unwrap_or_default();
+        let (status, response) =
+            match self.handle_rpc(request["method"].as_str().unwrap_or_default(), &params) {
+                Ok(result) => (
+                    "200 OK",
+                    json!({"result": result, "error": null, "id": request["id"]}),
+                ),
+                Err(error) => (
+                    if error.code == RPC_METHOD_NOT_FOUND {
+                        "404 Not Found"
+                    } else {
+                        "500 Internal Server Error"
+                    },
+                    json!({"result": null, "error": error, "id": request["id"]}),
+                ),
+            };
+
+        let body = response.to_string();
+        write!(
+            stream,
+            "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        )?;
+        Ok(())
+    }
+
+    fn is_authorized(&self, authorization: Option<&str>) -> bool {
+        let state = self.state();
+        let Some(credentials) = state.credentials.as_ref() else {
+            return true;
+        };
+        let expected = format!("Basic {}", BASE64_STANDARD.encode(credentials));
+        authorization == Some(expected.as_str())
+    }
+
+    /// Serves binary blocks and block hashes or the HTTP status and message
+    fn handle_rest(&self, path: &str) -> Result<Vec<u8>, (&'static str, String)> {
+        let state = self.state();
+        if let Some(status) = state.rest_status {
+            return Err((status, "REST unavailable".to_string()));
+        }
+        let not_found = |what: &str| ("404 Not Found", format!("{} not found", what));
+        if let Some(hash) = path
+            .strip_prefix("block/")
+            .and_then(|hash| hash.strip_suffix(".bin"))
+        {
+            let hash = BlockHash::from_str(hash)
+                .map_err(|_| ("400 Bad Request", format!("Invalid hash: {}", hash)))?;
+            let (_, block) = state
+                .find_block(&hash)
+                .map_err(|_| not_found(&hash.to_string()))?;
+            return Ok(serialize(block));
+        }
+        if let Some(hash) = path
+            .strip_prefix("headers/1/")
+            .and_then(|hash| hash.strip_suffix(".bin"))
+        {
+            let hash = BlockHash::from_str(hash)
+                .map_err(|_| ("400 Bad Request", format!("Invalid hash: {}", hash)))?;
+            let (_, block) = state
+                .find_block(&hash)
+                .map_err(|_| not_found(&hash.to_string()))?;
+            return Ok(serialize(&block.header));
+        }
+        if let Some(height) = path
+            .strip_prefix("blockhashbyheight/")
+            .and_then(|height| height.strip_suffix(".bin"))
+        {
+            let block = height
+                .parse::<usize>()
+                .ok()
+                .and_then(|height| state.blocks.get(height))
+                .ok_or_else(|| not_found("Block height"))?;
+            return Ok(serialize(&block.block_hash()));
+        }
+        Err(not_found(path))
+    }
+
+    /// Serves the Esplora block endpoints or the HTTP status and message
+    fn handle_esplora(&self, path: &str) -> Result<Vec<u8>, (&'static str, String)> {
+        let state = self.state();
+        let not_found = || ("404 Not Found", "Block not found".to_string());
+        let block_hash = |hash: &str| {
+            BlockHash::from_str(hash)
+                .map_err(|_| ("400 Bad Request", format!("Invalid hash: {}", hash)))
+        };
+        let parts: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+        match parts.as_slice() {
+            ["blocks", "tip", "hash"] => {
+                let tip = state.blocks.last().expect("genesis");
+                Ok(tip.block_hash().to_string().into_bytes())
+            }
+            ["blocks", "tip", "height"] => Ok(state.height().to_string().into_bytes()),
+            ["block-height", height] => height
+                .parse::<usize>()
+                .ok()
+                .and_then(|height| state.blocks.get(height))
+                .map(|block| block.block_hash().to_string().into_bytes())
+                .ok_or_else(not_found),
+            ["block", hash, "raw"] => {
+                let (_, block) = state
+                    .find_block(&block_hash(hash)?)
+                    .map_err(|_| not_found())?;
+                Ok(serialize(block))
+            }
+            ["block", hash, "header"] => {
+                let (_, block) = state
+                    .find_block(&block_hash(hash)?)
+                    .map_err(|_| not_found())?;
+                Ok(serialize_hex(&block.header).into_bytes())
+            }
+            ["block", hash] => {
+                let (height, block) = state
+                    .find_block(&block_hash(hash)?)
+                    .map_err(|_| not_found())?;
+                Ok(json!({
+                    "id": block.block_hash(),
+                    "height": height,
+                    "mediantime": state.median_time_at(height),
+                })
+                .to_string()
+                .into_bytes())
+            }
+            _ => Err(("404 Not Found", format!("{} not found", path))),
+        }
+    }
+
+    fn handle_rpc(&self, method: &str, params: &[Value]) -> Result<Value, JsonRpcError> {
+        let state = self.state();
+        let param = |index: usize| params.get(index).unwrap_or(&Value::Null);
+        let block_hash = |index: usize| {
+            param(index)
+                .as_str()
+                .and_then(|hash| BlockHash::from_str(hash).ok())
+                .ok_or_else(|| rpc_error(RPC_INVALID_PARAMETER, "Invalid block hash"))
+        };
+        let txid = |index: usize| {
+            param(index)
+                .as_str()
+                .and_then(|txid| Txid::from_str(txid).ok())
+                .ok_or_else(|| rpc_error(RPC_INVALID_PARAMETER, "Invalid txid"))
+        };
+
+        match method {
+            "getblockcount" => Ok(json!(state.height())),
+            "getblockhash" => {
+                let height = param(0).as_u64().unwrap_or(u64::MAX);
+                state
+                    .blocks
+                    .get(height as usize)
+                    .map(|block| json!(block.block_hash()))
+                    .ok_or_else(|| rpc_error(RPC_INVALID_PARAMETER, "Block height out of range"))
+            }
+            "getblock" => {
+                let (height, block) = state.find_block(&block_hash(0)?)?;
+                match param(1).as_u64().unwrap_or(1) {
+                    0 => Ok(json!(serialize_hex(block))),
+                    _ => {
+                        let mut info = state.header_info(height, block);
+                        info["tx"] = json!(block
+                            .txdata
+                            .iter()
+                            .map(|tx| tx.compute_txid())
+                            .collect::<Vec<_>>());
+                        Ok(info)
+                    }
+                }
+            }
+            "getblockheader" => {
+                let (height, block) = state.find_block(&block_hash(0)?)?;
+                match param(1).as_bool().unwrap_or(true) {
+                    false => Ok(json!(serialize_hex(&block.header))),
+                    true => Ok(state.header_info(height, block)),
+                }
+            }
+            "getblockchaininfo" => {
+                let tip = state.blocks.last().expect("genesis");
+                Ok(json!({
+                    "chain": "regtest",
+                    "blocks": state.height(),
+                    "headers": state.height(),
+                    "bestblockhash": tip.block_hash(),
+                    "mediantime": state.median_time_at(state.height()),
+                    "chainwork": state.chain_work().to_be_bytes().to_lower_hex_string(),
+                    "initialblockdownload": false,
+                }))
+            }
+            "getrawtransaction" => {
+                let txid = txid(0)?;
+                let verbose = param(1).as_bool().unwrap_or(false)
+                    || param(1).as_u64().is_some_and(|verbosity| verbosity > 0);
+                let (tx, confirmed) = state.find_tx(&txid).ok_or_else(|| {
+                    rpc_error(
+                        RPC_INVALID_ADDRESS_OR_KEY,
+                        "No such mempool or blockchain transaction",
+                    )
+                })?;
+                if !verbose {
+                    return Ok(json!(serialize_hex(&tx)));
+                }
+                let mut info = json!({
+                    "txid": txid,
+                    "hash": tx.compute_wtxid(),
+                    "hex": serialize_hex(&tx),
+                });
+                if let Some(height) = confirmed {
+                    let header = state.header_info(height, &state.blocks[height as usize]);
+                    info["blockhash"] = header["hash"].clone();
+                    info["confirmations"] = header["confirmations"].clone();
+                    info["blocktime"] = header["time"].clone();
+                }
+                Ok(info)
+            }
+            "getmempoolentry" => {
+                let txid = txid(0)?;
+                let (tx, time) = state
+                    .mempool
+                    .iter()
+                    .find(|(tx, _)| tx.compute_txid() == txid)
+                    .ok_or_else(|| {
+                        rpc_error(RPC_INVALID_ADDRESS_OR_KEY, "Transaction not in mempool")
+                    })?;
+                Ok(json!({
+                    "vsize": tx.vsize(),
+                    "weight": tx.weight().to_wu(),
+                    "time": time,
+                    "height": state.height(),
+                }))
+            }
+            "sendrawtransaction" => {
+                let tx: Transaction = param(0)
+                    .as_str()
+                    .and_then(|hex| deserialize_hex(hex).ok())
+                    .ok_or_else(|| rpc_error(RPC_DESERIALIZATION_ERROR, "TX decode failed"))?;
+                drop(state);
+                Ok(json!(self.add_to_mempool(tx)))
+            }
+            "estimatesmartfee" => {
+                let blocks = param(0).as_u64().unwrap_or(6);
+                Ok(match state.fee_rate {
+                    // BTC/kvB
+                    Some(fee_rate) => json!({
+                        "feerate": fee_rate.to_sat_per_kwu() as f64 * 4.0 / 100_000_000.0,
+                        "blocks": blocks,
+                    }),
+                    None => json!({
+                        "errors": ["Insufficient data or no feerate found"],
+                        "blocks": 0,
+                    }),
+                })
+            }
+            _ => Err(rpc_error(RPC_METHOD_NOT_FOUND, "Method not found")),
+        }
+    }
+}
+
+impl MockState {
+    fn height(&self) -> u32 {
+        (self.blocks.len() - 1) as u32
+    }
+
+    fn mine(&mut self, script_pubkey: ScriptBuf) -> BlockHash {
+        let prev = self.blocks.last().expect("genesis").header;
+        let height = self.blocks.len() as u32;
+        self.extra_nonce += 1;
+
+        let mut coinbase = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: Builder::new()
+                    .push_int(height as i64)
+                    .push_int(self.extra_nonce)
+                    .into_script(),
+                sequence: Sequence::MAX,
+                witness: Witness::new(),
+            }],
+            output: vec![TxOut {
+                value: Amount::from_sat(50 * 100_000_000),
+                script_pubkey,
+            }],
+        };
+        let txs: Vec<_> = self.mempool.drain(..).map(|(tx, _)| tx).collect();
+        let segwit = txs
+            .iter()
+            .any(|tx| tx.input.iter().any(|input| !input.witness.is_empty()));
+        if segwit {
+            coinbase.input[0].witness = Witness::from_slice(&[[0u8; 32]]);
+        }
+
+        let mut block = Block {
+            header: block::Header {
+                version: block::Version::from_consensus(0x2000_0000),
+                prev_blockhash: prev.block_hash(),
+                merkle_root: Hash::all_zeros(),
+                time: prev.time + 600,
+                bits: prev.bits,
+                nonce: 0,
+            },
+            txdata: std::iter::once(coinbase).chain(txs).collect(),
+        };
+        if segwit {
+            let witness_root = block.witness_root().expect("witness root");
+            let commitment = Block::compute_witness_commitment(&witness_root, &[0u8; 32]);
+            let mut data = vec![0xaa, 0x21, 0xa9, 0xed];
+            data.extend_from_slice(commitment.as_byte_array());
+            block.txdata[0].output.push(TxOut {
+                value: Amount::ZERO,
+                script_pubkey: ScriptBuf::new_op_return(
+                    PushBytesBuf::try_from(data).expect("push bytes"),
+                ),
+            });
+        }
+        block.header.merkle_root = block.compute_merkle_root().expect("merkle root");
+        // Regtest targets are met by about every other hash
+        while block.header.validate_pow(block.header.target()).is_err() {
+            block.header.nonce += 1;
+        }
+
+        let hash = block.block_hash();
+        self.blocks.push(block);
+        hash
+    }
+
+    /// Finds a block of the active chain or one that was reorged out
+    fn find_block(&self, hash: &BlockHash) -> Result<(u32, &Block), JsonRpcError> {
+        self.blocks
+            .iter()
+            .enumerate()
+            .map(|(height, block)| (height as u32, block))
+            .chain(self.stale.iter().map(|(height, block)| (*height, block)))
+            .find(|(_, block)| block.block_hash() == *hash)
+            .ok_or_else(|| rpc_error(RPC_INVALID_ADDRESS_OR_KEY, "Block not found"))
+    }
+
+    fn is_active(&self, height: u32, block: &Block) -> bool {
+        self.blocks
+            .get(height as usize)
+            .is_some_and(|active| active.block_hash() == block.block_hash())
+    }
+
+    /// Returns the transaction and the height of the block confirming it
+    fn find_tx(&self, txid: &Txid) -> Option<(Transaction, Option<u32>)> {
+        for (height, block) in self.blocks.iter().enumerate() {
+            if let Some(tx) = block.txdata.iter().find(|tx| tx.compute_txid() == *txid) {
+                return Some((tx.clone(), Some(height as u32)));
+            }
+        }
+        self.mempool
+            .iter()
+            .find(|(tx, _)| tx.compute_txid() == *txid)
+            .map(|(tx, _)| (tx.clone(), None))
+    }
+
+    /// Stale blocks have -1 confirmations like in bitcoind
+    fn header_info(&self, height: u32, block: &Block) -> Value {
+        let active = self.is_active(height, block);
+        let confirmations = if active {
+            (self.height() - height + 1) as i64
+        } else {
+            -1
+        };
+        let mut info = json!({
+            "hash": block.block_hash(),
+            "confirmations": confirmations,
+            "height": height,
+            "version": block.header.version.to_consensus(),
+            "merkleroot": block.header.merkle_root,
+            "time": block.header.time,
+            // approximated by the active chain for stale blocks
+            "mediantime": self.median_time_at(height.min(self.height())),
+            "nonce": block.header.nonce,
+            "bits": format!("{:08x}", block.header.bits.to_consensus()),
+            "nTx": block.txdata.len(),
+        });
+        if height > 0 {
+            info["previousblockhash"] = json!(block.header.prev_blockhash);
+        }
+        if let Some(next) = self.blocks.get(height as usize + 1).filter(|_| active) {
+            info["nextblockhash"] = json!(next.block_hash());
+        }
+        info
+    }
+
+    /// Median time of the last 11 blocks unless overridden for the tip
+    fn median_time_at(&self, height: u32) -> u64 {
+        if height == self.height() {
+            if let Some(time) = self.median_time {
+                return time;
+            }
+        }
+        let end = height as usize + 1;
+        let mut times: Vec<_> = self.blocks[end.saturating_sub(11)..end]
+            .iter()
+            .map(|block| block.header.time as u64)
+            .collect();
+        times.sort();
+        times[times.len() / 2]
+    }
+
+    fn chain_work(&self) -> Work {
+        self.blocks
+            .iter()
+            .map(|block| block.header.work())
+            .fold(Work::from_be_bytes([0; 32]), |total, work| total + work)
+    }
+}
+
+fn anyone_can_spend() -> ScriptBuf {
+    Builder::new().push_opcode(OP_PUSHNUM_1).into_script()
+}
+
+fn rpc_error(code: i32, message: &str) -> JsonRpcError {
+    JsonRpcError {
+        code,
+        message: message.to_string(),
+    }
+}
+
+fn not_found(message: String) -> BitcoinRpcError {
+    BitcoinRpcError::Rpc(rpc_error(RPC_INVALID_ADDRESS_OR_KEY, &message))
+}
+
+impl BlockSource for MockChain {
+    fn get_block_hash(&self, height: u32) -> Result<BlockHash, BitcoinRpcError> {
+        self.state()
+            .blocks
+            .get(height as usize)
+            .map(|block| block.block_hash())
+            .ok_or_else(|| {
+                BitcoinRpcError::Rpc(rpc_error(
+                    RPC_INVALID_PARAMETER,
+                    "Block height out of range",
+                ))
+            })
+    }
+
+    fn get_block(&self, hash: &BlockHash) -> Result<Block, BitcoinRpcError> {
+        self.state()
+            .find_block(hash)
+            .map(|(_, block)| block.clone())
+            .map_err(|_| not_found(format!("Block {} not found", hash)))
+    }
+
+    fn get_median_time(&self) -> Result<u64, BitcoinRpcError> {
+        let state = self.state();
+        Ok(state.median_time_at(state.height()))
+    }
+
+    fn get_block_count(&self) -> Result<u64, BitcoinRpcError> {
+        Ok(self.state().height() as u64)
+    }
+
+    fn get_best_chain(&self) -> Result<ChainAnchor, BitcoinRpcError> {
+        Ok(self.tip())
+    }
+
+    fn get_best_chain_work(&self) -> Result<Option<Work>, BitcoinRpcError> {
+        Ok(Some(self.state().chain_work()))
+    }
+}
@@ -1,6 +1,9 @@
 use core::{fmt, str::FromStr};
 
-use bech32::{primitives::decode::SegwitHrpstringError, Hrp};
+use bech32::{
+    primitives::{decode::SegwitHrpstringError, hrp},
+    Hrp,
+};
 use bitcoin::blockdata::script::witness_version::WitnessVersion;
 use protocol::{
     bitcoin,
@@ -14,18 +17,95 @@ use protocol::{
 #[derive(Debug, Clone)]
 pub struct SpaceAddress(pub Address);
 
+/// Human-readable part of space addresses on a custom chain along with
+/// the bitcoin network its addresses belong to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpaceHrp {
+    hrp: Hrp,
+    network: Network,
+}
+
+/// Formats a space address with a custom chain's prefix
+pub struct SpaceAddressDisplay<'a> {
+    address: &'a SpaceAddress,
+    custom: Option<&'a SpaceHrp>,
+}
+
+impl SpaceHrp {
+    /// Encodes space addresses of `network` with `hrp` instead of the default
+    /// prefix e.g. on a custom signet
+    pub fn new(hrp: &str, network: Network) -> Result<Self, hrp::Error> {
+        Ok(Self {
+            hrp: Hrp::parse(hrp)?,
+            network,
+        })
+    }
+}
+
 impl SpaceAddress {
     pub fn script_pubkey(&self) -> ScriptBuf {
         self.0.script_pubkey()
     }
+
+    /// Parses an address with one of the default prefixes or the custom one
+    pub fn parse(s: &str, custom: Option<&SpaceHrp>) -> Result<Self, ParseError> {
+        // try bech32
+        let bech32_prefix = find_bech32_prefix(s);
+        let network = match (bech32_prefix, custom) {
+            (prefix, Some(custom)) if prefix.eq_ignore_ascii_case(custom.hrp.as_str()) => {
+                Some(custom.network)
+            }
+            // note that upper or lowercase is allowed but NOT mixed case
+            ("bcs" | "BCS", _) => Some(Network::Bitcoin),
+            ("tbs" | "TBS", _) => Some(Network::Testnet),
+            ("bcrts" | "BCRTS", _) => Some(Network::Regtest),
+            _ => None,
+        };
+
+        if let Some(network) = network {
+            let (_hrp, version, data) = bech32::segwit::decode(s)?;
+
+            let version = WitnessVersion::try_from(version).expect("we know this is in range 0-16");
+            let witness_program = WitnessProgram::new(version, data.as_slice())?;
+
+            return Ok(SpaceAddress(Address::from_witness_program(
+                witness_program,
+                network,
+            )));
+        }
+
+        Err(ParseError::Bech32(SegwitHrpstringError::NoData.into()))
+    }
+
+    /// Displays the address with the custom prefix if it belongs to its network
+    pub fn display_with<'a>(&'a self, custom: Option<&'a SpaceHrp>) -> SpaceAddressDisplay<'a> {
+        SpaceAddressDisplay {
+            address: self,
+            custom,
+        }
+    }
 }
 
 impl fmt::Display for SpaceAddress {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-        let program = self.0.witness_program().expect("p2tr address");
-        let address = self.0.to_string();
-        let hrp = find_bech32_prefix(&address);
-        let hrp = Hrp::parse(&format!("{}s", hrp)).expect("valid hrp");
+        self.display_with(None).fmt(fmt)
+    }
+}
+
+impl fmt::Display for SpaceAddressDisplay<'_> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let address = &self.address.0;
+        let program = address.witness_program().expect("p2tr address");
+        let hrp = match self.custom {
+            Some(custom) if address.as_unchecked().is_valid_for_network(custom.network) => {
+                custom.hrp
+            }
+            _ => {
+                let address = address.to_string();
+                let hrp = find_bech32_prefix(&address);
+                Hrp::parse(&format!("{}s", hrp)).expect("valid hrp")
+            }
+        };
 
         let version = program.version().to_fe();
         let program = program.program().as_ref();
@@ -48,29 +128,7 @@ impl FromStr for SpaceAddress {
     type Err = ParseError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        // try bech32
-        let bech32_prefix = find_bech32_prefix(s);
-        let network = match bech32_prefix {
-            // note that upper or lowercase is allowed but NOT mixed case
-            "bcs" | "BCS" => Some(Network::Bitcoin),
-            "tbs" | "TBS" => Some(Network::Testnet),
-            "bcrts" | "BCRTS" => Some(Network::Regtest),
-            _ => None,
-        };
-
-        if let Some(network) = network {
-            let (_hrp, version, data) = bech32::segwit::decode(s)?;
-
-            let version = WitnessVersion::try_from(version).expect("we know this is in range 0-16");
-            let witness_program = WitnessProgram::new(version, data.as_slice())?;
-
-            return Ok(SpaceAddress(Address::from_witness_program(
-                witness_program,
-                network,
-            )));
-        }
-
-        Err(ParseError::Bech32(SegwitHrpstringError::NoData.into()))
+        Self::parse(s, None)
     }
 }
 
@@ -40,7 +40,7 @@ use protocol::{
 use serde::{ser::SerializeSeq, Deserialize, Deserializer, Serialize, Serializer};
 use protocol::prepare::is_magic_lock_time;
 use crate::{
-    address::SpaceAddress,
+    address::{SpaceAddress, SpaceHrp},
     builder::{is_connector_dust, is_space_dust, SpacesAwareCoinSelection},
 };
 
@@ -104,6 +104,8 @@ pub struct WalletConfig {
     pub start_block: u32,
     pub network: Network,
     pub genesis_hash: Option<BlockHash>,
+    /// Prefix of space addresses on a custom chain
+    pub space_hrp: Option<SpaceHrp>,
     pub space_descriptors: WalletDescriptors,
 }
 